@@ -11,8 +11,9 @@
 //!  3. Provide an implementation of `Factory` that provides some basic information about your
 //!     plugin and can create new sources from command-line arguments.
 //!
-//!  4. Expose a public `#[no_mangle]` function called `get_factories` in the root of your crate
-//!     that returns a `Vec<Box<Factory>>` containing your newly created factory/ies.
+//!  4. Call `declare_plugin!` in the root of your crate with your newly created factory/ies.  This
+//!     defines the `get_factories` function that TB looks for, and records which version of this
+//!     crate the plugin was built against.
 //!
 //!  5. Compile as a dynamic library with `crate_type = ["cdylib"]` (I feel like `dylib` should be
 //!     the right choice, but it doesn't work as well for me).
@@ -25,33 +26,147 @@
 //! request.  Check it out for help getting started.  The `fs` and `json` plugins built into TB are
 //! good examples of more practical backends.
 
-#[macro_use]
 extern crate anyhow;
 extern crate enumflags2;
+extern crate unicode_width;
 #[macro_use]
 extern crate enumflags2_derive;
 
 use anyhow::Result;
 pub use enumflags2::BitFlags;
 
+/// Version of the interface between TB and its plugins.  It changes whenever a change to this crate
+/// would make plugins built against the old version misbehave, so that TB can refuse to load them.
+pub const ABI_VERSION: u32 = 24;
+
+/// Define the symbols TB looks for in a plugin library, given an expression for each factory the
+/// plugin provides:
+///
+/// ```ignore
+/// declare_plugin!(random::RandFactory { }, hn::HnFactory { });
+/// ```
+#[macro_export]
+macro_rules! declare_plugin {
+	($($factory:expr),* $(,)*) => {
+		#[no_mangle]
+		pub fn get_factories() -> Vec<Box<dyn $crate::Factory>> {
+			vec![$(Box::new($factory) as Box<dyn $crate::Factory>),*]
+		}
+
+		#[no_mangle]
+		pub fn tb_abi_version() -> u32 {
+			$crate::ABI_VERSION
+		}
+	};
+}
+
+/// Where lines of content that are too long are broken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wrap {
+	/// At the right edge of the screen
+	Screen,
+	/// Nowhere: long lines run off the screen, and the user can scroll sideways to read them
+	Off,
+	/// After this many columns of content, not counting the tree's indentation, or at the edge of
+	/// the screen if that comes first
+	Column(usize),
+}
+
 /// This allows the plugin being used to configure certain aspects of the display tree's behavior.
+/// These are only defaults: settings the user has chosen take precedence over them.  Construct
+/// with `..Settings::default()` so that adding fields later doesn't break your plugin.
+#[derive(Clone)]
 pub struct Settings {
 	/// Hide the root level of the tree.  If the root does not contain any useful information and
 	/// will always have children, it is sometimes more intuitive to hide the top level, making it
 	/// appear like each child is its own independent root.
 	pub hide_root: bool,
+
+	/// How many levels of the tree to expand when it is first shown.  The root counts as the
+	/// first level, so the default of 1 shows the root's children, and `usize::MAX` expands the
+	/// whole tree.
+	pub initial_expand_depth: usize,
+
+	/// Whether search queries are treated as regular expressions (the default) or matched
+	/// literally.  Literal search can be friendlier for data full of regex metacharacters.
+	pub search_regex: bool,
+
+	/// Show keyed children, such as the members of an object, sorted by key instead of in their
+	/// original order.  It is up to the backend to honor this in `Source::configure`; positional
+	/// children like array elements should never be reordered.  The user can toggle it with the
+	/// `sort keys` command.
+	pub sort_keys: bool,
+
+	/// How many lines of a node's content to show when the user truncates it with the `peek`
+	/// command, followed by a line with an ellipsis.  This keeps values that wrap to many lines,
+	/// like long strings, from taking over the screen.
+	pub truncate_lines: usize,
+
+	/// When an expandable value turns out to have no children, show a muted `(empty)` line under
+	/// it, so that it doesn't look the same expanded as collapsed.  Turn this off if the backend
+	/// adds its own marker for empty values.
+	pub show_empty_marker: bool,
+
+	/// Show the number of children after each collapsed expandable value, like `{...} (12)`.  This
+	/// loads the children of every value as soon as it appears in the tree, so leave it off if
+	/// listing children is slow, as it is for directories or network resources.
+	pub show_counts: bool,
+
+	/// How to break long lines of content.  Backends for data with long lines whose layout
+	/// matters, like logs or tables, might prefer `Wrap::Off`.
+	pub wrap: Wrap,
+
+	/// How many columns each level of the tree is indented by, including the branch lines drawn
+	/// in them.  It must be at least 2.
+	pub indent: usize,
+
+	/// Break long lines at whitespace rather than in the middle of words, where possible.  Words
+	/// too long for a line of their own are still broken.  This suits backends whose content is
+	/// prose.
+	pub word_wrap: bool,
+}
+
+impl Default for Settings {
+	fn default() -> Self {
+		Settings {
+			hide_root: false,
+			initial_expand_depth: 1,
+			search_regex: true,
+			sort_keys: false,
+			truncate_lines: 1,
+			show_empty_marker: true,
+			show_counts: false,
+			wrap: Wrap::Screen,
+			indent: 4,
+			word_wrap: false,
+		}
+	}
 }
 
 /// Small enum used when a rendering mode needs to be selected.  Backend values can be rendered
-/// normally for display, or differently for search and clipboard-copy purposes.
+/// normally for display, or differently for search and clipboard-copy purposes.  `Keys` and
+/// `Values` are searches restricted by the user to keys or values; mark which parts of a value are
+/// which with `fmt::key` and `fmt::val`.  Unmarked text is matched by both.
 #[derive(EnumFlags, Copy, Clone, Debug, PartialEq)]
 #[repr(u32)]
 pub enum Render {
 	Debug = 0x1,
 	Search = 0x2,
 	Yank = 0x4,
+	Keys = 0x8,
+	Values = 0x10,
 }
 
+/// The regular text color, the first of the foreground colors TB reserves for itself.  These can
+/// be used with `Format::RawColor` regardless of what `Factory::colors` returns.
+pub const FG_REGULAR: usize = 0;
+
+/// The reserved color for de-emphasized text, such as metadata or placeholders.
+pub const FG_MUTED: usize = 1;
+
+/// The reserved color for errors.
+pub const FG_ERROR: usize = 2;
+
 /// Formatting is described by an enum tree that is rendered by TB to the appropriate sequence of
 /// escapes.  All formatting functionality is provided by these enums.  If a backend uses
 /// formatting commands heavily, consider `use`ing the `fmt` module, which provides slightly
@@ -65,42 +180,105 @@ pub enum Format {
 	Container(Vec<Format>),
 
 	/// Color the enclosed format nodes with the specified color.  The first argument is the index
-	/// of a color defined by `Factory::colors`.  All sub-nodes will inherit this color, but it can
-	/// be overridden.
+	/// of a color defined by `Factory::colors`, counting from 0 no matter how many colors TB
+	/// reserves for itself: TB puts its own colors first and offsets backend indices past them when
+	/// drawing.  An index past the end of `Factory::colors` is drawn in the error color, and TB
+	/// reports it in the status bar.  All sub-nodes will inherit this color, but it can be
+	/// overridden.
 	Color(usize, Box<Format>),
 
-	/// This is the same as `Color`, only it can access the static colors defined by the internal
-	/// display module.  It is only intended for internal use.
+	/// This is the same as `Color`, only it uses the colors TB defines for itself rather than the
+	/// backend's.  These are the same for every backend; use the `FG_*` constants to refer to
+	/// them, or `fmt::muted` for the common case.
 	RawColor(usize, Box<Format>),
 
 	/// Prevent automatic line wrapping in sub-nodes.  If there is a string of characters that need
-	/// to stay together, wrap them in a `NoBreak`.  Keep it short, though -- TB does not currently
-	/// support `NoBreak`s with lines longer than the screen width.  Hard wraps and line breaks
-	/// inside `NoBreak`s are also not supported.  Relaxing these requirements is a high priority.
+	/// to stay together, wrap them in a `NoBreak`.  If they're too long to fit on a line even by
+	/// themselves, they are wrapped like any other text.  Hard wraps and line breaks inside
+	/// `NoBreak`s are not supported.
 	NoBreak(Box<Format>),
 
 	/// Exclude sub-nodes from a given type of rendering.  For example, this can be used to exclude
 	/// decorative characters from being included in string searches.
 	Exclude(BitFlags<Render>, Box<Format>),
+
+	/// Text that appears only when the value is yanked, such as a tab between a key and its value so
+	/// that pasted rows split into columns.  It isn't drawn or searched.
+	YankSep(String),
+
+	/// Draw the enclosed format nodes with the given text attributes, such as bold or underline.
+	/// These are added to any attributes of enclosing `Attr` nodes, which apply again once the
+	/// enclosed nodes end.  Terminals that can't show an attribute ignore it.
+	Attr(BitFlags<Attr>, Box<Format>),
+
+	/// Draw the enclosed format nodes on a background color, such as red and green lines in a
+	/// diff.  The index is into `Factory::colors`, as for `Color`, using the color's values as a
+	/// background.  The selection, hover, and search match backgrounds take precedence, and the
+	/// rest of the line after the value takes the background of its last line.  On terminals
+	/// without enough color pairs for every combination, the regular background is used instead.
+	Background(usize, Box<Format>),
+
+	/// Make the enclosed format nodes a hyperlink to the given URL, for terminals that support
+	/// them.  The URL itself isn't drawn, and so isn't searched either, since there would be nothing
+	/// to highlight.  For the other kinds of rendering given (usually none, or `Render::Yank`), it
+	/// follows the content after a space.
+	Link(String, BitFlags<Render>, Box<Format>),
+}
+
+/// Text attributes for `Format::Attr`.
+#[derive(EnumFlags, Copy, Clone, Debug, PartialEq)]
+#[repr(u32)]
+pub enum Attr {
+	Bold = 0x1,
+	Italic = 0x2,
+	Underline = 0x4,
+	Reverse = 0x8,
 }
 
 /// To support both 8-color and 256+-color terminals, every color specification requires a standard
-/// ANSI color (0 to 7) and an XTerm color (0 to 255).
+/// ANSI color (0 to 7) and an XTerm color (0 to 255).  Either can be -1 to use the terminal's own
+/// default color, as `Color::DEFAULT` does.  A color can also give exact red, green, and blue values
+/// in `rgb`, which are used instead on terminals that support direct color.
 #[derive(Clone, Copy)]
 pub struct Color {
-	pub c8: u8,
-	pub c256: u8,
+	pub c8: i16,
+	pub c256: i16,
+	pub rgb: Option<(u8, u8, u8)>,
+}
+
+impl Color {
+	/// Whatever foreground or background color the terminal uses by default
+	pub const DEFAULT: Color = Color { c8: -1, c256: -1, rgb: None };
+}
+
+/// Identifies one of the actions a value returns from `Value::actions`.  The meaning of each ID is
+/// up to the value.
+pub type ActionId = usize;
+
+/// What TB should do once a value has been invoked, so that the value doesn't have to touch the
+/// terminal itself.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InvokeResult {
+	/// Just show the value's new content, in case invoking changed it
+	Nothing,
+	/// Reload the value and all of its children
+	Refresh,
+	/// Show a message in the status bar
+	Message(String),
+	/// Open a URL in the user's browser
+	OpenUrl(String),
 }
 
 /// A single value in the display tree.  This corresponds to a single array, object, or primitive
 /// value in JSON, a comment in a thread, a file or directory in a filesystem, or whatever other
 /// entity constitutes the nodes of the tree you are modeling.
 ///
-/// Note that `Results` are not accepted as return types.  This is because there is typically no
-/// meaningful error handling that TB can do on behalf of the backend.  Either the backend can
-/// handle the error internally -- which it should do, either silently or by creating a `Value`
-/// node exposing the error message -- or it is a fatal error and the backend should simply panic
-/// and the application will clean up and abort.
+/// Note that `Results` are mostly not accepted as return types.  This is because there is
+/// typically no meaningful error handling that TB can do on behalf of the backend.  Either the
+/// backend can handle the error internally -- which it should do, either silently or by creating a
+/// `Value` node exposing the error message -- or it is a fatal error and the backend should simply
+/// panic.  TB catches the panic and shows its message in place of the node's content or children.
+/// The exception is `try_children`, for backends whose children can fail to load.
 pub trait Value<'a> : Send {
 	/// Returns the format tree representing the content of this node.
 	fn content(&self) -> Format;
@@ -112,29 +290,108 @@ pub trait Value<'a> : Send {
 	/// The children of this node.  This is guaranteed not to be called if `expandable` is false.
 	fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>>;
 
+	/// Like `children`, but able to fail, as when the children are fetched over the network.  TB
+	/// calls this instead of `children` and shows an error in place of the children, so the user
+	/// can see what went wrong and refresh the node to try again.  The default implementation
+	/// returns the result of `children`.
+	fn try_children(&self) -> Result<Vec<Box<dyn Value<'a> + 'a>>> { Ok(self.children()) }
+
 	/// If it is desirable to format the value differently when it is collapsed, specify that
 	/// format here.  When the value is collapsed, the format returned by `placeholder` will be
 	/// used; when it is expanded, the format returned by `content` will be used.  By default, this
 	/// just mirrors `content`.
 	fn placeholder(&self) -> Format { self.content() }
 
+	/// Optionally provide a one-line summary of the value's contents, such as the first few fields
+	/// of an object, to be shown in place of the usual format while the value is collapsed.  By
+	/// default there is no summary.
+	fn summary(&self) -> Option<Format> { None }
+
 	/// Define an action to be run when the user "invokes" the value (by default, presses enter
 	/// when this node is selected).  This can be used to run some action on the current node --
 	/// for example, edit a JSON value, open a URL in a browser, or open a file in its associated
-	/// application.
-	fn invoke(&self) { }
+	/// application.  The result tells TB what to do next.
+	fn invoke(&self) -> InvokeResult { InvokeResult::Nothing }
+
+	/// List any further actions that make sense for this value -- such as opening the folder
+	/// containing a file, or copying a link -- each with a name to show the user and an ID to
+	/// identify it.  The user can choose one from a menu, and TB then passes its ID to `act`.  By
+	/// default there are none, and the menu just calls `invoke`.
+	fn actions(&self) -> Vec<(String, ActionId)> { vec![] }
+
+	/// Run the action with the given ID, as returned by `actions`.  Like `invoke`, the result tells
+	/// TB what to do next.
+	fn act(&self, _action: ActionId) -> InvokeResult { InvokeResult::Nothing }
+
+	/// If this value refers to another node in the same tree, such as a `$ref` in a JSON Schema,
+	/// return the path to that node, and invoking this value will select it instead of calling
+	/// `invoke`.  A path is a list of child indices, starting from the root of the tree currently
+	/// displayed -- the root of the transformed tree, if there is a transformation -- so an empty
+	/// path refers to the root itself.  Paths that don't resolve are ignored.
+	fn link_target(&self) -> Option<Vec<usize>> { None }
+
+	/// Optionally identify this value among its siblings, such as by a file name or an object key.
+	/// When a node is refreshed, its new children are matched up with the old ones by ID, so that
+	/// the ones that were expanded or selected stay that way even if they have moved.  Values
+	/// without an ID are matched by position instead.
+	fn id(&self) -> Option<String> { None }
+
+	/// Whether this value only gathers some of its parent's children under one node, like a range
+	/// of a long array, rather than being part of the document itself.  Grouping values are left out
+	/// of paths and flattened away in dumps.
+	fn grouping(&self) -> bool { false }
+
+	/// Whether this value is of the given kind, such as `array` or `null`.  A search query like
+	/// `type:null` matches values for which this returns true, rather than searching their text.
+	/// The kinds are up to the backend, and should be listed in its help text.  By default, no
+	/// kinds match.
+	fn matches_kind(&self, _kind: &str) -> bool { false }
+
+	/// Whether the user may copy this value's text, with `y` or into a shell command.  Return false
+	/// for values like passwords to keep them out of the clipboard entirely; excluding parts of the
+	/// content from `Render::Yank` only leaves those parts out.  By default, everything can be
+	/// yanked.
+	fn yankable(&self) -> bool { true }
+
+	/// What to copy when the user yanks this value, if it should be something other than its
+	/// content -- such as a story's URL rather than its title.  It is rendered like the content, so
+	/// `noyank` and `yanksep` still apply.  By default, this is `None`, and the content is copied.
+	fn yank(&self) -> Option<Format> { None }
 }
 
+/// The error returned by `Source::transform` for sources that don't support transformations.  TB
+/// recognizes it and tells the user so, rather than reporting a failed transformation.
+#[derive(Debug)]
+pub struct Unsupported;
+
+impl std::fmt::Display for Unsupported {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "This source does not support transformations")
+	}
+}
+
+impl std::error::Error for Unsupported { }
+
 /// An object that is responsible for owning of a value tree.  It can maintain any state necessary
 /// for the entire tree, and exists at least as long as any node in the tree.  It is only used on
-/// program startup, to retrieve the root of the tree.
-pub trait Source {
+/// program startup, to retrieve the root of the tree.  Values may refer back to their source, and
+/// values are `Send`, so sources must be `Sync` as well.
+pub trait Source : Send + Sync {
 	/// Return the root of the tree to be displayed.
 	fn root<'a>(&'a self) -> Box<dyn Value<'a> + 'a>;
 
 	/// Apply to the contained tree a transformation described by the provided string, returning the
-	/// transformed tree as a new source.
-	fn transform(&self, _transformation: &str) -> Result<Box<dyn Source>> { Err(anyhow!("This source does not implement transformations")) }
+	/// transformed tree as a new source.  What the string means is up to the backend (a JQ filter,
+	/// for JSON).  TB calls this with the user's input every time it changes, starting with an empty
+	/// string, which should leave the tree as it is, and further transformations are applied to the
+	/// source this returns.  An error is shown to the user, leaving the last good result on screen.
+	/// The default implementation returns `Unsupported`, which also keeps the prompt from opening.
+	fn transform(&self, _transformation: &str) -> Result<Box<dyn Source>> { Err(Unsupported.into()) }
+
+	/// Called with the tree's settings before the source is first displayed, and again whenever the
+	/// user changes them.  Sources that need to know, such as for `sort_keys`, should keep what
+	/// they need (using interior mutability) and apply it the next time children are requested.
+	fn configure(&self, _settings: &Settings) { }
 }
 
 /// Basic information about a backend.
@@ -164,17 +421,97 @@ pub trait Factory {
 	/// enter interactive mode.
 	fn from(&self, args: &[&str]) -> Option<Result<Box<dyn Source>>>;
 
+	/// Like `from`, but read the input from `reader` instead of from standard input or a file named
+	/// in the arguments, so that callers such as tests can supply it directly.  By default, the
+	/// reader is ignored and this just calls `from`, which suits backends that don't read a stream.
+	fn from_reader(&self, args: &[&str], _reader: Box<dyn std::io::Read>) -> Option<Result<Box<dyn Source>>> { self.from(args) }
+
 	/// Return a list of colors to be used in rendering the tree.  This sets the internal palette
 	/// used by the tree.  A color can then be used by specifying its index in this vector in the
-	/// `Format::Color` variant.
+	/// `Format::Color` or `Format::Background` variant.  Every index used must be in range; TB's
+	/// reserved colors don't count toward it, and are reached with `Format::RawColor` instead.
 	fn colors(&self) -> Vec<Color> { vec![] }
 
+	/// Like `colors`, but with a name for each color, like `"string"` or `"key"`, that stays the same
+	/// when colors are added or reordered so that users can refer to it.  Formats still use the
+	/// index of the color in this list.  Implement either this or `colors`; by default, the colors
+	/// from `colors` are named after their indices.
+	fn named_colors(&self) -> Vec<(String, Color)> {
+		self.colors().into_iter().enumerate().map(|(i, c)| (i.to_string(), c)).collect()
+	}
+
 	/// Configure how the tree is to render this backend.  See the `Settings` documentation for
 	/// more information.
-	fn settings(&self) -> Settings {
-		Settings {
-			hide_root: false,
-		}
+	fn settings(&self) -> Settings { Settings::default() }
+}
+
+const TABWIDTH: usize = 4;
+
+fn layout_plain(fmt: &Format, width: usize, lines: &mut Vec<String>, col: &mut usize) {
+	match fmt {
+		Format::Literal(s) => {
+			for c in s.chars() {
+				let cw = match c {
+					'\n' => {
+						lines.push(String::new());
+						*col = 0;
+						continue;
+					},
+					'\t' => TABWIDTH,
+					c => unicode_width::UnicodeWidthChar::width(c).unwrap_or(0),
+				};
+				if width > 0 && *col > 0 && *col + cw > width {
+					lines.push(String::new());
+					*col = 0;
+				}
+				let line = lines.last_mut().expect("Plain rendering has no lines");
+				match c {
+					'\t' => line.push_str(&" ".repeat(TABWIDTH)),
+					c => line.push(c),
+				}
+				*col += cw;
+			}
+		},
+		Format::Container(children) => children.iter().for_each(|child| layout_plain(child, width, lines, col)),
+		Format::Color(_, child) | Format::RawColor(_, child) | Format::Exclude(_, child) | Format::Attr(_, child) | Format::Background(_, child) | Format::Link(_, _, child) => layout_plain(child, width, lines, col),
+		Format::NoBreak(child) => {
+			let mut sub = vec![String::new()];
+			let mut subw = 0;
+			layout_plain(child, 0, &mut sub, &mut subw);
+			if width > 0 && *col > 0 && *col + subw > width {
+				lines.push(String::new());
+				*col = 0;
+			}
+			lines.last_mut().expect("Plain rendering has no lines").push_str(&sub.concat());
+			*col += subw;
+		},
+		Format::YankSep(_) => (),
+	}
+}
+
+/// Lay out a format tree as plain text wrapped to the given width (or not wrapped at all, if the
+/// width is zero), returning the lines as they would appear on screen without colors or
+/// indentation.  This is meant for testing backends' formatting without a terminal.
+pub fn render_plain(fmt: &Format, width: usize) -> Vec<String> {
+	let mut lines = vec![String::new()];
+	layout_plain(fmt, width, &mut lines, &mut 0);
+	lines
+}
+
+/// Get the text of a format tree as seen by a given kind of rendering, leaving out any parts
+/// excluded from it.  `Render::Search` gives the searchable text, and `Render::Yank` the text that
+/// is copied to the clipboard.
+pub fn render_text(fmt: &Format, kind: Render) -> String {
+	match fmt {
+		Format::Literal(s) => s.to_string(),
+		Format::Container(children) => children.iter().map(|child| render_text(child, kind)).collect::<Vec<String>>().concat(),
+		Format::Color(_, child) | Format::RawColor(_, child) | Format::NoBreak(child) | Format::Attr(_, child) | Format::Background(_, child) => render_text(child, kind),
+		Format::Link(url, render, child) => match render.contains(kind) && kind != Render::Search {
+			true => format!("{} {}", render_text(child, kind), url),
+			false => render_text(child, kind),
+		},
+		Format::Exclude(render, child) => if render.contains(kind) { String::new() } else { render_text(child, kind) },
+		Format::YankSep(s) => if kind == Render::Yank { s.to_string() } else { String::new() },
 	}
 }
 
@@ -189,5 +526,106 @@ pub mod fmt {
 	pub fn exclude(render: BitFlags<Render>, child: Format) -> Format { Format::Exclude(render, Box::new(child)) }
 	pub fn nosearch(child: Format) -> Format { Format::Exclude(BitFlags::from(Render::Search), Box::new(child)) }
 	pub fn noyank(child: Format) -> Format { Format::Exclude(BitFlags::from(Render::Yank), Box::new(child)) }
+	pub fn nodebug(child: Format) -> Format { Format::Exclude(BitFlags::from(Render::Debug), Box::new(child)) }
+	pub fn key(child: Format) -> Format { Format::Exclude(BitFlags::from(Render::Values), Box::new(child)) }
+	pub fn val(child: Format) -> Format { Format::Exclude(BitFlags::from(Render::Keys), Box::new(child)) }
 	pub fn hide(child: Format) -> Format { Format::Exclude(Render::Search | Render::Yank, Box::new(child)) }
+	pub fn muted(child: Format) -> Format { Format::RawColor(FG_MUTED, Box::new(child)) }
+	pub fn yanksep(s: &str) -> Format { Format::YankSep(s.to_string()) }
+	pub fn attr(attrs: BitFlags<Attr>, child: Format) -> Format { Format::Attr(attrs, Box::new(child)) }
+	pub fn bold(child: Format) -> Format { Format::Attr(BitFlags::from(Attr::Bold), Box::new(child)) }
+	pub fn italic(child: Format) -> Format { Format::Attr(BitFlags::from(Attr::Italic), Box::new(child)) }
+	pub fn underline(child: Format) -> Format { Format::Attr(BitFlags::from(Attr::Underline), Box::new(child)) }
+	pub fn background(c: usize, child: Format) -> Format { Format::Background(c, Box::new(child)) }
+	pub fn link(url: &str, child: Format) -> Format { Format::Link(url.to_string(), BitFlags::empty(), Box::new(child)) }
+	pub fn textlink(url: &str, child: Format) -> Format { Format::Link(url.to_string(), BitFlags::from(Render::Yank), Box::new(child)) }
+
+	/// Show binary data as a classic hex dump, sixteen bytes to a line, with the offset of each
+	/// line in the muted color and the printable ASCII characters at the end.  For large values,
+	/// consider using something like `<N bytes>` as the content and the dump as a child.
+	pub fn hexdump(data: &[u8]) -> Format {
+		const ROW: usize = 16;
+		let rows = data.chunks(ROW).enumerate().map(|(i, row)| {
+			let hex = (0..ROW).map(|j| match row.get(j) {
+				Some(b) => format!("{:02x} ", b),
+				None => "   ".to_string(),
+			}).collect::<Vec<String>>();
+			let ascii = row.iter().map(|b| if *b >= 0x20 && *b < 0x7f { *b as char } else { '.' }).collect::<String>();
+			nobreak(cat(vec![
+				muted(lit(&format!("{:08x}", i * ROW))),
+				lit(&format!("  {} {} |{}|", hex[..ROW / 2].concat(), hex[ROW / 2..].concat(), ascii)),
+			]))
+		}).collect::<Vec<Format>>();
+		let mut ret = vec![];
+		for (i, row) in rows.into_iter().enumerate() {
+			if i > 0 { ret.push(lit("\n")); }
+			ret.push(row);
+		}
+		cat(ret)
+	}
+}
+
+/// The types and formatting shortcuts most plugins need, so that `use tb_interface::prelude::*`
+/// is enough to get started.
+pub mod prelude {
+	pub use super::{Value, Source, Factory, Info, Settings, Color, Format, Attr, ActionId, InvokeResult, Unsupported, Wrap};
+	pub use super::{FG_REGULAR, FG_MUTED, FG_ERROR};
+	pub use super::fmt;
+	pub use super::fmt::*;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::fmt::*;
+
+	#[test]
+	fn test_render_plain() {
+		let f = cat(vec![color(0, lit("key")), hide(lit(": ")), lit("a value\nsecond line")]);
+		assert_eq!(render_plain(&f, 0), vec!["key: a value", "second line"]);
+		assert_eq!(render_plain(&f, 8), vec!["key: a v", "alue", "second l", "ine"]);
+		let nb = cat(vec![lit("abc "), nobreak(lit("defg"))]);
+		assert_eq!(render_plain(&nb, 6), vec!["abc ", "defg"]);
+	}
+
+	#[test]
+	fn test_render_text() {
+		let f = cat(vec![noyank(lit("key")), hide(lit(": ")), nosearch(lit("{...}"))]);
+		assert_eq!(render_text(&f, Render::Search), "key");
+		assert_eq!(render_text(&f, Render::Yank), "{...}");
+		assert_eq!(render_text(&f, Render::Debug), "key: {...}");
+		assert_eq!(render_text(&nodebug(lit("key")), Render::Debug), "");
+		assert_eq!(render_text(&link("http://a/", lit("a")), Render::Yank), "a");
+		assert_eq!(render_text(&textlink("http://a/", lit("a")), Render::Yank), "a http://a/");
+		assert_eq!(render_text(&textlink("http://a/", lit("a")), Render::Debug), "a");
+		assert_eq!(render_text(&Format::Link("http://a/".to_string(), BitFlags::all(), Box::new(lit("a"))), Render::Search), "a");
+	}
+
+	#[test]
+	fn test_unsupported_transform() {
+		struct Leaf;
+		impl<'a> Value<'a> for Leaf {
+			fn content(&self) -> Format { lit("leaf") }
+			fn expandable(&self) -> bool { false }
+			fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>> { vec![] }
+		}
+		struct LeafSource;
+		impl Source for LeafSource {
+			fn root<'a>(&'a self) -> Box<dyn Value<'a> + 'a> { Box::new(Leaf) }
+		}
+		let err = LeafSource.transform(".").err().expect("Transformation succeeded");
+		assert!(err.is::<Unsupported>());
+		assert_eq!(err.to_string(), "This source does not support transformations");
+	}
+
+	#[test]
+	fn test_hexdump() {
+		let data = (0..20).map(|i| i as u8 + 0x3c).collect::<Vec<u8>>();
+		assert_eq!(render_plain(&hexdump(&data), 0), vec![
+			"00000000  3c 3d 3e 3f 40 41 42 43  44 45 46 47 48 49 4a 4b  |<=>?@ABCDEFGHIJK|",
+			"00000010  4c 4d 4e 4f                                       |LMNO|",
+		]);
+		assert_eq!(render_plain(&hexdump(&[0, 0x7f, 0x41]), 0), vec!["00000000  00 7f 41                                          |..A|"]);
+		assert_eq!(render_plain(&hexdump(&[]), 0), vec![""]);
+	}
 }
@@ -1,9 +1,11 @@
-use ::tb_interface::*;
-use ::tb_interface::fmt::*;
+use ::tb_interface::prelude::*;
 use ::serde_json::Value as V;
 use anyhow::{Context, Result};
 use rayon::prelude::*;
 
+const ACT_LINK: ActionId = 0;
+const ACT_COMMENTS: ActionId = 1;
+
 #[derive(Clone)]
 pub struct PostInfo {
 	by: String,
@@ -61,8 +63,10 @@ impl Item {
 				Ok(ret)
 			},
 			Item::Story { info, .. } | Item::Comment { info, .. } => {
-				let jsonchildren = Self::hnjson(&format!("item/{}", info.id))?.get("kids").with_context(|| "Could not get children")?.clone();
-				Ok(serde_json::from_value(jsonchildren).with_context(|| "Children were not of requested type")?)
+				match Self::hnjson(&format!("item/{}", info.id))?.get("kids") {
+					Some(kids) => Ok(serde_json::from_value(kids.clone()).with_context(|| "Children were not of requested type")?),
+					None => Ok(vec![]), // No replies
+				}
 			}
 		}
 	}
@@ -80,14 +84,14 @@ impl<'a> Value<'a> for Item {
 			Item::Root => lit("Hacker News"),
 			Item::Story { title, url, score, descendants, info } => cat(vec![
 				noyank(cat(vec![color(0, lit(&title)), lit("\n")])),
-				lit(&url),
-				noyank(color(1, lit(&format!("\n{} points by {} {} - {} comments", score, info.by, timefmt(info.time), descendants)))),
+				link(&url, lit(&url)),
+				noyank(muted(lit(&format!("\n{} points by {} {} - {} comments", score, info.by, timefmt(info.time), descendants)))),
 			]),
 			Item::Comment { text, info, .. } => cat(vec![
-				color(1, lit(&format!("{} {}\n", info.by, timefmt(info.time)))),
+				muted(lit(&format!("{} {}\n", info.by, timefmt(info.time)))),
 				lit(&html2text::from_read(text.as_bytes(), 10090)),
 			]),
-			//Item::Error { msg } => color(1, lit(msg))
+			//Item::Error { msg } => muted(lit(msg))
 		}
 	}
 
@@ -96,16 +100,51 @@ impl<'a> Value<'a> for Item {
 	}
 
 	fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>> {
-		let ids = self.childids().unwrap_or(vec![]);
+		self.try_children().unwrap_or(vec![])
+	}
+
+	// Items that can't be read, like deleted comments, are skipped, but failing to get the list at
+	// all is shown as an error
+	fn try_children(&self) -> Result<Vec<Box<dyn Value<'a> + 'a>>> {
+		let ids = self.childids()?;
 		let ret: Vec<Item> = ids.par_iter().filter_map(|id| Self::get(*id).ok()).collect();
-		ret.into_iter().map(|x| Box::new(x) as Box<dyn Value>).collect()
+		Ok(ret.into_iter().map(|x| Box::new(x) as Box<dyn Value>).collect())
 	}
 
-	fn invoke(&self) {
-		if let Item::Story { url, .. } = &self {
-			if let Ok(browser) = std::env::var("BROWSER") {
-				let _ = std::process::Command::new(browser).arg(url).status();
-			}
+	fn invoke(&self) -> InvokeResult {
+		match self {
+			Item::Story { url, .. } => InvokeResult::OpenUrl(url.clone()),
+			_ => InvokeResult::Nothing,
+		}
+	}
+
+	fn actions(&self) -> Vec<(String, ActionId)> {
+		match self {
+			Item::Root => vec![],
+			Item::Story { .. } => vec![("open link".to_string(), ACT_LINK), ("open comments".to_string(), ACT_COMMENTS)],
+			Item::Comment { .. } => vec![("open comment".to_string(), ACT_COMMENTS)],
+		}
+	}
+
+	fn act(&self, action: ActionId) -> InvokeResult {
+		match (self, action) {
+			(Item::Story { url, .. }, ACT_LINK) => InvokeResult::OpenUrl(url.clone()),
+			(Item::Story { info, .. }, ACT_COMMENTS) | (Item::Comment { info, .. }, ACT_COMMENTS) => InvokeResult::OpenUrl(format!("https://news.ycombinator.com/item?id={}", info.id)),
+			_ => InvokeResult::Nothing,
+		}
+	}
+
+	fn yank(&self) -> Option<Format> {
+		match self {
+			Item::Story { url, .. } => Some(lit(url)),
+			_ => None,
+		}
+	}
+
+	fn id(&self) -> Option<String> {
+		match self {
+			Item::Root => None,
+			Item::Story { info, .. } | Item::Comment { info, .. } => Some(info.id.to_string()),
 		}
 	}
 }
@@ -156,10 +195,9 @@ impl Factory for HnFactory {
 		Some(Self::construct(args))
 	}
 
-	fn colors(&self) -> Vec<Color> {
+	fn named_colors(&self) -> Vec<(String, Color)> {
 		vec![
-			Color { c8: 2, c256: 2 }, // Headline
-			Color { c8: 4, c256: 244 }, // muted
+			("headline".to_string(), Color { c8: 2, c256: 2, rgb: None }),
 		]
 	}
 
@@ -167,6 +205,8 @@ impl Factory for HnFactory {
 	fn settings(&self) -> Settings {
 		Settings {
 			hide_root: true,
+			word_wrap: true,
+			..Settings::default()
 		}
 	}
 }
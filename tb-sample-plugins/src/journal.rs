@@ -0,0 +1,161 @@
+use ::tb_interface::prelude::*;
+use ::serde_json::{Map, Value as V};
+use anyhow::{Context, Result};
+
+const MAXLINES: usize = 1000; // Default number of entries to read, overridable with `-n`
+
+type Entry = Map<String, V>;
+
+fn fieldstr(v: &V) -> String {
+	match v {
+		V::String(s) => s.clone(),
+		V::Array(items) if items.iter().all(V::is_u64) => { // Non-UTF-8 fields are sent as byte arrays
+			let bytes = items.iter().filter_map(V::as_u64).map(|b| b as u8).collect::<Vec<u8>>();
+			String::from_utf8_lossy(&bytes).into_owned()
+		},
+		V::Array(items) => items.iter().map(fieldstr).collect::<Vec<String>>().join(", "), // Repeated fields
+		v => v.to_string(),
+	}
+}
+
+fn getfield(entry: &Entry, key: &str) -> String {
+	entry.get(key).map(fieldstr).unwrap_or(String::new())
+}
+
+fn timefmt(usec: &str) -> String {
+	use chrono::prelude::*;
+	match usec.parse::<i64>() {
+		Ok(usec) => timeago::Formatter::new().convert_chrono(Utc.timestamp(usec / 1_000_000, 0), Utc::now()),
+		Err(_) => "some time ago".to_string(),
+	}
+}
+
+pub enum Journal<'a> {
+	Root(&'a JournalSource),
+	Entry(&'a Entry),
+	Field(&'a str, String),
+}
+
+impl<'a> Value<'a> for Journal<'a> {
+	fn content(&self) -> Format {
+		match self {
+			Journal::Root(source) => lit(&format!("journalctl {}", source.args.join(" "))),
+			Journal::Entry(entry) => {
+				let msgcolor = match getfield(entry, "PRIORITY").parse::<u8>() {
+					Ok(p) if p <= 3 => 2,
+					Ok(4) => 3,
+					_ => 0,
+				};
+				let ident = match getfield(entry, "SYSLOG_IDENTIFIER").as_str() {
+					"" => getfield(entry, "_COMM"),
+					ident => ident.to_string(),
+				};
+				cat(vec![
+					noyank(color(0, lit(&format!("{} ", timefmt(&getfield(entry, "__REALTIME_TIMESTAMP")))))),
+					noyank(color(1, lit(&format!("{}: ", ident)))),
+					color(msgcolor, lit(&getfield(entry, "MESSAGE"))),
+				])
+			},
+			Journal::Field(name, value) => cat(vec![
+				key(noyank(color(1, lit(&format!("{}: ", name))))),
+				val(lit(value)),
+			]),
+		}
+	}
+
+	fn expandable(&self) -> bool {
+		match self {
+			Journal::Field(..) => false,
+			_ => true,
+		}
+	}
+
+	fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>> {
+		match self {
+			Journal::Root(source) => source.entries.iter().map(|entry| Box::new(Journal::Entry(entry)) as Box<dyn Value<'a> + 'a>).collect(),
+			Journal::Entry(entry) => entry.iter().map(|(k, v)| Box::new(Journal::Field(k, fieldstr(v))) as Box<dyn Value<'a> + 'a>).collect(),
+			Journal::Field(..) => vec![],
+		}
+	}
+}
+
+pub struct JournalSource {
+	args: Vec<String>, // Extra arguments passed to journalctl
+	entries: Vec<Entry>,
+}
+
+impl JournalSource {
+	fn new(args: Vec<String>) -> Result<Self> {
+		let output = std::process::Command::new("journalctl")
+			.args(&["--output=json", "--no-pager", &format!("--lines={}", MAXLINES)])
+			.args(&args)
+			.output().with_context(|| "Failed to run journalctl")?;
+		if !output.status.success() {
+			bail!("journalctl failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+		}
+		let entries = String::from_utf8_lossy(&output.stdout).lines().filter(|line| line.trim() != "")
+			.map(|line| serde_json::from_str::<Entry>(line).with_context(|| "Could not interpret journalctl output as JSON"))
+			.collect::<Result<Vec<Entry>>>()?;
+		Ok(JournalSource { args: args, entries: entries })
+	}
+}
+
+impl Source for JournalSource {
+	fn root<'a>(&'a self) -> Box<dyn Value<'a> + 'a> {
+		Box::new(Journal::Root(self))
+	}
+
+	// Transformations are extra journalctl arguments, such as `--grep=pattern` or `-p err`.  They are
+	// split at whitespace, without any shell quoting, so an argument can't contain a space.
+	fn transform(&self, transformation: &str) -> Result<Box<dyn Source>> {
+		let mut args = self.args.clone();
+		args.extend(transformation.split_whitespace().map(|arg| arg.to_string()));
+		Ok(Box::new(JournalSource::new(args)?))
+	}
+}
+
+pub struct JournalFactory { }
+
+const HELP: &str = r#"journalb: Browse the systemd journal interactively
+
+Any arguments are passed on to journalctl, as in `journalb -u sshd -p warning`.
+The most recent 1000 entries are read, unless another count is given with -n.
+
+Transformations add more journalctl arguments, such as `--grep=pattern`.  They are
+split at whitespace without any quoting, so no argument can contain a space.
+
+Part of Tree Browser <https://github.com/showermat/tb>
+Copyright (GPLv3) 2020 Matthew Schauer
+"#;
+
+impl Factory for JournalFactory {
+	fn info(&self) -> Info {
+		Info { name: "journal", desc: "Browse the systemd journal" }
+	}
+
+	fn from(&self, args: &[&str]) -> Option<Result<Box<dyn Source>>> {
+		match args {
+			&["-h"] | &["--help"] => {
+				print!("{}", HELP);
+				None
+			},
+			_ => Some(JournalSource::new(args.iter().map(|arg| arg.to_string()).collect()).map(|source| Box::new(source) as Box<dyn Source>)),
+		}
+	}
+
+	fn named_colors(&self) -> Vec<(String, Color)> {
+		vec![
+			("timestamp".to_string(), Color { c8: 4, c256: 244, rgb: None }),
+			("identifier".to_string(), Color { c8: 6, c256: 6, rgb: None }), // Also field names
+			("error".to_string(), Color { c8: 1, c256: 1, rgb: None }),
+			("warning".to_string(), Color { c8: 3, c256: 3, rgb: None }),
+		]
+	}
+
+	fn settings(&self) -> Settings {
+		Settings {
+			hide_root: true,
+			..Settings::default()
+		}
+	}
+}
@@ -1,5 +1,6 @@
 #[macro_use]
 extern crate anyhow;
+#[macro_use]
 extern crate tb_interface;
 extern crate rand;
 extern crate rand_distr;
@@ -11,15 +12,12 @@ extern crate chrono;
 extern crate timeago;
 extern crate html2text;
 
-use ::tb_interface::*;
-
 mod random;
 mod hn;
+mod journal;
 
-#[no_mangle]
-pub fn get_factories() -> Vec<Box<dyn Factory>> {
-	vec![
-		Box::new(random::RandFactory { }),
-		Box::new(hn::HnFactory { }),
-	]
-}
+declare_plugin!(
+	random::RandFactory { },
+	hn::HnFactory { },
+	journal::JournalFactory { },
+);
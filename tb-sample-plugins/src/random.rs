@@ -1,4 +1,4 @@
-use ::tb_interface::*;
+use ::tb_interface::prelude::*;
 use ::rand::Rng;
 use anyhow::Result;
 
@@ -0,0 +1,173 @@
+use ::interface::*;
+use ::interface::fmt::*;
+use anyhow::Result;
+
+// A file that couldn't be read or transformed is kept as its error message
+type File = (String, std::result::Result<Box<dyn Source>, String>);
+
+/// Several files opened with the same backend, shown under one root with a child for each file,
+/// named after it.  Files that fail to load are shown as errors without affecting the others.
+pub struct FilesSource {
+	files: Vec<File>,
+}
+
+impl FilesSource {
+	/// Open each of `paths` with `read`, which gets the file's decompressed contents.
+	pub fn open(paths: &[&str], read: impl Fn(Box<dyn std::io::Read>) -> Result<Box<dyn Source>>) -> Self {
+		let files = paths.iter().map(|path| (path.to_string(), super::open(path).and_then(&read).map_err(|e| format!("{:#}", e)))).collect();
+		FilesSource { files: files }
+	}
+}
+
+enum FilesValue<'a> {
+	Root(&'a FilesSource),
+	File(usize, &'a str, Box<dyn Value<'a> + 'a>), // Index among the files, name, and the file's own root
+	Error(&'a str, &'a str),
+}
+
+impl<'a> FilesValue<'a> {
+	fn name(name: &str) -> Format {
+		cat(vec![key(lit(name)), hide(muted(lit(": ")))])
+	}
+}
+
+impl<'a> Value<'a> for FilesValue<'a> {
+	fn placeholder(&self) -> Format {
+		match self {
+			FilesValue::File(_, name, _) => key(lit(name)),
+			_ => self.content(),
+		}
+	}
+
+	fn content(&self) -> Format {
+		match self {
+			FilesValue::Root(source) => nosearch(muted(lit(&format!("{} files", source.files.len())))),
+			FilesValue::File(_, name, value) => cat(vec![Self::name(name), value.content()]),
+			FilesValue::Error(name, msg) => cat(vec![Self::name(name), Format::RawColor(FG_ERROR, Box::new(lit(msg)))]),
+		}
+	}
+
+	fn summary(&self) -> Option<Format> {
+		match self {
+			FilesValue::File(_, name, value) => value.summary().map(|summary| cat(vec![Self::name(name), summary])),
+			_ => None,
+		}
+	}
+
+	fn expandable(&self) -> bool {
+		match self {
+			FilesValue::Root(_) => true,
+			FilesValue::File(_, _, value) => value.expandable(),
+			FilesValue::Error(..) => false,
+		}
+	}
+
+	fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>> {
+		match self {
+			FilesValue::Root(source) => source.files.iter().enumerate().map(|(i, (name, file))| Box::new(match file {
+				Ok(file) => FilesValue::File(i, name, file.root()),
+				Err(msg) => FilesValue::Error(name, msg),
+			}) as Box<dyn Value<'a> + 'a>).collect(),
+			FilesValue::File(_, _, value) => value.children(),
+			FilesValue::Error(..) => vec![],
+		}
+	}
+
+	fn try_children(&self) -> Result<Vec<Box<dyn Value<'a> + 'a>>> {
+		match self {
+			FilesValue::File(_, _, value) => value.try_children(),
+			_ => Ok(self.children()),
+		}
+	}
+
+	fn invoke(&self) -> InvokeResult {
+		match self {
+			FilesValue::File(_, _, value) => value.invoke(),
+			_ => InvokeResult::Nothing,
+		}
+	}
+
+	fn actions(&self) -> Vec<(String, ActionId)> {
+		match self {
+			FilesValue::File(_, _, value) => value.actions(),
+			_ => vec![],
+		}
+	}
+
+	fn act(&self, action: ActionId) -> InvokeResult {
+		match self {
+			FilesValue::File(_, _, value) => value.act(action),
+			_ => InvokeResult::Nothing,
+		}
+	}
+
+	fn link_target(&self) -> Option<Vec<usize>> {
+		// Links within a file are relative to its own root, which is a child of ours
+		match self {
+			FilesValue::File(i, _, value) => value.link_target().map(|path| std::iter::once(*i).chain(path).collect()),
+			_ => None,
+		}
+	}
+
+	fn id(&self) -> Option<String> {
+		match self {
+			FilesValue::Root(_) => None,
+			FilesValue::File(_, name, _) | FilesValue::Error(name, _) => Some(name.to_string()),
+		}
+	}
+
+	fn matches_kind(&self, kind: &str) -> bool {
+		match self {
+			FilesValue::File(_, _, value) => value.matches_kind(kind),
+			_ => false,
+		}
+	}
+
+	fn yankable(&self) -> bool {
+		match self {
+			FilesValue::File(_, _, value) => value.yankable(),
+			_ => true,
+		}
+	}
+
+	fn yank(&self) -> Option<Format> {
+		match self {
+			FilesValue::File(_, _, value) => value.yank(),
+			_ => None,
+		}
+	}
+}
+
+impl Source for FilesSource {
+	fn root<'a>(&'a self) -> Box<dyn Value<'a> + 'a> {
+		Box::new(FilesValue::Root(self))
+	}
+
+	// Each file is transformed separately.  If that fails for every file, as it will for a bad
+	// transformation, the transformation fails as a whole instead.
+	fn transform(&self, transformation: &str) -> Result<Box<dyn Source>> {
+		let mut first = None;
+		let mut ok = false;
+		let files = self.files.iter().map(|(name, file)| (name.clone(), match file {
+			Ok(file) => match file.transform(transformation) {
+				Ok(result) => { ok = true; Ok(result) },
+				Err(e) => {
+					let msg = format!("{:#}", e);
+					if first.is_none() { first = Some(e); }
+					Err(msg)
+				},
+			},
+			Err(msg) => Err(msg.clone()),
+		})).collect::<Vec<File>>();
+		match (ok, first) {
+			(false, Some(e)) => Err(e),
+			_ => Ok(Box::new(FilesSource { files: files })),
+		}
+	}
+
+	fn configure(&self, settings: &Settings) {
+		for (_, file) in self.files.iter() {
+			if let Ok(file) = file { file.configure(settings); }
+		}
+	}
+}
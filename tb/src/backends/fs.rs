@@ -4,6 +4,27 @@ use std::ffi::{OsStr, OsString};
 use ::interface::*;
 use anyhow::{Context, Result};
 
+const ACT_OPEN: ActionId = 0;
+const ACT_OPEN_DIR: ActionId = 1;
+const ACT_COPY_PATH: ActionId = 2;
+
+fn open(path: &Path) {
+	let path = path.as_os_str().to_os_string();
+	std::thread::spawn(move || {
+		let _ = std::process::Command::new("xdg-open").arg(path).status();
+	});
+}
+
+// A `file://` URL for the path, with everything but unreserved characters and slashes escaped
+fn file_url(path: &Path) -> String {
+	use std::os::unix::ffi::OsStrExt;
+	let escaped = path.as_os_str().as_bytes().iter().map(|b| match *b {
+		b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (*b as char).to_string(),
+		b => format!("%{:02X}", b),
+	}).collect::<String>();
+	format!("file://{}", escaped)
+}
+
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Kind {
 	Dir,
@@ -22,16 +43,16 @@ pub struct FsValue {
 }
 
 impl FsValue {
-	pub fn colors() -> Vec<(Kind, Color)> { // Is there a way to make a static value so this isn't recomputed every time?
+	pub fn colors() -> Vec<(Kind, &'static str, Color)> { // Is there a way to make a static value so this isn't recomputed every time?
 		[
-			(Kind::Dir, 27, 4),
-			(Kind::File, 231, 7),
-			(Kind::DirLink, 51, 6),
-			(Kind::FileLink, 51, 6),
-			(Kind::Special, 226, 3),
-			(Kind::Inaccessible, 196, 1),
-			(Kind::Meta, 244, 7),
-		].iter().map(|(t, c256, c8)| (*t, Color { c8: *c8, c256: *c256 })).collect()
+			(Kind::Dir, "dir", 27, 4),
+			(Kind::File, "file", 231, 7),
+			(Kind::DirLink, "dir-link", 51, 6),
+			(Kind::FileLink, "file-link", 51, 6),
+			(Kind::Special, "special", 226, 3),
+			(Kind::Inaccessible, "inaccessible", 196, 1),
+			(Kind::Meta, "meta", 244, 7),
+		].iter().map(|(t, name, c256, c8)| (*t, *name, Color { c8: *c8, c256: *c256, rgb: None })).collect()
 	}
 
 	fn metavalue<'a>(msg: &str) -> Box<dyn Value<'a> + 'a> {
@@ -62,8 +83,12 @@ impl FsValue {
 
 impl<'a> Value<'a> for FsValue {
 	fn content(&self) -> Format {
-		let color_ids = Self::colors().iter().enumerate().map(|(i, (t, _))| (t.clone(), i)).collect::<HashMap<Kind, usize>>();
-		fmt::color(color_ids[&self.kind], fmt::lit(&self.name))
+		let color_ids = Self::colors().iter().enumerate().map(|(i, (t, _, _))| (t.clone(), i)).collect::<HashMap<Kind, usize>>();
+		let name = fmt::color(color_ids[&self.kind], fmt::lit(&self.name));
+		match self.kind {
+			Kind::Meta => name,
+			_ => fmt::link(&file_url(&self.path), name),
+		}
 	}
 
 	fn expandable(&self) -> bool {
@@ -100,12 +125,51 @@ impl<'a> Value<'a> for FsValue {
 		}
 	}
 
-	fn invoke(&self) {
+	fn invoke(&self) -> InvokeResult {
 		if self.kind == Kind::File || self.kind == Kind::FileLink {
-			let path = self.path.as_os_str().to_os_string();
-			std::thread::spawn(move || {
-				let _ = std::process::Command::new("xdg-open").arg(path).status();
-			});
+			open(&self.path);
+		}
+		InvokeResult::Nothing
+	}
+
+	fn actions(&self) -> Vec<(String, ActionId)> {
+		let mut ret = vec![];
+		match self.kind {
+			Kind::Meta => return ret,
+			Kind::File | Kind::FileLink => ret.push(("open".to_string(), ACT_OPEN)),
+			Kind::Dir | Kind::DirLink => ret.push(("open folder".to_string(), ACT_OPEN)),
+			_ => (),
+		}
+		if self.path.parent().is_some() { ret.push(("open containing folder".to_string(), ACT_OPEN_DIR)); }
+		ret.push(("copy path".to_string(), ACT_COPY_PATH));
+		ret
+	}
+
+	fn act(&self, action: ActionId) -> InvokeResult {
+		match action {
+			ACT_OPEN => open(&self.path),
+			ACT_OPEN_DIR => if let Some(parent) = self.path.parent() { open(parent) },
+			ACT_COPY_PATH => return match ::arboard::Clipboard::new().and_then(|mut clip| clip.set_text(self.path.to_string_lossy().to_string())) {
+				Ok(()) => InvokeResult::Message("Copied path".to_string()),
+				Err(e) => InvokeResult::Message(format!("Couldn't copy path: {}", e)),
+			},
+			_ => (),
+		}
+		InvokeResult::Nothing
+	}
+
+	fn id(&self) -> Option<String> {
+		match self.kind {
+			Kind::Meta => None,
+			_ => Some(self.name.clone()),
+		}
+	}
+
+	fn matches_kind(&self, kind: &str) -> bool {
+		match (kind, self.kind) {
+			("dir", Kind::Dir) | ("file", Kind::File) | ("special", Kind::Special) | ("inaccessible", Kind::Inaccessible) => true,
+			("link", Kind::DirLink) | ("link", Kind::FileLink) => true,
+			_ => false,
 		}
 	}
 }
@@ -130,6 +194,7 @@ impl Factory for FsFactory {
 	fn from(&self, args: &[&str]) -> Option<Result<Box<dyn Source>>> {
 		if args.len() == 1 && ["-h", "--help"].contains(&args[0]) {
 			println!("fsb: Browse the file system interactively");
+			println!("\nSearch for type:KIND to find files of a kind, where KIND is dir, file, link, special,\nor inaccessible.");
 			None
 		}
 		else {
@@ -138,8 +203,8 @@ impl Factory for FsFactory {
 		}
 	}
 
-	fn colors(&self) -> Vec<Color> {
-		FsValue::colors().iter().map(|(_, c)| *c).collect::<Vec<Color>>()
+	fn named_colors(&self) -> Vec<(String, Color)> {
+		FsValue::colors().iter().map(|(_, name, c)| (name.to_string(), *c)).collect()
 	}
 }
 
@@ -1,3 +1,9 @@
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::process::Command;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use ::curses;
 use ::interface::*;
 use ::interface::fmt::*;
 use ::serde_json::{from_reader, Value as V};
@@ -9,18 +15,34 @@ const HI_KEY: usize = 2;
 const HI_MUT: usize = 3;
 const HI_NUM: usize = 4;
 
+// Arrays longer than this are split into groups of elements, so that expanding a huge array doesn't
+// have to build a display node for every element at once
+const CHUNK: usize = 1000;
+
 #[derive(Clone, Copy, Debug)]
 enum ParentType {
 	Root,
 	Object,
 	Array,
+	Range(usize, usize),
+}
+
+#[derive(Clone, Debug)]
+enum Step {
+	Key(String),
+	Index(usize),
 }
 
+// Values locate themselves in the document by path rather than holding a reference into it, so that
+// the document can be edited while the tree is displayed
 #[derive(Debug)]
 pub struct JsonValue<'a> {
 	key: String,
-	value: &'a V,
+	doc: &'a RwLock<V>,
+	path: Vec<Step>,
 	parent: ParentType,
+	sort: &'a AtomicBool, // Whether object members are listed in key order
+	edited: &'a AtomicBool, // Set once the document has been changed
 }
 
 impl<'a> JsonValue<'a> {
@@ -28,16 +50,54 @@ impl<'a> JsonValue<'a> {
 		super::fmtstr(s, HI_KWD)
 	}
 
+	fn with<T>(&self, f: impl FnOnce(&V) -> T) -> T {
+		let doc = self.doc.read().expect("Poisoned lock");
+		let value = self.path.iter().fold(Some(&*doc), |v, step| v.and_then(|v| match step {
+			Step::Key(k) => v.get(k.as_str()),
+			Step::Index(i) => v.get(*i),
+		}));
+		f(value.unwrap_or(&V::Null))
+	}
+
+	fn set(&self, newval: V) -> Result<()> {
+		let mut doc = self.doc.write().expect("Poisoned lock");
+		let value = self.path.iter().fold(Some(&mut *doc), |v, step| v.and_then(|v| match step {
+			Step::Key(k) => v.get_mut(k.as_str()),
+			Step::Index(i) => v.get_mut(*i),
+		}));
+		*value.ok_or(anyhow!("Edited value no longer exists in the document"))? = newval;
+		self.edited.store(true, Ordering::Relaxed);
+		Ok(())
+	}
+
+	fn child(&self, key: String, step: Option<Step>, parent: ParentType) -> Box<dyn Value<'a> + 'a> {
+		let mut path = self.path.clone();
+		path.extend(step);
+		Box::new(JsonValue { key: key, doc: self.doc, path: path, parent: parent, sort: self.sort, edited: self.edited })
+	}
+
 	fn fmtkey(&self) -> Format {
 		match self.parent {
 			ParentType::Root => nosearch(color(HI_MUT, lit("root"))),
-			ParentType::Object => noyank(color(HI_KEY, Self::fmtstr(&self.key))),
+			ParentType::Object => key(color(HI_KEY, Self::fmtstr(&self.key))),
 			ParentType::Array => hide(color(HI_MUT, Self::fmtstr(&self.key))),
+			ParentType::Range(start, end) => hide(color(HI_MUT, lit(&format!("[{}..{}]", start, end - 1)))),
 		}
 	}
 
-	fn fmtval(&self) -> Format {
-		match self.value {
+	fn elements(&self, start: usize, end: usize) -> Vec<Box<dyn Value<'a> + 'a>> {
+		if end - start <= CHUNK {
+			(start..end).map(|i| self.child(i.to_string(), Some(Step::Index(i)), ParentType::Array)).collect()
+		}
+		else {
+			let mut size = CHUNK;
+			while size * CHUNK < end - start { size *= CHUNK; }
+			(start..end).step_by(size).map(|i| self.child(String::new(), None, ParentType::Range(i, std::cmp::min(i + size, end)))).collect()
+		}
+	}
+
+	fn fmtvalue(value: &V) -> Format {
+		match value {
 			V::String(s) => color(HI_STR, Self::fmtstr(s)),
 			V::Number(n) => color(HI_NUM, lit(&n.to_string())),
 			V::Bool(b) => color(HI_KWD, lit(if *b { "true" } else { "false" })),
@@ -46,6 +106,59 @@ impl<'a> JsonValue<'a> {
 			V::Null => color(HI_KWD, lit("null")),
 		}
 	}
+
+	fn fmtval(&self) -> Format {
+		val(self.with(|value| Self::fmtvalue(value)))
+	}
+
+	fn fmtsummary(&self) -> Option<Format> {
+		self.with(|value| match value {
+			V::Object(items) if !items.is_empty() => Some(super::summary("{ ", " }", items.len(), items.iter().take(super::SUMMARY_LEN)
+				.map(|(k, v)| cat(vec![color(HI_KEY, Self::fmtstr(k)), color(HI_MUT, lit(": ")), Self::fmtvalue(v)])).collect(), HI_KWD, HI_MUT)),
+			V::Array(items) if !items.is_empty() => Some(super::summary("[", "]", items.len(), items.iter().take(super::SUMMARY_LEN).map(Self::fmtvalue).collect(), HI_KWD, HI_MUT)),
+			_ => None,
+		})
+	}
+
+	// Create a new file only we can read, with a name nobody could have guessed to put something
+	// else there first.  `create_new` also refuses to follow a symlink left in its place.
+	fn tmpfile() -> Result<(std::path::PathBuf, std::fs::File)> {
+		use std::os::unix::fs::OpenOptionsExt;
+		let mut last = None;
+		for _ in 0..16 {
+			let nonce = std::collections::hash_map::RandomState::new().build_hasher().finish();
+			let path = std::env::temp_dir().join(format!("tb-{}-{:016x}.json", std::process::id(), nonce));
+			match std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+				Ok(file) => return Ok((path, file)),
+				Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => last = Some(e),
+				Err(e) => return Err(e).with_context(|| format!("could not create {}", path.to_string_lossy())),
+			}
+		}
+		Err(last.expect("No attempts to create a temporary file")).with_context(|| "could not create a temporary file")
+	}
+
+	fn edit(&self) -> Result<()> {
+		let orig = self.with(|value| serde_json::to_string_pretty(value)).with_context(|| "could not serialize value")?;
+		let (tmpfile, mut file) = Self::tmpfile()?;
+		let written = file.write_all((orig + "\n").as_bytes()).with_context(|| format!("could not write {}", tmpfile.to_string_lossy()));
+		drop(file);
+		if let Err(e) = written {
+			let _ = std::fs::remove_file(&tmpfile);
+			return Err(e);
+		}
+		let editor = std::env::var("VISUAL").or(std::env::var("EDITOR")).unwrap_or("vi".to_string());
+		let mut words = editor.split_whitespace();
+		// Standard input might be the document we're browsing, so give the editor the terminal
+		let tty = || std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty").with_context(|| "could not open /dev/tty");
+		let res = Command::new(words.next().unwrap_or("vi")).args(words).arg(&tmpfile).stdin(tty()?).stdout(tty()?).status()
+			.with_context(|| format!("could not run editor \"{}\"", editor))
+			.and_then(|status| if status.success() { Ok(()) } else { Err(anyhow!("editor exited with {}", status)) })
+			.and_then(|_| std::fs::read_to_string(&tmpfile).with_context(|| "could not read edited value"))
+			.and_then(|text| serde_json::from_str::<V>(&text).with_context(|| "edited value is not valid JSON"))
+			.and_then(|newval| self.set(newval));
+		let _ = std::fs::remove_file(&tmpfile);
+		res
+	}
 }
 
 impl<'a> Value<'a> for JsonValue<'a> {
@@ -56,89 +169,359 @@ impl<'a> Value<'a> for JsonValue<'a> {
 	fn content(&self) -> Format {
 		match self.parent {
 			ParentType::Root => self.fmtval(),
-			_ => cat(vec![self.fmtkey(), hide(color(HI_MUT, lit(": "))), self.fmtval()]),
+			ParentType::Range(..) => self.fmtkey(),
+			ParentType::Object => super::member(self.fmtkey(), self.fmtval(), HI_MUT, true),
+			ParentType::Array => super::member(self.fmtkey(), self.fmtval(), HI_MUT, false),
+		}
+	}
+
+	fn summary(&self) -> Option<Format> {
+		match self.parent {
+			ParentType::Root => self.fmtsummary(),
+			ParentType::Range(..) => None,
+			_ => self.fmtsummary().map(|summary| super::member(self.fmtkey(), summary, HI_MUT, false)),
 		}
 	}
 
 	fn expandable(&self) -> bool {
-		match *self.value {
+		self.with(|value| match value {
 			V::Array(_) | V::Object(_) => true,
 			_ => false,
-		}
+		})
 	}
 
 	fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>> {
-		match self.value {
-			V::Array(items) =>
-				items.iter().enumerate().map(|(i, v)| Box::new(JsonValue { key: i.to_string(), value: &v, parent: ParentType::Array }) as Box<dyn Value>).collect(),
-			V::Object(items) =>
-				items.iter().map(|(k, v)| Box::new(JsonValue { key: k.to_string(), value: &v, parent: ParentType::Object }) as Box<dyn Value>).collect(),
+		self.with(|value| match (value, self.parent) {
+			(V::Array(_), ParentType::Range(start, end)) => self.elements(start, end),
+			(V::Array(items), _) => self.elements(0, items.len()),
+			(V::Object(items), _) => {
+				let mut keys = items.keys().collect::<Vec<&String>>();
+				if self.sort.load(Ordering::Relaxed) { keys.sort_by_key(|k| k.to_lowercase()); }
+				keys.into_iter().map(|k| self.child(k.to_string(), Some(Step::Key(k.to_string())), ParentType::Object)).collect()
+			},
 			_ => vec![],
+		})
+	}
+
+	/// Edit scalar values in the user's editor.  A failed edit is reported in the status bar.
+	fn invoke(&self) -> InvokeResult {
+		if !self.expandable() && curses::suspend().is_ok() {
+			let res = self.edit();
+			let _ = curses::resume();
+			if let Err(e) = res { return InvokeResult::Message(format!("{:#}", e)); }
+		}
+		InvokeResult::Nothing
+	}
+
+	// Elements of a long array give their index in the whole array, not just in their range
+	fn id(&self) -> Option<String> {
+		match self.parent {
+			ParentType::Object | ParentType::Array => Some(self.key.clone()),
+			_ => None,
+		}
+	}
+
+	fn grouping(&self) -> bool {
+		match self.parent {
+			ParentType::Range(..) => true,
+			_ => false,
 		}
 	}
+
+	fn matches_kind(&self, kind: &str) -> bool {
+		if let ParentType::Range(..) = self.parent { return false; } // Part of an array, not a value
+		self.with(|value| match (kind, value) {
+			("null", V::Null) | ("bool", V::Bool(_)) | ("number", V::Number(_)) | ("string", V::String(_)) | ("array", V::Array(_)) | ("object", V::Object(_)) => true,
+			("empty", V::String(s)) => s.is_empty(),
+			("empty", V::Array(items)) => items.is_empty(),
+			("empty", V::Object(items)) => items.is_empty(),
+			_ => false,
+		})
+	}
 }
 
 pub struct JsonSource {
-	json: V,
+	json: RwLock<V>,
+	sort: AtomicBool,
+	edited: AtomicBool,
+	raw: Option<Arc<[u8]>>, // The original text, if it came from somewhere it can't be read again
 }
 
 impl JsonSource {
+	fn new(json: V, raw: Option<Arc<[u8]>>) -> Self {
+		Self { json: RwLock::new(json), sort: AtomicBool::new(false), edited: AtomicBool::new(false), raw: raw }
+	}
+
 	pub fn read<T: std::io::Read>(input: T) -> Result<Box<dyn Source>> {
-		Ok(Box::new(Self { json: from_reader(input).with_context(|| "could not parse input as JSON")? }))
+		Ok(Box::new(Self::new(from_reader(input).with_context(|| "could not parse input as JSON")?, None)))
+	}
+
+	// Parse input that has been read into memory, keeping the original to transform
+	fn parse(raw: Arc<[u8]>) -> Result<Self> {
+		let json = serde_json::from_slice(&raw).with_context(|| "could not parse input as JSON")?;
+		Ok(Self::new(json, Some(raw)))
+	}
+
+	// The document as text to hand to a transformation: the original input if we still have it and
+	// nothing has been edited since, so that transformations see it exactly as it was given
+	fn text(&self) -> String {
+		match &self.raw {
+			Some(raw) if !self.edited.load(Ordering::Relaxed) => String::from_utf8_lossy(raw).into_owned(),
+			_ => self.json.read().expect("Poisoned lock").to_string(),
+		}
 	}
 }
 
 impl Source for JsonSource {
 	fn root<'a>(&'a self) -> Box<dyn Value<'a> + 'a> {
-		Box::new(JsonValue { key: "root".to_string(), value: &self.json, parent: ParentType::Root })
+		Box::new(JsonValue { key: "root".to_string(), doc: &self.json, path: vec![], parent: ParentType::Root, sort: &self.sort, edited: &self.edited })
 	}
 
+	#[cfg(feature = "jq")]
 	fn transform(&self, transformation: &str) -> Result<Box<dyn Source>> {
-		let result = jq_rs::run(transformation, &self.json.to_string()).map_err(|e| anyhow!("JQ filter failed: {}", e))?;
-		Ok(Box::new(Self { json: serde_json::from_str(&result).with_context(|| "JQ returned invalid JSON")? }))
+		let input = self.text();
+		let result = jq_rs::run(transformation, &input).map_err(|e| anyhow!("JQ filter failed: {}", e))?;
+		// A filter that produces nothing is not an error, so show it as null, and collect multiple outputs into an array
+		let mut outputs = serde_json::Deserializer::from_str(&result).into_iter::<V>().collect::<Result<Vec<V>, _>>().with_context(|| "JQ returned invalid JSON")?;
+		let json = match outputs.len() {
+			0 => V::Null,
+			1 => outputs.remove(0),
+			_ => V::Array(outputs),
+		};
+		let ret = Self::new(json, None);
+		ret.sort.store(self.sort.load(Ordering::Relaxed), Ordering::Relaxed);
+		Ok(Box::new(ret))
+	}
+
+	#[cfg(not(feature = "jq"))]
+	fn transform(&self, _transformation: &str) -> Result<Box<dyn Source>> {
+		Err(anyhow::Error::new(Unsupported).context("jq support not compiled in"))
+	}
+
+	fn configure(&self, settings: &Settings) {
+		self.sort.store(settings.sort_keys, Ordering::Relaxed);
 	}
 }
 
 pub struct JsonFactory { }
 
-impl Factory for JsonFactory {
-	fn info(&self) -> Info {
-		Info { name: "j", desc: "Browse JSON documents" }
-	}
+const HELP: &str = r#"jb: Browse JSON documents interactively
 
-	fn from<'a>(&self, args: &[&str]) -> Option<Result<Box<dyn Source>>> {
-		match args.get(0) {
-			Some(&"-h") | Some(&"--help") => {
-				print!(r#"jb: Browse JSON documents interactively
+Usage: jb [--root PATH] [FILE...]
 
 Provide the name of the input file to read as the sole command-line argument, or
-provide no arguments to read from standard input.
+provide no arguments to read from standard input.  Input compressed with gzip or
+zstd is decompressed automatically.  Several files are shown side by side, each
+under its own name.
+
+Arguments:
+--root PATH:  Start at the value selected by the jq filter PATH, such as .data.items
+
+Search for type:KIND to find values of a kind, where KIND is null, bool, number,
+string, array, object, or empty (for empty strings, arrays, and objects).
 
 Part of Tree Browser <https://github.com/showermat/tb>
 Copyright (GPLv3) 2020 Matthew Schauer
-"#);
-				None
-			},
-			Some(fname) => Some(std::fs::File::open(fname).with_context(|| "could not open file").and_then(|file| JsonSource::read(std::io::BufReader::new(file)))),
-			None => {
-				let stdin = std::io::stdin();
-				let inlock = stdin.lock();
-				Some(JsonSource::read(inlock))
-			},
+"#;
+
+impl JsonFactory {
+	// Take `--root PATH` out of the arguments, and open the rest like any other document backend
+	fn open(&self, args: &[&str], input: Option<Box<dyn std::io::Read>>) -> Option<Result<Box<dyn Source>>> {
+		let mut rest = vec![];
+		let mut root = None;
+		let mut iter = args.iter();
+		while let Some(arg) = iter.next() {
+			match *arg {
+				"--root" => match iter.next() {
+					Some(path) => root = Some(*path),
+					None => return Some(Err(anyhow!("--root requires a path"))),
+				},
+				arg => rest.push(arg),
+			}
+		}
+		let source = super::open_docs(&rest, input, HELP, JsonSource::read, |raw| JsonSource::parse(raw).map(|source| Box::new(source) as Box<dyn Source>))?;
+		match root {
+			Some(path) => Some(source.and_then(|source| source.transform(path).with_context(|| format!("invalid root path \"{}\"", path)))),
+			None => Some(source),
 		}
 	}
+}
 
-	fn colors(&self) -> Vec<Color> {
+impl Factory for JsonFactory {
+	fn info(&self) -> Info {
+		Info { name: "j", desc: "Browse JSON documents" }
+	}
+
+	fn from(&self, args: &[&str]) -> Option<Result<Box<dyn Source>>> {
+		self.open(args, None)
+	}
+
+	fn from_reader(&self, args: &[&str], reader: Box<dyn std::io::Read>) -> Option<Result<Box<dyn Source>>> {
+		self.open(args, Some(reader))
+	}
+
+	fn named_colors(&self) -> Vec<(String, Color)> {
 		vec![
-			Color { c8: 2, c256: 77 }, // string
-			Color { c8: 1, c256: 214 }, // keyword
-			Color { c8: 5, c256: 177 }, // key
-			Color { c8: 4, c256: 244 }, // muted
-			Color { c8: 6, c256: 204 }, // number
+			("string".to_string(), Color { c8: 2, c256: 77, rgb: None }),
+			("keyword".to_string(), Color { c8: 1, c256: 214, rgb: None }),
+			("key".to_string(), Color { c8: 5, c256: 177, rgb: None }),
+			("muted".to_string(), Color { c8: 4, c256: 244, rgb: None }),
+			("number".to_string(), Color { c8: 6, c256: 204, rgb: None }),
 		]
 	}
+
+	fn settings(&self) -> Settings {
+		Settings {
+			show_counts: true, // Counting children is cheap once the document is parsed
+			..Settings::default()
+		}
+	}
 }
 
 pub fn get_factory() -> Box<dyn Factory> {
 	Box::new(JsonFactory { })
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn dump(input: &str, sort: bool) -> String {
+		dumpsource(JsonSource::read(input.as_bytes()).expect("Invalid test JSON"), sort)
+	}
+
+	fn dumpsource(source: Box<dyn Source>, sort: bool) -> String {
+		let mut out = vec![];
+		::display::dump(source.as_ref(), Settings { hide_root: true, sort_keys: sort, ..Settings::default() }, None, usize::MAX, &mut out).expect("Dump failed");
+		String::from_utf8(out).expect("Dump was not UTF-8")
+	}
+
+	#[test]
+	fn sort_keys() {
+		let input = r#"{"b": 1, "C": [3, 1, 2], "a": {"z": 0, "y": 0}}"#; // Parsed objects are in byte order
+		assert_eq!(dump(input, false), "C\n    0: 3\n    1: 1\n    2: 2\na\n    y: 0\n    z: 0\nb: 1\n");
+		assert_eq!(dump(input, true), "a\n    y: 0\n    z: 0\nb: 1\nC\n    0: 3\n    1: 1\n    2: 2\n");
+	}
+
+	#[test]
+	fn dump_depth() {
+		let source = JsonSource::read(r#"{"a": {"b": {"c": 1}}, "d": [1]}"#.as_bytes()).expect("Invalid test JSON");
+		let mut out = vec![];
+		::display::dump(source.as_ref(), Settings { hide_root: true, ..Settings::default() }, None, 2, &mut out).expect("Dump failed");
+		assert_eq!(String::from_utf8(out).expect("Dump was not UTF-8"), "a\n    b: { c: 1 }\nd\n    0: 1\n");
+		let mut out = vec![];
+		::display::dump(source.as_ref(), Settings { hide_root: true, indent: 2, ..Settings::default() }, None, 2, &mut out).expect("Dump failed");
+		assert_eq!(String::from_utf8(out).expect("Dump was not UTF-8"), "a\n  b: { c: 1 }\nd\n  0: 1\n");
+	}
+
+	#[test]
+	fn snapshot() {
+		let input = "{\"b\": 1, \"a\": 2.50}";
+		let source = JsonSource::parse(input.as_bytes().into()).expect("Invalid test JSON");
+		assert_eq!(source.text(), input);
+		let b = JsonValue { key: "b".to_string(), doc: &source.json, path: vec![Step::Key("b".to_string())], parent: ParentType::Object, sort: &source.sort, edited: &source.edited };
+		b.set(V::from(3)).expect("Edit failed");
+		assert_eq!(source.text(), r#"{"a":2.5,"b":3}"#);
+	}
+
+	#[test]
+	fn ranges() {
+		let keys = |values: Vec<Box<dyn Value + '_>>| values.iter().map(|value| render_text(&value.content(), Render::Debug)).collect::<Vec<String>>();
+		let array = |n: usize| JsonSource::read(format!("[{}]", vec!["0"; n].join(",")).as_bytes()).expect("Invalid test JSON");
+		// Up to a full chunk is listed element by element
+		let source = array(CHUNK);
+		let elements = source.root().children();
+		assert_eq!(elements.len(), CHUNK);
+		assert_eq!(render_text(&elements[CHUNK - 1].content(), Render::Yank), "0");
+		// One more starts a second range, holding just the last element
+		let source = array(CHUNK + 1);
+		assert_eq!(keys(source.root().children()), vec!["[0..999]", "[1000..1000]"]);
+		let last = source.root().children().remove(1).children();
+		assert_eq!(last.len(), 1);
+		assert_eq!(last[0].id(), Some("1000".to_string()));
+		assert!(source.root().children()[1].grouping());
+		let dump = dumpsource(array(CHUNK + 1), false); // Dumps leave the ranges out
+		assert_eq!(dump.lines().count(), CHUNK + 1);
+		assert_eq!(dump.lines().last(), Some("1000: 0"));
+		assert!(last[0].matches_kind("number"));
+		assert!(!source.root().children()[1].matches_kind("number"));
+		// Arrays too long for a chunk of chunks get ranges of ranges.  Ranges don't look at the array's
+		// contents until they reach single elements, so an empty one stands in for a huge one here.
+		let source = JsonSource::parse("[]".as_bytes().into()).expect("Invalid test JSON");
+		let json = JsonValue { key: "root".to_string(), doc: &source.json, path: vec![], parent: ParentType::Root, sort: &source.sort, edited: &source.edited };
+		let huge = CHUNK * CHUNK * 2 + 5;
+		let outer = json.elements(0, huge);
+		assert_eq!(keys(outer), vec!["[0..999999]", "[1000000..1999999]", "[2000000..2000004]"]);
+		let inner = json.child(String::new(), None, ParentType::Range(CHUNK * CHUNK, 2 * CHUNK * CHUNK)).children();
+		assert_eq!(inner.len(), CHUNK);
+		assert_eq!(keys(inner.into_iter().skip(CHUNK - 2).collect()), vec!["[1998000..1998999]", "[1999000..1999999]"]);
+		let partial = json.child(String::new(), None, ParentType::Range(2 * CHUNK * CHUNK, huge)).children();
+		assert_eq!(keys(partial), vec!["2000000: null", "2000001: null", "2000002: null", "2000003: null", "2000004: null"]);
+	}
+
+	#[test]
+	fn tmpfile() {
+		use std::os::unix::fs::PermissionsExt;
+		let (a, _) = JsonValue::tmpfile().expect("Couldn't create temporary file");
+		let (b, _) = JsonValue::tmpfile().expect("Couldn't create temporary file");
+		let mode = std::fs::metadata(&a).map(|meta| meta.permissions().mode());
+		let _ = std::fs::remove_file(&a);
+		let _ = std::fs::remove_file(&b);
+		assert_ne!(a, b);
+		assert_eq!(mode.expect("Couldn't read temporary file") & 0o777, 0o600);
+	}
+
+	#[test]
+	fn yank() {
+		let source = JsonSource::read(r#"{"a": [1]}"#.as_bytes()).expect("Invalid test JSON");
+		let yank = |value: &Box<dyn Value + '_>| render_text(&value.content(), Render::Yank);
+		let member = &source.root().children()[0];
+		assert_eq!(yank(member), "a\t[...]");
+		assert_eq!(yank(&member.children()[0]), "1");
+	}
+
+	#[test]
+	fn compressed() {
+		use std::io::Write;
+		let input = r#"{"a": [1, 2]}"#;
+		let mut gz = ::flate2::write::GzEncoder::new(vec![], ::flate2::Compression::default());
+		gz.write_all(input.as_bytes()).expect("Compression failed");
+		let zst = ::zstd::encode_all(input.as_bytes(), 0).expect("Compression failed");
+		for data in vec![gz.finish().expect("Compression failed"), zst, input.as_bytes().to_vec()] {
+			let source = ::backends::decompress(data.as_slice()).and_then(JsonSource::read).expect("Failed to read compressed JSON");
+			assert_eq!(dumpsource(source, false), "a\n    0: 1\n    1: 2\n");
+		}
+	}
+
+	#[test]
+	fn kinds() {
+		let source = JsonSource::read(r#"[null, "", "a", [], {"b": 1}]"#.as_bytes()).expect("Invalid test JSON");
+		let kinds = |kind: &str| source.root().children().iter().map(|child| child.matches_kind(kind)).collect::<Vec<bool>>();
+		assert_eq!(kinds("null"), vec![true, false, false, false, false]);
+		assert_eq!(kinds("string"), vec![false, true, true, false, false]);
+		assert_eq!(kinds("empty"), vec![false, true, false, true, false]);
+		assert_eq!(kinds("object"), vec![false, false, false, false, true]);
+	}
+
+	#[test]
+	fn multiple_files() {
+		let dir = std::env::temp_dir();
+		let good = dir.join(format!("tb-test-{}-good.json", std::process::id()));
+		let bad = dir.join(format!("tb-test-{}-bad.json", std::process::id()));
+		std::fs::write(&good, r#"{"a": [1]}"#).expect("Failed to write test file");
+		std::fs::write(&bad, "{").expect("Failed to write test file");
+		let (good, bad) = (good.to_string_lossy().into_owned(), bad.to_string_lossy().into_owned());
+		let source = JsonFactory { }.from(&[&good, &bad]).expect("No source");
+		let _ = std::fs::remove_file(&good);
+		let _ = std::fs::remove_file(&bad);
+		let dump = dumpsource(source.expect("One bad file failed the others"), false);
+		assert_eq!(dump, format!("{}\n    a\n        0: 1\n{}: could not parse input as JSON: EOF while parsing an object at line 1 column 1\n", good, bad));
+	}
+
+	#[test]
+	fn from_reader() {
+		let source = JsonFactory { }.from_reader(&[], Box::new(r#"{"b": [1]}"#.as_bytes())).expect("No source").expect("Failed to read input");
+		assert_eq!(dumpsource(source, false), "b\n    0: 1\n");
+		assert!(JsonFactory { }.from_reader(&["file.json"], Box::new("{}".as_bytes())).expect("No source").is_err());
+	}
+}
@@ -1,13 +1,90 @@
-use ::interface::fmt;
+use std::io::{BufRead, BufReader, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use ::interface::{fmt, Format, Source};
+use anyhow::{Context, Result};
 
-fn fmtstr(s: &str, ctrlcolor: usize) -> ::interface::Format {
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Wrap the input in a decompressor if it starts like gzip or zstd data.  Otherwise, it is read as is.
+pub fn decompress<'a, T: Read + 'a>(input: T) -> Result<Box<dyn Read + 'a>> {
+	let mut input = BufReader::new(input);
+	let head = input.fill_buf().with_context(|| "could not read input")?;
+	if head.starts_with(GZIP_MAGIC) { Ok(Box::new(::flate2::bufread::MultiGzDecoder::new(input))) }
+	else if head.starts_with(ZSTD_MAGIC) { Ok(Box::new(::zstd::Decoder::with_buffer(input).with_context(|| "could not start zstd decompression")?)) }
+	else { Ok(Box::new(input)) }
+}
+
+/// Read all of a stream that can't be read again, such as standard input, decompressing it if
+/// necessary.  Sources can keep the result to go back to the original input later.
+pub fn snapshot<T: Read>(input: T) -> Result<Arc<[u8]>> {
+	let mut buf = vec![];
+	decompress(input)?.read_to_end(&mut buf).with_context(|| "could not read input")?;
+	Ok(buf.into())
+}
+
+/// Open a file for reading, decompressing it if necessary.
+pub fn open(path: &str) -> Result<Box<dyn Read>> {
+	std::fs::File::open(path).with_context(|| "could not open file").and_then(decompress)
+}
+
+/// Open the document for a backend whose only arguments are file names: `input` if given, or else
+/// the files in the arguments or standard input.  Several files are shown together in a
+/// `FilesSource`.  Input that can't be read again, like standard input, is passed to `snapshot`
+/// once it has been read into memory, and anything else to `read`.  `-h` or `--help` prints `help`.
+pub fn open_docs(args: &[&str], input: Option<Box<dyn Read>>, help: &str, read: fn(Box<dyn Read>) -> Result<Box<dyn Source>>, snapshot: fn(Arc<[u8]>) -> Result<Box<dyn Source>>) -> Option<Result<Box<dyn Source>>> {
+	match (args, input) {
+		(&["-h", ..], _) | (&["--help", ..], _) => {
+			print!("{}", help);
+			None
+		},
+		(&[_, ..], Some(_)) => Some(Err(anyhow!("Can't read from a file when given input directly"))),
+		(&[], Some(input)) => Some(self::snapshot(input).and_then(snapshot)),
+		(&[fname], None) => Some(self::open(fname).and_then(read)),
+		(&[], None) => Some(self::snapshot(std::io::stdin().lock()).and_then(snapshot)),
+		(fnames, None) => Some(Ok(Box::new(files::FilesSource::open(fnames, read)))),
+	}
+}
+
+/// How `fmtstr` shows control characters other than tab and newline.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CtrlStyle {
+	Caret, // ^A
+	Hex, // \x01
+	Picture, // The Unicode control picture, ␁
+}
+
+static CTRL_STYLE: AtomicUsize = AtomicUsize::new(CtrlStyle::Caret as usize);
+
+pub fn set_ctrl_style(style: CtrlStyle) {
+	CTRL_STYLE.store(style as usize, Ordering::Relaxed);
+}
+
+fn ctrl_style() -> CtrlStyle {
+	match CTRL_STYLE.load(Ordering::Relaxed) {
+		x if x == CtrlStyle::Hex as usize => CtrlStyle::Hex,
+		x if x == CtrlStyle::Picture as usize => CtrlStyle::Picture,
+		_ => CtrlStyle::Caret,
+	}
+}
+
+pub fn fmtstr(s: &str, ctrlcolor: usize) -> Format {
+	fmtstr_style(s, ctrlcolor, ctrl_style())
+}
+
+pub fn fmtstr_style(s: &str, ctrlcolor: usize, style: CtrlStyle) -> Format {
 	let mut parts = vec![];
 	let mut cur = "".to_string();
 	for c in s.chars() {
-		match c as i32 {
+		match c as u32 {
 			0..=8 | 11..=31 | 127 => {
-				let ctrlchar = (((c as i32 + 64) % 128) as u8 as char).to_string();
-				parts.extend(vec![fmt::lit(&cur), fmt::nosearch(fmt::nobreak(fmt::color(ctrlcolor, fmt::lit(&("^".to_string() + &ctrlchar)))))]);
+				let ctrlstr = match style {
+					CtrlStyle::Caret => format!("^{}", ((c as u32 + 64) % 128) as u8 as char),
+					CtrlStyle::Hex => format!("\\x{:02x}", c as u32),
+					CtrlStyle::Picture => std::char::from_u32(if c as u32 == 127 { 0x2421 } else { 0x2400 + c as u32 }).unwrap_or(c).to_string(),
+				};
+				parts.extend(vec![fmt::lit(&cur), fmt::nosearch(fmt::nobreak(fmt::color(ctrlcolor, fmt::lit(&ctrlstr))))]);
 				cur = "".to_string();
 			},
 			_ => cur.push(c),
@@ -17,7 +94,146 @@ fn fmtstr(s: &str, ctrlcolor: usize) -> ::interface::Format {
 	fmt::cat(parts)
 }
 
+// Apply the parameters of an SGR escape sequence to the current color, which is one of the 16 ANSI
+// colors.  Everything but the foreground color is ignored.
+fn sgr(params: &str, mut color: Option<usize>) -> Option<usize> {
+	let codes = params.split(';').map(|code| code.parse::<usize>().unwrap_or(0)).collect::<Vec<usize>>();
+	let mut i = 0;
+	while i < codes.len() {
+		match codes[i] {
+			0 | 39 => color = None,
+			c @ 30..=37 => color = Some(c - 30),
+			c @ 90..=97 => color = Some(c - 90 + 8),
+			kind @ 38 | kind @ 48 => match codes.get(i + 1) { // Extended colors, of which only the first 16 can be shown
+				Some(5) => {
+					if let (38, Some(n)) = (kind, codes.get(i + 2)) {
+						if *n < 16 { color = Some(*n); }
+					}
+					i += 2;
+				},
+				Some(2) => i += 4,
+				_ => (),
+			},
+			_ => (),
+		}
+		i += 1;
+	}
+	color
+}
+
+/// Like `fmtstr`, but interprets the ANSI color escape sequences in the string.  The 16 ANSI colors
+/// are drawn with consecutive palette colors starting at `firstcolor`.  Other escape sequences are
+/// dropped.
+pub fn fmtansi(s: &str, ctrlcolor: usize, firstcolor: usize) -> Format {
+	let mut parts = vec![];
+	let mut color = None;
+	let mut rest = s;
+	let push = |parts: &mut Vec<Format>, text: &str, color: Option<usize>| if text != "" {
+		parts.push(match color {
+			Some(c) => fmt::color(firstcolor + c, fmtstr(text, ctrlcolor)),
+			None => fmtstr(text, ctrlcolor),
+		});
+	};
+	while let Some(start) = rest.find('\x1b') {
+		push(&mut parts, &rest[..start], color);
+		let seq = &rest[start + 1..];
+		if seq.starts_with('[') { // Control sequence: parameters, then a final byte between @ and ~
+			match seq[1..].find(|c: char| c >= '@' && c <= '~') {
+				Some(end) => {
+					if seq[1 + end..].starts_with('m') { color = sgr(&seq[1..1 + end], color); }
+					rest = &seq[2 + end..];
+				},
+				None => rest = "",
+			}
+		}
+		else { // Other escapes are any intermediate bytes followed by one final character
+			let start = seq.find(|c: char| c < ' ' || c > '/').unwrap_or(seq.len());
+			rest = seq[start..].chars().next().map(|c| &seq[start + c.len_utf8()..]).unwrap_or("");
+		}
+	}
+	push(&mut parts, rest, color);
+	fmt::cat(parts)
+}
+
+/// Number of elements shown in the summary of a collapsed collection
+pub const SUMMARY_LEN: usize = 3;
+
+/// The summary of a collapsed collection of `len` elements, showing `items`, the first few of them,
+/// between `open` and `close`.  Punctuation is drawn with the backend's colors `kwdcolor` and
+/// `mutcolor`.  It's not searchable, since the same text is found again in the children.
+pub fn summary(open: &str, close: &str, len: usize, items: Vec<Format>, kwdcolor: usize, mutcolor: usize) -> Format {
+	let sep = || fmt::color(kwdcolor, fmt::lit(", "));
+	let shown = items.len();
+	let mut parts = vec![fmt::color(kwdcolor, fmt::lit(open))];
+	for (i, item) in items.into_iter().enumerate() {
+		if i > 0 { parts.push(sep()); }
+		parts.push(item);
+	}
+	if len > shown { parts.extend(vec![sep(), fmt::color(mutcolor, fmt::lit("..."))]); }
+	parts.push(fmt::color(kwdcolor, fmt::lit(close)));
+	fmt::hide(fmt::cat(parts))
+}
+
+/// A member of a collection, shown as `key: value` with the separator drawn in `mutcolor` and left
+/// out of searches.  With `tabsep`, the member is yanked as `key<Tab>value`, so that pasted rows
+/// split into columns; members of maps use this, while list elements hide their index from yanks.
+pub fn member(key: Format, value: Format, mutcolor: usize, tabsep: bool) -> Format {
+	let mut parts = vec![key, fmt::hide(fmt::color(mutcolor, fmt::lit(": ")))];
+	if tabsep { parts.push(fmt::yanksep("\t")); }
+	parts.push(value);
+	fmt::cat(parts)
+}
+
+pub mod files;
 pub mod json;
 pub mod fs;
 pub mod textproto;
 pub mod txt;
+pub mod toml;
+pub mod yaml;
+pub mod recent;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ::interface::{Format, Render, render_text};
+
+	#[test]
+	fn ctrl_styles() {
+		let render = |style| render_text(&fmtstr_style("a\x01b\tc\x7f", 0, style), Render::Debug);
+		assert_eq!(render(CtrlStyle::Caret), "a^Ab\tc^?");
+		assert_eq!(render(CtrlStyle::Hex), "a\\x01b\tc\\x7f");
+		assert_eq!(render(CtrlStyle::Picture), "a\u{2401}b\tc\u{2421}");
+	}
+
+	#[test]
+	fn collections() {
+		let items = || vec![fmt::lit("1"), fmt::lit("2")];
+		assert_eq!(render_text(&summary("[", "]", 2, items(), 0, 0), Render::Debug), "[1, 2]");
+		assert_eq!(render_text(&summary("[", "]", 5, items(), 0, 0), Render::Debug), "[1, 2, ...]");
+		assert_eq!(render_text(&summary("[", "]", 5, items(), 0, 0), Render::Search), "");
+		let pair = |tabsep| member(fmt::key(fmt::lit("a")), fmt::val(fmt::lit("b")), 0, tabsep);
+		assert_eq!(render_text(&pair(true), Render::Debug), "a: b");
+		assert_eq!(render_text(&pair(true), Render::Yank), "a\tb");
+		assert_eq!(render_text(&pair(false), Render::Yank), "ab");
+	}
+
+	#[test]
+	fn ansi_colors() {
+		let red = fmtansi("\x1b[31mred\x1b[0m", 0, 1);
+		assert_eq!(render_text(&red, Render::Debug), "red");
+		match red {
+			Format::Container(parts) => match parts.as_slice() {
+				[Format::Color(2, child)] => assert_eq!(render_text(child, Render::Debug), "red"),
+				_ => panic!("Expected a single colored part"),
+			},
+			_ => panic!("Expected a container"),
+		}
+		assert_eq!(render_text(&fmtansi("a\x1b[1;92mb\x1b[2Kc\x1b(Bd\x1b[38;5;200;39me", 0, 1), Render::Debug), "abcde");
+		assert_eq!(sgr("1;92", None), Some(10));
+		assert_eq!(sgr("38;5;3", Some(1)), Some(3));
+		assert_eq!(sgr("38;2;1;2;3;33", None), Some(3));
+		assert_eq!(sgr("48;5;1", Some(4)), Some(4));
+		assert_eq!(sgr("", Some(4)), None);
+	}
+}
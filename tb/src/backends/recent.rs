@@ -0,0 +1,139 @@
+extern crate libc;
+
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+use ::curses;
+use ::interface::*;
+use ::interface::fmt::*;
+use anyhow::{Context, Result};
+
+const MAXLEN: usize = 50; // Number of entries to keep
+
+// Each line of the file is a JSON array holding the working directory followed by the arguments
+fn path() -> Result<PathBuf> {
+	let dir = std::env::var("XDG_DATA_HOME").or(std::env::var("HOME").map(|home| home + "/.local/share")).with_context(|| "Couldn't find XDG data home")?;
+	Ok(PathBuf::from(dir).join(::APPNAME).join("recent"))
+}
+
+fn load() -> Result<Vec<Vec<String>>> {
+	let path = path()?;
+	if !path.exists() { return Ok(vec![]); }
+	let content = std::fs::read_to_string(&path).with_context(|| format!("Couldn't read {}", path.to_string_lossy()))?;
+	Ok(content.lines().filter_map(|line| serde_json::from_str::<Vec<String>>(line).ok()).filter(|entry| entry.len() > 1).collect())
+}
+
+/// Move the given invocation (backend name and arguments) to the top of the recent list.
+pub fn record(args: &[&str]) -> Result<()> {
+	// A source read from standard input can't be reopened later
+	if unsafe { libc::isatty(0) } == 0 { return Ok(()); }
+	let dir = std::env::current_dir().with_context(|| "Couldn't get working directory")?;
+	let mut entry = vec![dir.to_string_lossy().to_string()];
+	entry.extend(args.iter().map(|arg| arg.to_string()));
+	let mut entries = load()?;
+	entries.retain(|old| *old != entry);
+	entries.insert(0, entry);
+	entries.truncate(MAXLEN);
+	let path = path()?;
+	if let Some(parent) = path.parent() { std::fs::create_dir_all(parent)?; }
+	let mut file = std::fs::File::create(&path).with_context(|| format!("Couldn't write {}", path.to_string_lossy()))?;
+	for entry in entries {
+		writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+	}
+	Ok(())
+}
+
+pub struct RecentValue {
+	dir: String,
+	args: Vec<String>,
+}
+
+impl RecentValue {
+	fn run(&self) -> Result<()> {
+		// Name the process after the application so the first argument is taken as the backend
+		let status = Command::new(std::env::current_exe()?).arg0(::APPNAME).args(&self.args).current_dir(&self.dir).status()?;
+		if !status.success() { bail!("{} exited with {}", ::APPNAME, status); }
+		Ok(())
+	}
+}
+
+impl<'a> Value<'a> for RecentValue {
+	fn content(&self) -> Format {
+		cat(vec![lit(&self.args.join(" ")), color(0, lit(&format!("  ({})", self.dir)))])
+	}
+
+	fn expandable(&self) -> bool { false }
+
+	fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>> { unreachable!(); }
+
+	fn invoke(&self) -> InvokeResult {
+		if curses::suspend().is_ok() {
+			if let Err(e) = self.run() {
+				eprint!("Error: {:#}\nPress Enter to continue", e);
+				let _ = std::io::stdin().read_line(&mut String::new());
+			}
+			let _ = curses::resume();
+		}
+		InvokeResult::Nothing
+	}
+}
+
+pub struct RecentSource {
+	entries: Vec<Vec<String>>,
+}
+
+impl Source for RecentSource {
+	fn root<'a>(&'a self) -> Box<dyn Value<'a> + 'a> {
+		Box::new(RecentRoot { entries: &self.entries })
+	}
+}
+
+pub struct RecentRoot<'a> {
+	entries: &'a Vec<Vec<String>>,
+}
+
+impl<'a> Value<'a> for RecentRoot<'a> {
+	fn content(&self) -> Format { lit("") }
+
+	fn expandable(&self) -> bool { true }
+
+	fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>> {
+		self.entries.iter().map(|entry| Box::new(RecentValue { dir: entry[0].clone(), args: entry[1..].to_vec() }) as Box<dyn Value<'a> + 'a>).collect()
+	}
+}
+
+pub struct RecentFactory { }
+
+impl Factory for RecentFactory {
+	fn info(&self) -> Info {
+		Info { name: "recent", desc: "Reopen a recently browsed source" }
+	}
+
+	fn from(&self, args: &[&str]) -> Option<Result<Box<dyn Source>>> {
+		match args {
+			&["-h"] | &["--help"] => {
+				println!("recentb: List recently opened sources, and press enter on one to open it again");
+				None
+			},
+			_ => Some(load().map(|entries| Box::new(RecentSource { entries: entries }) as Box<dyn Source>)),
+		}
+	}
+
+	fn named_colors(&self) -> Vec<(String, Color)> {
+		vec![
+			("directory".to_string(), Color { c8: 4, c256: 244, rgb: None }),
+		]
+	}
+
+	fn settings(&self) -> Settings {
+		Settings {
+			hide_root: true,
+			..Settings::default()
+		}
+	}
+}
+
+pub fn get_factory() -> Box<dyn Factory> {
+	Box::new(RecentFactory { })
+}
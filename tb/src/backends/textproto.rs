@@ -31,18 +31,18 @@ impl<'a> TextprotoValue<'a> {
 	fn fmtkey(&self) -> Format {
 		match self.parent {
 			ParentType::Root => nosearch(color(HI_MUT, lit("root"))),
-			ParentType::Message => noyank(color(HI_KEY, Self::fmtstr(&self.key))),
+			ParentType::Message => key(noyank(color(HI_KEY, Self::fmtstr(&self.key)))),
 		}
 	}
 
 	fn fmtval(&self) -> Format {
-		match self.value {
+		val(match self.value {
 			V::String(s) => color(HI_STR, Self::fmtstr(s)),
 			V::Int(i) => color(HI_NUM, lit(&i.to_string())),
 			V::Float(f) => color(HI_NUM, lit(&f.to_string())),
 			V::Enum(s) => color(HI_KWD, lit(s)),
 			V::Message(items) => nosearch(color(HI_KWD, lit(if items.is_empty() { "{ }" } else { "{...}" }))),
-		}
+		})
 	}
 }
 
@@ -86,7 +86,16 @@ impl TextprotoSource {
 	pub fn read<T: std::io::Read>(mut input: T) -> Result<Box<dyn Source>> {
 		let mut buf = String::new();
 		input.read_to_string(&mut buf).with_context(|| "failed reading input file to string")?;
-		Ok(Box::new(Self { value: textproto::parse(&buf).with_context(|| "could not parse input as textproto")? }))
+		Self::parse(&buf)
+	}
+
+	fn parse(text: &str) -> Result<Box<dyn Source>> {
+		Ok(Box::new(Self { value: textproto::parse(text).with_context(|| "could not parse input as textproto")? }))
+	}
+
+	// Input that has already been read into memory, as from standard input
+	fn snapshot(raw: std::sync::Arc<[u8]>) -> Result<Box<dyn Source>> {
+		Self::parse(std::str::from_utf8(&raw).with_context(|| "input is not valid UTF-8")?)
 	}
 }
 
@@ -98,40 +107,37 @@ impl Source for TextprotoSource {
 
 pub struct TextprotoFactory { }
 
+const HELP: &str = r#"pbb: Browse Protocol Buffer text-format documents interactively
+
+Provide the name of the input file to read as the sole command-line argument, or
+provide no arguments to read from standard input.  Input compressed with gzip or
+zstd is decompressed automatically.  Several files are shown side by side, each
+under its own name.
+
+Part of Tree Browser <https://github.com/showermat/tb>
+Copyright (GPLv3) 2020 Matthew Schauer
+"#;
+
 impl Factory for TextprotoFactory {
 	fn info(&self) -> Info {
 		Info { name: "pb", desc: "Browse Protocol Buffer text-format documents" }
 	}
 
-	fn from<'a>(&self, args: &[&str]) -> Option<Result<Box<dyn Source>>> {
-		match args.get(0) {
-			Some(&"-h") | Some(&"--help") => {
-				print!(r#"pbb: Browse Protocol Buffer text-format documents interactively
-
-Provide the name of the input file to read as the sole command-line argument, or
-provide no arguments to read from standard input.
+	fn from(&self, args: &[&str]) -> Option<Result<Box<dyn Source>>> {
+		super::open_docs(args, None, HELP, TextprotoSource::read, TextprotoSource::snapshot)
+	}
 
-Part of Tree Browser <https://github.com/showermat/tb>
-Copyright (GPLv3) 2020 Matthew Schauer
-"#);
-				None
-			},
-			Some(fname) => Some(std::fs::File::open(fname).with_context(|| "could not open file").and_then(|file| TextprotoSource::read(std::io::BufReader::new(file)))),
-			None => {
-				let stdin = std::io::stdin();
-				let inlock = stdin.lock();
-				Some(TextprotoSource::read(inlock))
-			},
-		}
+	fn from_reader(&self, args: &[&str], reader: Box<dyn std::io::Read>) -> Option<Result<Box<dyn Source>>> {
+		super::open_docs(args, Some(reader), HELP, TextprotoSource::read, TextprotoSource::snapshot)
 	}
 
-	fn colors(&self) -> Vec<Color> {
+	fn named_colors(&self) -> Vec<(String, Color)> {
 		vec![
-			Color { c8: 2, c256: 77 }, // string
-			Color { c8: 1, c256: 214 }, // keyword
-			Color { c8: 5, c256: 177 }, // key
-			Color { c8: 4, c256: 244 }, // muted
-			Color { c8: 6, c256: 204 }, // number
+			("string".to_string(), Color { c8: 2, c256: 77, rgb: None }),
+			("keyword".to_string(), Color { c8: 1, c256: 214, rgb: None }),
+			("key".to_string(), Color { c8: 5, c256: 177, rgb: None }),
+			("muted".to_string(), Color { c8: 4, c256: 244, rgb: None }),
+			("number".to_string(), Color { c8: 6, c256: 204, rgb: None }),
 		]
 	}
 }
@@ -0,0 +1,257 @@
+use ::interface::*;
+use ::interface::fmt::*;
+use ::toml::Value as V;
+use std::sync::atomic::{AtomicBool, Ordering};
+use anyhow::{Context, Result};
+
+const HI_STR: usize = 0;
+const HI_KWD: usize = 1;
+const HI_KEY: usize = 2;
+const HI_MUT: usize = 3;
+const HI_NUM: usize = 4;
+const HI_FLT: usize = 5;
+const HI_DATE: usize = 6;
+
+#[derive(Clone, Copy, Debug)]
+enum ParentType {
+	Root,
+	Table,
+	Array,
+}
+
+#[derive(Debug)]
+pub struct TomlValue<'a> {
+	key: String,
+	value: &'a V,
+	parent: ParentType,
+	sort: &'a AtomicBool, // Whether table entries are listed in key order rather than document order
+}
+
+impl<'a> TomlValue<'a> {
+	fn fmtstr(s: &str) -> Format {
+		super::fmtstr(s, HI_KWD)
+	}
+
+	fn fmtkey(&self) -> Format {
+		match self.parent {
+			ParentType::Root => nosearch(color(HI_MUT, lit("root"))),
+			ParentType::Table => key(color(HI_KEY, Self::fmtstr(&self.key))),
+			ParentType::Array => hide(color(HI_MUT, Self::fmtstr(&self.key))),
+		}
+	}
+
+	fn fmtvalue(value: &V) -> Format {
+		match value {
+			V::String(s) => color(HI_STR, Self::fmtstr(s)),
+			V::Integer(i) => color(HI_NUM, lit(&i.to_string())),
+			V::Float(_) => color(HI_FLT, lit(&value.to_string())), // As written in TOML, so whole numbers keep their `.0`
+			V::Boolean(b) => color(HI_KWD, lit(if *b { "true" } else { "false" })),
+			V::Datetime(d) => color(HI_DATE, lit(&d.to_string())),
+			V::Table(items) => nosearch(color(HI_KWD, lit(if items.is_empty() { "{ }" } else { "{...}" }))),
+			V::Array(items) => nosearch(color(HI_KWD, lit(if items.is_empty() { "[ ]" } else { "[...]" }))),
+		}
+	}
+
+	fn fmtval(&self) -> Format {
+		val(Self::fmtvalue(self.value))
+	}
+
+	fn fmtsummary(&self) -> Option<Format> {
+		match self.value {
+			V::Table(items) if !items.is_empty() => Some(super::summary("{ ", " }", items.len(), items.iter().take(super::SUMMARY_LEN)
+				.map(|(k, v)| cat(vec![color(HI_KEY, Self::fmtstr(k)), color(HI_MUT, lit(": ")), Self::fmtvalue(v)])).collect(), HI_KWD, HI_MUT)),
+			V::Array(items) if !items.is_empty() => Some(super::summary("[", "]", items.len(), items.iter().take(super::SUMMARY_LEN).map(Self::fmtvalue).collect(), HI_KWD, HI_MUT)),
+			_ => None,
+		}
+	}
+}
+
+impl<'a> Value<'a> for TomlValue<'a> {
+	fn placeholder(&self) -> Format {
+		self.fmtkey()
+	}
+
+	fn content(&self) -> Format {
+		match self.parent {
+			ParentType::Root => self.fmtval(),
+			ParentType::Table => super::member(self.fmtkey(), self.fmtval(), HI_MUT, true),
+			ParentType::Array => super::member(self.fmtkey(), self.fmtval(), HI_MUT, false),
+		}
+	}
+
+	fn summary(&self) -> Option<Format> {
+		match self.parent {
+			ParentType::Root => self.fmtsummary(),
+			_ => self.fmtsummary().map(|summary| super::member(self.fmtkey(), summary, HI_MUT, false)),
+		}
+	}
+
+	fn expandable(&self) -> bool {
+		match self.value {
+			V::Array(_) | V::Table(_) => true,
+			_ => false,
+		}
+	}
+
+	fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>> {
+		match self.value {
+			V::Array(items) => items.iter().enumerate()
+				.map(|(i, v)| Box::new(TomlValue { key: i.to_string(), value: v, parent: ParentType::Array, sort: self.sort }) as Box<dyn Value<'a> + 'a>).collect(),
+			// Tables keep the order of the document unless sorted
+			V::Table(items) => {
+				let mut items = items.iter().collect::<Vec<(&String, &V)>>();
+				if self.sort.load(Ordering::Relaxed) { items.sort_by_key(|(k, _)| k.to_lowercase()); }
+				items.into_iter().map(|(k, v)| Box::new(TomlValue { key: k.to_string(), value: v, parent: ParentType::Table, sort: self.sort }) as Box<dyn Value<'a> + 'a>).collect()
+			},
+			_ => vec![],
+		}
+	}
+
+	fn id(&self) -> Option<String> {
+		match self.parent {
+			ParentType::Table => Some(self.key.clone()),
+			_ => None,
+		}
+	}
+
+	fn matches_kind(&self, kind: &str) -> bool {
+		match (kind, self.value) {
+			("string", V::String(_)) | ("integer", V::Integer(_)) | ("float", V::Float(_)) | ("bool", V::Boolean(_)) | ("datetime", V::Datetime(_)) | ("array", V::Array(_)) | ("table", V::Table(_)) => true,
+			("number", V::Integer(_)) | ("number", V::Float(_)) => true,
+			("empty", V::String(s)) => s.is_empty(),
+			("empty", V::Array(items)) => items.is_empty(),
+			("empty", V::Table(items)) => items.is_empty(),
+			_ => false,
+		}
+	}
+}
+
+pub struct TomlSource {
+	doc: V,
+	sort: AtomicBool,
+}
+
+impl TomlSource {
+	pub fn read<T: std::io::Read>(mut input: T) -> Result<Box<dyn Source>> {
+		let mut buf = String::new();
+		input.read_to_string(&mut buf).with_context(|| "failed reading input file to string")?;
+		Self::parse(&buf)
+	}
+
+	fn parse(text: &str) -> Result<Box<dyn Source>> {
+		let table = text.parse::<toml::Table>().with_context(|| "could not parse input as TOML")?;
+		Ok(Box::new(Self { doc: V::Table(table), sort: AtomicBool::new(false) }))
+	}
+
+	// Input that has already been read into memory, as from standard input
+	fn snapshot(raw: std::sync::Arc<[u8]>) -> Result<Box<dyn Source>> {
+		Self::parse(std::str::from_utf8(&raw).with_context(|| "input is not valid UTF-8")?)
+	}
+}
+
+impl Source for TomlSource {
+	fn root<'a>(&'a self) -> Box<dyn Value<'a> + 'a> {
+		Box::new(TomlValue { key: "root".to_string(), value: &self.doc, parent: ParentType::Root, sort: &self.sort })
+	}
+
+	fn configure(&self, settings: &Settings) {
+		self.sort.store(settings.sort_keys, Ordering::Relaxed);
+	}
+}
+
+pub struct TomlFactory { }
+
+const HELP: &str = r#"tomlb: Browse TOML documents interactively
+
+Provide the name of the input file to read as the sole command-line argument, or
+provide no arguments to read from standard input.  Input compressed with gzip or
+zstd is decompressed automatically.  Several files are shown side by side, each
+under its own name.
+
+Search for type:KIND to find values of a kind, where KIND is string, integer,
+float, number, bool, datetime, array, table, or empty (for empty strings, arrays,
+and tables).
+
+Part of Tree Browser <https://github.com/showermat/tb>
+Copyright (GPLv3) 2020 Matthew Schauer
+"#;
+
+impl Factory for TomlFactory {
+	fn info(&self) -> Info {
+		Info { name: "toml", desc: "Browse TOML documents" }
+	}
+
+	fn from(&self, args: &[&str]) -> Option<Result<Box<dyn Source>>> {
+		super::open_docs(args, None, HELP, TomlSource::read, TomlSource::snapshot)
+	}
+
+	fn from_reader(&self, args: &[&str], reader: Box<dyn std::io::Read>) -> Option<Result<Box<dyn Source>>> {
+		super::open_docs(args, Some(reader), HELP, TomlSource::read, TomlSource::snapshot)
+	}
+
+	fn named_colors(&self) -> Vec<(String, Color)> {
+		vec![
+			("string".to_string(), Color { c8: 2, c256: 77, rgb: None }),
+			("keyword".to_string(), Color { c8: 1, c256: 214, rgb: None }),
+			("key".to_string(), Color { c8: 5, c256: 177, rgb: None }),
+			("muted".to_string(), Color { c8: 4, c256: 244, rgb: None }),
+			("integer".to_string(), Color { c8: 6, c256: 204, rgb: None }),
+			("float".to_string(), Color { c8: 6, c256: 168, rgb: None }),
+			("datetime".to_string(), Color { c8: 3, c256: 117, rgb: None }),
+		]
+	}
+
+	fn settings(&self) -> Settings {
+		Settings {
+			show_counts: true,
+			..Settings::default()
+		}
+	}
+}
+
+pub fn get_factory() -> Box<dyn Factory> {
+	Box::new(TomlFactory { })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn source(input: &str) -> Box<dyn Source> {
+		TomlSource::parse(input).expect("Invalid test TOML")
+	}
+
+	#[test]
+	fn structure() {
+		let source = source("z = 1\na = 2.0\n[m]\nwhen = 1979-05-27T07:32:00Z\n[[list]]\nok = true\n");
+		let mut out = vec![];
+		::display::dump(source.as_ref(), Settings { hide_root: true, ..Settings::default() }, None, usize::MAX, &mut out).expect("Dump failed");
+		assert_eq!(String::from_utf8(out).expect("Dump was not UTF-8"), "z: 1\na: 2.0\nm\n    when: 1979-05-27T07:32:00Z\nlist\n    0\n        ok: true\n");
+	}
+
+	#[test]
+	fn sort_keys() {
+		let source = source("b = 1\nC = 2\n[a]\nz = 0\ny = 0\n");
+		let mut out = vec![];
+		::display::dump(source.as_ref(), Settings { hide_root: true, sort_keys: true, ..Settings::default() }, None, usize::MAX, &mut out).expect("Dump failed");
+		assert_eq!(String::from_utf8(out).expect("Dump was not UTF-8"), "a\n    y: 0\n    z: 0\nb: 1\nC: 2\n");
+	}
+
+	#[test]
+	fn datetime() {
+		let source = source("when = 1979-05-27\n");
+		let when = &source.root().children()[0];
+		assert!(when.matches_kind("datetime"));
+		match when.content() {
+			Format::Container(parts) => match parts.last() {
+				Some(Format::Exclude(_, value)) => match value.as_ref() {
+					Format::Color(HI_DATE, _) => (),
+					_ => panic!("Datetime is not colored as one"),
+				},
+				_ => panic!("Expected the value last"),
+			},
+			_ => panic!("Expected a container"),
+		}
+		assert!(render_text(&when.content(), Render::Search).ends_with("1979-05-27"));
+	}
+}
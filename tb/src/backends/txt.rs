@@ -1,111 +1,224 @@
 use std::process::{Command, Stdio};
 use std::io::{Read, Write};
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 use ::interface::*;
+use ::memmap2::Mmap;
+use ::regex::bytes::Regex;
 use anyhow::{Context, Result};
 
-pub struct TxtValue {
-	v: String,
+// Palette index of the first of the 16 ANSI colors
+const ANSI_COLORS: usize = 1;
+
+// Inputs longer than this many bytes are split into blocks of about this size, so that opening a huge
+// file doesn't have to find every record boundary up front
+const BLOCK: usize = 1 << 20;
+
+// Input text, which is mapped rather than copied into memory when it comes from a regular file
+enum Buf {
+	Owned(Vec<u8>),
+	Mapped(Mmap),
+}
+
+impl Buf {
+	fn read<T: Read + AsRawFd>(input: T) -> Result<Self> {
+		// Pipes and terminals can't be mapped.  The mapping is only unsafe if the file is truncated
+		// while we're browsing it.
+		if let Ok(map) = unsafe { Mmap::map(&input) } { return Ok(Buf::Mapped(map)); }
+		Self::read_all(input)
+	}
+
+	fn read_all<T: Read>(mut input: T) -> Result<Self> {
+		let mut buf = vec![];
+		input.read_to_end(&mut buf)?;
+		Ok(Buf::Owned(buf))
+	}
 }
 
-impl TxtValue {
-	fn new(s: String) -> Self {
-		Self { v: s }
+impl Deref for Buf {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		match self {
+			Buf::Owned(buf) => buf,
+			Buf::Mapped(map) => map,
+		}
 	}
 }
 
-impl<'a> Value<'a> for TxtValue {
-	fn content(&self) -> Format { super::fmtstr(&self.v, 0) }
+#[derive(Clone)]
+struct Sep {
+	re: Regex,
+	trim: bool, // Drop empty records at the end of the input
+}
+
+pub enum TxtValue<'a> {
+	Root(&'a TxtSource),
+	Block(&'a TxtSource, usize, usize), // Byte range of a group of records in a large input
+	Record(&'a [u8], bool), // Text of the record, and whether to interpret color escapes
+}
+
+impl<'a> Value<'a> for TxtValue<'a> {
+	fn content(&self) -> Format {
+		match self {
+			TxtValue::Root(_) => fmt::lit(""),
+			TxtValue::Block(_, start, end) => fmt::hide(fmt::color(0, fmt::lit(&format!("[bytes {}..{}]", start, end)))),
+			TxtValue::Record(v, true) => super::fmtansi(&String::from_utf8_lossy(v), 0, ANSI_COLORS),
+			TxtValue::Record(v, false) => super::fmtstr(&String::from_utf8_lossy(v), 0),
+		}
+	}
 
-	fn expandable(&self) -> bool { false }
+	fn expandable(&self) -> bool {
+		match self {
+			TxtValue::Record(..) => false,
+			_ => true,
+		}
+	}
 
-	fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>> { unreachable!(); }
+	fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>> {
+		match *self {
+			TxtValue::Root(source) if source.buf.len() > BLOCK => source.blocks().into_iter()
+				.map(|(start, end)| Box::new(TxtValue::Block(source, start, end)) as Box<dyn Value<'a> + 'a>).collect(),
+			TxtValue::Root(source) => source.records(0, source.buf.len()),
+			TxtValue::Block(source, start, end) => source.records(start, end),
+			TxtValue::Record(..) => unreachable!(),
+		}
+	}
 }
 
 pub struct TxtSource {
-	buf: Arc<String>,
-	sep: String,
+	buf: Arc<Buf>,
+	sep: Sep,
+	ansi: bool, // Interpret color escape sequences
+}
+
+impl TxtSource {
+	// Divide the input into ranges of at least `BLOCK` bytes, each ending just before a separator
+	fn blocks(&self) -> Vec<(usize, usize)> {
+		let mut ret = vec![];
+		let mut start = 0;
+		while self.buf.len() - start > BLOCK {
+			match self.sep.re.find_at(&self.buf, start + BLOCK) {
+				Some(m) => {
+					ret.push((start, m.start()));
+					start = m.end();
+				},
+				None => break,
+			}
+		}
+		if start < self.buf.len() || !self.sep.trim { ret.push((start, self.buf.len())); }
+		ret
+	}
+
+	fn records<'a>(&'a self, start: usize, end: usize) -> Vec<Box<dyn Value<'a> + 'a>> {
+		let mut ret = self.sep.re.split(&self.buf[start..end]).collect::<Vec<&[u8]>>();
+		if self.sep.trim && end == self.buf.len() {
+			while ret.last().map(|r| r.is_empty()) == Some(true) { ret.pop(); }
+		}
+		ret.into_iter().map(|v| Box::new(TxtValue::Record(v, self.ansi)) as Box<dyn Value<'a> + 'a>).collect()
+	}
 }
 
 impl Source for TxtSource {
 	fn root<'a>(&'a self) -> Box<dyn Value<'a> + 'a> {
-		Box::new(TxtSource { buf: Arc::clone(&self.buf), sep: self.sep.clone() })
+		Box::new(TxtValue::Root(self))
 	}
 
 	fn transform(&self, transformation: &str) -> Result<Box<dyn Source>> {
-		if transformation == "" { Ok(Box::new(TxtSource { buf: self.buf.clone(), sep: self.sep.clone() })) }
+		if transformation == "" { Ok(Box::new(TxtSource { buf: self.buf.clone(), sep: self.sep.clone(), ansi: self.ansi })) }
 		else {
 			let mut proc = Command::new("bash").args(vec!["-c", transformation]).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().with_context(|| "Failed to spawn tranform command")?;
 			let instream = proc.stdin.as_mut().with_context(|| "Couldn't get input handle to transform command")?;
-			instream.write_all(self.buf.as_bytes()).with_context(|| "Failed to send input to transform command")?;
+			instream.write_all(&self.buf).with_context(|| "Failed to send input to transform command")?;
 			let output = proc.wait_with_output().with_context(|| "Couldn't get output from transform command")?;
 			if !output.status.success() { bail!(String::from_utf8_lossy(&output.stderr).to_string()) }
-			Ok(Box::new(TxtSource { buf: Arc::new(String::from_utf8_lossy(&output.stdout).to_string()), sep: self.sep.clone() }))
+			Ok(Box::new(TxtSource { buf: Arc::new(Buf::Owned(output.stdout)), sep: self.sep.clone(), ansi: self.ansi }))
 		}
 	}
 }
 
-impl<'a> Value<'a> for TxtSource {
-	fn content(&self) -> Format { fmt::lit("") }
-
-	fn expandable(&self) -> bool { true }
-
-	fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>> {
-		self.buf.split(&self.sep).map(|x| Box::new(TxtValue::new(x.to_string())) as Box<dyn Value<'a> + 'a>).collect()
-	}
-}
-
 pub struct TxtFactory { }
 
-impl Factory for TxtFactory {
-	fn info(&self) -> Info {
-		Info { name: "txt", desc: "View and manipulate arbitrarily structured text data" }
-	}
-
-	fn from(&self, args: &[&str]) -> Option<Result<Box<dyn Source>>> {
-		let mut sep = "\n".to_string();
-		let err = match args {
-			&[] => None,
-			&["-h"] | &["--help"] => {
-				print!(r#"
-txtb: Browse arbitrarily structured text data.  Provide input on standard input.
+impl TxtFactory {
+	// Read from `input` if given, or else from the file in the arguments or standard input
+	fn open(&self, args: &[&str], input: Option<Box<dyn Read>>) -> Option<Result<Box<dyn Source>>> {
+		let mut sep = Sep { re: Regex::new("\n").expect("Invalid default separator"), trim: false };
+		let mut ansi = false;
+		let mut file = None;
+		let mut iter = args.iter();
+		let err = loop {
+			match iter.next() {
+				None => break None,
+				Some(&"-h") | Some(&"--help") => {
+					print!(r#"
+txtb: Browse arbitrarily structured text data.  Provide input on standard input or as a file.
 
-Usage: txtb [-s SEP]
+Usage: txtb [-s SEP | -r REGEX] [--ansi] [FILE]
 
 Arguments:
--s SEP:  Use SEP as the separator between lines of text
+-s SEP:    Use SEP as the separator between lines of text
+-r REGEX:  Separate lines of text wherever REGEX matches
+--ansi:    Show ANSI color escape sequences in the input as colors
+FILE:      Read FILE instead of standard input
 "#);
-				Some(None)
-			},
-			&["-s", s] => {
-				sep = s.to_string();
-				None
-			},
-			_ => Some(Some(Err(anyhow!("Unrecognized arguments")))),
+					break Some(None);
+				},
+				Some(&"-s") => match iter.next() {
+					Some(s) => sep = Sep { re: Regex::new(&::regex::escape(s)).expect("Invalid escaped separator"), trim: false },
+					None => break Some(Some(Err(anyhow!("-s requires an argument")))),
+				},
+				Some(&"-r") => match iter.next().map(|r| Regex::new(r)) {
+					Some(Ok(re)) => sep = Sep { re: re, trim: true },
+					Some(Err(e)) => break Some(Some(Err(anyhow!("Invalid separator pattern: {}", e)))),
+					None => break Some(Some(Err(anyhow!("-r requires an argument")))),
+				},
+				Some(&"--ansi") => ansi = true,
+				Some(path) if file.is_none() && !path.starts_with('-') => file = Some(path.to_string()),
+				Some(_) => break Some(Some(Err(anyhow!("Unrecognized arguments")))),
+			}
 		};
 		match err {
 			Some(e) => e,
 			None => {
-				let stdin = std::io::stdin();
-				let mut inlock = stdin.lock();
-				let mut buf = vec![];
-				match inlock.read_to_end(&mut buf).with_context(|| "Couldn't read stdin") {
-					Ok(_) => Some(Ok(Box::new(TxtSource { buf: Arc::new(String::from_utf8_lossy(&buf).to_string()), sep: sep }))),
-					Err(e) => Some(Err(e)),
-				}
+				let buf = match (input, file) {
+					(Some(_), Some(_)) => Err(anyhow!("Can't read from a file when given input directly")),
+					(Some(input), None) => Buf::read_all(input).with_context(|| "Couldn't read input"),
+					(None, Some(path)) => std::fs::File::open(&path).map_err(|e| e.into()).and_then(Buf::read).with_context(|| format!("Couldn't read {}", path)),
+					(None, None) => Buf::read(std::io::stdin()).with_context(|| "Couldn't read stdin"),
+				};
+				Some(buf.map(|buf| Box::new(TxtSource { buf: Arc::new(buf), sep: sep, ansi: ansi }) as Box<dyn Source>))
 			},
 		}
 	}
+}
+
+impl Factory for TxtFactory {
+	fn info(&self) -> Info {
+		Info { name: "txt", desc: "View and manipulate arbitrarily structured text data" }
+	}
 
-	fn colors(&self) -> Vec<Color> {
-		vec![
-			Color { c8: 4, c256: 244 }, // Control characters
-		]
+	fn from(&self, args: &[&str]) -> Option<Result<Box<dyn Source>>> {
+		self.open(args, None)
+	}
+
+	fn from_reader(&self, args: &[&str], reader: Box<dyn Read>) -> Option<Result<Box<dyn Source>>> {
+		self.open(args, Some(reader))
+	}
+
+	fn named_colors(&self) -> Vec<(String, Color)> {
+		let mut ret = vec![
+			("control".to_string(), Color { c8: 4, c256: 244, rgb: None }),
+		];
+		ret.extend((0..16).map(|i| (format!("ansi-{}", i), Color { c8: i % 8, c256: i, rgb: None })));
+		ret
 	}
 
 	fn settings(&self) -> Settings {
 		Settings {
 			hide_root: true,
+			word_wrap: true,
+			..Settings::default()
 		}
 	}
 }
@@ -113,3 +226,45 @@ Arguments:
 pub fn get_factory() -> Box<dyn Factory> {
 	Box::new(TxtFactory { })
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn text(v: &Box<dyn Value + '_>) -> String {
+		render_text(&v.content(), Render::Debug)
+	}
+
+	#[test]
+	fn large_file() {
+		let path = std::env::temp_dir().join(format!("tb-test-{}.txt", std::process::id()));
+		let lines = 3 * BLOCK / 10;
+		std::fs::write(&path, (0..lines).map(|i| format!("{:08}\n", i)).collect::<String>()).expect("Failed to write test file");
+		let buf = std::fs::File::open(&path).map_err(|e| e.into()).and_then(Buf::read).expect("Failed to read test file");
+		let _ = std::fs::remove_file(&path);
+		match buf {
+			Buf::Mapped(_) => (),
+			Buf::Owned(_) => panic!("Regular file was not mapped"),
+		}
+		let source = TxtSource { buf: Arc::new(buf), sep: Sep { re: Regex::new("\n").unwrap(), trim: true }, ansi: false };
+		let blocks = source.root().children();
+		assert_eq!(blocks.len(), 3);
+		let records = blocks.iter().flat_map(|block| block.children()).map(|record| text(&record)).collect::<Vec<String>>();
+		assert_eq!(records, (0..lines).map(|i| format!("{:08}", i)).collect::<Vec<String>>());
+	}
+
+	#[test]
+	fn color_names() {
+		let colors = TxtFactory { }.named_colors();
+		assert_eq!(colors.len(), ANSI_COLORS + 16);
+		assert_eq!(colors[ANSI_COLORS].0, "ansi-0");
+	}
+
+	#[test]
+	fn from_reader() {
+		let source = TxtFactory { }.from_reader(&["-s", ","], Box::new("a,b\x01,c".as_bytes())).expect("No source").expect("Failed to read input");
+		let records = source.root().children().iter().map(text).collect::<Vec<String>>();
+		assert_eq!(records, vec!["a", "b^A", "c"]);
+		assert!(TxtFactory { }.from_reader(&["file.txt"], Box::new("".as_bytes())).expect("No source").is_err());
+	}
+}
@@ -0,0 +1,271 @@
+use ::interface::*;
+use ::interface::fmt::*;
+use ::serde::Deserialize;
+use ::serde_yaml::Value as V;
+use std::sync::atomic::{AtomicBool, Ordering};
+use anyhow::{Context, Result};
+
+const HI_STR: usize = 0;
+const HI_KWD: usize = 1;
+const HI_KEY: usize = 2;
+const HI_MUT: usize = 3;
+const HI_NUM: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+enum ParentType {
+	Root,
+	Mapping,
+	Sequence,
+}
+
+// The value itself, without any tag it was given
+fn untag(value: &V) -> &V {
+	match value {
+		V::Tagged(tagged) => untag(&tagged.value),
+		_ => value,
+	}
+}
+
+// Mapping keys can be any value, but are almost always strings
+fn keystr(key: &V) -> String {
+	match untag(key) {
+		V::String(s) => s.clone(),
+		V::Number(n) => n.to_string(),
+		V::Bool(b) => b.to_string(),
+		V::Null => "null".to_string(),
+		k => serde_yaml::to_string(k).map(|s| s.trim_end().to_string()).unwrap_or_default(),
+	}
+}
+
+#[derive(Debug)]
+pub struct YamlValue<'a> {
+	key: String,
+	value: &'a V,
+	parent: ParentType,
+	sort: &'a AtomicBool, // Whether mapping entries are listed in key order
+}
+
+impl<'a> YamlValue<'a> {
+	fn fmtstr(s: &str) -> Format {
+		super::fmtstr(s, HI_KWD)
+	}
+
+	fn fmtkey(&self) -> Format {
+		match self.parent {
+			ParentType::Root => nosearch(color(HI_MUT, lit("root"))),
+			ParentType::Mapping => key(color(HI_KEY, Self::fmtstr(&self.key))),
+			ParentType::Sequence => hide(color(HI_MUT, Self::fmtstr(&self.key))),
+		}
+	}
+
+	fn fmtvalue(value: &V) -> Format {
+		match value {
+			V::String(s) => color(HI_STR, Self::fmtstr(s)),
+			V::Number(n) => color(HI_NUM, lit(&n.to_string())),
+			V::Bool(b) => color(HI_KWD, lit(if *b { "true" } else { "false" })),
+			V::Mapping(items) => nosearch(color(HI_KWD, lit(if items.is_empty() { "{ }" } else { "{...}" }))),
+			V::Sequence(items) => nosearch(color(HI_KWD, lit(if items.is_empty() { "[ ]" } else { "[...]" }))),
+			V::Null => color(HI_KWD, lit("null")),
+			V::Tagged(tagged) => cat(vec![color(HI_MUT, lit(&format!("{} ", tagged.tag))), Self::fmtvalue(&tagged.value)]),
+		}
+	}
+
+	fn fmtval(&self) -> Format {
+		val(Self::fmtvalue(self.value))
+	}
+
+	fn fmtsummary(&self) -> Option<Format> {
+		match untag(self.value) {
+			V::Mapping(items) if !items.is_empty() => Some(super::summary("{ ", " }", items.len(), items.iter().take(super::SUMMARY_LEN)
+				.map(|(k, v)| cat(vec![color(HI_KEY, Self::fmtstr(&keystr(k))), color(HI_MUT, lit(": ")), Self::fmtvalue(v)])).collect(), HI_KWD, HI_MUT)),
+			V::Sequence(items) if !items.is_empty() => Some(super::summary("[", "]", items.len(), items.iter().take(super::SUMMARY_LEN).map(Self::fmtvalue).collect(), HI_KWD, HI_MUT)),
+			_ => None,
+		}
+	}
+}
+
+impl<'a> Value<'a> for YamlValue<'a> {
+	fn placeholder(&self) -> Format {
+		self.fmtkey()
+	}
+
+	fn content(&self) -> Format {
+		match self.parent {
+			ParentType::Root => self.fmtval(),
+			ParentType::Mapping => super::member(self.fmtkey(), self.fmtval(), HI_MUT, true),
+			ParentType::Sequence => super::member(self.fmtkey(), self.fmtval(), HI_MUT, false),
+		}
+	}
+
+	fn summary(&self) -> Option<Format> {
+		match self.parent {
+			ParentType::Root => self.fmtsummary(),
+			_ => self.fmtsummary().map(|summary| super::member(self.fmtkey(), summary, HI_MUT, false)),
+		}
+	}
+
+	fn expandable(&self) -> bool {
+		match untag(self.value) {
+			V::Sequence(_) | V::Mapping(_) => true,
+			_ => false,
+		}
+	}
+
+	fn children(&self) -> Vec<Box<dyn Value<'a> + 'a>> {
+		match untag(self.value) {
+			V::Sequence(items) => items.iter().enumerate()
+				.map(|(i, v)| Box::new(YamlValue { key: i.to_string(), value: v, parent: ParentType::Sequence, sort: self.sort }) as Box<dyn Value<'a> + 'a>).collect(),
+			V::Mapping(items) => {
+				let mut items = items.iter().map(|(k, v)| (keystr(k), v)).collect::<Vec<(String, &V)>>();
+				if self.sort.load(Ordering::Relaxed) { items.sort_by_key(|(k, _)| k.to_lowercase()); }
+				items.into_iter().map(|(k, v)| Box::new(YamlValue { key: k, value: v, parent: ParentType::Mapping, sort: self.sort }) as Box<dyn Value<'a> + 'a>).collect()
+			},
+			_ => vec![],
+		}
+	}
+
+	fn id(&self) -> Option<String> {
+		match self.parent {
+			ParentType::Mapping => Some(self.key.clone()),
+			_ => None,
+		}
+	}
+
+	fn matches_kind(&self, kind: &str) -> bool {
+		match (kind, untag(self.value)) {
+			("null", V::Null) | ("bool", V::Bool(_)) | ("number", V::Number(_)) | ("string", V::String(_)) | ("sequence", V::Sequence(_)) | ("mapping", V::Mapping(_)) => true,
+			("tagged", _) => match self.value { V::Tagged(_) => true, _ => false },
+			("empty", V::String(s)) => s.is_empty(),
+			("empty", V::Sequence(items)) => items.is_empty(),
+			("empty", V::Mapping(items)) => items.is_empty(),
+			_ => false,
+		}
+	}
+}
+
+pub struct YamlSource {
+	doc: V,
+	sort: AtomicBool,
+}
+
+impl YamlSource {
+	// A stream of several documents is shown as a sequence of them
+	pub fn read<T: std::io::Read>(input: T) -> Result<Box<dyn Source>> {
+		let mut docs = serde_yaml::Deserializer::from_reader(input).map(V::deserialize).collect::<Result<Vec<V>, _>>().with_context(|| "could not parse input as YAML")?;
+		let doc = match docs.len() {
+			0 => V::Null,
+			1 => docs.remove(0),
+			_ => V::Sequence(docs),
+		};
+		Ok(Box::new(Self { doc: doc, sort: AtomicBool::new(false) }))
+	}
+}
+
+impl Source for YamlSource {
+	fn root<'a>(&'a self) -> Box<dyn Value<'a> + 'a> {
+		Box::new(YamlValue { key: "root".to_string(), value: &self.doc, parent: ParentType::Root, sort: &self.sort })
+	}
+
+	fn configure(&self, settings: &Settings) {
+		self.sort.store(settings.sort_keys, Ordering::Relaxed);
+	}
+}
+
+pub struct YamlFactory { }
+
+const HELP: &str = r#"yamlb: Browse YAML documents interactively
+
+Provide the name of the input file to read as the sole command-line argument, or
+provide no arguments to read from standard input.  Input compressed with gzip or
+zstd is decompressed automatically.  Several files are shown side by side, each
+under its own name.  A file containing several documents is shown as a sequence
+of them.
+
+Search for type:KIND to find values of a kind, where KIND is null, bool, number,
+string, sequence, mapping, tagged, or empty (for empty strings, sequences, and
+mappings).
+
+Part of Tree Browser <https://github.com/showermat/tb>
+Copyright (GPLv3) 2020 Matthew Schauer
+"#;
+
+impl Factory for YamlFactory {
+	fn info(&self) -> Info {
+		Info { name: "yaml", desc: "Browse YAML documents" }
+	}
+
+	fn from(&self, args: &[&str]) -> Option<Result<Box<dyn Source>>> {
+		super::open_docs(args, None, HELP, YamlSource::read, |raw| YamlSource::read(&raw[..]))
+	}
+
+	fn from_reader(&self, args: &[&str], reader: Box<dyn std::io::Read>) -> Option<Result<Box<dyn Source>>> {
+		super::open_docs(args, Some(reader), HELP, YamlSource::read, |raw| YamlSource::read(&raw[..]))
+	}
+
+	fn named_colors(&self) -> Vec<(String, Color)> {
+		vec![
+			("string".to_string(), Color { c8: 2, c256: 77, rgb: None }),
+			("keyword".to_string(), Color { c8: 1, c256: 214, rgb: None }),
+			("key".to_string(), Color { c8: 5, c256: 177, rgb: None }),
+			("muted".to_string(), Color { c8: 4, c256: 244, rgb: None }),
+			("number".to_string(), Color { c8: 6, c256: 204, rgb: None }),
+		]
+	}
+
+	fn settings(&self) -> Settings {
+		Settings {
+			show_counts: true,
+			..Settings::default()
+		}
+	}
+}
+
+pub fn get_factory() -> Box<dyn Factory> {
+	Box::new(YamlFactory { })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn dump(input: &str) -> String {
+		dumpsorted(input, false)
+	}
+
+	fn dumpsorted(input: &str, sort: bool) -> String {
+		let source = YamlSource::read(input.as_bytes()).expect("Invalid test YAML");
+		let mut out = vec![];
+		::display::dump(source.as_ref(), Settings { hide_root: true, sort_keys: sort, ..Settings::default() }, None, usize::MAX, &mut out).expect("Dump failed");
+		String::from_utf8(out).expect("Dump was not UTF-8")
+	}
+
+	#[test]
+	fn structure() {
+		let input = "b: [1, x]\na:\n  z: true\n  1: ~\nc: !Ref other\n";
+		assert_eq!(dump(input), "b\n    0: 1\n    1: x\na\n    z: true\n    1: null\nc: !Ref other\n");
+	}
+
+	#[test]
+	fn sort_keys() {
+		let input = "b: 1\nC: 2\na: {z: 0, y: 0}\n";
+		assert_eq!(dumpsorted(input, false), "b: 1\nC: 2\na\n    z: 0\n    y: 0\n");
+		assert_eq!(dumpsorted(input, true), "a\n    y: 0\n    z: 0\nb: 1\nC: 2\n");
+	}
+
+	#[test]
+	fn documents() {
+		assert_eq!(dump("a: 1\n---\n- 2\n"), "0\n    a: 1\n1\n    0: 2\n");
+	}
+
+	#[test]
+	fn kinds() {
+		let source = YamlSource::read("[~, '', a, [], {b: 1}, !t 1]".as_bytes()).expect("Invalid test YAML");
+		let kinds = |kind: &str| source.root().children().iter().map(|child| child.matches_kind(kind)).collect::<Vec<bool>>();
+		assert_eq!(kinds("null"), vec![true, false, false, false, false, false]);
+		assert_eq!(kinds("string"), vec![false, true, true, false, false, false]);
+		assert_eq!(kinds("empty"), vec![false, true, false, true, false, false]);
+		assert_eq!(kinds("mapping"), vec![false, false, false, false, true, false]);
+		assert_eq!(kinds("number"), vec![false, false, false, false, false, true]);
+		assert_eq!(kinds("tagged"), vec![false, false, false, false, false, true]);
+	}
+}
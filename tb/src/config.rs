@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use anyhow::{Context, Result};
+use ::backends::CtrlStyle;
+use ::interface::{Settings, Wrap};
+use ::regex::Regex;
+
+/// What to do when a search runs off one end of the document.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SearchWrap {
+	Wrap, // Silently continue from the other end
+	Notify, // Continue from the other end, and say so
+	Stop, // Stay on the last match in the search direction
+}
+
+/// When searches should ignore case.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SearchCase {
+	Smart, // Ignore case unless the query contains an uppercase letter
+	Sensitive,
+	Insensitive,
+}
+
+/// How to measure East Asian characters whose width depends on context.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EastAsianWidth {
+	Auto, // Wide in CJK locales, narrow elsewhere
+	Narrow,
+	Wide,
+}
+
+/// How to signal that a navigation or search command couldn't move the selection.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Bell {
+	Off,
+	Beep, // Ring the terminal bell
+	Flash, // Flash the screen
+}
+
+/// User-adjustable settings for the interactive display.  Each one can be changed at runtime with
+/// `:set <name> <value>`.  Built-in defaults are overridden by the backend's `Settings`, which are
+/// in turn overridden by anything the user sets.
+#[derive(Clone)]
+pub struct Config {
+	pub search_wrap: SearchWrap,
+	pub east_asian_width: EastAsianWidth,
+	pub mouse_hover: bool,
+	pub wrap_glyph: Option<char>,
+	pub word_wrap: bool,
+	pub wrap: Wrap,
+	pub indent: usize, // Columns per level of the tree
+	pub key_timeout: i32,
+	pub search_regex: bool,
+	pub search_case: SearchCase,
+	pub search_count: bool,
+	pub expand_depth: usize,
+	pub scrolloff: usize,
+	pub scroll_past_end: bool, // Let moving the selection scroll the end of the document up from the bottom of the screen
+	pub status_format: String, // Shown at the right of the status bar, with `%` escapes expanded
+	pub bell: Bell,
+	pub control_chars: CtrlStyle,
+	pub minimap: bool, // Show an overview of the document in the rightmost column
+	pub breadcrumb: bool, // Show the path to the selection in the status bar
+	pub transparent_bg: bool, // Draw the regular background in the terminal's default color
+}
+
+// Escapes recognized in `status-format`: position in the document as a percentage, time, search
+// query, and transformation
+const STATUS_ESCAPES: &str = "ptqx%";
+
+// The branch glyphs need two columns, and the screen has to fit at least one level of the tree
+fn indent(key: &str, value: &str) -> Result<usize> {
+	value.parse::<usize>().ok().filter(|n| (2..=16).contains(n)).ok_or(anyhow!("Invalid value \"{}\" for {} (expected a number of columns from 2 to 16)", value, key))
+}
+
+fn choose<T: Copy>(key: &str, value: &str, options: &[(&str, T)]) -> Result<T> {
+	match options.iter().find(|(name, _)| *name == value) {
+		Some((_, ret)) => Ok(*ret),
+		None => bail!("Invalid value \"{}\" for {} (expected {})", value, key, options.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(", ")),
+	}
+}
+
+impl Config {
+	pub fn new(settings: &Settings) -> Self {
+		Config {
+			search_wrap: SearchWrap::Wrap,
+			east_asian_width: EastAsianWidth::Auto,
+			mouse_hover: false,
+			wrap_glyph: None,
+			word_wrap: settings.word_wrap,
+			wrap: settings.wrap,
+			indent: settings.indent,
+			key_timeout: 4000,
+			search_regex: settings.search_regex,
+			search_case: SearchCase::Smart,
+			search_count: false,
+			expand_depth: settings.initial_expand_depth,
+			scrolloff: 0,
+			scroll_past_end: false,
+			status_format: String::new(),
+			bell: Bell::Off,
+			control_chars: CtrlStyle::Caret,
+			minimap: false,
+			breadcrumb: false,
+			transparent_bg: false,
+		}
+	}
+
+	/// Whether ambiguous-width characters should be drawn double-width
+	pub fn cjk(&self) -> bool {
+		match self.east_asian_width {
+			EastAsianWidth::Narrow => false,
+			EastAsianWidth::Wide => true,
+			EastAsianWidth::Auto => {
+				// Same precedence as setlocale(3)
+				let locale = ["LC_ALL", "LC_CTYPE", "LANG"].iter().filter_map(|var| std::env::var(var).ok()).find(|val| val != "").unwrap_or(String::new());
+				["zh", "ja", "ko"].iter().any(|lang| locale.starts_with(lang))
+			},
+		}
+	}
+
+	/// Expand the escapes in `status_format`, getting the text for each from `field`
+	pub fn status(&self, field: impl Fn(char) -> String) -> String {
+		let mut ret = String::new();
+		let mut chars = self.status_format.chars();
+		while let Some(c) = chars.next() {
+			match c {
+				'%' => match chars.next() {
+					Some('%') => ret.push('%'),
+					Some(esc) => ret.push_str(&field(esc)),
+					None => (),
+				},
+				c => ret.push(c),
+			}
+		}
+		ret
+	}
+
+	pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+		match key {
+			"search-wrap" => self.search_wrap = choose(key, value, &[("on", SearchWrap::Wrap), ("notify", SearchWrap::Notify), ("off", SearchWrap::Stop)])?,
+			"east-asian-width" => self.east_asian_width = choose(key, value, &[("auto", EastAsianWidth::Auto), ("narrow", EastAsianWidth::Narrow), ("wide", EastAsianWidth::Wide)])?,
+			"mouse-hover" => self.mouse_hover = choose(key, value, &[("on", true), ("off", false)])?,
+			"wrap-glyph" => {
+				let mut chars = value.chars();
+				self.wrap_glyph = match (value, chars.next(), chars.next()) {
+					("off", _, _) => None,
+					(_, Some(c), None) => Some(c),
+					_ => bail!("Invalid value \"{}\" for {} (expected off or a single character)", value, key),
+				};
+			},
+			"indent" => self.indent = indent(key, value)?,
+			"word-wrap" => self.word_wrap = choose(key, value, &[("on", true), ("off", false)])?,
+			"wrap" => self.wrap = match value {
+				"screen" => Wrap::Screen,
+				"off" => Wrap::Off,
+				n => Wrap::Column(n.parse::<usize>().ok().filter(|n| *n > 0).ok_or(anyhow!("Invalid value \"{}\" for {} (expected screen, off, or a number of columns)", value, key))?),
+			},
+			"key-timeout" => self.key_timeout = value.parse::<u16>().map_err(|_| anyhow!("Invalid value \"{}\" for {} (expected milliseconds)", value, key))? as i32,
+			"search-regex" => self.search_regex = choose(key, value, &[("on", true), ("off", false)])?,
+			"search-case" => self.search_case = choose(key, value, &[("smart", SearchCase::Smart), ("sensitive", SearchCase::Sensitive), ("insensitive", SearchCase::Insensitive)])?,
+			"search-count" => self.search_count = choose(key, value, &[("on", true), ("off", false)])?,
+			"scrolloff" => self.scrolloff = value.parse::<usize>().map_err(|_| anyhow!("Invalid value \"{}\" for {} (expected a number of lines)", value, key))?,
+			"scroll-past-end" => self.scroll_past_end = choose(key, value, &[("on", true), ("off", false)])?,
+			"status-format" => {
+				let mut chars = value.chars();
+				while let Some(c) = chars.next() {
+					if c == '%' {
+						match chars.next() {
+							Some(esc) if STATUS_ESCAPES.contains(esc) => (),
+							_ => bail!("Invalid value \"{}\" for {} (escapes are %{})", value, key, STATUS_ESCAPES.chars().map(|c| c.to_string()).collect::<Vec<String>>().join(", %")),
+						}
+					}
+				}
+				self.status_format = value.to_string();
+			},
+			"control-chars" => self.control_chars = choose(key, value, &[("caret", CtrlStyle::Caret), ("hex", CtrlStyle::Hex), ("picture", CtrlStyle::Picture)])?,
+			"minimap" => self.minimap = choose(key, value, &[("on", true), ("off", false)])?,
+			"breadcrumb" => self.breadcrumb = choose(key, value, &[("on", true), ("off", false)])?,
+			"transparent-bg" => self.transparent_bg = choose(key, value, &[("on", true), ("off", false)])?,
+			"bell" => self.bell = choose(key, value, &[("off", Bell::Off), ("beep", Bell::Beep), ("flash", Bell::Flash)])?,
+			"expand-depth" => self.expand_depth = match value {
+				"all" => usize::MAX,
+				_ => value.parse::<usize>().map_err(|_| anyhow!("Invalid value \"{}\" for {} (expected a number or all)", value, key))?,
+			},
+			_ => bail!("Unknown setting \"{}\"", key),
+		}
+		Ok(())
+	}
+}
+
+/// Set one of the backend's `Settings` by name, overriding the factory's default.  Returns false if
+/// `key` isn't one of them, in which case it's probably for `Config::set` instead.
+pub fn set_backend(settings: &mut Settings, key: &str, value: &str) -> Result<bool> {
+	match key {
+		"hide-root" => settings.hide_root = choose(key, value, &[("on", true), ("off", false)])?,
+		"sort-keys" => settings.sort_keys = choose(key, value, &[("on", true), ("off", false)])?,
+		"truncate-lines" => settings.truncate_lines = value.parse::<usize>().ok().filter(|n| *n > 0).ok_or(anyhow!("Invalid value \"{}\" for {} (expected a number of lines)", value, key))?,
+		"empty-marker" => settings.show_empty_marker = choose(key, value, &[("on", true), ("off", false)])?,
+		"child-counts" => settings.show_counts = choose(key, value, &[("on", true), ("off", false)])?,
+		"indent" => settings.indent = indent(key, value)?,
+		_ => return Ok(false),
+	}
+	Ok(true)
+}
+
+/// Settings read at startup from `$XDG_CONFIG_HOME/tb/config`.  Each line of the file is a setting
+/// name followed by its value; blank lines and lines starting with `#` are ignored.  Settings after
+/// a `[backend.<name>]` line only apply to that backend, until the next section starts.
+pub struct ConfigFile {
+	pub backend_re: Regex, // Extracts the backend name from the name the program is invoked as
+	pub aliases: HashMap<String, Vec<String>>, // Names that stand for a backend plus default arguments
+	pub settings: Vec<(String, String)>, // Everything else, to be passed to `set_backend` or `Config::set`
+	pub sections: HashMap<String, Vec<(String, String)>>, // Settings for a single backend, by backend name
+	pub keys: Vec<(Vec<i32>, String)>, // Key bindings from the keys file, with an empty command to unbind
+}
+
+impl ConfigFile {
+	pub fn load() -> Result<Self> {
+		let dir = std::env::var("XDG_CONFIG_HOME").or(std::env::var("HOME").map(|home| home + "/.config")).with_context(|| "Couldn't find XDG config home")? + "/" + ::APPNAME;
+		// A missing file is the same as an empty one
+		let read = |name: &str| {
+			let path = format!("{}/{}", dir, name);
+			match std::fs::read_to_string(&path) {
+				Ok(text) => Ok((path, text)),
+				Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok((path, String::new())),
+				Err(e) => Err(e).with_context(|| format!("Couldn't read {}", path)),
+			}
+		};
+		let (path, text) = read("config")?;
+		let mut ret = Self::parse(&text).with_context(|| format!("Error in {}", path))?;
+		let (path, text) = read("keys")?;
+		ret.keys = Self::parse_keys(&text).with_context(|| format!("Error in {}", path))?;
+		Ok(ret)
+	}
+
+	// Each line of the keys file is a key sequence, as `curses::parse_keysyms` reads it, followed by
+	// a command.  Since sequences can contain spaces, as much of the line as makes a valid sequence is
+	// taken as the keys.  A line with no command unbinds the keys.
+	fn parse_keys(text: &str) -> Result<Vec<(Vec<i32>, String)>> {
+		let mut ret = vec![];
+		for (i, line) in text.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+			if line == "" || line.starts_with('#') { continue; }
+			// The keys end at whitespace or, if it's escaped, just after it
+			let ends = line.char_indices().filter(|(_, c)| c.is_whitespace()).flat_map(|(j, c)| vec![j, j + c.len_utf8()]).chain(std::iter::once(line.len()));
+			let (keys, cmd) = ends.collect::<Vec<usize>>().into_iter().rev()
+				.filter_map(|j| ::curses::parse_keysyms(&line[..j]).ok().map(|keys| (keys, line[j..].trim())))
+				.next().ok_or(anyhow!("Invalid key sequence on line {}", i))?;
+			let cmd = cmd.split_whitespace().collect::<Vec<&str>>();
+			if let Some(name) = cmd.first() {
+				if !::display::COMMANDS.contains(name) { bail!("Unknown command {} on line {}", name, i); }
+			}
+			ret.push((keys, cmd.join(" ")));
+		}
+		Ok(ret)
+	}
+
+	fn parse(text: &str) -> Result<Self> {
+		let mut ret = ConfigFile {
+			backend_re: Regex::new("^([a-z]+)b$").expect("Invalid default backend regex"),
+			aliases: HashMap::new(),
+			settings: vec![],
+			sections: HashMap::new(),
+			keys: vec![],
+		};
+		let mut section: Option<String> = None;
+		for (i, line) in text.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+			if line == "" || line.starts_with('#') { continue; }
+			if line.starts_with('[') {
+				match line.strip_prefix("[backend.").and_then(|rest| rest.strip_suffix(']')) {
+					Some(name) if name != "" => section = Some(name.to_string()),
+					_ => bail!("Invalid section on line {} (expected [backend.<name>])", i),
+				}
+				continue;
+			}
+			let (key, value) = match line.find(char::is_whitespace) {
+				Some(split) => (&line[..split], line[split..].trim()),
+				None => (line, ""),
+			};
+			match key {
+				"backend-regex" | "alias" if section.is_some() => bail!("{} on line {} can't be set for a single backend", key, i),
+				"backend-regex" => {
+					ret.backend_re = Regex::new(value).with_context(|| format!("Invalid backend-regex on line {}", i))?;
+					if ret.backend_re.captures_len() < 2 { bail!("backend-regex on line {} must capture the backend name", i); }
+				},
+				"alias" => {
+					let words = value.split_whitespace().map(|word| word.to_string()).collect::<Vec<String>>();
+					if words.len() < 2 { bail!("Alias on line {} needs a name and a backend", i); }
+					ret.aliases.insert(words[0].clone(), words[1..].to_vec());
+				},
+				_ => {
+					let valid = set_backend(&mut Settings::default(), key, value).and_then(|found| match found {
+						true => Ok(()),
+						false => Config::new(&Settings::default()).set(key, value),
+					});
+					valid.with_context(|| format!("Invalid setting on line {}", i))?;
+					let settings = match &section {
+						Some(name) => ret.sections.entry(name.clone()).or_insert(vec![]),
+						None => &mut ret.settings,
+					};
+					settings.push((key.to_string(), value.to_string()));
+				},
+			}
+		}
+		Ok(ret)
+	}
+
+	/// Apply the settings for `backend` that override its own defaults to `settings`, and return the
+	/// rest, for `Config`.  Settings in the backend's section take precedence over the others.
+	pub fn resolve(&self, backend: &str, settings: &mut Settings) -> Result<Vec<(String, String)>> {
+		let mut ret = vec![];
+		for (key, value) in self.settings.iter().chain(self.sections.get(backend).into_iter().flatten()) {
+			if !set_backend(settings, key, value)? { ret.push((key.clone(), value.clone())); }
+		}
+		Ok(ret)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_file() {
+		let file = ConfigFile::parse("# Comment\n\nbackend-regex ^(.+)-tb$\nalias jq j --transform\nsearch-wrap   notify\n").expect("Failed to parse");
+		assert_eq!(file.backend_re.captures("json-tb").and_then(|c| c.get(1)).map(|m| m.as_str()), Some("json"));
+		assert_eq!(file.aliases.get("jq"), Some(&vec!["j".to_string(), "--transform".to_string()]));
+		assert_eq!(file.settings, vec![("search-wrap".to_string(), "notify".to_string())]);
+		assert!(ConfigFile::parse("backend-regex ^[a-z]+$").is_err());
+		assert!(ConfigFile::parse("backend-regex (").is_err());
+		assert!(ConfigFile::parse("alias jq").is_err());
+		assert!(ConfigFile::parse("search-wrap sometimes").is_err());
+	}
+
+	#[test]
+	fn sections() {
+		let file = ConfigFile::parse("search-wrap notify\nsort-keys on\n[backend.j]\nsearch-wrap off\n[backend.fs]\nsort-keys off\nhide-root on\n").expect("Failed to parse");
+		let mut settings = Settings::default();
+		assert_eq!(file.resolve("j", &mut settings).expect("Failed to resolve"), vec![("search-wrap".to_string(), "notify".to_string()), ("search-wrap".to_string(), "off".to_string())]);
+		assert!(settings.sort_keys && !settings.hide_root);
+		let mut settings = Settings::default();
+		assert_eq!(file.resolve("fs", &mut settings).expect("Failed to resolve"), vec![("search-wrap".to_string(), "notify".to_string())]);
+		assert!(!settings.sort_keys && settings.hide_root);
+		assert!(ConfigFile::parse("[backend.j]\nalias jq j --transform").is_err());
+		assert!(ConfigFile::parse("[json]").is_err());
+		assert!(ConfigFile::parse("[backend.j]\ntruncate-lines 0").is_err());
+	}
+
+	#[test]
+	fn keys() {
+		let keys = ConfigFile::parse_keys("# Comment\nx   node toggle\ng g\n\\  search forward\n^X ^Y quit\n").expect("Failed to parse");
+		assert_eq!(keys, vec![
+			(vec!['x' as i32], "node toggle".to_string()),
+			(vec!['g' as i32, 'g' as i32], "".to_string()),
+			(vec![' ' as i32], "search forward".to_string()),
+			(vec![0x18, 0x19], "quit".to_string()),
+		]);
+		assert!(ConfigFile::parse_keys("x frobnicate").is_err());
+		assert!(ConfigFile::parse_keys("Nope quit").is_err());
+	}
+
+	#[test]
+	fn status_format() {
+		let mut config = Config::new(&Settings::default());
+		config.set("status-format", "%x [%q] %p%%").expect("Valid status format rejected");
+		assert_eq!(config.status(|c| match c { 'p' => "42".to_string(), 'q' => "foo".to_string(), _ => String::new() }), " [foo] 42%");
+		assert!(config.set("status-format", "%y").is_err());
+		assert!(config.set("status-format", "100%").is_err());
+	}
+
+	#[test]
+	fn backend_defaults() {
+		let mut config = Config::new(&Settings { word_wrap: true, wrap: Wrap::Column(60), ..Settings::default() });
+		assert!(config.word_wrap);
+		assert_eq!(config.wrap, Wrap::Column(60));
+		config.set("word-wrap", "off").expect("Valid setting rejected");
+		assert!(!config.word_wrap);
+	}
+}
@@ -6,9 +6,13 @@ extern crate libc_stdhandle;
 
 use self::ncurses::*;
 use self::libc_stdhandle::*;
+use std::cell::{Cell, RefCell};
 use std::ffi::CString;
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
-use ::interface::Color;
+use ::interface::{Attr, BitFlags, Color};
 use anyhow::{Error, Result};
 use nom::IResult;
 use nom::branch::alt;
@@ -87,19 +91,64 @@ fn check(ret: i32) -> Result<()> {
 	Ok(())
 }
 
+// Terminals like the VT100 can't hide the cursor or use the mouse, which is no reason to give up
 pub fn prompt_on() -> Result<()> {
-	if curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE).is_none() { bail!("Cannot set cursor visibility"); }
+	curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE);
 	mousemask(0, None); 
+	if HOVER.load(Ordering::Relaxed) { report_motion(false)?; }
 	Ok(())
 }
 
-pub fn prompt_off() -> Result<()> {
-	if curs_set(CURSOR_VISIBILITY::CURSOR_INVISIBLE).is_none() { bail!("Cannot set cursor visibility"); }
-	if mousemask((BUTTON1_PRESSED | BUTTON4_PRESSED | BUTTON5_PRESSED) as u32, None) == 0 { bail!("Cannot set mouse mask"); }
+static HOVER: AtomicBool = AtomicBool::new(false); // Whether to report mouse movement
+static DEFAULT_COLORS: AtomicBool = AtomicBool::new(false); // Whether colors can be -1 for the terminal's default
+
+/// Whether to draw in color, following the `NO_COLOR` and `CLICOLOR` conventions: `NO_COLOR` turns
+/// color off, `CLICOLOR_FORCE` turns it on, and `CLICOLOR=0` turns it off unless forced.  Otherwise,
+/// use color only if the output is a terminal.
+pub fn use_color(tty: bool) -> bool {
+	let var = |name| std::env::var(name).ok().filter(|val| val != "");
+	if var("NO_COLOR").is_some() { false }
+	else if var("CLICOLOR_FORCE").filter(|val| val != "0").is_some() { true }
+	else if var("CLICOLOR").as_ref().map(|val| val.as_str()) == Some("0") { false }
+	else { tty }
+}
+
+pub fn stdout_tty() -> bool {
+	unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+/// Whether the screen is drawing in color, rather than using attributes in its place
+pub fn color() -> bool {
+	screen().color()
+}
+
+fn report_motion(on: bool) -> Result<()> {
+	// Most terminals only send movement events if asked with this private mode, regardless of the mask
+	print!("{}", if on { "\x1b[?1003h" } else { "\x1b[?1003l" });
+	std::io::stdout().flush()?;
+	Ok(())
+}
+
+fn mouse_on() -> Result<()> {
+	let hover = HOVER.load(Ordering::Relaxed);
+	let mask = BUTTON1_PRESSED | BUTTON4_PRESSED | BUTTON5_PRESSED | if hover { REPORT_MOUSE_POSITION } else { 0 };
+	mousemask(mask as u32, None);
 	mouseinterval(0);
+	if hover { report_motion(true)?; }
 	Ok(())
 }
 
+pub fn set_hover(hover: bool) -> Result<()> {
+	let was = HOVER.swap(hover, Ordering::Relaxed);
+	if was && !hover { report_motion(false)?; }
+	mouse_on()
+}
+
+pub fn prompt_off() -> Result<()> {
+	curs_set(CURSOR_VISIBILITY::CURSOR_INVISIBLE);
+	mouse_on()
+}
+
 pub fn setup() -> Result<()> {
 	unsafe {
 		let cstr = |s: &str| { CString::new(s).expect("Tried to create null C string").into_raw() };
@@ -119,8 +168,12 @@ pub fn setup() -> Result<()> {
 	check(keypad(stdscr(), true))?;
 	check(cbreak())?;
 	check(noecho())?;
-	if !has_colors() { bail!("This terminal does not support color"); }
-	check(start_color())?;
+	// The interactive display always draws on the terminal, so only the environment can turn color off
+	SCREEN.with(|screen| *screen.borrow_mut() = Rc::new(Terminal { color: has_colors() && use_color(true) }));
+	if color() {
+		check(start_color())?;
+		DEFAULT_COLORS.store(use_default_colors() != ERR, Ordering::Relaxed);
+	}
 	check(idlok(stdscr(), true))?;
 	check(scrollok(stdscr(), true))?;
 	check(leaveok(stdscr(), false))?;
@@ -130,10 +183,26 @@ pub fn setup() -> Result<()> {
 }
 
 pub fn cleanup() -> Result<()> {
+	if HOVER.load(Ordering::Relaxed) { set_hover(false)?; }
+	check(endwin())?;
+	Ok(())
+}
+
+// Temporarily hand the terminal back, for example to run an interactive program
+pub fn suspend() -> Result<()> {
+	if HOVER.load(Ordering::Relaxed) { report_motion(false)?; }
+	check(def_prog_mode())?;
 	check(endwin())?;
 	Ok(())
 }
 
+pub fn resume() -> Result<()> {
+	check(reset_prog_mode())?;
+	refresh();
+	if HOVER.load(Ordering::Relaxed) { report_motion(true)?; }
+	Ok(())
+}
+
 pub enum Key {
 	Timeout,
 	Invalid,
@@ -170,34 +239,338 @@ pub fn read(timeout: i32) -> Key { // Read a UTF-8 char from input
 	ret
 }
 
+/// How many colors the terminal can draw, which decides which of a `Color`'s values to use
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Depth {
+	Ansi,
+	Xterm,
+	Direct, // Any RGB value, as on terminals like `xterm-direct`
+}
+
+impl Depth {
+	pub fn of(colors: i32) -> Self {
+		if colors >= 1 << 24 { Depth::Direct }
+		else if colors >= 256 { Depth::Xterm }
+		else { Depth::Ansi }
+	}
+}
+
+// The red, green, and blue values XTerm uses for each of its 256 colors
+fn xterm_rgb(c: u8) -> (u8, u8, u8) {
+	const ANSI: [(u8, u8, u8); 16] = [
+		(0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0), (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+		(127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0), (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255),
+	];
+	let level = |i: u8| if i == 0 { 0 } else { 55 + 40 * i };
+	match c {
+		0..=15 => ANSI[c as usize],
+		16..=231 => (level((c - 16) / 36), level((c - 16) / 6 % 6), level((c - 16) % 6)),
+		_ => { let gray = 8 + 10 * (c - 232); (gray, gray, gray) },
+	}
+}
+
+/// The number the terminal knows `c` by at the given depth, or -1 for its default color
+pub fn colnum(c: &Color, depth: Depth) -> i32 {
+	match depth {
+		Depth::Ansi => c.c8 as i32,
+		Depth::Xterm => c.c256 as i32,
+		Depth::Direct => {
+			let (r, g, b) = match (c.rgb, c.c256) {
+				(Some(rgb), _) => rgb,
+				(None, col) if col < 8 => return col as i32, // Including the default color
+				(None, col) => xterm_rgb(col as u8),
+			};
+			// Numbers below 8 still stand for the ANSI colors, so the darkest blues are bumped up to
+			// one that looks the same
+			std::cmp::max((r as i32) << 16 | (g as i32) << 8 | b as i32, 8)
+		},
+	}
+}
+
 #[derive(Clone)]
 pub struct Palette {
 	fg: Vec<Color>,
 	bg: Vec<Color>,
+	attrs: Option<Vec<attr_t>>, // Attributes standing in for background colors when drawing without color
+	undefined: Cell<Option<usize>>, // A foreground color that was drawn with but isn't in `fg`
 }
 
 impl Palette {
 	fn pairnum(&self, fg: usize, bg: usize) -> i16 {
 		(bg * self.fg.len() + fg + 1) as i16
 	}
-	pub fn new(fglist: Vec<Color>, bglist: Vec<Color>) -> Result<Self> {
-		fn getcol(c: &Color) -> i16 {
-			( if ncurses::COLORS() >= 256 { c.c256 }
-			else { c.c8 } ) as i16
-		}
-		let ret = Self { fg: fglist, bg: bglist };
+	/// Backgrounds past the first few can be dropped if the terminal doesn't have enough color pairs
+	/// for every combination.  They're drawn with the regular background instead.
+	pub fn new(fglist: Vec<Color>, mut bglist: Vec<Color>) -> Result<Self> {
+		let depth = Depth::of(ncurses::COLORS());
+		// Without support for the terminal's default colors, fall back to white on black
+		let getcol = |c: &Color, fallback: i16| match colnum(c, depth) {
+			col if col < 0 && !DEFAULT_COLORS.load(Ordering::Relaxed) => fallback as i32,
+			col => col,
+		};
+		// `COLOR_PAIR` only has room for pair numbers below 256
+		let pairs = std::cmp::min(ncurses::COLOR_PAIRS(), 256) as usize;
+		bglist.truncate(std::cmp::max(pairs.saturating_sub(1) / std::cmp::max(fglist.len(), 1), 1));
+		let ret = Self { fg: fglist, bg: bglist, attrs: None, undefined: Cell::new(None) };
 		for (i, bgcol) in ret.bg.iter().enumerate() {
 			for (j, fgcol) in ret.fg.iter().enumerate() {
-				check(ncurses::init_pair(ret.pairnum(j, i), getcol(fgcol), getcol(bgcol)))?;
+				let (fg, bg) = (getcol(fgcol, COLOR_WHITE), getcol(bgcol, COLOR_BLACK));
+				check(match depth {
+					Depth::Direct => ncurses::init_extended_pair(ret.pairnum(j, i) as i32, fg, bg),
+					_ => ncurses::init_pair(ret.pairnum(j, i), fg as i16, bg as i16),
+				})?;
 			}
 		}
 		Ok(ret)
 	}
-	pub fn set(&self, fg: usize, bg: usize, fillchar: char) {
-		let pair = self.pairnum(fg, bg);
-		ncurses::color_set(pair);
-		ncurses::bkgdset(fillchar as u32 | ncurses::COLOR_PAIR(pair));
+	pub fn fg(&self) -> &[Color] {
+		&self.fg
+	}
+	// Foreground colors are all drawn the same, and each background color is replaced by an attribute
+	pub fn mono(attrs: Vec<attr_t>) -> Self {
+		Self { fg: vec![], bg: vec![], attrs: Some(attrs), undefined: Cell::new(None) }
+	}
+	/// The last foreground color asked for that the palette doesn't have, if there has been one
+	/// since the last call.  Such colors are drawn in the error color instead, since their pairs
+	/// would belong to another background.
+	pub fn undefined(&self) -> Option<usize> {
+		self.undefined.take()
+	}
+	pub fn set(&self, fg: usize, bg: usize, attrs: attr_t, fillchar: char) {
+		let fg = match self.attrs.is_some() || fg < self.fg.len() {
+			true => fg,
+			false => { self.undefined.set(Some(fg)); ::interface::FG_ERROR },
+		};
+		let attr = match &self.attrs {
+			Some(attrs) => attrs.get(bg).cloned().unwrap_or(A_NORMAL()),
+			None => ncurses::COLOR_PAIR(self.pairnum(fg, if bg < self.bg.len() { bg } else { 0 })),
+		};
+		screen().style(attr | attrs, fillchar);
+	}
+}
+
+/// The drawing operations the display uses.  Normally these go straight to the terminal, but a
+/// test can call `headless` to draw into a `Grid` in memory instead.
+pub trait Screen {
+	fn size(&self) -> Size;
+	fn pos(&self) -> (usize, usize);
+	fn mv(&self, y: usize, x: usize);
+	fn addstr(&self, s: &str);
+	fn clrtoeol(&self); // Fill the rest of the line with the character last passed to `style`
+	fn scrl(&self, n: i32); // Scroll the contents up by `n` lines, or down if it's negative
+	fn clear(&self);
+	fn refresh(&self);
+	fn style(&self, attr: attr_t, fill: char);
+	fn attron(&self, attr: attr_t);
+	fn attroff(&self, attr: attr_t);
+	fn beep(&self);
+	fn flash(&self);
+	fn color(&self) -> bool; // Whether the screen was set up to draw in color
+	fn link(&self, url: Option<&str>); // Make what's drawn next a hyperlink to the URL, or stop
+}
+
+struct Terminal {
+	color: bool,
+}
+
+impl Screen for Terminal {
+	fn size(&self) -> Size {
+		Size { w: COLS() as usize, h: LINES() as usize }
+	}
+	fn pos(&self) -> (usize, usize) {
+		let (mut y, mut x) = (0, 0);
+		getyx(stdscr(), &mut y, &mut x);
+		(y as usize, x as usize)
+	}
+	fn mv(&self, y: usize, x: usize) {
+		ncurses::mv(y as i32, x as i32);
+	}
+	fn addstr(&self, s: &str) {
+		ncurses::addstr(s);
+	}
+	fn clrtoeol(&self) {
+		ncurses::clrtoeol();
+	}
+	fn scrl(&self, n: i32) {
+		ncurses::scrl(n);
+	}
+	fn clear(&self) {
+		ncurses::clear();
+	}
+	fn refresh(&self) {
+		ncurses::refresh();
+	}
+	fn style(&self, attr: attr_t, fill: char) {
+		ncurses::attrset(attr);
+		ncurses::bkgdset(fill as chtype | attr);
+	}
+	fn attron(&self, attr: attr_t) {
+		ncurses::attron(attr);
+	}
+	fn attroff(&self, attr: attr_t) {
+		ncurses::attroff(attr);
+	}
+	fn beep(&self) {
+		ncurses::beep();
+	}
+	fn flash(&self) {
+		ncurses::flash();
+	}
+	fn color(&self) -> bool {
+		self.color
+	}
+	fn link(&self, url: Option<&str>) {
+		// Curses has no way to send OSC 8 escapes, so what it has so far goes out first, and the
+		// escape is written straight to the terminal after it.  The text after it is linked when
+		// Curses next refreshes.
+		if stdout_tty() {
+			ncurses::refresh();
+			let url = url.unwrap_or("").chars().filter(|c| !c.is_control()).collect::<String>();
+			print!("\x1b]8;;{}\x1b\\", url);
+			let _ = std::io::stdout().flush();
+		}
+	}
+}
+
+/// A screen that keeps its contents in memory, for testing the display without a terminal.  Only
+/// the characters are kept, not their colors, and each takes up one cell regardless of its width.
+#[cfg(test)]
+pub struct Grid {
+	size: Size,
+	cells: RefCell<Vec<Vec<char>>>,
+	pos: Cell<(usize, usize)>,
+	fill: Cell<char>,
+}
+
+#[cfg(test)]
+impl Grid {
+	fn new(size: Size) -> Self {
+		Grid { size: size, cells: RefCell::new(vec![vec![' '; size.w]; size.h]), pos: Cell::new((0, 0)), fill: Cell::new(' ') }
+	}
+
+	/// The text on each line of the screen, without trailing spaces
+	pub fn lines(&self) -> Vec<String> {
+		self.cells.borrow().iter().map(|row| row.iter().collect::<String>().trim_end().to_string()).collect()
+	}
+}
+
+#[cfg(test)]
+impl Screen for Grid {
+	fn size(&self) -> Size {
+		self.size
+	}
+	fn pos(&self) -> (usize, usize) {
+		self.pos.get()
 	}
+	fn mv(&self, y: usize, x: usize) {
+		self.pos.set((std::cmp::min(y, self.size.h - 1), std::cmp::min(x, self.size.w - 1)));
+	}
+	fn addstr(&self, s: &str) {
+		for c in s.chars() {
+			let (y, x) = self.pos.get();
+			self.cells.borrow_mut()[y][x] = c;
+			// Like the terminal, wrap at the end of the line and scroll at the bottom of the screen
+			self.pos.set(match (x + 1 < self.size.w, y + 1 < self.size.h) {
+				(true, _) => (y, x + 1),
+				(false, true) => (y + 1, 0),
+				(false, false) => { self.scrl(1); (y, 0) },
+			});
+		}
+	}
+	fn clrtoeol(&self) {
+		let (y, x) = self.pos.get();
+		for cell in self.cells.borrow_mut()[y][x..].iter_mut() { *cell = self.fill.get(); }
+	}
+	fn scrl(&self, n: i32) {
+		let mut cells = self.cells.borrow_mut();
+		for _ in 0..std::cmp::min(n.abs() as usize, self.size.h) {
+			if n > 0 {
+				cells.remove(0);
+				cells.push(vec![' '; self.size.w]);
+			}
+			else {
+				cells.pop();
+				cells.insert(0, vec![' '; self.size.w]);
+			}
+		}
+	}
+	fn clear(&self) {
+		*self.cells.borrow_mut() = vec![vec![' '; self.size.w]; self.size.h];
+		self.pos.set((0, 0));
+	}
+	fn refresh(&self) { }
+	fn style(&self, _attr: attr_t, fill: char) {
+		self.fill.set(fill);
+	}
+	fn attron(&self, _attr: attr_t) { }
+	fn attroff(&self, _attr: attr_t) { }
+	fn beep(&self) { }
+	fn flash(&self) { }
+	fn color(&self) -> bool {
+		false
+	}
+	fn link(&self, _url: Option<&str>) { }
+}
+
+thread_local! {
+	static SCREEN: RefCell<Rc<dyn Screen>> = RefCell::new(Rc::new(Terminal { color: true }));
+}
+
+fn screen() -> Rc<dyn Screen> {
+	SCREEN.with(|screen| screen.borrow().clone())
+}
+
+/// Draw into an in-memory grid of the given size instead of the terminal, for the rest of the
+/// current thread, and without color
+#[cfg(test)]
+pub fn headless(size: Size) -> Rc<Grid> {
+	let grid = Rc::new(Grid::new(size));
+	SCREEN.with(|screen| *screen.borrow_mut() = grid.clone());
+	grid
+}
+
+pub fn mv(y: usize, x: usize) {
+	screen().mv(y, x);
+}
+
+pub fn addstr(s: &str) {
+	screen().addstr(s);
+}
+
+pub fn clrtoeol() {
+	screen().clrtoeol();
+}
+
+pub fn scrl(n: i32) {
+	screen().scrl(n);
+}
+
+pub fn clear() {
+	screen().clear();
+}
+
+pub fn refresh() {
+	screen().refresh();
+}
+
+pub fn attron(attr: attr_t) {
+	screen().attron(attr);
+}
+
+pub fn attroff(attr: attr_t) {
+	screen().attroff(attr);
+}
+
+pub fn beep() {
+	screen().beep();
+}
+
+pub fn flash() {
+	screen().flash();
+}
+
+pub fn link(url: Option<&str>) {
+	screen().link(url);
 }
 
 #[derive(Clone, Copy)]
@@ -207,17 +580,15 @@ pub struct Size {
 }
 
 pub fn scrsize() -> Size {
-	Size { w: COLS() as usize, h: LINES() as usize }
+	screen().size()
 }
 
 pub fn curpos() -> (usize, usize) {
-	let (mut y, mut x) = (0, 0);
-	getyx(stdscr(), &mut y, &mut x);
-	(y as usize, x as usize)
+	screen().pos()
 }
 
 #[derive(Clone, Debug)]
-pub enum MouseClick { Press, Release, Click, DoubleClick, TripleClick }
+pub enum MouseClick { Press, Release, Click, DoubleClick, TripleClick, Move }
 
 #[derive(Clone, Debug)]
 pub struct MouseEvent {
@@ -257,6 +628,7 @@ impl MouseEvent {
 			else if b & BUTTON5_CLICKED != 0 { (5, Click) }
 			else if b & BUTTON5_DOUBLE_CLICKED != 0 { (5, DoubleClick) }
 			else if b & BUTTON5_TRIPLE_CLICKED != 0 { (5, TripleClick) }
+			else if b & REPORT_MOUSE_POSITION != 0 { (0, Move) }
 			else { panic!("Unknown button state in mouse event"); }
 		;
 		Self { x: e.x as u32, y: e.y as u32, button: button, kind: kind }
@@ -275,23 +647,29 @@ pub fn mouseevents() -> Vec<MouseEvent> {
 
 pub fn move_in_line(by: isize) { // Apparently ncurses doesn't provide relative movement, so we have to simulate it
 	let (y, x) = curpos();
-	ncurses::mv(y as i32, (x as isize + by) as i32);
+	mv(y, (x as isize + by) as usize);
+}
+
+/// The Curses attributes for a set of text attributes from a backend
+pub fn attrs(attrs: BitFlags<Attr>) -> attr_t {
+	[(Attr::Bold, A_BOLD()), (Attr::Italic, A_ITALIC()), (Attr::Underline, A_UNDERLINE()), (Attr::Reverse, A_REVERSE())].iter()
+		.filter(|(a, _)| attrs.contains(*a)).fold(A_NORMAL(), |acc, (_, attr)| acc | attr)
 }
 
 #[derive(Clone, Debug)]
 pub enum Output {
 	Str(String),
-//	AttrOn(ncurses::attr_t),
-//	AttrOff(ncurses::attr_t),
+	Attr(attr_t), // Replaces the attributes in effect, rather than adding to them
 	Fg(usize),
 	Bg(usize),
 //	Move(usize, usize),
-	Fill(char),
+	Fill(char), // The fill is drawn without attributes, so underlines don't run to the edge of the screen
+	Link(Option<String>), // Starts a hyperlink to the URL, or ends the one in effect
 }
 
 impl Output {
 	pub fn write(line: &[Output], p: &Palette) -> Result<()> {
-		let (mut curfg, mut curbg) = (0, 0);
+		let (mut curfg, mut curbg, mut curattr) = (0, 0, A_NORMAL());
 		let mut wrap = false;
 		line.iter().for_each(|elem| {
 			match elem {
@@ -305,14 +683,14 @@ impl Output {
 						if curpos().1 == 0 { wrap = true; }
 					}
 				},
-//				Output::AttrOn(a) => { ncurses::attr_on(*a); },
-//				Output::AttrOff(a) => { ncurses::attr_off(*a); },
-				Output::Fg(c) => { curfg = *c; p.set(curfg, curbg, ' '); },
-				Output::Bg(c) => { curbg = *c; p.set(curfg, curbg, ' '); },
+				Output::Attr(a) => { curattr = *a; p.set(curfg, curbg, curattr, ' '); },
+				Output::Fg(c) => { curfg = *c; p.set(curfg, curbg, curattr, ' '); },
+				Output::Bg(c) => { curbg = *c; p.set(curfg, curbg, curattr, ' '); },
 //				Output::Move(y, x) => { ncurses::mv(*y as i32, *x as i32); },
 				Output::Fill(c) => {
-					if !wrap { p.set(curfg, curbg, *c); clrtoeol(); }
+					if !wrap { p.set(curfg, curbg, A_NORMAL(), *c); clrtoeol(); }
 				},
+				Output::Link(url) => link(url.as_ref().map(|url| url.as_str())),
 			}
 		});
 		Ok(())
@@ -363,6 +741,23 @@ pub fn parse_keysyms(s: &str) -> Result<Vec<i32>> {
 mod tests {
 	use super::*;
 
+	#[test]
+	fn test_colnum() {
+		let plain = Color { c8: 2, c256: 77, rgb: None };
+		let exact = Color { c8: 2, c256: 77, rgb: Some((0x12, 0x34, 0x56)) };
+		assert_eq!(Depth::of(8), Depth::Ansi);
+		assert_eq!(Depth::of(256), Depth::Xterm);
+		assert_eq!(Depth::of(1 << 24), Depth::Direct);
+		assert_eq!(colnum(&exact, Depth::Ansi), 2);
+		assert_eq!(colnum(&exact, Depth::Xterm), 77);
+		assert_eq!(colnum(&exact, Depth::Direct), 0x123456);
+		assert_eq!(colnum(&plain, Depth::Direct), 0x5fd75f);
+		assert_eq!(colnum(&Color { c8: 3, c256: 3, rgb: None }, Depth::Direct), 3);
+		assert_eq!(colnum(&Color::DEFAULT, Depth::Direct), -1);
+		assert_eq!(colnum(&Color { c8: 0, c256: 0, rgb: Some((0, 0, 0)) }, Depth::Direct), 8);
+		assert_eq!(xterm_rgb(244), (128, 128, 128));
+	}
+
 	#[test]
 	fn test_keysym() {
 		let tests = vec![
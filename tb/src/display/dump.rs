@@ -0,0 +1,162 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use ::format::FmtCmd;
+use ::interface::{Attr, BitFlags, Color, Render, Settings, Source};
+use super::node::with_count;
+use super::value::Value;
+use anyhow::Result;
+
+// SGR parameters that turn on each text attribute
+const ATTR_SGR: [(Attr, &str); 4] = [(Attr::Bold, "1"), (Attr::Italic, "3"), (Attr::Underline, "4"), (Attr::Reverse, "7")];
+
+fn truecolor(colorterm: Option<&str>) -> bool {
+	match colorterm {
+		Some("truecolor") | Some("24bit") => true,
+		_ => false,
+	}
+}
+
+// Like `FmtCmd::render` for `Render::Debug`, with ANSI escape sequences to set the colors and
+// attributes.  Color 0 is the terminal's default, and colors the backend doesn't define are drawn in
+// the error color, as on screen.  A `bg` of None is the terminal's default background.
+fn render_color(fmt: &FmtCmd, colors: &[Color], color: usize, bg: Option<usize>, attrs: BitFlags<Attr>) -> String {
+	let sgr = |c: usize| match (c, colors.get(c).or(colors.get(::interface::FG_ERROR))) {
+		(0, _) | (_, None) => "\x1b[39m".to_string(),
+		(_, Some(Color { rgb: Some((r, g, b)), .. })) => format!("\x1b[38;2;{};{};{}m", r, g, b),
+		(_, Some(col)) if col.c256 < 0 => "\x1b[39m".to_string(),
+		(_, Some(col)) => format!("\x1b[38;5;{}m", col.c256),
+	};
+	let recolor = |c: usize, child: &FmtCmd| format!("{}{}{}", sgr(c), render_color(child, colors, c, bg, attrs), sgr(color));
+	let sgrbg = |c: Option<usize>| match c.and_then(|c| colors.get(c)) {
+		Some(Color { rgb: Some((r, g, b)), .. }) => format!("\x1b[48;2;{};{};{}m", r, g, b),
+		Some(col) if col.c256 >= 0 => format!("\x1b[48;5;{}m", col.c256),
+		_ => "\x1b[49m".to_string(),
+	};
+	// Turn off every attribute, then turn on the ones wanted
+	let sgrattr = |a: BitFlags<Attr>| format!("\x1b[22;23;24;27{}m", ATTR_SGR.iter().filter(|(x, _)| a.contains(*x)).map(|(_, p)| format!(";{}", p)).collect::<String>());
+	match fmt {
+		FmtCmd::Literal(value) => value.to_string(),
+		FmtCmd::Container(children) => children.iter().map(|x| render_color(x, colors, color, bg, attrs)).collect(),
+		FmtCmd::Color(c, child) => recolor(c + super::FG_COLORS.len(), child),
+		FmtCmd::RawColor(c, child) => recolor(*c, child),
+		FmtCmd::NoBreak(child) => render_color(child, colors, color, bg, attrs),
+		FmtCmd::Exclude(r, child) => match r.contains(Render::Debug) {
+			true => "".to_string(),
+			false => render_color(child, colors, color, bg, attrs),
+		},
+		FmtCmd::YankSep(_) => "".to_string(),
+		FmtCmd::Background(c, child) => {
+			let newbg = Some(c + super::FG_COLORS.len());
+			format!("{}{}{}", sgrbg(newbg), render_color(child, colors, color, newbg, attrs), sgrbg(bg))
+		},
+		FmtCmd::Link(url, _, child) => {
+			// OSC 8 hyperlink.  It's closed at the end of each line, so that the indentation of the
+			// next one isn't part of it, and opened again after.
+			let url = url.chars().filter(|c| !c.is_control()).collect::<String>();
+			let (open, close) = (format!("\x1b]8;;{}\x1b\\", url), "\x1b]8;;\x1b\\");
+			format!("{}{}{}", open, render_color(child, colors, color, bg, attrs).replace('\n', &format!("{}\n{}", close, open)), close)
+		},
+		FmtCmd::Attr(a, child) => format!("{}{}{}", sgrattr(attrs | *a), render_color(child, colors, color, bg, attrs | *a), sgrattr(attrs)),
+	}
+}
+
+// `levels` is how many more levels to expand, counting this one
+fn dump_value<'a>(value: &Arc<Mutex<Value<'a>>>, depth: usize, levels: usize, indent: usize, counts: bool, colors: Option<&[Color]>, out: &mut dyn Write) -> Result<()> {
+	// Values that only group their siblings, like ranges of a long array, aren't part of the
+	// document, so their children are printed in their place
+	if value.lock().expect("Poisoned lock").grouping() {
+		for child in Value::children(value).iter() {
+			dump_value(child, depth, levels, indent, counts, colors, out)?;
+		}
+		return Ok(());
+	}
+	let expandable = value.lock().expect("Poisoned lock").expandable() && levels > 0;
+	// Values get the same text they do on screen when expanded or collapsed
+	let fmt = match expandable {
+		true => value.lock().expect("Poisoned lock").placeholder(),
+		false => {
+			let (content, counted) = {
+				let value = value.lock().expect("Poisoned lock");
+				(value.summary().unwrap_or_else(|| value.content()), value.expandable() && counts)
+			};
+			match counted {
+				true => with_count(content, Value::count(value)),
+				false => content,
+			}
+		},
+	};
+	let prefix = " ".repeat(depth * indent);
+	let text = match colors {
+		Some(colors) => render_color(&fmt, colors, 0, None, BitFlags::empty()) + "\x1b[0m",
+		None => fmt.render(Render::Debug, ""),
+	};
+	for line in text.lines() {
+		writeln!(out, "{}{}", prefix, line)?;
+	}
+	if expandable {
+		for child in Value::children(value).iter() {
+			dump_value(child, depth + 1, levels - 1, indent, counts, colors, out)?;
+		}
+	}
+	Ok(())
+}
+
+/// Print the tree as indented plain text, expanded to `levels` levels like `expand-depth` (so
+/// `usize::MAX` prints everything).  If `colors` are given, they are the backend's colors, and the
+/// text is colored with ANSI escape sequences.  Exact RGB colors are only used if `COLORTERM` says
+/// the terminal supports them.
+pub fn dump(source: &dyn Source, settings: Settings, colors: Option<Vec<Color>>, levels: usize, out: &mut dyn Write) -> Result<()> {
+	source.configure(&settings);
+	let truecolor = truecolor(std::env::var("COLORTERM").ok().as_ref().map(|s| s.as_str()));
+	let palette = colors.map(|colors| super::FG_COLORS.iter().cloned().chain(colors)
+		.map(|col| Color { rgb: col.rgb.filter(|_| truecolor), ..col }).collect::<Vec<Color>>());
+	let root = Value::new_root(source.root(), settings.show_empty_marker);
+	if settings.hide_root {
+		for child in Value::children(&root).iter() {
+			dump_value(child, 0, levels.saturating_sub(1), settings.indent, settings.show_counts, palette.as_ref().map(|p| p.as_slice()), out)?;
+		}
+	}
+	else {
+		dump_value(&root, 0, levels, settings.indent, settings.show_counts, palette.as_ref().map(|p| p.as_slice()), out)?;
+	}
+	out.flush()?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn undefined_color() {
+		let colors = super::super::FG_COLORS.iter().cloned().chain(vec![Color { c8: 2, c256: 77, rgb: None }]).collect::<Vec<Color>>();
+		let fmt = |c| FmtCmd::Color(c, Box::new(FmtCmd::Literal("x".to_string())));
+		assert_eq!(render_color(&fmt(0), &colors, 0, None, BitFlags::empty()), "\x1b[38;5;77mx\x1b[39m");
+		assert_eq!(render_color(&fmt(1), &colors, 0, None, BitFlags::empty()), "\x1b[38;5;196mx\x1b[39m");
+	}
+
+	#[test]
+	fn rgb() {
+		let colors = super::super::FG_COLORS.iter().cloned().chain(vec![Color { c8: 2, c256: 77, rgb: Some((1, 2, 3)) }]).collect::<Vec<Color>>();
+		let fmt = FmtCmd::Color(0, Box::new(FmtCmd::Literal("x".to_string())));
+		assert_eq!(render_color(&fmt, &colors, 0, Some(3), BitFlags::empty()), "\x1b[38;2;1;2;3mx\x1b[39m");
+		assert!(truecolor(Some("24bit")));
+		assert!(!truecolor(Some("yes")));
+		assert!(!truecolor(None));
+	}
+
+	#[test]
+	fn links() {
+		use ::interface::fmt::*;
+		let fmt = FmtCmd::from_format(link("http://a/\x1b", lit("x\ny")));
+		assert_eq!(render_color(&fmt, &[], 0, None, BitFlags::empty()), "\x1b]8;;http://a/\x1b\\x\x1b]8;;\x1b\\\n\x1b]8;;http://a/\x1b\\y\x1b]8;;\x1b\\");
+		assert_eq!(fmt.render(Render::Yank, ""), "x\ny");
+	}
+
+	#[test]
+	fn nested_attrs() {
+		use ::interface::fmt::*;
+		let fmt = FmtCmd::from_format(bold(cat(vec![lit("a"), underline(lit("b")), lit("c")])));
+		assert_eq!(render_color(&fmt, &[], 0, None, BitFlags::empty()), "\x1b[22;23;24;27;1ma\x1b[22;23;24;27;1;4mb\x1b[22;23;24;27;1mc\x1b[22;23;24;27m");
+	}
+}
@@ -1,21 +1,38 @@
 use ::interface::Color;
 
-const COLWIDTH: usize = 4;
+// Indexed by the `FG_*` constants in the interface
 const FG_COLORS: [Color; 3] = [
-	Color { c8: 7, c256: 7 }, // regular
-	Color { c8: 4, c256: 244 }, // muted
-	Color { c8: 1, c256: 196 }, // error
+	Color { c8: 7, c256: 7, rgb: None }, // regular
+	Color { c8: 4, c256: 244, rgb: None }, // muted
+	Color { c8: 1, c256: 196, rgb: None }, // error
 ];
-const BG_COLORS: [Color; 3] = [
-	Color { c8: 0, c256: 0 }, // regular
-	Color { c8: 7, c256: 237 }, // selected
-	Color { c8: 3, c256: 88 }, // highlighted
+const BG_COLORS: [Color; 4] = [
+	Color { c8: 0, c256: 0, rgb: None }, // regular
+	Color { c8: 7, c256: 237, rgb: None }, // selected
+	Color { c8: 3, c256: 88, rgb: None }, // highlighted
+	Color { c8: 0, c256: 235, rgb: None }, // hovered
 ];
 
+// The background colors, with the regular one left to the terminal if `transparent` is set,
+// followed by the backend's colors for use as backgrounds
+fn bg_colors(transparent: bool, backend: &[Color]) -> Vec<Color> {
+	let mut ret = BG_COLORS.to_vec();
+	if transparent { ret[0] = Color::DEFAULT; }
+	ret.extend(backend);
+	ret
+}
+
+// Stand-ins for the background colors when drawing without color
+fn bg_attrs() -> Vec<ncurses::attr_t> {
+	vec![ncurses::A_NORMAL(), ncurses::A_REVERSE(), ncurses::A_BOLD() | ncurses::A_UNDERLINE(), ncurses::A_BOLD()]
+}
+
 mod value;
 mod node;
 mod pos;
 mod tree;
-mod statmsg;
+mod dump;
 
-pub use self::tree::Tree;
+pub use self::tree::{Tree, COMMANDS};
+pub use self::dump::dump;
+pub use self::value::FAIL_FAST;
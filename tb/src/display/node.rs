@@ -1,10 +1,43 @@
 use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use ::regex::Regex;
-use ::format::{Preformatted, Search};
+use ::format::{FmtCmd, Layout, Preformatted, Search};
 use ::curses;
-use super::value::Value;
+use super::value::{Query, Value};
+use super::pos::Pos;
+use ::interface::{Render, Wrap};
 use ::interface::Value as BackendValue;
-use super::COLWIDTH;
+use anyhow::Result;
+
+// Frames of the indicator drawn under a node whose children are still loading
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+static FRAME: AtomicUsize = AtomicUsize::new(0); // Current frame of `SPINNER`
+static LOADING: AtomicUsize = AtomicUsize::new(0); // Number of nodes in the `Loading` state
+
+/// A collapsed value's number of children, shown after its content but not searched or yanked
+pub fn count_suffix(count: usize) -> FmtCmd {
+	FmtCmd::from_format(::interface::fmt::hide(::interface::fmt::muted(::interface::fmt::lit(&format!(" ({})", count)))))
+}
+
+/// Follow a collapsed value's content with its number of children
+pub fn with_count(content: FmtCmd, count: usize) -> FmtCmd {
+	FmtCmd::Container(vec![content, count_suffix(count)])
+}
+
+/// Whether any node is waiting for its children, so that the spinner needs to keep moving
+pub fn loading() -> bool {
+	LOADING.load(Ordering::Relaxed) > 0
+}
+
+/// Advance the spinner shown under loading nodes to its next frame
+pub fn spin() {
+	FRAME.fetch_add(1, Ordering::Relaxed);
+}
+
+lazy_static! {
+	static ref ANY: Regex = Regex::new("(?s).+").expect("Invalid internal regex"); // Highlights everything in a kind-only search
+}
 
 struct NodeCache {
 	prefix0: String,
@@ -12,6 +45,19 @@ struct NodeCache {
 	placeholder: Preformatted,
 	content: Preformatted,
 	search: Option<Search>,
+	query: Option<(Query, Render)>, // What `search` was computed for
+	count: Option<usize>, // Number of children, once the node has been drawn collapsed
+	suffix: Option<Preformatted>, // `count` formatted to follow the content
+	width: usize, // Columns available for content after the prefix
+	indent: usize, // Width of each level of the prefix
+}
+
+// Identifies a node among its siblings across refreshes: by the backend's ID if it has one, or
+// else by position
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Key {
+	Id(String),
+	Index(usize),
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -33,6 +79,7 @@ pub struct Node<'a> {
 	value: Arc<Mutex<Value<'a>>>,
 	cache: NodeCache,
 	hide: bool,
+	truncate: Option<usize>, // Show only this many lines of content, followed by an ellipsis
 }
 
 impl<'a> Node<'a> {
@@ -47,13 +94,53 @@ impl<'a> Node<'a> {
 	pub fn lines(&self) -> usize {
 		if self.hide { 0 }
 		else {
+			let content = match self.cutoff() {
+				Some(n) => n + 1,
+				None => self.shown().len(),
+			};
 			match self.state {
-				State::Loading | State::Expanded => self.cache.placeholder.len(),
-				State::Collapsed => self.cache.content.len(),
+				State::Loading => content + 1,
+				_ => content,
 			}
 		}
 	}
 
+	// The line with the loading indicator, after the content, if the node is loading
+	fn loadline(&self) -> Option<usize> {
+		match self.state {
+			State::Loading if !self.hide => Some(self.lines() - 1),
+			_ => None,
+		}
+	}
+
+	fn loadtext(&self) -> (String, String) {
+		let prefix = self.cache.prefix1.to_string() + "└" + &std::iter::repeat("─").take(self.cache.indent - 2).collect::<String>() + " ";
+		(prefix, format!("{} loading…", SPINNER[FRAME.load(Ordering::Relaxed) % SPINNER.len()]))
+	}
+
+	fn shown(&self) -> &Preformatted {
+		match self.state {
+			State::Loading | State::Expanded => &self.cache.placeholder,
+			State::Collapsed => &self.cache.content,
+		}
+	}
+
+	// The line where the ellipsis goes, if the node is truncated and has more lines than that
+	fn cutoff(&self) -> Option<usize> {
+		match self.truncate {
+			Some(n) if self.shown().len() > n + 1 => Some(n),
+			_ => None,
+		}
+	}
+
+	pub fn truncated(&self) -> bool {
+		self.truncate.is_some()
+	}
+
+	pub fn truncate(this: &mut Arc<Mutex<Node<'a>>>, lines: Option<usize>) {
+		this.lock().expect("Poisoned lock").truncate = lines.map(|n| std::cmp::max(n, 1));
+	}
+
 	/* Things I dislike about Rust:
 	 * Mein Gott!  This is an incredibly nasty syntax for doing a simple tree insertion.  In Java,
 	 * Python, etc., the procedure would be a few fairly self-documenting pointer manipulations:
@@ -87,58 +174,73 @@ impl<'a> Node<'a> {
 		}
 	}
 
-	fn prefix(&self, maxdepth: usize, firstline: bool) -> String {
+	fn prefix(&self, maxdepth: usize, indent: usize, firstline: bool) -> String {
 		fn repeat(s: &str, n: usize) -> String {
 			std::iter::repeat(s).take(n).collect::<String>()
 		}
 		/* Things I dislike about Rust:
 		 * You can't reference items from the environment in `fn`s, but you can't make recursive
-		 * closures.  Oops, I guess I just need to pass around `maxdepth` in every function call
-		 * and make everything look more complicated than it really is.
+		 * closures.  Oops, I guess I just need to pass around `maxdepth` and `indent` in every
+		 * function call and make everything look more complicated than it really is.
 		 */
-		fn parent_prefix(n: &Node, depth: usize, maxdepth: usize) -> String {
+		fn parent_prefix(n: &Node, depth: usize, maxdepth: usize, indent: usize) -> String {
 			if depth > maxdepth { "".to_string() }
 			else {
 				match n.parent.upgrade() {
 					None => "".to_string(),
 					Some(parent) => {
-						let ppref = parent_prefix(&parent.lock().expect("Poisoned lock"), depth + 1, maxdepth);
+						let ppref = parent_prefix(&parent.lock().expect("Poisoned lock"), depth + 1, maxdepth, indent);
 						if parent.lock().expect("Poisoned lock").hide { ppref }
-						else if n.last { ppref  + &repeat(" ", COLWIDTH) }
-						else { ppref + "│" + &repeat(" ", COLWIDTH - 1) }
+						else if n.last { ppref  + &repeat(" ", indent) }
+						else { ppref + "│" + &repeat(" ", indent - 1) }
 					},
 				}
 			}
 		}
-		fn cur_prefix(n: &Node, maxdepth: usize) -> String {
+		fn cur_prefix(n: &Node, maxdepth: usize, indent: usize) -> String {
 			match n.parent.upgrade() {
 				None => "".to_string(),
 				Some(parent) => {
 					let branch = if n.last { "└".to_string() } else { "├".to_string() };
-					let ppref = parent_prefix(&parent.lock().expect("Poisoned lock"), 1, maxdepth);
+					let ppref = parent_prefix(&parent.lock().expect("Poisoned lock"), 1, maxdepth, indent);
 					if parent.lock().expect("Poisoned lock").hide { ppref }
-					else { ppref + &branch + &repeat("─", COLWIDTH - 2) + " " }
+					else { ppref + &branch + &repeat("─", indent - 2) + " " }
 				}
 			}
 		}
 		match firstline {
-			true => cur_prefix(self, maxdepth),
-			false => parent_prefix(self, 0, maxdepth),
+			true => cur_prefix(self, maxdepth, indent),
+			false => parent_prefix(self, 0, maxdepth, indent),
 		}
 	}
 
-	pub fn reformat(&mut self, screenwidth: usize) {
+	pub fn reformat(&mut self, layout: Layout) {
+		let screenwidth = layout.width;
 		assert!(screenwidth > 0);
-		let maxdepth = if self.depth() == 0 { 0 } else { (self.depth() - 1) % ((screenwidth - 1) / COLWIDTH) };
-		self.cache.prefix0 = self.prefix(maxdepth, true);
-		self.cache.prefix1 = self.prefix(maxdepth, false);
-		let contentw = screenwidth - ((maxdepth + 1) * COLWIDTH) % screenwidth;
-		self.cache.content = self.value.lock().expect("Poisoned lock").content().format(contentw, super::FG_COLORS.len());
-		self.cache.placeholder = self.value.lock().expect("Poisoned lock").placeholder().format(contentw, super::FG_COLORS.len());
+		let indent = layout.indent;
+		let maxdepth = if self.depth() == 0 { 0 } else { (self.depth() - 1) % ((screenwidth - 1) / indent) };
+		self.cache.prefix0 = self.prefix(maxdepth, indent, true);
+		self.cache.prefix1 = self.prefix(maxdepth, indent, false);
+		self.cache.indent = indent;
+		let contentw = screenwidth - ((maxdepth + 1) * indent) % screenwidth;
+		self.cache.width = contentw;
+		let width = match layout.wrap {
+			Wrap::Screen => contentw,
+			Wrap::Off => 0,
+			Wrap::Column(n) => std::cmp::min(n, contentw),
+		};
+		let contentlayout = Layout { width: width, ..layout };
+		let content = {
+			let value = self.value.lock().expect("Poisoned lock");
+			value.summary().unwrap_or_else(|| value.content())
+		};
+		self.cache.content = content.format(contentlayout, super::FG_COLORS.len(), super::BG_COLORS.len());
+		self.cache.suffix = self.cache.count.map(|count| Self::format_count(count, layout));
+		self.cache.placeholder = self.value.lock().expect("Poisoned lock").placeholder().format(contentlayout, super::FG_COLORS.len(), super::BG_COLORS.len());
 		self.cache.search = None;
 	}
 
-	fn new(parent: Weak<Mutex<Node<'a>>>, val: Arc<Mutex<Value<'a>>>, width: usize, last: bool, hide: bool) -> Self {
+	fn new(parent: Weak<Mutex<Node<'a>>>, val: Arc<Mutex<Value<'a>>>, layout: Layout, last: bool, hide: bool) -> Self {
 		let mut ret = Node {
 			children: vec![],
 			parent: parent,
@@ -152,18 +254,39 @@ impl<'a> Node<'a> {
 			cache: NodeCache {
 				prefix0: "".to_string(),
 				prefix1: "".to_string(),
-				placeholder: Preformatted::new(0),
-				content: Preformatted::new(0),
+				placeholder: Preformatted::new(layout),
+				content: Preformatted::new(layout),
 				search: None,
+				query: None,
+				count: None,
+				suffix: None,
+				width: 0,
+				indent: layout.indent,
 			},
 			hide: hide,
+			truncate: None,
 		};
-		ret.reformat(width);
+		ret.reformat(layout);
 		ret
 	}
 
-	pub fn new_root(val: Box<dyn BackendValue<'a> + 'a>, width: usize, hide: bool) -> Self {
-		Self::new(Weak::new(), Value::new_root(val), width, true, hide)
+	// The suffix is drawn past the end of the content's last line, so it's never wrapped
+	fn format_count(count: usize, layout: Layout) -> Preformatted {
+		count_suffix(count).format(Layout { width: 0, ..layout }, super::FG_COLORS.len(), super::BG_COLORS.len())
+	}
+
+	/// Count the children of a collapsed node, to show after its content.  That loads them, so it
+	/// waits until the node is about to be drawn.
+	pub fn count(&mut self, layout: Layout) {
+		if self.cache.count.is_none() && self.state == State::Collapsed && self.expandable() {
+			let count = Value::count(&self.value);
+			self.cache.count = Some(count);
+			self.cache.suffix = Some(Self::format_count(count, layout));
+		}
+	}
+
+	pub fn new_root(val: Box<dyn BackendValue<'a> + 'a>, layout: Layout, hide: bool, empty_marker: bool) -> Self {
+		Self::new(Weak::new(), Value::new_root(val, empty_marker), layout, true, hide)
 	}
 
 	fn traverse_unhidden(start: &Arc<Mutex<Node<'a>>>, op: &dyn Fn(&Arc<Mutex<Node<'a>>>) -> Weak<Mutex<Node<'a>>>) -> Weak<Mutex<Node<'a>>> {
@@ -207,36 +330,31 @@ impl<'a> Node<'a> {
 		self.value.lock().expect("Poisoned lock").expandable()
 	}
 
-	fn mark_loading(this: &mut Arc<Mutex<Node<'a>>>, _width: usize) {
+	pub fn mark_loading(this: &mut Arc<Mutex<Node<'a>>>, _layout: Layout) {
 		this.lock().expect("Poisoned lock").children.clear();
 		// This is blocked on multi-threading the code, since I want to wait a few milliseconds to
 		// see if the children finish loading before taking the time to do a screen redraw to
 		// display the loading node.
-		/*let val = Value::new_raw(Box::new(StatMsg::new("Loading...".to_string(), 1)), Some(this.lock().expect("Poisoned lock").value.clone()), 0);
-		let mut node = Arc::new(Mutex::new(Self::new(Arc::downgrade(this), val, width, true, false)));
-		{
-			let mut mut_this = this.lock().expect("Poisoned lock");
-			mut_this.next = mut_this.nextsib.clone();
-			mut_this.children.push(node.clone());
-		}
-		Self::insert(&mut this, &mut node);*/
+		// Until then, the loading state is only visible if something draws the tree in between.
+		// `drawline` shows a spinner below the node's placeholder while it lasts.
 		this.lock().expect("Poisoned lock").state = State::Loading;
+		LOADING.fetch_add(1, Ordering::Relaxed);
 	}
 
-	fn load_children(this: &mut Arc<Mutex<Node<'a>>>, width: usize) {
+	pub fn load_children(this: &mut Arc<Mutex<Node<'a>>>, layout: Layout) {
 		assert!(this.lock().expect("Poisoned lock").state == State::Loading);
 		this.lock().expect("Poisoned lock").children.clear();
 		let children = Value::children(&this.lock().expect("Poisoned lock").value);
 		if children.len() > 0 {
 			let lastidx = children.len() - 1;
-			for (i, child) in children.into_iter().enumerate() {
-				let node = Arc::new(Mutex::new(Self::new(Arc::downgrade(this), child, width, i == lastidx, false)));
+			for (i, child) in children.iter().enumerate() {
+				let node = Arc::new(Mutex::new(Self::new(Arc::downgrade(this), Arc::clone(child), layout, i == lastidx, false)));
 				this.lock().expect("Poisoned lock").children.push(node.clone());
 			}
 		}
 	}
 
-	fn finish_loading(this: &mut Arc<Mutex<Node<'a>>>) {
+	pub fn finish_loading(this: &mut Arc<Mutex<Node<'a>>>) {
 		assert!(this.lock().expect("Poisoned lock").state == State::Loading);
 		if let Some(next) = this.lock().expect("Poisoned lock").nextsib.upgrade() {
 			next.lock().expect("Poisoned lock").prev = Arc::downgrade(this);
@@ -250,16 +368,17 @@ impl<'a> Node<'a> {
 			cur = child.clone();
 		}
 		this.lock().expect("Poisoned lock").state = State::Expanded;
+		LOADING.fetch_sub(1, Ordering::Relaxed);
 	}
 
-	pub fn expand(this: &mut Arc<Mutex<Node<'a>>>, width: usize) {
+	pub fn expand(this: &mut Arc<Mutex<Node<'a>>>, layout: Layout) {
 		let (expandable, state) = {
 			let locked_this = this.lock().expect("Poisoned lock");
 			(locked_this.expandable(), locked_this.state)
 		};
 		if expandable && state == State::Collapsed {
-			Self::mark_loading(this, width);
-			Self::load_children(this, width);
+			Self::mark_loading(this, layout);
+			Self::load_children(this, layout);
 			Self::finish_loading(this);
 			// The below code should load children in a different thread to avoid blocking the user
 			// on slow loads.  Unfortunately, it looks like it's strictly forbidden to send data
@@ -269,12 +388,12 @@ impl<'a> Node<'a> {
 			/*use std::sync::Condvar;
 			use std::thread;
 			use std::time::Duration;
-			Self::mark_loading(this, width);
+			Self::mark_loading(this, layout);
 			let notify = Arc::new((Mutex::new(0), Condvar::new())); // 0 = still loading, 1 = done loading and caller reloads, 2 = caller exited so thread reloads
 			let (thread_this, thread_notify) = (this.clone(), notify.clone());
 			thread::spawn(move || {
 				let (lock, cond) = &*thread_notify;
-				Self::load_children(&mut thread_this, width);
+				Self::load_children(&mut thread_this, layout);
 				let mut state = lock.lock().expect("Poisoned lock");
 				if *state == 2 {
 					Self::finish_loading(&mut thread_this);
@@ -306,57 +425,182 @@ impl<'a> Node<'a> {
 		}
 	}
 
-	pub fn toggle(this: &mut Arc<Mutex<Node<'a>>>, width: usize) {
+	pub fn toggle(this: &mut Arc<Mutex<Node<'a>>>, layout: Layout) {
 		let state = this.lock().expect("Poisoned lock").state;
 		match state {
 			State::Expanded => Self::collapse(this),
-			State::Collapsed => Self::expand(this, width),
+			State::Collapsed => Self::expand(this, layout),
 			_ => (),
 		}
 	}
 
-	pub fn recursive_expand(this: &mut Arc<Mutex<Node<'a>>>, width: usize) {
-		if this.lock().expect("Poisoned lock").expandable() {
-			if this.lock().expect("Poisoned lock").state == State::Collapsed { Self::expand(this, width); }
+	pub fn recursive_expand(this: &mut Arc<Mutex<Node<'a>>>, layout: Layout, depth: usize) { // Expand `depth` levels, starting with this one
+		if depth > 0 && this.lock().expect("Poisoned lock").expandable() {
+			if this.lock().expect("Poisoned lock").state == State::Collapsed { Self::expand(this, layout); }
 			let mut children = this.lock().expect("Poisoned lock").children.clone(); // `clone` necessary to prevent a runtime borrow loop
-			for child in children.iter_mut() { Self::recursive_expand(child, width); }
+			for child in children.iter_mut() { Self::recursive_expand(child, layout, depth - 1); }
+		}
+	}
+
+	pub fn expand_to(this: &mut Arc<Mutex<Node<'a>>>, layout: Layout, depth: usize) { // Like `recursive_expand`, but collapse anything deeper
+		if depth == 0 { Self::collapse(this); }
+		else if this.lock().expect("Poisoned lock").expandable() {
+			if this.lock().expect("Poisoned lock").state == State::Collapsed { Self::expand(this, layout); }
+			let mut children = this.lock().expect("Poisoned lock").children.clone();
+			for child in children.iter_mut() { Self::expand_to(child, layout, depth - 1); }
+		}
+	}
+
+	pub fn subtree_lines(this: &Arc<Mutex<Node<'a>>>) -> usize { // Lines taken up by this node and its expanded descendants
+		let end = this.lock().expect("Poisoned lock").nextsib.clone(); // The first node after the subtree, even if it isn't a sibling
+		Pos::new(Arc::downgrade(this), 0).dist_fwd(Pos::new(end, 0)).expect("Subtree does not end at next sibling")
+	}
+
+	pub fn path(&self) -> Vec<usize> {
+		self.value.lock().expect("Poisoned lock").path()
+	}
+
+	pub fn breadcrumb(&self) -> Vec<String> {
+		self.value.lock().expect("Poisoned lock").breadcrumb()
+	}
+
+	pub fn expanded_paths(this: &Arc<Mutex<Node<'a>>>) -> Vec<Vec<usize>> { // Parents come before their children
+		let locked = this.lock().expect("Poisoned lock");
+		if locked.state != State::Expanded { return vec![]; }
+		let mut ret = vec![locked.path()];
+		for child in locked.children.iter() { ret.extend(Self::expanded_paths(child)); }
+		ret
+	}
+
+	pub fn key(&self) -> Key {
+		let value = self.value.lock().expect("Poisoned lock");
+		match value.id() {
+			Some(id) => Key::Id(id),
+			None => Key::Index(value.index),
+		}
+	}
+
+	// Like `expanded_paths`, but relative to this node and by key rather than index
+	pub fn expanded_keys(this: &Arc<Mutex<Node<'a>>>) -> Vec<Vec<Key>> {
+		let locked = this.lock().expect("Poisoned lock");
+		if locked.state != State::Expanded { return vec![]; }
+		let mut ret = vec![vec![]];
+		for child in locked.children.iter() {
+			let key = child.lock().expect("Poisoned lock").key();
+			ret.extend(Self::expanded_keys(child).into_iter().map(|mut keys| { keys.insert(0, key.clone()); keys }));
+		}
+		ret
+	}
+
+	// With `groups` false, values that only group their siblings are left out, giving the path as the
+	// document sees it rather than the steps through the tree
+	pub fn keys_to(this: &Arc<Mutex<Node<'a>>>, descendant: &Arc<Mutex<Node<'a>>>, groups: bool) -> Option<Vec<Key>> { // None if `descendant` isn't under this node
+		let mut ret = vec![];
+		let mut cur = descendant.clone();
+		while !Arc::ptr_eq(&cur, this) {
+			let parent = {
+				let node = cur.lock().expect("Poisoned lock");
+				if groups || !node.value.lock().expect("Poisoned lock").grouping() { ret.push(node.key()); }
+				node.parent.upgrade()?
+			};
+			cur = parent;
 		}
+		ret.reverse();
+		Some(ret)
 	}
 
-	pub fn refresh(this: &mut Arc<Mutex<Node<'a>>>, w: usize) {
-		this.lock().expect("Poisoned lock").reformat(w);
+	pub fn find_key(this: &Arc<Mutex<Node<'a>>>, keys: &[Key], layout: Layout) -> Option<Arc<Mutex<Node<'a>>>> { // Expands ancestors along the way
+		let mut n = this.clone();
+		for key in keys {
+			Self::expand(&mut n, layout);
+			let next = n.lock().expect("Poisoned lock").children.iter().find(|child| child.lock().expect("Poisoned lock").key() == *key).cloned();
+			n = next?;
+		}
+		Some(n)
+	}
+
+	pub fn find(this: &Arc<Mutex<Node<'a>>>, path: &[usize], layout: Layout) -> Option<Arc<Mutex<Node<'a>>>> { // Expands ancestors along the way
+		let mut n = this.clone();
+		for i in path {
+			Self::expand(&mut n, layout);
+			let next = n.lock().expect("Poisoned lock").children.get(*i).cloned();
+			n = next?;
+		}
+		Some(n)
+	}
+
+	pub fn refresh(this: &mut Arc<Mutex<Node<'a>>>, layout: Layout) {
+		let expanded = Self::expanded_keys(this);
+		this.lock().expect("Poisoned lock").value.lock().expect("Poisoned lock").refresh();
+		this.lock().expect("Poisoned lock").cache.count = None;
+		this.lock().expect("Poisoned lock").reformat(layout);
 		if this.lock().expect("Poisoned lock").state == State::Expanded {
 			Self::collapse(this);
-			Self::expand(this, w);
+			for keys in expanded {
+				if let Some(mut node) = Self::find_key(this, &keys, layout) { Self::expand(&mut node, layout); }
+			}
+		}
+	}
+
+	// The child count, if it goes on this line
+	fn suffix(&self, line: usize) -> Option<&Preformatted> {
+		match self.state {
+			State::Collapsed if line + 1 == self.cache.content.len() => self.cache.suffix.as_ref(),
+			_ => None,
 		}
 	}
 
-	pub fn drawline(&self, palette: &curses::Palette, line: usize, selected: bool) {
+	pub fn drawline(&self, palette: &curses::Palette, line: usize, selected: bool, hovered: bool, hoffset: usize) { // `hoffset` columns of content are scrolled off the left
 		let prefixstr = match line {
 			0 => &self.cache.prefix0,
 			_ => &self.cache.prefix1,
 		};
 		let prefix = vec![curses::Output::Fg(1), curses::Output::Str(prefixstr.to_string())];
-		let bg = match selected {
-			true => 1,
-			false => 0,
+		let bg = match (selected, hovered) {
+			(true, _) => 1,
+			(false, true) => 3,
+			(false, false) => 0,
 		};
 		let highlight = 2;
-		match self.state {
-			State::Expanded | State::Loading => self.cache.placeholder.write(line, palette, prefix, bg, highlight, &self.cache.search),
-			State::Collapsed => self.cache.content.write(line, palette, prefix, bg, highlight, &self.cache.search),
-		}.expect("Failed to write line to terminal");
+		if self.loadline() == Some(line) {
+			let (prefix, text) = self.loadtext();
+			let all = vec![curses::Output::Fg(1), curses::Output::Str(prefix), curses::Output::Bg(bg), curses::Output::Str(text), curses::Output::Fill(' '), curses::Output::Fg(0), curses::Output::Bg(0)];
+			curses::Output::write(&all, palette)
+		}
+		else if self.cutoff() == Some(line) {
+			let mut all = prefix;
+			all.extend(vec![curses::Output::Bg(bg), curses::Output::Str("…".to_string()), curses::Output::Fill(' '), curses::Output::Fg(0), curses::Output::Bg(0)]);
+			curses::Output::write(&all, palette)
+		}
+		else { self.shown().write(line, palette, prefix, self.suffix(line), bg, highlight, &self.cache.search, (hoffset, self.cache.width)) }.expect("Failed to write line to terminal");
 	}
 
-	pub fn search(&mut self, query: &Option<Regex>) {
-		let fmt = match self.state {
-			State::Expanded | State::Loading => &self.cache.placeholder,
-			State::Collapsed => &self.cache.content,
+	pub fn linetext(&self, line: usize) -> String {
+		let prefix = match line {
+			0 => &self.cache.prefix0,
+			_ => &self.cache.prefix1,
 		};
+		if self.loadline() == Some(line) {
+			let (prefix, text) = self.loadtext();
+			prefix + &text
+		}
+		else if self.cutoff() == Some(line) { prefix.to_string() + "…" }
+		else { prefix.to_string() + &self.shown().text(line) + &self.suffix(line).map(|suffix| suffix.text(0)).unwrap_or_default() }
+	}
+
+	pub fn search(&mut self, query: &Option<Query>, scope: Render) {
 		if let Some(q) = query {
-			if self.cache.search.is_none() || self.cache.search.as_ref().expect("Failed to get content of non-empty option")
-				.query().map(|x| x.as_str().to_string()) != Some(q.as_str().to_string()) {
-				self.cache.search = Some(fmt.search(q));
+			// This is called for every line drawn, so the check has to be cheap
+			let stale = self.cache.search.is_none() || self.cache.query.as_ref().map(|(cached, cachedscope)| cached == q && *cachedscope == scope) != Some(true);
+			if stale {
+				let kind = q.kind.as_ref().map(|kind| self.value.lock().expect("Poisoned lock").matches_kind(kind)).unwrap_or(true);
+				self.cache.search = Some(match (kind, &q.text) {
+					(false, _) => Search::default(),
+					(true, Some(text)) => self.shown().search(text, scope),
+					// Nothing to highlight but the kind, so highlight everything shown
+					(true, None) => self.shown().search(&ANY, Render::Debug),
+				});
+				self.cache.query = Some((q.clone(), scope));
 			}
 		}
 		else if self.cache.search.is_some() {
@@ -375,16 +619,25 @@ impl<'a> Node<'a> {
 		&self.cache.search
 	}
 
-	pub fn searchfrom(this: &Arc<Mutex<Node>>, query: &Regex, offset: isize) -> Vec<usize> {
+	pub fn searchfrom(this: &Arc<Mutex<Node>>, query: &Query, scope: Render, offset: isize, wrap: bool) -> (Vec<usize>, bool) {
 		// If the user provides an enormous offset, that's their problem.  We could choose to first
 		// check the number of occurrences and mod by that, but that requires a full document scan,
 		// which isn't practical for some backends.
 		let value = this.lock().expect("Poisoned lock").value.clone();
-		(0..offset.abs()).fold(value, |val, _| {
-			Value::searchfrom(&val, query, offset > 0).unwrap_or(val)
-		}).lock().expect("Poisoned lock").path()
+		let mut hitend = false;
+		let found = (0..offset.abs()).fold(value, |val, _| {
+			let (next, end) = Value::searchfrom(&val, query, scope, offset > 0, wrap);
+			hitend |= end;
+			next.unwrap_or(val)
+		});
+		let path = found.lock().expect("Poisoned lock").path();
+		(path, hitend)
 	}
 	
+	pub fn matchindex(&self, query: &Query, scope: Render) -> (usize, usize) {
+		Value::matchindex(&self.value, query, scope)
+	}
+
 	pub fn is_before(this: &Arc<Mutex<Node>>, n: &Arc<Mutex<Node>>) -> bool {
 		let path1 = this.lock().expect("Poisoned lock").value.lock().expect("Poisoned lock").path();
 		let path2 = n.lock().expect("Poisoned lock").value.lock().expect("Poisoned lock").path();
@@ -397,6 +650,17 @@ impl<'a> Node<'a> {
 		false
 	}
 
+	// Signed number of lines from the first line of this node to the first line of `n`
+	pub fn dist(this: &Arc<Mutex<Node<'a>>>, n: &Arc<Mutex<Node<'a>>>) -> isize {
+		if Arc::ptr_eq(this, n) { 0 }
+		else if Node::is_before(this, n) {
+			Pos::new(Arc::downgrade(this), 0).dist_fwd(Pos::new(Arc::downgrade(n), 0)).expect("Node is before another but the other isn't after it") as isize
+		}
+		else {
+			-(Pos::new(Arc::downgrade(n), 0).dist_fwd(Pos::new(Arc::downgrade(this), 0)).expect("Node isn't before another but the other isn't before it") as isize)
+		}
+	}
+
 	pub fn is_ancestor_of(this: &Arc<Mutex<Node>>, n: &Arc<Mutex<Node>>) -> bool {
 		let path1 = this.lock().expect("Poisoned lock").value.lock().expect("Poisoned lock").path();
 		let path2 = n.lock().expect("Poisoned lock").value.lock().expect("Poisoned lock").path();
@@ -405,12 +669,35 @@ impl<'a> Node<'a> {
 		else { true }
 	}
 
-	pub fn invoke(&self) {
-		self.value.lock().expect("Poisoned lock").invoke();
+	pub fn invoke(&self) -> Result<::interface::InvokeResult> {
+		self.value.lock().expect("Poisoned lock").invoke()
 	}
 
-	pub fn yank(&self) -> String {
-		self.value.lock().expect("Poisoned lock").content().render(interface::Render::Yank, "")
+	pub fn actions(&self) -> Vec<(String, ::interface::ActionId)> {
+		self.value.lock().expect("Poisoned lock").actions()
+	}
+
+	pub fn act(&self, action: ::interface::ActionId) -> Result<::interface::InvokeResult> {
+		self.value.lock().expect("Poisoned lock").act(action)
+	}
+
+	pub fn link_target(&self) -> Option<Vec<usize>> {
+		self.value.lock().expect("Poisoned lock").link_target()
+	}
+
+	pub fn yankable(&self) -> bool {
+		self.value.lock().expect("Poisoned lock").yankable()
+	}
+
+	// The text to copy for this node, or `None` if the backend doesn't allow copying it
+	pub fn yank(&self) -> Option<String> {
+		match self.yankable() {
+			true => {
+				let value = self.value.lock().expect("Poisoned lock");
+				Some(value.yank().unwrap_or_else(|| value.content()).render(interface::Render::Yank, ""))
+			},
+			false => None,
+		}
 	}
 }
 
@@ -420,3 +707,218 @@ impl<'a> std::fmt::Debug for Node<'a> {
 		write!(f, "Node({})", content)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ::interface::{fmt, Format};
+	use std::sync::atomic::{AtomicBool, Ordering};
+	use ::config::SearchCase;
+
+	struct TestValue(&'static str, Vec<TestValue>);
+
+	struct TestRef<'a>(&'a TestValue);
+
+	impl<'a> BackendValue<'a> for TestRef<'a> {
+		fn content(&self) -> Format { fmt::lit(self.0 .0) }
+		fn expandable(&self) -> bool { !self.0 .1.is_empty() }
+		fn children(&self) -> Vec<Box<dyn BackendValue<'a> + 'a>> {
+			self.0 .1.iter().map(|child| Box::new(TestRef(child)) as Box<dyn BackendValue<'a> + 'a>).collect()
+		}
+		fn matches_kind(&self, kind: &str) -> bool { kind == "leaf" && self.0 .1.is_empty() }
+		fn yank(&self) -> Option<Format> { if self.0 .0 == "b" { Some(fmt::lit("bee")) } else { None } }
+	}
+
+	fn sample() -> TestValue {
+		TestValue("root", vec![
+			TestValue("a1", vec![]),
+			TestValue("b", vec![TestValue("a2", vec![])]),
+			TestValue("c", vec![TestValue("a3", vec![]), TestValue("d", vec![TestValue("a4", vec![])])]),
+		])
+	}
+
+	const LAYOUT: Layout = Layout { width: 80, cjk: false, wrapglyph: None, wordwrap: false, wrap: Wrap::Screen, indent: 4 };
+
+	#[test]
+	fn search_count() {
+		let tree = sample();
+		let root = Arc::new(Mutex::new(Node::new_root(Box::new(TestRef(&tree)), LAYOUT, false, false)));
+		let query = Query::new("a", false, SearchCase::Sensitive).expect("Empty test query");
+		assert_eq!(Node::searchfrom(&root, &query, Render::Search, 1, true), (vec![0], false));
+		assert_eq!(Node::searchfrom(&root, &query, Render::Search, 3, true), (vec![2, 0], false));
+		assert_eq!(Node::searchfrom(&root, &query, Render::Search, 5, true), (vec![0], true));
+		assert_eq!(Node::searchfrom(&root, &query, Render::Search, 5, false), (vec![2, 1, 0], true));
+		assert_eq!(Node::searchfrom(&root, &query, Render::Search, -1, true), (vec![2, 1, 0], true));
+		assert_eq!(root.lock().expect("Poisoned lock").matchindex(&query, Render::Search), (0, 4));
+	}
+
+	#[test]
+	fn yank() {
+		let tree = sample();
+		let mut root = Arc::new(Mutex::new(Node::new_root(Box::new(TestRef(&tree)), LAYOUT, false, false)));
+		Node::expand(&mut root, LAYOUT);
+		let node = |path: &[usize]| Node::find(&root, path, LAYOUT).expect("Path should resolve");
+		assert_eq!(node(&[0]).lock().expect("Poisoned lock").yank(), Some("a1".to_string()));
+		assert_eq!(node(&[1]).lock().expect("Poisoned lock").yank(), Some("bee".to_string()));
+	}
+
+	#[test]
+	fn order() {
+		let tree = sample();
+		let mut root = Arc::new(Mutex::new(Node::new_root(Box::new(TestRef(&tree)), LAYOUT, false, false)));
+		Node::recursive_expand(&mut root, LAYOUT, usize::MAX);
+		let node = |path: &[usize]| Node::find(&root, path, LAYOUT).expect("Path should resolve");
+		let (a1, b, a2, c, a4) = (node(&[0]), node(&[1]), node(&[1, 0]), node(&[2]), node(&[2, 1, 0]));
+		// Equal
+		assert!(!Node::is_before(&b, &b));
+		assert_eq!(Node::dist(&b, &b), 0);
+		// Ancestor and descendant
+		assert!(Node::is_before(&b, &a2) && !Node::is_before(&a2, &b));
+		assert!(Node::is_ancestor_of(&b, &a2) && !Node::is_ancestor_of(&a2, &b));
+		assert_eq!((Node::dist(&b, &a2), Node::dist(&a2, &b)), (1, -1));
+		assert_eq!((Node::dist(&root, &a4), Node::dist(&a4, &root)), (7, -7));
+		// Siblings, and cousins deeper in the tree
+		assert!(Node::is_before(&a1, &c) && !Node::is_before(&c, &a1));
+		assert!(!Node::is_ancestor_of(&a1, &c));
+		assert_eq!((Node::dist(&a1, &c), Node::dist(&c, &a1)), (3, -3));
+		assert_eq!((Node::dist(&a2, &a4), Node::dist(&a4, &a2)), (4, -4));
+	}
+
+	#[test]
+	fn kind_search() {
+		let tree = sample();
+		let root = Arc::new(Mutex::new(Node::new_root(Box::new(TestRef(&tree)), LAYOUT, false, false)));
+		let leaves = Query::new("type:leaf", false, SearchCase::Sensitive).expect("Empty test query");
+		assert_eq!(Node::searchfrom(&root, &leaves, Render::Search, 2, true), (vec![1, 0], false));
+		assert_eq!(root.lock().expect("Poisoned lock").matchindex(&leaves, Render::Search), (0, 4));
+		let narrowed = Query::new("type:leaf  4", false, SearchCase::Sensitive).expect("Empty test query");
+		assert_eq!(narrowed.text.as_ref().map(|text| text.as_str()), Some("4"));
+		assert_eq!(Node::searchfrom(&root, &narrowed, Render::Search, 1, true), (vec![2, 1, 0], false));
+		assert!(Node::searchfrom(&root, &Query::new("type:dir", false, SearchCase::Sensitive).expect("Empty test query"), Render::Search, 1, true).0.is_empty());
+	}
+
+	#[test]
+	fn count_suffix() {
+		let counted = with_count(FmtCmd::from_format(fmt::lit("{ a: 1 }")), 12);
+		assert_eq!(counted.render(Render::Debug, ""), "{ a: 1 } (12)");
+		assert_eq!(counted.render(Render::Search, ""), "{ a: 1 }");
+		assert_eq!(counted.render(Render::Yank, ""), "{ a: 1 }");
+	}
+
+	// Run with `cargo test -- --ignored --nocapture`.  Simulates redrawing a screenful of matching
+	// nodes on every keystroke of an incremental search.
+	#[test]
+	#[ignore]
+	fn search_bench() {
+		let lines = (0..60).map(|i| TestValue(Box::leak(format!("{} {}", "abc ".repeat(50), i).into_boxed_str()), vec![])).collect::<Vec<TestValue>>();
+		let mut nodes = lines.iter().map(|line| Node::new_root(Box::new(TestRef(line)), LAYOUT, false, false)).collect::<Vec<Node>>();
+		let start = std::time::Instant::now();
+		for q in ["a", "ab", "abc"].iter() {
+			let query = Query::new(q, false, SearchCase::Sensitive);
+			for _ in 0..1000 {
+				for node in nodes.iter_mut() { node.search(&query, Render::Search); }
+			}
+		}
+		println!("{:?} per screen", start.elapsed() / 3000);
+	}
+
+	#[test]
+	fn find_paths() {
+		let tree = sample();
+		let root = Arc::new(Mutex::new(Node::new_root(Box::new(TestRef(&tree)), LAYOUT, false, false)));
+		assert_eq!(Node::expanded_paths(&root), Vec::<Vec<usize>>::new());
+		let found = Node::find(&root, &[2, 1, 0], LAYOUT).expect("Path should resolve");
+		assert_eq!(found.lock().expect("Poisoned lock").yank(), Some("a4".to_string()));
+		assert_eq!(Node::expanded_paths(&root), vec![vec![], vec![2], vec![2, 1]]);
+		assert!(Node::find(&root, &[1, 1], LAYOUT).is_none());
+	}
+
+	// Two children identified by name, which swap places when `flipped` is set
+	struct Swapping<'a>(&'a AtomicBool, &'static str);
+
+	impl<'a> BackendValue<'a> for Swapping<'a> {
+		fn content(&self) -> Format { fmt::lit(self.1) }
+		fn expandable(&self) -> bool { self.1.len() < 2 }
+		fn children(&self) -> Vec<Box<dyn BackendValue<'a> + 'a>> {
+			let names = match (self.1, self.0.load(Ordering::Relaxed)) {
+				("", false) => vec!["x", "y"],
+				("", true) => vec!["y", "x"],
+				("x", _) => vec!["x1"],
+				_ => vec!["y1"],
+			};
+			names.into_iter().map(|name| Box::new(Swapping(self.0, name)) as Box<dyn BackendValue<'a> + 'a>).collect()
+		}
+		fn id(&self) -> Option<String> { Some(self.1.to_string()) }
+	}
+
+	#[test]
+	fn refresh_keys() {
+		let flipped = AtomicBool::new(false);
+		let mut root = Arc::new(Mutex::new(Node::new_root(Box::new(Swapping(&flipped, "")), LAYOUT, false, false)));
+		let x = Node::find(&root, &[0, 0], LAYOUT).expect("Path should resolve");
+		assert_eq!(Node::keys_to(&root, &x, true), Some(vec![Key::Id("x".to_string()), Key::Id("x1".to_string())]));
+		flipped.store(true, Ordering::Relaxed);
+		Node::refresh(&mut root, LAYOUT);
+		assert_eq!(Node::expanded_paths(&root), vec![vec![], vec![1]]);
+		assert_eq!(root.lock().expect("Poisoned lock").children[1].lock().expect("Poisoned lock").yank(), Some("x".to_string()));
+
+		// Without IDs, expansion follows position
+		let tree = sample();
+		let mut root = Arc::new(Mutex::new(Node::new_root(Box::new(TestRef(&tree)), LAYOUT, false, false)));
+		Node::find(&root, &[2, 1, 0], LAYOUT).expect("Path should resolve");
+		Node::refresh(&mut root, LAYOUT);
+		assert_eq!(Node::expanded_paths(&root), vec![vec![], vec![2], vec![2, 1]]);
+	}
+
+	struct Secret(bool); // Whether the backend allows yanking
+
+	impl<'a> BackendValue<'a> for Secret {
+		fn content(&self) -> Format { fmt::noyank(fmt::lit("hunter2")) }
+		fn expandable(&self) -> bool { false }
+		fn children(&self) -> Vec<Box<dyn BackendValue<'a> + 'a>> { vec![] }
+		fn yankable(&self) -> bool { self.0 }
+	}
+
+	#[test]
+	fn yank_veto() {
+		assert_eq!(Node::new_root(Box::new(Secret(false)), LAYOUT, false, false).yank(), None);
+		assert_eq!(Node::new_root(Box::new(Secret(true)), LAYOUT, false, false).yank(), Some(String::new()));
+	}
+
+	#[test]
+	fn truncate() {
+		let tree = TestValue("long line", vec![]);
+		let narrow = Layout { width: LAYOUT.indent + 3, ..LAYOUT }; // Three columns of content
+		let mut root = Arc::new(Mutex::new(Node::new_root(Box::new(TestRef(&tree)), narrow, false, false)));
+		assert_eq!(root.lock().expect("Poisoned lock").lines(), 3);
+		Node::truncate(&mut root, Some(1));
+		let node = root.lock().expect("Poisoned lock");
+		assert_eq!(node.lines(), 2);
+		assert_eq!((0..2).map(|i| node.linetext(i)).collect::<Vec<String>>(), vec!["lon", "…"]);
+		drop(node);
+		Node::truncate(&mut root, Some(2)); // Nothing is hidden if the ellipsis would replace the last line
+		assert_eq!(root.lock().expect("Poisoned lock").lines(), 3);
+	}
+
+	#[test]
+	fn loading() {
+		let tree = sample();
+		let root = Arc::new(Mutex::new(Node::new_root(Box::new(TestRef(&tree)), LAYOUT, false, false)));
+		let mut b = Node::find(&root, &[1], LAYOUT).expect("Path should resolve");
+		Node::mark_loading(&mut b, LAYOUT);
+		assert!(super::loading());
+		{
+			let node = b.lock().expect("Poisoned lock");
+			assert_eq!(node.lines(), 2);
+			assert_eq!(node.linetext(0), "├── b");
+			let frame = node.linetext(1);
+			assert!(frame.starts_with("│   └── ") && frame.ends_with(" loading…"), "Unexpected loading line {:?}", frame);
+			super::spin();
+			assert_ne!(node.linetext(1), frame);
+		}
+		Node::load_children(&mut b, LAYOUT);
+		Node::finish_loading(&mut b);
+		assert_eq!(b.lock().expect("Poisoned lock").lines(), 1);
+		assert_eq!(Node::subtree_lines(&b), 2);
+	}
+}
@@ -1,108 +1,210 @@
+use std::cell::{Cell, RefCell};
 use std::cmp;
 use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::process::{Command, Stdio};
 use std::sync::{Arc, Mutex, Weak};
 use std::time;
+use ::config::{Bell, Config, SearchWrap};
 use ::curses;
+use ::format::Layout;
 use ::interface::*;
 use ::keybinder::Keybinder;
 use ::owning_ref::OwningHandle;
 use ::regex::Regex;
-use super::node::{Node, State};
+use super::node::{self, Key, Node, State};
 use super::pos::Pos;
-use super::statmsg::StatMsg;
+use super::value::{Failed, Query};
 use anyhow::Result;
 
+const SPIN_INTERVAL: i32 = 100; // Milliseconds between frames of the loading indicator
+
+/// The first word of every command `Tree::command` accepts, so that key bindings can be checked
+/// before they're used
+pub const COMMANDS: [&str; 21] = ["select", "node", "peek", "search", "transform", "tab", "sort", "invoke", "actions", "yank",
+	"mark", "refresh", "scroll", "redraw", "command", "shell", "echo", "set", "q", "quit", "nop"];
+
 type OwnedRoot<'a> = OwningHandle<Box<dyn Source>, Box<Arc<Mutex<Node<'a>>>>>;
 
 struct TransformManager<'a> {
 	base: OwnedRoot<'a>,
 	cur: Option<OwnedRoot<'a>>,
 	next: Option<OwnedRoot<'a>>,
+	error: Option<String>, // Why the last proposed transformation failed, if it did
+	applied: Vec<String>, // Transformations that produced `cur`, in order
+	proposed: Option<String>, // Transformation that produced `next`
 }
 
 impl<'a> TransformManager<'a> {
-	fn new_owned_root(source: Box<dyn Source>, w: usize, hideroot: bool) -> OwnedRoot<'a> {
-		OwningHandle::new_with_fn(source, |s| unsafe { Box::new(Arc::new(Mutex::new(Node::new_root(s.as_ref().expect("OwningHandle provided null pointer").root(), w, hideroot)))) } )
+	fn new_owned_root(source: Box<dyn Source>, layout: Layout, settings: &Settings) -> OwnedRoot<'a> {
+		source.configure(settings);
+		OwningHandle::new_with_fn(source, |s| unsafe { Box::new(Arc::new(Mutex::new(Node::new_root(s.as_ref().expect("OwningHandle provided null pointer").root(), layout, settings.hide_root, settings.show_empty_marker)))) } )
 	}
 
-	pub fn new(source: Box<dyn Source>, w: usize, hideroot: bool) -> Self {
+	pub fn new(source: Box<dyn Source>, layout: Layout, settings: &Settings) -> Self {
 		Self {
-			base: Self::new_owned_root(source, w, hideroot),
+			base: Self::new_owned_root(source, layout, settings),
 			cur: None,
 			next: None,
+			error: None,
+			applied: vec![],
+			proposed: None,
 		}
 	}
 
 	pub fn clear(&mut self) -> &Arc<Mutex<Node<'a>>> {
 		self.next = None;
+		self.error = None;
 		self.cur = None;
+		self.applied.clear();
+		self.proposed = None;
 		&*self.base
 	}
 
-	pub fn propose(&mut self, q: &str, w: usize, hideroot: bool) -> Result<&Arc<Mutex<Node<'a>>>> {
+	pub fn propose(&mut self, q: &str, layout: Layout, settings: &Settings) -> Result<&Arc<Mutex<Node<'a>>>> {
 		match self.cur.as_ref().unwrap_or(&self.base).as_owner().transform(q) {
 			Ok(tree) => {
-				self.next = Some(Self::new_owned_root(tree, w, hideroot));
+				self.next = Some(Self::new_owned_root(tree, layout, settings));
+				self.error = None;
+				self.proposed = Some(q.to_string());
 				Ok(&*(self.next.as_ref().expect("self.next was not Some after assigning")))
 			},
-			Err(error) => Err(error),
+			Err(error) => {
+				self.next = None;
+				self.proposed = None;
+				self.error = Some(error.chain().map(|x| x.to_string()).collect::<Vec<String>>().join("\n"));
+				Err(error)
+			},
 		}
 	}
 
-	pub fn accept(&mut self) {
+	pub fn accept(&mut self) -> Result<()> {
+		if let Some(error) = self.error.take() { bail!(error); }
 		std::mem::swap(&mut self.cur, &mut self.next);
 		self.next = None;
+		self.applied.extend(self.proposed.take().filter(|q| q != ""));
+		Ok(())
+	}
+
+	pub fn applied(&self) -> String {
+		self.applied.join(" | ")
+	}
+
+	pub fn configure(&mut self, settings: &Settings, layout: Layout) { // Reloads the trees, since their children may change
+		for root in vec![Some(&mut self.base), self.cur.as_mut(), self.next.as_mut()].into_iter().flatten() {
+			root.as_owner().configure(settings);
+			Node::refresh(root, layout);
+		}
 	}
 
 	pub fn reject(&mut self) -> &Arc<Mutex<Node<'a>>> {
 		self.next = None;
+		self.error = None;
+		self.proposed = None;
 		&*(self.cur.as_ref().unwrap_or(&self.base))
 	}
 }
 
-pub struct Tree<'a> {
+// Cut a string down to fit in the given number of columns, returning it and its width
+fn fit(s: &str, width: usize, cjk: bool) -> (String, usize) {
+	let mut ret = String::new();
+	let mut retw = 0;
+	for c in s.chars().filter(|c| !c.is_control()) {
+		let cw = ::format::charwidth(c, cjk);
+		if retw + cw > width { break; }
+		ret.push(c);
+		retw += cw;
+	}
+	(ret, retw)
+}
+
+// The local time as hours and minutes
+fn clock() -> String {
+	unsafe {
+		let now = libc::time(std::ptr::null_mut());
+		let mut tm: libc::tm = std::mem::zeroed();
+		if libc::localtime_r(&now, &mut tm).is_null() { return String::new(); }
+		format!("{:02}:{:02}", tm.tm_hour, tm.tm_min)
+	}
+}
+
+struct Tab<'a> {
+	name: String, // Label shown in the status bar
 	source: TransformManager<'a>, // Holds tree source and manages transformations
+	view: Option<(Arc<Mutex<Node<'a>>>, Weak<Mutex<Node<'a>>>, Pos<'a>, isize)>, // Root, selection, start, and offset saved while in the background
+	folded: Vec<Vec<usize>>, // Paths that were expanded when the root was collapsed
+}
+
+pub struct Tree<'a> {
+	tabs: Vec<Tab<'a>>, // One tree source per tab
+	curtab: usize, // Index of the tab being displayed
 	root: Arc<Mutex<Node<'a>>>, // Root node of the displayed tree
 	sel: Weak<Mutex<Node<'a>>>, // Currently selected node
+	hover: Weak<Mutex<Node<'a>>>, // Node under the mouse pointer, if hover highlighting is on
 	size: curses::Size, // Terminal size
 	start: Pos<'a>, // Node and line corresponding to the top of the screen
 	offset: isize, // Line number of currently selected node (distance from start to first line of sel)
-	query: Option<Regex>, // Current search query
+	hoffset: usize, // Columns of content scrolled off the left of the screen, when lines aren't wrapped
+	total: Cell<Option<usize>>, // Number of lines in the whole document, if it has been counted since the last reformat
+	marks: RefCell<Option<Vec<usize>>>, // Lines of the document with search matches, if they have been found since the last change
+	selline: usize, // Line within the selected node that vertical motion is tracking
+	query: Option<Query>, // Current search query
 	searchhist: Vec<String>, // Past search queries
 	xformhist: Vec<String>, // Past transformations
 	searchfwd: bool, // Whether the user is searching forward or backward
+	searchscope: Render, // Whether the user is searching only keys or values, or everything (`Search`)
+	centered: bool, // Keep the selection in the middle of the screen whenever it moves
 	lastclick: time::Instant, // Time of the last click, for double-click detection
 	numbuf: Vec<char>, // Buffer for numbers entered to prefix a command
+	bookmarks: HashMap<char, Vec<usize>>, // Paths of nodes marked by the user, by the name they were given
 	palette: curses::Palette, // Colors available for drawing this tree
 	settings: Settings, // Configuration info
+	config: Config, // User settings
 	quit: Arc<Mutex<bool>>, // Whether we should quit after next update
 	msg: String, // Current message to desplay in the status bar
 	lock: Arc<Mutex<()>>, // Single-thread all updates
 }
 
 impl<'a> Tree<'a> {
-	pub fn new(tree: Box<dyn Source>, colors: Vec<Color>, settings: Settings) -> Result<Self> {
+	pub fn new(trees: Vec<(String, Box<dyn Source>)>, colors: Vec<Color>, settings: Settings, user: &[(String, String)]) -> Result<Self> {
 		let size = curses::scrsize();
-		let mut source = TransformManager::new(tree, size.w, settings.hide_root);
-		let root = Arc::clone(source.clear());
+		let mut config = Config::new(&settings);
+		for (key, value) in user { config.set(key, value)?; }
+		::backends::set_ctrl_style(config.control_chars);
+		let layout = Layout { width: size.w, cjk: config.cjk(), wrapglyph: config.wrap_glyph, wordwrap: config.word_wrap, wrap: config.wrap, indent: config.indent };
+		let mut tabs = trees.into_iter().map(|(name, tree)| Tab { name: name, source: TransformManager::new(tree, layout, &settings), view: None, folded: vec![] }).collect::<Vec<Tab>>();
+		let root = Arc::clone(tabs.first_mut().ok_or(anyhow!("No trees to display"))?.source.clear());
 		let mut fgcol = super::FG_COLORS.to_vec();
 		fgcol.extend(colors);
-		let palette = curses::Palette::new(fgcol, super::BG_COLORS.to_vec())?;
+		let palette = match curses::color() {
+			true => curses::Palette::new(fgcol.clone(), super::bg_colors(config.transparent_bg, &fgcol[super::FG_COLORS.len()..]))?,
+			false => curses::Palette::mono(super::bg_attrs()),
+		};
 		Ok(Tree {
-			source: source,
+			tabs: tabs,
+			curtab: 0,
 			sel: Arc::downgrade(&root),
+			hover: Weak::new(),
 			size: size,
 			start: Pos::new(Arc::downgrade(&root), 0),
 			offset: 0,
+			hoffset: 0,
+			total: Cell::new(None),
+			marks: RefCell::new(None),
+			selline: 0,
 			query: None,
 			searchhist: vec![],
 			xformhist: vec![],
 			searchfwd: true,
+			searchscope: Render::Search,
+			centered: false,
 			lastclick: time::Instant::now().checked_sub(time::Duration::from_secs(60)).expect("This program cannot be run before January 2, 1970"),
 			numbuf: vec![],
+			bookmarks: HashMap::new(),
 			palette: palette,
 			root: root,
 			settings: settings,
+			config: config,
 			quit: Arc::new(Mutex::new(false)),
 			msg: String::new(),
 			lock: Arc::new(Mutex::new(())),
@@ -131,10 +233,20 @@ impl<'a> Tree<'a> {
 		cur
 	}
 
+	fn source(&mut self) -> &mut TransformManager<'a> {
+		&mut self.tabs[self.curtab].source
+	}
+
+	fn layout(&self) -> Layout {
+		let gutter = if self.config.minimap { 1 } else { 0 };
+		Layout { width: self.size.w - gutter, cjk: self.config.cjk(), wrapglyph: self.config.wrap_glyph, wordwrap: self.config.word_wrap, wrap: self.config.wrap, indent: self.config.indent }
+	}
+
 	fn check_term_size(&self) -> bool {
 		if self.size.h < 1 || self.size.w < 24 {
-			ncurses::clear();
-			ncurses::mvaddstr(0, 0, "Terminal too small!");
+			curses::clear();
+			curses::mv(0, 0);
+			curses::addstr("Terminal too small!");
 			false
 		}
 		else { true }
@@ -143,23 +255,25 @@ impl<'a> Tree<'a> {
 	fn drawline(&self, line: usize, cur: Pos<'a>) {
 		const DEBUG: bool = false;
 		if self.check_term_size() {
-			ncurses::mv(line as i32, 0);
-			ncurses::clrtoeol();
+			curses::mv(line, 0);
+			curses::clrtoeol();
 			let selected = self.sel.ptr_eq(&cur.node);
+			let hovered = self.hover.ptr_eq(&cur.node);
 			if let Some(node) = cur.node.upgrade() {
 				if DEBUG {
 					let fill = std::iter::repeat(" ").take(self.size.w).collect::<String>();
-					ncurses::attron(ncurses::A_REVERSE());
-					ncurses::addstr(&fill);
-					ncurses::refresh();
+					curses::attron(ncurses::A_REVERSE());
+					curses::addstr(&fill);
+					curses::refresh();
 					std::thread::sleep(time::Duration::from_millis(100));
-					ncurses::mv(line as i32, 0);
-					ncurses::attroff(ncurses::A_REVERSE());
-					ncurses::addstr(&fill);
-					ncurses::mv(line as i32, 0);
+					curses::mv(line, 0);
+					curses::attroff(ncurses::A_REVERSE());
+					curses::addstr(&fill);
+					curses::mv(line, 0);
 				}
-				node.lock().expect("Poisoned lock").search(&self.query);
-				node.lock().expect("Poisoned lock").drawline(&self.palette, cur.line, selected);
+				if self.settings.show_counts { node.lock().expect("Poisoned lock").count(self.layout()); }
+				node.lock().expect("Poisoned lock").search(&self.query, self.searchscope);
+				node.lock().expect("Poisoned lock").drawline(&self.palette, cur.line, selected, hovered, self.hoffset);
 			}
 		}
 	}
@@ -172,6 +286,12 @@ impl<'a> Tree<'a> {
 		}
 	}
 
+	// Advance the loading indicator and redraw the screen around it
+	fn spin(&self) {
+		node::spin();
+		self.drawlines((0, self.size.h));
+	}
+
 	fn sellines(&self) -> (usize, usize) {
 		let sel = self.sel.upgrade().expect("Couldn't get selection in sellines");
 		let lines = sel.lock().expect("Poisoned lock").lines();
@@ -179,17 +299,174 @@ impl<'a> Tree<'a> {
 		(cmp::max(self.offset, 0) as usize, cmp::min((self.offset + lines as isize) as usize, self.size.h))
 	}
 
+	fn bell(&self) {
+		match self.config.bell {
+			Bell::Off => (),
+			Bell::Beep => { curses::beep(); },
+			Bell::Flash => { curses::flash(); },
+		}
+	}
+
 	fn echo(&mut self, s: String) {
 		self.msg = s;
 	}
 
+	// Counting the whole document is slow, so the count is kept up to date as nodes are expanded and
+	// collapsed, and only recounted after something invalidates it
+	fn total(&self) -> usize {
+		if let Some(total) = self.total.get() { return total; }
+		let total = Pos::new(Arc::downgrade(&self.root), 0).dist_fwd(Pos::nil()).expect("Couldn't find distance from root to end");
+		self.total.set(Some(total));
+		total
+	}
+
+	fn reshape(&mut self, node: &mut Arc<Mutex<Node<'a>>>, op: &dyn Fn(&mut Arc<Mutex<Node>>, Layout) -> ()) {
+		let before = self.total.get().map(|_| Node::subtree_lines(node));
+		op(node, self.layout());
+		if let (Some(total), Some(before)) = (self.total.get(), before) {
+			self.total.set(Some(total + Node::subtree_lines(node) - before));
+		}
+		self.marks.replace(None);
+	}
+
+	// Forget everything counted over the whole document, after a change that could affect it anywhere
+	fn recount(&self) {
+		self.total.set(None);
+		self.marks.replace(None);
+	}
+
+	// Lines of the document with search matches.  Like `total`, this takes a walk through the whole
+	// document, so the result is kept until something changes.
+	fn matchlines(&self) -> Vec<usize> {
+		if let Some(marks) = self.marks.borrow().as_ref() { return marks.clone(); }
+		let mut ret = vec![];
+		if self.query.is_some() {
+			let mut line = 0;
+			let mut cur = Some(self.root.clone());
+			while let Some(node) = cur {
+				let mut locked = node.lock().expect("Poisoned lock");
+				locked.search(&self.query, self.searchscope);
+				let lines = locked.lines();
+				if let Some(search) = locked.getsearch() {
+					ret.extend(search.matchlines().into_iter().filter(|m| *m < lines).map(|m| line + m));
+				}
+				line += lines;
+				drop(locked);
+				cur = Node::next(&node).upgrade();
+			}
+		}
+		self.marks.replace(Some(ret.clone()));
+		ret
+	}
+
+	// A one-column overview of the whole document down the right edge of the screen, with the part
+	// on screen shaded and lines with search matches marked
+	fn drawgutter(&self) {
+		if !self.config.minimap || !self.check_term_size() { return; }
+		let h = self.size.h;
+		let total = cmp::max(self.total(), 1);
+		let top = Pos::new(Arc::downgrade(&self.root), 0).dist_fwd(self.start.clone()).unwrap_or(0);
+		let marks = self.matchlines();
+		for row in 0..h {
+			let (lo, hi) = (row * total / h, cmp::max((row + 1) * total / h, row * total / h + 1));
+			let onscreen = lo < top + h && hi > top;
+			let marked = marks.get(marks.partition_point(|m| *m < lo)).map(|m| *m < hi) == Some(true);
+			curses::mv(row, self.size.w - 1);
+			let cell = vec![
+				curses::Output::Fg(0),
+				curses::Output::Bg(if onscreen { 1 } else { 0 }),
+				curses::Output::Str((if marked { "•" } else { " " }).to_string()),
+				curses::Output::Bg(0),
+			];
+			curses::Output::write(&cell, &self.palette).expect("Failed to write line to terminal");
+		}
+	}
+
+	// Percentage of the document that is above the bottom of the screen
+	fn percent(&self) -> usize {
+		let top = Pos::new(Arc::downgrade(&self.root), 0);
+		let total = self.total();
+		let seen = top.dist_fwd(self.start.clone()).unwrap_or(0) + self.size.h;
+		if total == 0 { 100 } else { cmp::min(seen, total) * 100 / total }
+	}
+
+	fn statfield(&self, field: char) -> String {
+		match field {
+			'p' => format!("{}%", self.percent()),
+			't' => clock(),
+			'q' => match (&self.query, self.searchhist.last()) {
+				(Some(_), Some(query)) => format!("{}{}", if self.searchfwd { "/" } else { "?" }, query),
+				_ => String::new(),
+			},
+			'x' => match self.tabs[self.curtab].source.applied() {
+				ref xform if xform.is_empty() => String::new(),
+				xform => format!("|{}", xform),
+			},
+			_ => String::new(),
+		}
+	}
+
 	fn statline(&self) {
 		if self.check_term_size() {
-			ncurses::mv(self.size.h as i32, 0);
-			ncurses::clrtoeol();
-			ncurses::addstr(&self.msg); // TODO Truncate to fit
-			ncurses::mv(self.size.h as i32, self.size.w as i32 - 8);
-			ncurses::addstr(&self.numbuf.iter().collect::<String>());
+			// The number being typed goes in the last eight columns, the status format just before it,
+			// and the message gets whatever room is left
+			let cjk = self.config.cjk();
+			let avail = self.size.w - 8;
+			let status = match self.centered {
+				true => ("[centered] ".to_string() + &self.config.status(|c| self.statfield(c))).trim_end().to_string(),
+				false => self.config.status(|c| self.statfield(c)),
+			};
+			let (status, statusw) = fit(&status, avail, cjk);
+			let msgw = avail - statusw - if statusw > 0 { 1 } else { 0 };
+			let msg = match self.msg.is_empty() {
+				true => {
+					let mut msg = match self.tabs.len() > 1 {
+						true => format!("[{}/{}] {} ", self.curtab + 1, self.tabs.len(), self.tabs[self.curtab].name),
+						false => String::new(),
+					};
+					if self.config.breadcrumb {
+						let room = msgw.saturating_sub(fit(&msg, msgw, cjk).1);
+						msg += &self.breadcrumb(room);
+					}
+					msg
+				},
+				false => self.msg.clone(),
+			};
+			let (msg, _) = fit(&msg, msgw, cjk);
+			curses::mv(self.size.h, 0);
+			curses::clrtoeol();
+			curses::addstr(&msg);
+			curses::mv(self.size.h, avail - statusw);
+			curses::addstr(&status);
+			curses::mv(self.size.h, avail);
+			curses::addstr(&self.numbuf.iter().collect::<String>());
+		}
+	}
+
+	// The labels of the selection and its ancestors, with as many of the outermost dropped as needed
+	// to fit in `width` columns
+	fn breadcrumb(&self, width: usize) -> String {
+		let mut crumbs = self.sel.upgrade().expect("Couldn't get selection in breadcrumb").lock().expect("Poisoned lock").breadcrumb();
+		if self.settings.hide_root && crumbs.len() > 1 { crumbs.remove(0); }
+		let mut skip = 0;
+		loop {
+			let ret = match skip {
+				0 => crumbs.join(" > "),
+				_ => format!("… > {}", crumbs[skip..].join(" > ")),
+			};
+			if skip + 1 >= crumbs.len() || fit(&ret, usize::MAX, self.config.cjk()).1 <= width { return ret; }
+			skip += 1;
+		}
+	}
+
+	// Shift unwrapped content sideways by `by` columns, to the left for positive values
+	fn hscroll(&mut self, by: isize) {
+		if self.config.wrap != Wrap::Off { return self.echo("Lines are wrapped; use :set wrap off to scroll sideways".to_string()); }
+		let hoffset = cmp::max(self.hoffset as isize + by, 0) as usize;
+		if hoffset == self.hoffset { self.bell(); }
+		else {
+			self.hoffset = hoffset;
+			self.drawlines((0, self.size.h));
 		}
 	}
 
@@ -225,11 +502,11 @@ impl<'a> Tree<'a> {
 			if dist >= self.size.h { self.drawlines((0, self.size.h)); }
 			else if diff != 0 {
 				if diff > 0 {
-					ncurses::scrl(dist as i32);
+					curses::scrl(dist as i32);
 					self.drawlines((self.size.h - dist, self.size.h));
 				}
 				else if diff < 0 {
-					ncurses::scrl(-(dist as i32));
+					curses::scrl(-(dist as i32));
 					self.drawlines((0, dist));
 				}
 				if !self.sel.ptr_eq(&oldsel) { self.drawlines(self.sellines()); }
@@ -241,25 +518,28 @@ impl<'a> Tree<'a> {
 	}
 
 	fn select(&mut self, sel: Arc<Mutex<Node<'a>>>, scrollin: bool) -> isize {
+		let oldsel = self.sel.upgrade().expect("Couldn't get selection in select");
+		let same = Arc::ptr_eq(&oldsel, &sel);
+		// Reselecting the selection, as when `p` is pressed at the root, shouldn't move the view
+		// back to the top of a tall node
+		let scrollin = scrollin && !same;
+		if !same { self.selline = 0; }
 		if self.check_term_size() {
-			let oldsel = self.sel.upgrade().expect("Couldn't get selection in select");
-			let same = Arc::ptr_eq(&oldsel, &sel);
-			let down = Node::is_before(&oldsel, &sel);
 			let oldlines = self.sellines();
-			self.offset += match down {
-				true => Pos::new(self.sel.clone(), 0).dist_fwd(Pos::new(Arc::downgrade(&sel), 0))
-					.expect("Down is true but new selection not after old") as isize,
-				false => -(Pos::new(Arc::downgrade(&sel), 0).dist_fwd(Pos::new(self.sel.clone(), 0))
-					.expect("Down is false but new selection not before old") as isize),
-			};
+			self.offset += Node::dist(&oldsel, &sel);
 			self.sel = Arc::downgrade(&sel);
 			let scrolldist = self.scroll({
 				let lines = sel.lock().expect("Poisoned lock").lines() as isize;
 				let off = self.offset;
 				let h = self.size.h as isize;
+				// Scrolling in brings the selection at least `scrolloff` lines away from the edges, but
+				// not so far that the view goes past the end of the document, unless that's allowed
+				let margin = cmp::min(self.config.scrolloff, (self.size.h - 1) / 2) as isize;
+				let inner = h - 2 * margin;
 				if lines == 0 { if scrollin { self.statline(); } 0 }
-				else if scrollin && off < 0 { off + lines - h - cmp::min(lines - h, 0) }
-				else if scrollin && off + lines >= h { off + cmp::min(lines - h, 0) }
+				else if self.centered && !same { self.centerdist() }
+				else if scrollin && off < margin { off - margin + lines - inner - cmp::min(lines - inner, 0) }
+				else if scrollin && off + lines >= h - margin { self.clampdist(off - margin + cmp::min(lines - inner, 0)) }
 				else if off + lines <= 0 { off + lines - 1 }
 				else if off >= h { off - h + 1 }
 				else { if scrollin { self.statline(); } 0 }
@@ -285,6 +565,23 @@ impl<'a> Tree<'a> {
 		else { 0 }
 	}
 
+	// Shorten a scroll down so that it stops once the end of the document reaches the bottom of the
+	// screen, unless the user would rather be able to scroll past it
+	fn clampdist(&self, dist: isize) -> isize {
+		let h = self.size.h as isize;
+		if dist <= 0 || self.config.scroll_past_end { dist }
+		else {
+			let end = self.start.fwd((dist + h - 1) as usize, true);
+			let room = self.start.dist_fwd(end).expect("Seek returned an incorrect node") as isize - (h - 1);
+			cmp::min(dist, cmp::max(room, 0))
+		}
+	}
+
+	// How far to scroll to put the selection in the middle of the screen
+	fn centerdist(&self) -> isize {
+		self.clampdist(self.offset - (self.size.h as isize) / 2)
+	}
+
 	fn foreach(&mut self, f: &dyn Fn(&mut Node)) {
 		let mut cur = Arc::downgrade(&self.root);
 		while let Some(n) = cur.upgrade() {
@@ -294,8 +591,9 @@ impl<'a> Tree<'a> {
 	}
 
 	fn redraw(&self) {
-		ncurses::clear();
+		curses::clear();
 		self.drawlines((0, self.size.h));
+		self.drawgutter();
 		self.statline();
 	}
 
@@ -305,8 +603,9 @@ impl<'a> Tree<'a> {
 		size.h -= 1;
 		self.size = size;
 		if self.check_term_size() {
-			let w = self.size.w;
-			self.foreach(&|n: &mut Node| n.reformat(w));
+			let layout = self.layout();
+			self.foreach(&|n: &mut Node| n.reformat(layout));
+			self.recount();
 			self.start = Pos::new(self.start.node.clone(), 0).fwd(self.start.line, true);
 			let sel = self.sel.upgrade().expect("Couldn't get selection in resize");
 			// If `start` is the last line of a multi-line wrapped node, but we make the terminal
@@ -317,29 +616,71 @@ impl<'a> Tree<'a> {
 				if let Some(ret) = fwd { ret }
 				else { -(curpos.dist_fwd(self.start.clone()).expect("Could not determine new offset in resize") as isize) }
 			};
+			let selline = self.selline;
 			self.select(sel, false);
+			self.selline = selline;
+			self.showline();
 			self.redraw();
 		}
 	}
 
 	fn selpos(&mut self, line: usize) {
-		let target = self.start.fwd(line, true).node.upgrade().expect("Tried to select invalid line");
-		self.select(target, true);
+		let target = self.start.fwd(line, true);
+		self.select(target.node.upgrade().expect("Tried to select invalid line"), true);
+		self.selline = target.line;
+	}
+
+	fn showline(&mut self) {
+		// Scroll the tracked line of the selection onto the screen, clamping it to the lines the
+		// selection actually has (which may have changed since it was recorded)
+		let lines = self.sel.upgrade().expect("Couldn't get selection in showline").lock().expect("Poisoned lock").lines();
+		self.selline = cmp::min(self.selline, cmp::max(lines, 1) - 1);
+		let line = self.offset + self.selline as isize;
+		let h = self.size.h as isize;
+		if line < 0 { self.scroll(line); }
+		else if line >= h { self.scroll(line - h + 1); }
 	}
 
-	fn accordion(&mut self, mut node: &mut Arc<Mutex<Node<'a>>>, op: &dyn Fn(&mut Arc<Mutex<Node>>, usize) -> ()) {
+	fn stepline(&mut self, down: bool) {
+		// Vertical motion through a node taller than the screen moves through it a line at a time,
+		// rather than jumping straight past the part that isn't visible yet
+		let sel = self.sel.upgrade().expect("Couldn't get selection in stepline");
+		let lines = sel.lock().expect("Poisoned lock").lines() as isize;
+		let h = self.size.h as isize;
+		if down && self.offset + lines > h {
+			self.scroll(1);
+			self.selline = (h - 1 - self.offset) as usize;
+		}
+		else if !down && self.offset < 0 {
+			self.scroll(-1);
+			self.selline = -self.offset as usize;
+		}
+		else {
+			let next = if down { Node::next(&sel) } else { Node::prev(&sel) };
+			match next.upgrade() {
+				Some(n) => {
+					let nlines = n.lock().expect("Poisoned lock").lines();
+					self.select(n, true);
+					if !down { self.selline = cmp::max(nlines, 1) - 1; }
+				},
+				None => self.bell(),
+			}
+		}
+	}
+
+	fn accordion(&mut self, mut node: &mut Arc<Mutex<Node<'a>>>, op: &dyn Fn(&mut Arc<Mutex<Node>>, Layout) -> ()) {
 		let start = self.start.node.upgrade().expect("Couldn't get start node in accordion");
 		let sel = self.sel.upgrade().expect("Couldn't get selection in accordion");
 		if Node::is_before(&node, &sel) {
-			if Node::is_before(&node, &start) && !Node::is_ancestor_of(&node, &start) { op(&mut node, self.size.w); }
+			if Node::is_before(&node, &start) && !Node::is_ancestor_of(&node, &start) { self.reshape(&mut node, op); }
 			else {
 				if Node::is_ancestor_of(&node, &sel) {
 					self.select(node.clone(), true); // TODO Use path resolution to select a new sel
-					op(&mut node, self.size.w);
+					self.reshape(&mut node, op);
 				}
 				else {
 					let oldoff = Pos::new(Arc::downgrade(&node), 0).dist_fwd(Pos::new(Arc::downgrade(&sel), 0)).expect("is_before returned true, but dist_fwd returned None") as isize;
-					op(&mut node, self.size.w);
+					self.reshape(&mut node, op);
 					let newoff = Pos::new(Arc::downgrade(&node), 0).dist_fwd(Pos::new(Arc::downgrade(&sel), 0)).expect("is_before returned true, but dist_fwd returned None") as isize;
 					if Node::is_before(&node, &start) {
 						// The node is an ancestor of the start node.  In the case of a collapse,
@@ -358,10 +699,10 @@ impl<'a> Tree<'a> {
 				self.redraw();
 			}
 		}
-		else if Node::is_before(&self.start.fwd(self.size.h - 1, true).node.upgrade().expect("Safe traversal returned None"), &node) { op(&mut node, self.size.w); }
+		else if Node::is_before(&self.start.fwd(self.size.h - 1, true).node.upgrade().expect("Safe traversal returned None"), &node) { self.reshape(&mut node, op); }
 		else {
 			let mut maxend = Pos::new(Arc::downgrade(&node), 0).dist_fwd(Pos::nil()).expect("Failed to find distance to end of document");
-			op(&mut node, self.size.w);
+			self.reshape(&mut node, op);
 			maxend = cmp::max(maxend, Pos::new(Arc::downgrade(&node), 0).dist_fwd(Pos::nil()).expect("Failed to find distance to end of document"));
 			// Unfortunately we need to redraw the whole selection, because we don't know how much it's changed due to the (un)expansion
 			let startoff = cmp::max(self.offset, 0) as usize;
@@ -369,42 +710,126 @@ impl<'a> Tree<'a> {
 		}
 	}
 
+	// Collapsing the root folds the whole document into its first line.  Expanding it again restores
+	// everything that was expanded inside it, rather than just its children.  Returns false if the
+	// selection isn't a visible root, so the caller should expand or collapse it as usual.
+	fn foldroot(&mut self, expand: bool) -> bool {
+		if !self.sel.ptr_eq(&Arc::downgrade(&self.root)) || self.root.lock().expect("Poisoned lock").lines() == 0 { return false; }
+		let state = self.root.lock().expect("Poisoned lock").state;
+		match (expand, state) {
+			(false, State::Expanded) => {
+				self.tabs[self.curtab].folded = Node::expanded_paths(&self.root);
+				Node::collapse(&mut self.root.clone());
+			},
+			(true, State::Collapsed) if !self.tabs[self.curtab].folded.is_empty() => {
+				let layout = self.layout();
+				for path in std::mem::replace(&mut self.tabs[self.curtab].folded, vec![]) {
+					if let Some(mut node) = Node::find(&self.root, &path, layout) { Node::expand(&mut node, layout); }
+				}
+			},
+			_ => return false,
+		}
+		self.recount();
+		// The root might have been scrolled partway off the top
+		self.start = Pos::new(Arc::downgrade(&self.root), 0);
+		self.offset = 0;
+		self.redraw();
+		true
+	}
+
+	fn toggle(&mut self) {
+		let expand = self.sel.upgrade().expect("Couldn't get selection in toggle").lock().expect("Poisoned lock").state != State::Expanded;
+		if !self.foldroot(expand) {
+			self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection in toggle"), &|mut sel, w| Node::toggle(&mut sel, w));
+		}
+	}
+
+	// Show only the first few lines of the selection, or all of it again.  `on` of None toggles.
+	fn truncate(&mut self, on: Option<bool>) {
+		let mut sel = self.sel.upgrade().expect("Couldn't get selection in truncate");
+		let on = on.unwrap_or(!sel.lock().expect("Poisoned lock").truncated());
+		if self.offset < 0 { // Bring the top of the selection on screen, since the lines above might go away
+			self.start = Pos::new(self.sel.clone(), 0);
+			self.offset = 0;
+		}
+		let lines = if on { Some(self.settings.truncate_lines) } else { None };
+		self.accordion(&mut sel, &|n, _| Node::truncate(n, lines));
+		self.showline();
+	}
+
+	// Expand the selection if necessary, and select its first child
+	fn enter(&mut self) {
+		if !self.foldroot(true) { self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection in enter"), &|mut sel, w| Node::expand(&mut sel, w)); }
+		let first = self.sel.upgrade().expect("Couldn't get selection in enter").lock().expect("Poisoned lock").children.first().cloned();
+		match first {
+			Some(child) => { self.select(child, true); },
+			None => self.bell(),
+		}
+	}
+
+	// Collapse the selection if it's expanded, or else select its parent, like Left in a file manager
+	fn leave(&mut self) {
+		let mut sel = self.sel.upgrade().expect("Couldn't get selection in leave");
+		let expanded = sel.lock().expect("Poisoned lock").state == State::Expanded;
+		if expanded {
+			if !self.foldroot(false) { self.accordion(&mut sel, &|mut n, _| Node::collapse(&mut n)); }
+		}
+		else {
+			match Node::parent(&sel).upgrade() {
+				Some(parent) => { self.select(parent, true); },
+				None => self.bell(),
+			}
+		}
+	}
+
 	fn refresh(&mut self, node: &mut Arc<Mutex<Node<'a>>>) {
+		// If the selection is under the refreshed node, keep it on the same value, if that survives
+		let sel = self.sel.upgrade().expect("Couldn't get selection in refresh");
+		let selkeys = if Arc::ptr_eq(node, &sel) { None } else { Node::keys_to(node, &sel, true) };
 		self.accordion(node, &|n, w| Node::refresh(n, w));
+		if let Some(keys) = selkeys {
+			if let Some(newsel) = Node::find_key(node, &keys, self.layout()) {
+				self.recount(); // Finding the selection might have expanded more
+				self.select(newsel, true);
+				self.redraw();
+			}
+		}
 	}
 
-	fn query_from_str(query: &str) -> Option<Regex> {
-		match query {
-			"" => None,
-			q => Some(Regex::new(q).unwrap_or(Regex::new(&regex::escape(q)).expect("Regex construction failed even after escaping"))),
-		}
+	fn prompt_resize() -> Box<dyn FnMut(&mut Self) -> ((usize, usize), usize)> {
+		Box::new(|dt: &mut Tree| {
+			dt.resize();
+			((dt.size.h, 0), dt.size.w.saturating_sub(20))
+		})
 	}
 
-	fn setquery(&mut self, query: Option<Regex>) {
+	fn setquery(&mut self, query: Option<Query>) {
 		self.query = query;
+		self.marks.replace(None);
 		let mut to_redraw: HashMap<usize, Pos> = HashMap::new();
 		let mut cur = self.start.clone().node.upgrade().expect("Couldn't get starting node in setquery");
 		let mut line = -(self.start.line as isize);
 		let onscreen = |i: isize| i >= 0 && i < self.size.h as isize;
 		while line < self.size.h as isize {
+			let lines = cur.lock().expect("Poisoned lock").lines(); // Matches past a truncation aren't shown
 			if let Some(search) = cur.lock().expect("Poisoned lock").getsearch().as_ref() {
-				for m in search.matchlines() {
+				for m in search.matchlines().into_iter().filter(|m| *m < lines) {
 					let matchline = line + m as isize;
 					if onscreen(matchline) {
 						to_redraw.insert(matchline as usize, Pos::new(Arc::downgrade(&cur), m));
 					}
 				}
 			}
-			cur.lock().expect("Poisoned lock").search(&self.query);
+			cur.lock().expect("Poisoned lock").search(&self.query, self.searchscope);
 			if self.query.is_some() {
-				for m in cur.lock().expect("Poisoned lock").getsearch().as_ref().expect("Query is empty after calling search").matchlines() {
+				for m in cur.lock().expect("Poisoned lock").getsearch().as_ref().expect("Query is empty after calling search").matchlines().into_iter().filter(|m| *m < lines) {
 					let matchline = line + m as isize;
 					if onscreen(matchline) {
 						to_redraw.insert(matchline as usize, Pos::new(Arc::downgrade(&cur), m));
 					}
 				}
 			}
-			line += cur.lock().expect("Poisoned lock").lines() as isize;
+			line += lines as isize;
 			let next = Node::next(&cur).upgrade();
 			match next {
 				None => break,
@@ -417,9 +842,26 @@ impl<'a> Tree<'a> {
 	}
 
 	fn searchnext(&mut self, offset: isize) {
-		if let Some(q) = &self.query {
+		if let Some(q) = self.query.clone() { // Cloning a `Regex` is cheap
 			let sel = self.sel.upgrade().expect("Couldn't get selection in searchnext");
-			let path = Node::searchfrom(&sel, q, offset * (if self.searchfwd { 1 } else { -1 }));
+			let dir = offset * (if self.searchfwd { 1 } else { -1 });
+			let (path, hitend) = Node::searchfrom(&sel, &q, self.searchscope, dir, self.config.search_wrap != SearchWrap::Stop);
+			if path == sel.lock().expect("Poisoned lock").path() {
+				self.bell();
+				// Staying put on a node that doesn't match means nothing does
+				if !sel.lock().expect("Poisoned lock").matches() {
+					self.echo("Pattern not found".to_string());
+					return;
+				}
+			}
+			if hitend {
+				let (end, other) = if dir > 0 { ("BOTTOM", "TOP") } else { ("TOP", "BOTTOM") };
+				match self.config.search_wrap {
+					SearchWrap::Wrap => (),
+					SearchWrap::Notify => self.echo(format!("Search hit {}, continuing at {}", end, other)),
+					SearchWrap::Stop => self.echo(format!("Search hit {}", end)),
+				}
+			}
 			let mut n = self.root.clone();
 			let mut firstline: Option<isize> = None;
 			for i in path {
@@ -432,7 +874,7 @@ impl<'a> Tree<'a> {
 					if firstline.is_none() {
 						firstline = self.start.dist_fwd(nextsib_pos.clone()).map(|x| x as isize - 1);
 					}
-					Node::expand(&mut n, self.size.w);
+					self.reshape(&mut n, &|n, w| Node::expand(n, w));
 					if Node::is_before(&n, &sel) {
 						if !Node::is_before(&n, &self.start.node.upgrade().expect("Tree has invalid start position")) {
 							// If n was before sel while collapsed, then n must have a next sibling
@@ -444,6 +886,11 @@ impl<'a> Tree<'a> {
 				let target = n.lock().expect("Poisoned lock").children[i].clone();
 				n = target;
 			}
+			if self.config.search_count {
+				let (index, total) = n.lock().expect("Poisoned lock").matchindex(&q, self.searchscope);
+				let count = format!("Match {} of {}", index, total);
+				self.msg = if self.msg.is_empty() { count } else { format!("{} ({})", self.msg, count) };
+			}
 			let mut lastline = cmp::min(self.start.dist_fwd(Pos::nil()).expect("Couldn't find distance from start to end"), self.size.h) as isize;
 			let scrolldist = self.select(n, true);
 			if let Some(mut first) = firstline {
@@ -455,6 +902,11 @@ impl<'a> Tree<'a> {
 					}
 				}
 			}
+			// After skipping several matches, the user has no idea where they landed, so center it
+			if offset.abs() > 1 && scrolldist != 0 {
+				let dist = self.centerdist();
+				self.scroll(dist);
+			}
 		}
 	}
 
@@ -462,12 +914,13 @@ impl<'a> Tree<'a> {
 		if self.check_term_size() {
 			let oldquery = self.query.clone();
 			self.setquery(None);
-			let incsearch = Box::new(|dt: &mut Tree, q: &str| dt.setquery(Self::query_from_str(q)));
+			let incsearch = Box::new(|dt: &mut Tree, q: &str| { dt.setquery(Query::new(q, !dt.config.search_regex, dt.config.search_case)); true });
 			let size = self.size; // For borrowing
 			let palette = self.palette.clone();
+			let cjk = self.config.cjk();
 			let searchhist = self.searchhist.clone(); // Any way to avoid these expensive clones?
 			// We should probably bubble up "non-internal" errors all the way up to the user, just to get nice error traces
-			let res = ::prompt::prompt(self, (size.h, 0), size.w - 20, if forward { "/" } else { "?" }, "", searchhist, incsearch, &palette).expect("Prompt failed");
+			let res = ::prompt::prompt(self, (size.h, 0), size.w - 20, if forward { "/" } else { "?" }, "", searchhist, incsearch, Self::prompt_resize(), &palette, cjk).expect("Prompt failed");
 			if res == "" { self.setquery(oldquery); }
 			else {
 				self.searchhist.push(res);
@@ -483,59 +936,210 @@ impl<'a> Tree<'a> {
 		self.sel = Arc::downgrade(&self.root);
 		self.start = Pos::new(Arc::downgrade(&self.root), 0);
 		self.offset = 0;
-		self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection in setroot"), &|mut sel, w| Node::expand(&mut sel, w));
+		self.recount();
+		self.tabs[self.curtab].folded.clear();
+		let depth = self.config.expand_depth;
+		self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection in setroot"), &|mut sel, w| Node::recursive_expand(&mut sel, w, depth));
 		self.select(self.first(), false);
 		self.drawlines((0, self.size.h));
 	}
 
+	// Like `setroot`, but carries over which nodes were expanded and selected, to the extent that the
+	// same paths exist in the new tree
+	fn transplant(&mut self, root: Arc<Mutex<Node<'a>>>) {
+		let expanded = Node::expanded_paths(&self.root);
+		let selpath = self.sel.upgrade().expect("Couldn't get selection in transplant").lock().expect("Poisoned lock").path();
+		self.setroot(root);
+		let layout = self.layout();
+		for path in expanded {
+			if let Some(mut node) = Node::find(&self.root, &path, layout) { Node::expand(&mut node, layout); }
+		}
+		self.recount();
+		if !selpath.is_empty() {
+			if let Some(sel) = Node::find(&self.root, &selpath, layout) { self.select(sel, true); }
+		}
+		self.drawlines((0, self.size.h));
+	}
+
 	fn transform(&mut self, initq: &str) {
 		if self.check_term_size() {
+			// Find out whether the source can be transformed at all before opening the prompt
+			let layout = self.layout();
+			let settings = self.settings.clone();
+			if let Err(error) = self.source().propose(initq, layout, &settings) {
+				if error.is::<Unsupported>() {
+					self.source().reject();
+					self.echo(error.to_string());
+					return;
+				}
+			}
+			// An invalid filter only colors the prompt, leaving the last good result on screen
 			let incxform = Box::new(|dt: &mut Tree, query: &str| {
-				let root = match dt.source.propose(query, dt.size.w, dt.settings.hide_root) {
-					Ok(tree) => Arc::clone(tree),
-					Err(error) => {
-						let message = error.chain().fold("Error:".to_string(), |acc, x| acc + "\n" + &x.to_string()).to_string();
-						Arc::new(Mutex::new(Node::new_root(Box::new(StatMsg::new(message, 2)), dt.size.w, false)))
+				let layout = dt.layout();
+				let settings = dt.settings.clone();
+				match dt.source().propose(query, layout, &settings) {
+					Ok(tree) => {
+						let root = Arc::clone(tree);
+						dt.transplant(root);
+						true
 					},
-				};
-				dt.setroot(root);
+					Err(_) => false,
+				}
 			});
 			let size = self.size; // For borrowing
 			let palette = self.palette.clone();
+			let cjk = self.config.cjk();
 			let xformhist = self.xformhist.clone();
-			let res = ::prompt::prompt(self, (size.h, 0), size.w - 20, "|", initq, xformhist, incxform, &palette).expect("Prompt failed");
+			let res = ::prompt::prompt(self, (size.h, 0), size.w - 20, "|", initq, xformhist, incxform, Self::prompt_resize(), &palette, cjk).expect("Prompt failed");
 			if res == "" {
-				let root = Arc::clone(self.source.reject());
-				self.setroot(Arc::clone(&root));
+				let root = Arc::clone(self.source().reject());
+				self.transplant(Arc::clone(&root));
 			}
 			else {
-				self.source.accept();
 				self.xformhist.push(res);
+				self.acceptxform();
 			}
 		}
 	}
 
+	// Keep the proposed transformation.  If it failed, the error is shown in place of the tree, a
+	// line to a node so that a long one can be scrolled through, and the next transformation starts
+	// from the last one that worked.
+	fn acceptxform(&mut self) {
+		if let Err(error) = self.source().accept() {
+			self.source().reject();
+			let root = Node::new_root(Box::new(Failed(format!("Error: {}", error))), self.layout(), false, false);
+			self.setroot(Arc::new(Mutex::new(root)));
+		}
+	}
+
+	fn switchtab(&mut self, idx: usize) {
+		if idx != self.curtab {
+			self.tabs[self.curtab].view = Some((Arc::clone(&self.root), self.sel.clone(), self.start.clone(), self.offset));
+			self.curtab = idx;
+			match self.tabs[idx].view.take() {
+				Some((root, sel, start, offset)) => {
+					self.root = root;
+					self.sel = sel;
+					self.start = start;
+					self.offset = offset;
+				},
+				None => {
+					let root = Arc::clone(self.source().reject());
+					self.setroot(root);
+				},
+			}
+			self.resize(); // The layout may have changed while this tab was in the background
+		}
+	}
+
+	// Select the node at `path`, expanding its ancestors.  Returns false if there's no such node.
+	fn follow(&mut self, path: &[usize]) -> bool {
+		let layout = self.layout();
+		match Node::find(&self.root, path, layout) {
+			None => false,
+			Some(target) => {
+				self.recount();
+				// Expanding the target's ancestors may have moved the selection down the screen
+				let curpos = Pos::new(self.sel.clone(), 0);
+				self.offset = match self.start.dist_fwd(curpos.clone()) {
+					Some(dist) => dist as isize,
+					None => -(curpos.dist_fwd(self.start.clone()).expect("Could not determine new offset in follow") as isize),
+				};
+				let target = if Arc::ptr_eq(&target, &self.root) { self.first() } else { target };
+				self.select(target, true);
+				self.drawlines((0, self.size.h));
+				true
+			},
+		}
+	}
+
 	fn invokesel(&mut self) {
-		let sel = self.sel.upgrade().expect("Couldn't get selection in invokesel");
-		sel.lock().expect("Poisoned lock").invoke();
+		let mut sel = self.sel.upgrade().expect("Couldn't get selection in invokesel");
+		let link = sel.lock().expect("Poisoned lock").link_target();
+		if let Some(path) = link {
+			if !self.follow(&path) { self.echo("Link target not found".to_string()); }
+			return;
+		}
+		let result = sel.lock().expect("Poisoned lock").invoke();
+		self.invoked(&mut sel, result);
+	}
+
+	// Carry out what the backend asked for after invoking a node or one of its actions
+	fn invoked(&mut self, sel: &mut Arc<Mutex<Node<'a>>>, result: Result<InvokeResult>) {
+		let mut reload = false;
+		match result {
+			Ok(InvokeResult::Nothing) => (),
+			Ok(InvokeResult::Refresh) => reload = true,
+			Ok(InvokeResult::Message(msg)) => self.echo(msg),
+			Ok(InvokeResult::OpenUrl(url)) => {
+				let browser = std::env::var("BROWSER").unwrap_or("xdg-open".to_string());
+				std::thread::spawn(move || {
+					let _ = std::process::Command::new(browser).arg(url)
+						.stdin(std::process::Stdio::null()).stdout(std::process::Stdio::null()).stderr(std::process::Stdio::null()).status();
+				});
+			},
+			Err(e) => self.echo(e.to_string()),
+		}
+		// Invoking may have changed the value, so pick up its new content.  Unless asked to, expanded
+		// nodes are left alone, since refreshing them would reload all of their children.
+		if reload || sel.lock().expect("Poisoned lock").state != State::Expanded { self.refresh(sel); }
 		self.redraw();
 	}
 
+	fn actionmenu(&mut self) {
+		let mut sel = self.sel.upgrade().expect("Couldn't get selection in actionmenu");
+		let actions = sel.lock().expect("Poisoned lock").actions();
+		if actions.is_empty() {
+			self.invokesel();
+			return;
+		}
+		// Actions are chosen with a single digit, so only the first nine are offered
+		let menu = actions.iter().take(9).enumerate().map(|(i, (name, _))| format!("{}: {}", i + 1, name)).collect::<Vec<String>>();
+		self.echo(menu.join("  "));
+		self.statline();
+		curses::refresh();
+		let choice = match curses::read(-1) {
+			curses::Key::Char(c) => c.to_digit(10).filter(|d| *d > 0).and_then(|d| actions.get(d as usize - 1)).map(|(_, id)| *id),
+			curses::Key::Special(ncurses::KEY_RESIZE) => { self.resize(); None },
+			_ => None,
+		};
+		self.msg.clear();
+		if let Some(id) = choice {
+			let result = sel.lock().expect("Poisoned lock").act(id);
+			self.invoked(&mut sel, result);
+		}
+	}
+
 	fn click(&mut self, y: usize) {
 		let now = time::Instant::now();
 		let oldsel = self.sel.clone();
 		self.selpos(y);
 		if oldsel.ptr_eq(&self.sel) && now.duration_since(self.lastclick).as_millis() < 400 {
-			self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection in click"), &|mut sel, w| Node::toggle(&mut sel, w));
+			self.toggle();
 			self.lastclick = now.checked_sub(time::Duration::from_secs(60)).expect("We're less than 60 seconds after the epoch?"); // Epoch would be better
 		}
 		else { self.lastclick = now; }
 	}
 
+	fn sethover(&mut self, y: usize) {
+		// Terminals don't tell us when the pointer leaves the window, so the closest we can get is
+		// clearing the highlight when it moves off the tree
+		let node = match y < self.size.h {
+			true => self.start.fwd(y, false).node,
+			false => Weak::new(),
+		};
+		if !node.ptr_eq(&self.hover) {
+			self.hover = node;
+			self.drawlines((0, self.size.h));
+		}
+	}
+
 	fn mouse(&mut self, events: Vec<curses::MouseEvent>) {
 		use curses::MouseClick::*;
 		for event in events {
 			match (event.button, event.kind) {
+				(0, Move) => self.sethover(event.y as usize),
 				(1, Press) => self.click(event.y as usize),
 				(4, Press) => { self.scroll(-4); },
 				(5, Press) => { self.scroll(4); },
@@ -562,15 +1166,37 @@ impl<'a> Tree<'a> {
 		}
 	}
 
-	fn yanksel(&self) {
+	fn yanksel(&mut self) {
+		let data = match self.sel.upgrade().expect("Couldn't get selection in yanksel").lock().expect("Poisoned lock").yank() {
+			None => return self.echo("This node can't be yanked".to_string()),
+			Some(ref data) if data.is_empty() => return self.echo("Nothing to yank (excluded)".to_string()),
+			Some(data) => data,
+		};
 		// Swallowing an error getting the clipboard here isn't the best thing, but it's not the worst, and I'm not sure what the
 		// better option is given the policy of no runtime errors during interactive session
 		if let Ok(mut clip) = arboard::Clipboard::new() {
-			let data = self.sel.upgrade().expect("Couldn't get selection in yanksel").lock().expect("Poisoned lock").yank();
 			let _ = clip.set_text(data);
 		}
 	}
 
+	fn yankscreen(&self) {
+		if let Ok(mut clip) = arboard::Clipboard::new() {
+			let mut lines = vec![];
+			let mut cur = self.start.clone();
+			for _ in 0..self.size.h {
+				match cur.node.upgrade() {
+					Some(node) => {
+						let node = node.lock().expect("Poisoned lock");
+						if node.yankable() { lines.push(node.linetext(cur.line)); }
+					},
+					None => break,
+				}
+				cur = cur.fwd(1, false);
+			}
+			let _ = clip.set_text(lines.join("\n"));
+		}
+	}
+
 	fn seek(&self, rel: &dyn Fn(&Arc<Mutex<Node<'a>>>) -> Weak<Mutex<Node<'a>>>) -> Arc<Mutex<Node<'a>>> {
 		let mut ret = self.sel.upgrade().expect("Couldn't get selection in seek");
 		for _ in 1..=self.getnum() {
@@ -578,19 +1204,27 @@ impl<'a> Tree<'a> {
 			if let Some(newret) = next.upgrade() { ret = newret; }
 			else { break; }
 		}
+		if self.sel.ptr_eq(&Arc::downgrade(&ret)) { self.bell(); }
 		ret
 	}
 
 	fn command(&mut self, cmd: &[&str]) -> Result<()> {
 		match &cmd[..] {
 			&["select", dir] => match dir {
-				"prev" => { let sel = self.seek(&|n: &Arc<Mutex<Node<'a>>>| Node::prev(&n).clone()); self.select(sel, true); },
-				"next" => { let sel = self.seek(&|n: &Arc<Mutex<Node<'a>>>| Node::next(&n).clone()); self.select(sel, true); },
+				"prev" => { for _ in 0..self.getnum() { self.stepline(false); } },
+				"next" => { for _ in 0..self.getnum() { self.stepline(true); } },
 				"prevsib" => { let sel = self.seek(&|n: &Arc<Mutex<Node<'a>>>| Node::prevsib(&n).clone()); self.select(sel, true); },
 				"nextsib" => { let sel = self.seek(&|n: &Arc<Mutex<Node<'a>>>| Node::nextsib(&n).clone()); self.select(sel, true); },
 				"parent" => { let sel = self.seek(&|n: &Arc<Mutex<Node<'a>>>| Node::parent(&n).clone()); self.select(sel, true); },
 				"first" => { let sel = self.first(); self.select(sel, true); },
-				"last" => { let sel = self.last(); self.select(sel, true); },
+				"last" if self.numbuf.is_empty() => { let sel = self.last(); self.select(sel, true); },
+				"last" => { // With a count, go to that line of the document, like Vim
+					let line = cmp::min(self.getnum(), self.total());
+					let target = Pos::new(Arc::downgrade(&self.root), 0).fwd(cmp::max(line, 1) - 1, true);
+					self.select(target.node.upgrade().expect("Couldn't find line in document"), true);
+					self.selline = target.line;
+					self.showline();
+				},
 				"top" => { self.selpos(0); },
 				"middle" => { let pos = self.size.h / 2; self.selpos(pos); },
 				"bottom" => { let pos = self.size.h - 1; self.selpos(pos); },
@@ -599,44 +1233,128 @@ impl<'a> Tree<'a> {
 			&["scroll", dir] => match dir {
 				"up" => { self.scroll(-1); },
 				"down" => { self.scroll(1); },
-				"center" => { let dist = self.offset - (self.size.h as isize) / 2; self.scroll(dist); },
+				"left" => { let dist = self.getnum() as isize; self.hscroll(-dist); },
+				"right" => { let dist = self.getnum() as isize; self.hscroll(dist); },
+				"center" => { let dist = self.centerdist(); self.scroll(dist); },
+				"lock" => {
+					self.centered = !self.centered;
+					if self.centered { let dist = self.centerdist(); self.scroll(dist); }
+					self.echo(format!("Centering {}", if self.centered { "on" } else { "off" }));
+				},
 				_ => bail!("Unknown direction"),
 			},
 			&["scroll", dir, frac] => {
-				let numfrac = frac.parse::<usize>().unwrap();
+				let numfrac = frac.parse::<usize>().map_err(|_| anyhow!("Invalid percentage"))?;
 				match dir {
 					"up" => { let dist = self.getnum() * self.size.h * numfrac / 100; self.scroll(-(dist as isize)); },
 					"down" => { let dist = self.getnum() * self.size.h * numfrac / 100; self.scroll(dist as isize); },
+					"left" => { let dist = self.getnum() * self.size.w * numfrac / 100; self.hscroll(-(dist as isize)); },
+					"right" => { let dist = self.getnum() * self.size.w * numfrac / 100; self.hscroll(dist as isize); },
 					_ => bail!("Unknown direction"),
 				};
 			},
 			&["node", act] => match act {
-				"expand" => { self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection"), &|mut sel, w| Node::expand(&mut sel, w)) },
-				"recursive-expand" => { self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection"), &|mut sel, w| Node::recursive_expand(&mut sel, w)) },
-				"collapse" => { self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection"), &|mut sel, _| Node::collapse(&mut sel)) },
-				"toggle" => { self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection"), &|mut sel, w| Node::toggle(&mut sel, w)) },
+				"expand" => if !self.foldroot(true) { self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection"), &|mut sel, w| Node::expand(&mut sel, w)) },
+				"recursive-expand" => { self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection"), &|mut sel, w| Node::recursive_expand(&mut sel, w, usize::MAX)) },
+				"collapse" => if !self.foldroot(false) { self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection"), &|mut sel, _| Node::collapse(&mut sel)) },
+				"collapse-all" => { self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection"), &|mut sel, w| Node::expand_to(&mut sel, w, 1)) },
+				"toggle" => { self.toggle(); },
+				"enter" => { self.enter(); },
+				"smart-collapse" => { self.leave(); },
+				_ => bail!("Unknown action"),
+			},
+			&["node", "collapse-to", depth] => {
+				let depth = depth.parse::<usize>().map_err(|_| anyhow!("Invalid depth"))?;
+				self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection"), &|mut sel, w| Node::expand_to(&mut sel, w, depth));
+			},
+			&["node", "truncate", act] => match act {
+				"on" => { self.truncate(Some(true)); },
+				"off" => { self.truncate(Some(false)); },
+				"toggle" => { self.truncate(None); },
 				_ => bail!("Unknown action"),
 			},
+			&["peek"] => { self.truncate(None); },
 			&["search", act] => match act {
 				"forward" => { self.search(true); },
 				"backward" => { self.search(false); },
 				"next" => { let n = self.getnum() as isize; self.searchnext(n); },
 				"prev" => { let n = -(self.getnum() as isize); self.searchnext(n); },
 				"clear" => { self.setquery(None); },
+				"keys" | "values" | "both" => {
+					self.searchscope = match act {
+						"keys" => Render::Keys,
+						"values" => Render::Values,
+						_ => Render::Search,
+					};
+					let query = self.query.clone();
+					self.setquery(query);
+					self.echo(format!("Searching {}", if act == "both" { "keys and values" } else { act }));
+				},
 				_ => bail!("Unknown action"),
 			}
 			&["transform"] => { self.transform(""); },
-			&["transform", "reset"] => { let root = Arc::clone(self.source.clear()); self.setroot(root); },
+			&["transform", "reset"] => { let root = Arc::clone(self.source().clear()); self.transplant(root); },
+			&["tab", "next"] => { let n = self.tabs.len(); self.switchtab((self.curtab + self.getnum()) % n); },
+			&["tab", "prev"] => { let n = self.tabs.len(); self.switchtab((self.curtab + n - self.getnum() % n) % n); },
+			&["sort", "keys"] => {
+				self.settings.sort_keys = !self.settings.sort_keys;
+				let layout = self.layout();
+				for tab in self.tabs.iter_mut() {
+					tab.source.configure(&self.settings, layout);
+					tab.view = None; // The saved selection no longer exists
+				}
+				let root = Arc::clone(&self.root);
+				self.setroot(root);
+				self.echo(format!("Key sorting {}", if self.settings.sort_keys { "on" } else { "off" }));
+			},
 			&["invoke"] => { self.invokesel(); },
+			&["actions"] => { self.actionmenu(); },
 			&["yank"] => { self.yanksel(); },
+			&["yank", "screen"] => { self.yankscreen(); },
+			&["mark", act, name] => {
+				let mut chars = name.chars();
+				let name = match (chars.next(), chars.next()) {
+					(Some(c), None) => c,
+					_ => bail!("Mark names must be a single character"),
+				};
+				match act {
+					"set" => {
+						let path = self.sel.upgrade().expect("Couldn't get selection in mark").lock().expect("Poisoned lock").path();
+						self.bookmarks.insert(name, path);
+					},
+					"goto" => match self.bookmarks.get(&name).cloned() {
+						Some(path) => if !self.follow(&path) { bail!("Mark {} no longer exists", name) },
+						None => bail!("Mark {} not set", name),
+					},
+					_ => bail!("Unknown action"),
+				}
+			},
 			&["refresh", node] => match node {
-				"root" => { self.refresh(&mut self.root.clone()); self.select(self.first(), true); },
+				"root" => {
+					self.refresh(&mut self.root.clone());
+					// If the selection didn't survive, it's on the root now, which may be hidden
+					if self.sel.upgrade().expect("Couldn't get selection in refresh").lock().expect("Poisoned lock").lines() == 0 { self.select(self.first(), true); }
+				},
 				"current" => { self.refresh(&mut self.sel.upgrade().expect("Couldn't get selection in refresh")); },
 				_ => bail!("Unknown node"),
 			},
 			&["redraw"] => { self.redraw(); },
 			&["command"] => { self.cmdline(); },
+			&["shell"] => { self.shellprompt(); },
+			&["shell", ref args @ ..] => { self.shell(&args.join(" ")); },
 			&["echo", ref args @ ..] => { self.echo(args.join(" ")); },
+			&["set", key, ref value @ ..] if !value.is_empty() => {
+				self.config.set(key, &value.join(" "))?;
+				curses::set_hover(self.config.mouse_hover)?;
+				::backends::set_ctrl_style(self.config.control_chars);
+				if curses::color() {
+					let fg = self.palette.fg().to_vec();
+					self.palette = curses::Palette::new(fg.clone(), super::bg_colors(self.config.transparent_bg, &fg[super::FG_COLORS.len()..]))?;
+				}
+				if !self.config.mouse_hover { self.hover = Weak::new(); }
+				if self.config.wrap != Wrap::Off { self.hoffset = 0; }
+				self.resize(); // Reformat everything in case the layout changed
+			},
 			&["q"] => { *self.quit.lock().expect("Poisoned lock") = true; },
 			&["quit"] => { *self.quit.lock().expect("Poisoned lock") = true; },
 			&["nop"] => { },
@@ -646,10 +1364,79 @@ impl<'a> Tree<'a> {
 		Ok(())
 	}
 
+	// The path to a node from the root, by ID or index, as given to shell commands
+	fn path(&self, node: &Arc<Mutex<Node<'a>>>) -> String {
+		Node::keys_to(&self.root, node, false).unwrap_or(vec![]).into_iter().map(|key| match key {
+			Key::Id(id) => id,
+			Key::Index(i) => i.to_string(),
+		}).collect::<Vec<String>>().join("/")
+	}
+
+	// Run a shell command, with `%y` replaced by the selection's yank text and `%p` by its path from
+	// the root.  A trailing `&` runs it in the background with its output discarded; otherwise, the
+	// terminal is handed over until it finishes.
+	fn shell(&mut self, cmd: &str) {
+		fn quote(s: &str) -> String {
+			format!("'{}'", s.replace('\'', "'\\''"))
+		}
+		let sel = self.sel.upgrade().expect("Couldn't get selection in shell");
+		let yank = sel.lock().expect("Poisoned lock").yank();
+		let path = self.path(&sel);
+		let (cmd, background) = match cmd.trim_end() {
+			cmd if cmd.ends_with('&') => (&cmd[..cmd.len() - 1], true),
+			cmd => (cmd, false),
+		};
+		let mut line = String::new();
+		let mut chars = cmd.chars().peekable();
+		while let Some(c) = chars.next() {
+			match (c, chars.peek()) {
+				('%', Some('y')) => match &yank {
+					Some(yank) => { line.push_str(&quote(yank)); chars.next(); },
+					None => return self.echo("This node can't be yanked".to_string()),
+				},
+				('%', Some('p')) => { line.push_str(&quote(&path)); chars.next(); },
+				('%', Some('%')) => { line.push('%'); chars.next(); },
+				(c, _) => line.push(c),
+			}
+		}
+		let shell = std::env::var("SHELL").unwrap_or("sh".to_string());
+		if background {
+			match Command::new(&shell).arg("-c").arg(&line).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn() {
+				Ok(mut child) => { std::thread::spawn(move || child.wait()); },
+				Err(e) => self.echo(format!("Couldn't run {}: {}", shell, e)),
+			}
+		}
+		else if curses::suspend().is_ok() {
+			// Standard input might be the document we're browsing, so give the command the terminal
+			let run = || -> Result<()> {
+				let tty = || std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty");
+				let status = Command::new(&shell).arg("-c").arg(&line).stdin(tty()?).stdout(tty()?).stderr(tty()?).status()?;
+				let mut out = tty()?;
+				if !status.success() { write!(out, "\n{}", status)?; }
+				write!(out, "\nPress Enter to continue")?;
+				std::io::BufReader::new(out).read_line(&mut String::new())?;
+				Ok(())
+			};
+			let res = run();
+			let _ = curses::resume();
+			self.redraw();
+			if let Err(e) = res { self.echo(format!("Couldn't run {}: {}", shell, e)); }
+		}
+	}
+
+	fn shellprompt(&mut self) {
+		let inccb = Box::new(|_: &mut Tree, _: &str| true);
+		let palette = self.palette.clone();
+		let cjk = self.config.cjk();
+		let res = ::prompt::prompt(self, (self.size.h, 0), self.size.w - 20, "!", "", vec![], inccb, Self::prompt_resize(), &palette, cjk).expect("Prompt failed");
+		if res != "" { self.shell(&res); }
+	}
+
 	fn cmdline(&mut self) {
-		let inccb = Box::new(|_: &mut Tree, _: &str| { });
+		let inccb = Box::new(|_: &mut Tree, _: &str| true);
 		let palette = self.palette.clone();
-		let res = ::prompt::prompt(self, (self.size.h, 0), self.size.w - 20, ":", "", vec![], inccb, &palette).expect("Prompt failed");
+		let cjk = self.config.cjk();
+		let res = ::prompt::prompt(self, (self.size.h, 0), self.size.w - 20, ":", "", vec![], inccb, Self::prompt_resize(), &palette, cjk).expect("Prompt failed");
 		if res != "" {
 			// Someday, we may want to replace this with "real" parsing with Nom.  In that case, be
 			// sure to replace the `cmd.split()` in `interactive()` below as well.
@@ -660,7 +1447,17 @@ impl<'a> Tree<'a> {
 		}
 	}
 
-	pub fn interactive(&mut self) {
+	// Draw the tree for the first time, expanded to the configured depth
+	fn start(&mut self) {
+		self.resize();
+		let depth = self.config.expand_depth;
+		self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection"), &|mut sel, w| Node::recursive_expand(&mut sel, w, depth));
+		self.select(self.first(), false);
+	}
+
+	/// Run the interface until the user quits.  `user_keys` are bindings from the keys file, which
+	/// replace the defaults for the same keys; an empty command just removes the default.
+	pub fn interactive(&mut self, user_keys: &[(Vec<i32>, String)]) {
 		let digits = ('0'..='9').map(|x| vec![x as i32]).collect::<Vec<Vec<i32>>>();
 		let mut keys: Keybinder<Self> = Keybinder::new();
 		let keymap = HashMap::from([
@@ -671,15 +1468,23 @@ impl<'a> Tree<'a> {
 			("Up", "select prev"),
 			("K", "select prevsib"),
 			("p", "select parent"),
-			("g", "select first"),
+			("g g", "select first"),
+			("Home", "select first"),
 			("G", "select last"),
+			("End", "select last"),
+			("g t", "tab next"),
+			("g T", "tab prev"),
 			("H", "select top"),
 			("M", "select middle"),
 			("L", "select bottom"),
 			("\\ ", "node toggle"),
-			("Left", "node collapse"),
+			("Left", "node smart-collapse"),
+			("h", "node smart-collapse"),
 			("Right", "node expand"),
+			("l", "node enter"),
 			("x", "node recursive-expand"),
+			("X", "node collapse-all"),
+			("t", "peek"),
 			("^F", "scroll down 100"),
 			("Next", "scroll down 100"),
 			("^B", "scroll up 100"),
@@ -689,6 +1494,10 @@ impl<'a> Tree<'a> {
 			("^E", "scroll down"),
 			("^Y", "scroll up"),
 			("z z", "scroll center"),
+			("z l", "scroll right"),
+			("z h", "scroll left"),
+			("z L", "scroll right 50"),
+			("z H", "scroll left 50"),
 			("/", "search forward"),
 			("?", "search backward"),
 			("n", "search next"),
@@ -700,25 +1509,41 @@ impl<'a> Tree<'a> {
 			("R", "refresh root"),
 			("y", "yank"),
 			("\n", "invoke"),
+			("a", "actions"),
 			("^L", "redraw"),
 			(":", "command"),
+			("!", "shell"),
 			("q", "quit"),
 		]);
+		let mut bindings = vec![];
 		for (key, cmd) in keymap {
-			let cmdparts = cmd.split(' ').collect::<Vec<&str>>();
 			match curses::parse_keysyms(key) {
-				Ok(keyseq) => { keys.register(&[&keyseq], Box::new(move |dt, _| { if let Err(e) = dt.command(&cmdparts) { dt.echo(e.to_string()); } })); },
+				Ok(keyseq) => bindings.push((keyseq, cmd.to_string())),
 				Err(e) => self.echo(e.to_string()),
 			}
 		}
+		for c in ('a'..='z').chain('A'..='Z') {
+			bindings.push((vec!['m' as i32, c as i32], format!("mark set {}", c)));
+			bindings.push((vec!['\'' as i32, c as i32], format!("mark goto {}", c)));
+		}
+		for (keyseq, cmd) in user_keys {
+			bindings.retain(|(k, _)| k != keyseq);
+			if cmd != "" { bindings.push((keyseq.clone(), cmd.clone())); }
+		}
+		for (keyseq, cmd) in bindings {
+			keys.register(&[&keyseq], Box::new(move |dt, _| {
+				let cmdparts = cmd.split(' ').collect::<Vec<&str>>();
+				if let Err(e) = dt.command(&cmdparts) { dt.echo(e.to_string()); }
+			}));
+		}
 		keys.register(&digits.iter().map(|x| &x[..]).collect::<Vec<&[i32]>>(), Box::new(|dt, digit| dt.addnum(digit[0] as u8 as char)));
 		keys.register(&[&[ncurses::KEY_RESIZE]], Box::new(|dt, _| { dt.resize(); }));
 		keys.register(&[&[ncurses::KEY_MOUSE]], Box::new(|dt, _| dt.mouse(curses::mouseevents())));
 
-		self.resize();
-		self.accordion(&mut self.sel.upgrade().expect("Couldn't get selection"), &|mut sel, w| Node::expand(&mut sel, w));
-		self.select(self.first(), false);
+		self.start();
 		while !*self.quit.lock().expect("Poisoned lock") {
+			keys.set_timeout(self.config.key_timeout);
+			keys.set_idle(if node::loading() { SPIN_INTERVAL } else { -1 });
 			let (maybe_action, cmd) = keys.wait(self);
 			if let Some(action) = maybe_action {
 				let lambda: &mut dyn FnMut(&mut Self, &[i32]) = &mut *action.borrow_mut();
@@ -726,7 +1551,17 @@ impl<'a> Tree<'a> {
 				let _guard = lock.lock().expect("Poisoned lock");
 				lambda(self, &cmd);
 			}
+			else if cmd.is_empty() { // Nothing was pressed before the idle timeout
+				let lock = Arc::clone(&self.lock);
+				let _guard = lock.lock().expect("Poisoned lock");
+				self.spin();
+			}
 			if !digits.contains(&cmd) { self.numbuf.clear(); }
+			if let Some(color) = self.palette.undefined() {
+				let reserved = super::FG_COLORS.len();
+				self.echo(format!("Backend used color {}, but only defines {}", color - reserved, self.palette.fg().len() - reserved));
+			}
+			self.drawgutter();
 			self.statline();
 			self.msg.clear();
 		}
@@ -734,3 +1569,370 @@ impl<'a> Tree<'a> {
 }
 
 unsafe impl<'a> Sync for Tree<'a> { }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ::backends::json::JsonSource;
+	use ::config::SearchCase;
+
+	fn tree(json: &str, size: curses::Size) -> (Tree<'static>, std::rc::Rc<curses::Grid>) {
+		let grid = curses::headless(size);
+		let source = JsonSource::read(json.as_bytes()).expect("Invalid test JSON");
+		let mut dt = Tree::new(vec![("test".to_string(), source)], vec![], Settings::default(), &[]).expect("Failed to create tree");
+		dt.start();
+		(dt, grid)
+	}
+
+	#[test]
+	fn headless() {
+		// The last line of the screen is the status bar
+		let (mut dt, grid) = tree(r#"{"a": [1, 2], "b": "x"}"#, curses::Size { w: 30, h: 5 });
+		assert_eq!(grid.lines(), vec!["root", "├── a: [1, 2]", "└── b: x", "", ""]);
+		dt.command(&["select", "next"]).expect("Command failed");
+		dt.command(&["node", "expand"]).expect("Command failed");
+		assert_eq!(grid.lines(), vec!["root", "├── a", "│   ├── 0: 1", "│   └── 1: 2", ""]);
+		dt.command(&["select", "last"]).expect("Command failed");
+		assert_eq!(grid.lines(), vec!["├── a", "│   ├── 0: 1", "│   └── 1: 2", "└── b: x", ""]);
+		dt.command(&["echo", "hello"]).expect("Command failed");
+		dt.statline();
+		assert_eq!(grid.lines()[4], "hello");
+	}
+
+	#[test]
+	fn centered() {
+		let json = format!("[{}]", (0..20).map(|i| i.to_string()).collect::<Vec<String>>().join(", "));
+		let (mut dt, grid) = tree(&json, curses::Size { w: 30, h: 7 });
+		dt.command(&["scroll", "lock"]).expect("Command failed");
+		dt.statline();
+		assert!(grid.lines()[6].ends_with("[centered]"));
+		dt.command(&["select", "next"]).expect("Command failed");
+		assert_eq!(dt.offset, 1); // Can't scroll above the top
+		for _ in 0..5 { dt.command(&["select", "next"]).expect("Command failed"); }
+		assert_eq!(dt.offset, 3);
+		assert_eq!(grid.lines()[3], "├── 5: 5");
+		dt.command(&["select", "last"]).expect("Command failed");
+		assert_eq!(grid.lines()[5], "└── 19: 19"); // The end of the document stays at the bottom
+		dt.command(&["scroll", "lock"]).expect("Command failed");
+		dt.command(&["select", "first"]).expect("Command failed");
+		dt.command(&["select", "next"]).expect("Command failed");
+		assert_eq!(dt.offset, 1);
+	}
+
+	#[test]
+	fn select_ends() {
+		let json = format!("[{}]", (0..20).map(|i| i.to_string()).collect::<Vec<String>>().join(", "));
+		let (mut dt, grid) = tree(&json, curses::Size { w: 30, h: 7 });
+		dt.config.set("scrolloff", "2").expect("Invalid setting");
+		dt.command(&["select", "last"]).expect("Command failed");
+		assert_eq!(dt.offset, 5);
+		assert_eq!(grid.lines()[5], "└── 19: 19"); // Not scrolled up by the margin past the end
+		dt.command(&["select", "first"]).expect("Command failed");
+		assert_eq!(dt.offset, 0);
+		assert_eq!(grid.lines()[0], "root");
+		dt.command(&["select", "last"]).expect("Command failed");
+		dt.command(&["select", "prev"]).expect("Command failed");
+		assert_eq!(dt.offset, 4); // Moving within the last screenful doesn't scroll
+		dt.command(&["set", "scroll-past-end", "on"]).expect("Command failed");
+		dt.command(&["select", "first"]).expect("Command failed");
+		dt.command(&["select", "last"]).expect("Command failed");
+		assert_eq!(dt.offset, 3); // The margin is kept below the last node
+		assert_eq!(grid.lines()[3], "└── 19: 19");
+		for _ in 0..3 { dt.command(&["scroll", "down"]).expect("Command failed"); }
+		assert_eq!(grid.lines()[0], "└── 19: 19"); // The last node can reach the top
+	}
+
+	#[test]
+	fn center_ends() {
+		let json = format!("[{}]", (0..20).map(|i| i.to_string()).collect::<Vec<String>>().join(", "));
+		let (mut dt, grid) = tree(&json, curses::Size { w: 30, h: 7 });
+		dt.command(&["select", "last"]).expect("Command failed");
+		dt.command(&["scroll", "center"]).expect("Command failed");
+		assert_eq!(dt.offset, 5); // Centering doesn't scroll past the end of the document
+		assert_eq!(grid.lines()[5], "└── 19: 19");
+		dt.command(&["select", "first"]).expect("Command failed");
+		dt.setquery(Query::new("9", true, SearchCase::Sensitive));
+		dt.addnum('2');
+		dt.command(&["search", "next"]).expect("Command failed");
+		assert_eq!(dt.offset, 5); // Nor does centering after a counted jump
+		assert_eq!(grid.lines()[5], "└── 19: 19");
+	}
+
+	#[test]
+	fn empty_marker() {
+		let (mut dt, grid) = tree(r#"{"a": {}, "b": 1}"#, curses::Size { w: 30, h: 6 });
+		dt.command(&["select", "next"]).expect("Command failed");
+		dt.command(&["node", "expand"]).expect("Command failed");
+		assert_eq!(grid.lines(), vec!["root", "├── a", "│   └── (empty)", "└── b: 1", "", ""]);
+		dt.command(&["select", "last"]).expect("Command failed");
+		assert_eq!(grid.lines()[3], "└── b: 1");
+		dt.command(&["select", "prev"]).expect("Command failed");
+		dt.command(&["select", "prev"]).expect("Command failed");
+		dt.command(&["node", "collapse"]).expect("Command failed");
+		assert_eq!(grid.lines(), vec!["root", "├── a: { }", "└── b: 1", "", "", ""]);
+	}
+
+	static LOADS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0); // Times any `Fanout` has loaded its children
+
+	// Has as many children like itself as the number it holds
+	struct Fanout(usize);
+
+	impl<'a> ::interface::Value<'a> for Fanout {
+		fn content(&self) -> Format { fmt::lit("fanout") }
+		fn expandable(&self) -> bool { true }
+		fn children(&self) -> Vec<Box<dyn (::interface::Value<'a>) + 'a>> {
+			LOADS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+			(0..self.0).map(|_| Box::new(Fanout(self.0)) as Box<dyn (::interface::Value<'a>) + 'a>).collect()
+		}
+	}
+
+	impl Source for Fanout {
+		fn root<'a>(&'a self) -> Box<dyn (::interface::Value<'a>) + 'a> { Box::new(Fanout(self.0)) }
+	}
+
+	#[test]
+	fn lazy_counts() {
+		let loads = || LOADS.load(std::sync::atomic::Ordering::Relaxed);
+		let grid = curses::headless(curses::Size { w: 30, h: 4 });
+		let mut dt = Tree::new(vec![("test".to_string(), Box::new(Fanout(20)) as Box<dyn Source>)], vec![], Settings { show_counts: true, ..Settings::default() }, &[]).expect("Failed to create tree");
+		dt.start();
+		assert_eq!(grid.lines(), vec!["fanout", "├── fanout (20)", "├── fanout (20)", ""]);
+		assert_eq!(loads(), 3); // The root, and then only the children on the screen
+		dt.command(&["scroll", "down"]).expect("Command failed");
+		assert_eq!(loads(), 4);
+		dt.command(&["select", "last"]).expect("Command failed");
+		assert_eq!(grid.lines(), vec!["├── fanout (20)", "├── fanout (20)", "└── fanout (20)", ""]);
+		assert_eq!(loads(), 7);
+	}
+
+	#[test]
+	fn wide_array() {
+		// Expanding a huge array only builds a node for each range, and then each element of the one
+		// range that is expanded
+		let json = format!("[{}]", vec!["0"; 100_000].join(", "));
+		let (mut dt, grid) = tree(&json, curses::Size { w: 30, h: 5 });
+		assert_eq!(dt.root.lock().expect("Poisoned lock").children.len(), 100);
+		assert_eq!(dt.total(), 101);
+		dt.command(&["select", "last"]).expect("Command failed");
+		dt.command(&["node", "expand"]).expect("Command failed");
+		assert_eq!(dt.total(), 1101);
+		dt.command(&["select", "last"]).expect("Command failed");
+		assert_eq!(grid.lines()[3], "    └── 99999: 0");
+	}
+
+	#[test]
+	fn loading() {
+		let (mut dt, grid) = tree(r#"{"a": [1, 2], "b": "x"}"#, curses::Size { w: 30, h: 5 });
+		dt.command(&["select", "next"]).expect("Command failed");
+		let mut sel = dt.sel.upgrade().expect("No selection");
+		Node::mark_loading(&mut sel, dt.layout());
+		assert!(node::loading());
+		dt.spin();
+		let lines = grid.lines();
+		assert_eq!((&lines[..2], &lines[3..]), (&["root".to_string(), "├── a".to_string()][..], &["└── b: x".to_string(), "".to_string()][..]));
+		assert!(lines[2].starts_with("│   └── ") && lines[2].ends_with(" loading…"), "Unexpected loading line {:?}", lines[2]);
+		dt.spin();
+		assert_ne!(grid.lines()[2], lines[2]);
+		Node::load_children(&mut sel, dt.layout());
+		Node::finish_loading(&mut sel);
+		dt.spin();
+		assert_eq!(grid.lines(), vec!["root", "├── a", "│   ├── 0: 1", "│   └── 1: 2", ""]);
+	}
+
+	#[test]
+	fn range_path() {
+		let json = format!(r#"{{"a": [{}]}}"#, vec!["0"; 1600].join(", "));
+		let (mut dt, _grid) = tree(&json, curses::Size { w: 30, h: 5 });
+		dt.command(&["select", "next"]).expect("Command failed");
+		dt.command(&["node", "expand"]).expect("Command failed");
+		dt.command(&["select", "last"]).expect("Command failed");
+		dt.command(&["node", "expand"]).expect("Command failed");
+		dt.command(&["select", "last"]).expect("Command failed");
+		let sel = dt.sel.upgrade().expect("No selection");
+		assert_eq!(dt.path(&sel), "a/1599"); // Not through the range holding it
+	}
+
+	#[test]
+	fn smart_collapse() {
+		let (mut dt, grid) = tree(r#"{"a": [1, 2], "b": "x"}"#, curses::Size { w: 30, h: 6 });
+		dt.command(&["select", "next"]).expect("Command failed");
+		dt.command(&["node", "enter"]).expect("Command failed");
+		dt.command(&["select", "next"]).expect("Command failed");
+		dt.command(&["node", "smart-collapse"]).expect("Command failed"); // On a leaf, go to the parent
+		assert_eq!(grid.lines(), vec!["root", "├── a", "│   ├── 0: 1", "│   └── 1: 2", "└── b: x", ""]);
+		dt.command(&["node", "smart-collapse"]).expect("Command failed"); // Then collapse it
+		assert_eq!(grid.lines(), vec!["root", "├── a: [1, 2]", "└── b: x", "", "", ""]);
+		dt.command(&["node", "smart-collapse"]).expect("Command failed");
+		dt.command(&["node", "smart-collapse"]).expect("Command failed");
+		assert_eq!(grid.lines(), vec!["{ a: [...], b: x }", "", "", "", "", ""]);
+	}
+
+	struct Greeter;
+
+	impl<'a> ::interface::Value<'a> for Greeter {
+		fn content(&self) -> Format { fmt::lit("greeter") }
+		fn expandable(&self) -> bool { false }
+		fn children(&self) -> Vec<Box<dyn (::interface::Value<'a>) + 'a>> { vec![] }
+		fn invoke(&self) -> InvokeResult { InvokeResult::Message("hello".to_string()) }
+	}
+
+	impl Source for Greeter {
+		fn root<'a>(&'a self) -> Box<dyn (::interface::Value<'a>) + 'a> { Box::new(Greeter) }
+	}
+
+	#[test]
+	fn invoke_message() {
+		let grid = curses::headless(curses::Size { w: 30, h: 3 });
+		let mut dt = Tree::new(vec![("test".to_string(), Box::new(Greeter) as Box<dyn Source>)], vec![], Settings::default(), &[]).expect("Failed to create tree");
+		dt.start();
+		dt.command(&["invoke"]).expect("Command failed");
+		dt.statline();
+		assert_eq!(grid.lines(), vec!["greeter", "", "hello"]);
+	}
+
+	struct Broken;
+
+	impl Source for Broken {
+		fn root<'a>(&'a self) -> Box<dyn (::interface::Value<'a>) + 'a> { Box::new(Greeter) }
+		fn transform(&self, _transformation: &str) -> Result<Box<dyn Source>> {
+			Err(anyhow!("unexpected token").context("at line 1, column 3").context("Filter failed"))
+		}
+	}
+
+	#[test]
+	fn transform_error() {
+		let grid = curses::headless(curses::Size { w: 30, h: 5 });
+		let mut dt = Tree::new(vec![("test".to_string(), Box::new(Broken) as Box<dyn Source>)], vec![], Settings::default(), &[]).expect("Failed to create tree");
+		dt.start();
+		let (layout, settings) = (dt.layout(), dt.settings.clone());
+		assert!(dt.source().propose("x", layout, &settings).is_err());
+		dt.acceptxform();
+		assert_eq!(grid.lines(), vec!["Error: Filter failed", "├── at line 1, column 3", "└── unexpected token", "", ""]);
+		dt.command(&["transform", "reset"]).expect("Command failed");
+		assert_eq!(grid.lines()[0], "greeter");
+	}
+
+	#[test]
+	fn not_found() {
+		let (mut dt, grid) = tree(r#"{"a": [1, 2], "b": "x"}"#, curses::Size { w: 30, h: 5 });
+		dt.setquery(Query::new("zzz", false, SearchCase::Sensitive));
+		dt.command(&["search", "next"]).expect("Command failed");
+		dt.statline();
+		assert_eq!(grid.lines()[4], "Pattern not found");
+		dt.msg.clear();
+		dt.setquery(Query::new("x", false, SearchCase::Sensitive));
+		dt.command(&["search", "next"]).expect("Command failed");
+		dt.statline();
+		assert_eq!(grid.lines()[4], "");
+		assert_eq!(dt.sel.upgrade().expect("No selection").lock().expect("Poisoned lock").path(), vec![1]);
+	}
+
+	#[test]
+	fn bookmarks() {
+		let (mut dt, grid) = tree(r#"{"a": [1, 2], "b": "x"}"#, curses::Size { w: 30, h: 5 });
+		let selpath = |dt: &Tree| dt.sel.upgrade().expect("No selection").lock().expect("Poisoned lock").path();
+		dt.command(&["select", "next"]).expect("Command failed");
+		dt.command(&["node", "expand"]).expect("Command failed");
+		dt.command(&["select", "next"]).expect("Command failed");
+		dt.command(&["mark", "set", "x"]).expect("Command failed");
+		dt.command(&["node", "smart-collapse"]).expect("Command failed");
+		dt.command(&["node", "collapse"]).expect("Command failed");
+		dt.command(&["select", "last"]).expect("Command failed");
+		dt.command(&["mark", "goto", "x"]).expect("Command failed");
+		assert_eq!(selpath(&dt), vec![0, 0]);
+		assert_eq!(grid.lines(), vec!["root", "├── a", "│   ├── 0: 1", "│   └── 1: 2", ""]);
+		assert!(dt.command(&["mark", "goto", "y"]).is_err());
+		// A mark can outlive its node, for instance after a transformation
+		dt.bookmarks.insert('z', vec![5]);
+		assert_eq!(dt.command(&["mark", "goto", "z"]).map_err(|e| e.to_string()), Err("Mark z no longer exists".to_string()));
+		assert_eq!(selpath(&dt), vec![0, 0]);
+	}
+
+	#[test]
+	fn breadcrumb() {
+		let (mut dt, grid) = tree(r#"{"users": [{"address": "here"}]}"#, curses::Size { w: 40, h: 5 });
+		dt.command(&["set", "breadcrumb", "on"]).expect("Command failed");
+		for _ in 0..3 {
+			dt.command(&["select", "next"]).expect("Command failed");
+			dt.command(&["node", "expand"]).expect("Command failed");
+		}
+		dt.statline();
+		assert_eq!(grid.lines()[4], "root > users > 0 > address");
+		dt.command(&["set", "status-format", "%p of the way"]).expect("Command failed");
+		dt.statline();
+		assert_eq!(grid.lines()[4].trim_end(), "… > 0 > address  100% of the way");
+		dt.command(&["echo", "hello"]).expect("Command failed");
+		dt.statline();
+		assert_eq!(grid.lines()[4].trim_end(), "hello            100% of the way");
+	}
+
+	#[test]
+	fn collapse_to() {
+		let (mut dt, grid) = tree(r#"{"a": {"b": {"c": 1}}, "d": [1]}"#, curses::Size { w: 30, h: 8 });
+		dt.command(&["node", "recursive-expand"]).expect("Command failed");
+		assert_eq!(grid.lines(), vec!["root", "├── a", "│   └── b", "│       └── c: 1", "└── d", "    └── 0: 1", "", ""]);
+		dt.command(&["node", "collapse-to", "2"]).expect("Command failed");
+		assert_eq!(grid.lines(), vec!["root", "├── a", "│   └── b: { c: 1 }", "└── d", "    └── 0: 1", "", "", ""]);
+		dt.command(&["node", "collapse-all"]).expect("Command failed");
+		assert_eq!(grid.lines(), vec!["root", "├── a: { b: {...} }", "└── d: [1]", "", "", "", "", ""]);
+		assert!(dt.command(&["node", "collapse-to", "deep"]).is_err());
+	}
+
+	#[test]
+	fn total() {
+		let json = r#"{"a": [1, [2, 3], {"b": [4, 5, 6], "c": "x"}], "d": {"e": [7, {"f": 8}], "g": [9, 10]}, "h": [[11], [12, 13]]}"#;
+		let (mut dt, _grid) = tree(json, curses::Size { w: 30, h: 6 });
+		let cmds: &[&[&str]] = &[&["select", "next"], &["select", "prev"], &["select", "nextsib"], &["select", "parent"], &["select", "next"],
+			&["node", "toggle"], &["node", "expand"], &["node", "collapse"], &["node", "recursive-expand"], &["node", "collapse-all"]];
+		let mut seed = 12345usize;
+		let mut checked = 0;
+		for _ in 0..300 {
+			seed = seed.wrapping_mul(1103515245).wrapping_add(12345) % (1 << 31);
+			dt.total(); // Count the document, so that the following command has to keep the count up to date
+			dt.command(cmds[seed % cmds.len()]).expect("Command failed");
+			if let Some(total) = dt.total.get() {
+				assert_eq!(total, Pos::new(Arc::downgrade(&dt.root), 0).dist_fwd(Pos::nil()).expect("Couldn't find end of document"));
+				checked += 1;
+			}
+		}
+		assert!(checked > 200); // Only folding the root should need a full recount
+	}
+
+	#[test]
+	fn hscroll() {
+		let (mut dt, grid) = tree(r#"{"a": "abcdefghijklmnopqrstuvwxyz0123456789", "b": "x"}"#, curses::Size { w: 24, h: 5 });
+		assert_eq!(grid.lines(), vec!["root", "├── a: abcdefghijklmnopq", "│   rstuvwxyz0123456789", "└── b: x", ""]);
+		dt.command(&["set", "wrap", "off"]).expect("Command failed");
+		assert_eq!(grid.lines(), vec!["root", "├── a: abcdefghijklmnopq", "└── b: x", "", ""]);
+		dt.numbuf = vec!['5'];
+		dt.command(&["scroll", "right"]).expect("Command failed");
+		assert_eq!(grid.lines(), vec!["", "├── cdefghijklmnopqrstuv", "└──", "", ""]);
+		dt.command(&["scroll", "left", "50"]).expect("Command failed");
+		assert_eq!(grid.lines()[1], "├── a: abcdefghijklmnopq");
+		assert!(dt.command(&["scroll", "right", "x"]).is_err());
+		dt.command(&["set", "wrap", "screen"]).expect("Command failed");
+		dt.command(&["scroll", "right"]).expect("Command failed");
+		assert_eq!(grid.lines()[1], "├── a: abcdefghijklmnopq");
+	}
+
+	#[test]
+	fn wrap_column() {
+		let (mut dt, grid) = tree(r#"{"a": "abcdefghijklmnop", "b": ["abcdefghijklmnop"]}"#, curses::Size { w: 30, h: 8 });
+		dt.command(&["set", "wrap", "10"]).expect("Command failed");
+		dt.command(&["select", "last"]).expect("Command failed");
+		dt.command(&["node", "expand"]).expect("Command failed");
+		// Every level gets the same width of content, however deep it's indented
+		assert_eq!(grid.lines(), vec!["root", "├── a: abcdefg", "│   hijklmnop", "└── b", "    └── 0: abcdefg", "        hijklmnop", "", ""]);
+		assert!(dt.command(&["set", "wrap", "0"]).is_err());
+	}
+
+	#[test]
+	fn indent() {
+		let (mut dt, grid) = tree(r#"{"a": [1, 2], "b": "x"}"#, curses::Size { w: 30, h: 6 });
+		dt.command(&["select", "next"]).expect("Command failed");
+		dt.command(&["node", "expand"]).expect("Command failed");
+		dt.command(&["set", "indent", "2"]).expect("Command failed");
+		assert_eq!(grid.lines(), vec!["root", "├ a", "│ ├ 0: 1", "│ └ 1: 2", "└ b: x", ""]);
+		assert!(dt.command(&["set", "indent", "1"]).is_err());
+	}
+}
@@ -1,26 +1,128 @@
-use std::sync::{Arc, Mutex};
-use ::regex::Regex;
-use ::interface::Format;
+use std::cell::Cell;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, Once};
+use std::sync::atomic::{AtomicBool, Ordering};
+use ::regex::{Regex, RegexBuilder};
+use ::config::SearchCase;
 use ::format::FmtCmd;
+use ::interface::{Format, Render};
+use anyhow::Result;
 
 type BackendValue<'a> = Box<dyn (::interface::Value<'a>) + 'a>;
 
-// FIXME! Both tb and its plugins need to be able to access the FmtCmd type.  However, I don't want
-// to include all of the formatting code in FmtCmd's impl, especially since that will require
-// drawing in the regex crate, a bunch of curses commands, and a ton of other junk that isn't
-// necessary to instantiate FmtCmds, which is all that's necessary from the plugin's side.  I'd
-// rather not pull the majority of my display code into the interface library, because I imagine it
-// will inflate the sizes of plugins quite a bit.  Until I figure out a more elegant solution, I'm
-// providing a dummy struct with the same interface in the interface library, and doing a deep-copy
-// conversion to the full-featured struct here.  Ick.
-fn fmtcmd_from_format(fmt: Format) -> FmtCmd {
-	match fmt {
-		Format::Literal(s) => FmtCmd::Literal(s),
-		Format::Container(v) => FmtCmd::Container(v.into_iter().map(|x| fmtcmd_from_format(x)).collect()),
-		Format::Color(c, v) => FmtCmd::Color(c, Box::new(fmtcmd_from_format(*v))),
-		Format::RawColor(c, v) => FmtCmd::RawColor(c, Box::new(fmtcmd_from_format(*v))),
-		Format::NoBreak(v) => FmtCmd::NoBreak(Box::new(fmtcmd_from_format(*v))),
-		Format::Exclude(r, v) => FmtCmd::Exclude(r, Box::new(fmtcmd_from_format(*v))),
+/// When set, a panic in the backend aborts the program as it used to, rather than being shown in
+/// the tree.  Useful for getting a backtrace when debugging a backend.
+pub static FAIL_FAST: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+	static GUARDED: Cell<bool> = Cell::new(false); // Whether a panic on this thread will be caught by `guard`
+}
+
+// Call into the backend, turning a panic into an error message.  The default panic hook would
+// print the message over the display, so it is silenced while a guarded call is running.
+fn guard<T>(f: impl FnOnce() -> T) -> ::std::result::Result<T, String> {
+	static HOOK: Once = Once::new();
+	if FAIL_FAST.load(Ordering::Relaxed) { return Ok(f()); }
+	HOOK.call_once(|| {
+		let prev = std::panic::take_hook();
+		std::panic::set_hook(Box::new(move |info| if !GUARDED.with(|g| g.get()) { prev(info) }));
+	});
+	let outer = GUARDED.with(|g| g.replace(true));
+	let ret = catch_unwind(AssertUnwindSafe(f));
+	GUARDED.with(|g| g.set(outer));
+	ret.map_err(|payload| {
+		let msg = payload.downcast_ref::<&str>().map(|s| s.to_string()).or(payload.downcast_ref::<String>().cloned()).unwrap_or("unknown error".to_string());
+		format!("Backend panicked: {}", msg)
+	})
+}
+
+/// Stands in for the children of a value whose backend failed or panicked while listing them, or for
+/// the whole tree when a transformation fails.  Only the first line of a long message is shown, with
+/// the rest as children, so they can be browsed.
+pub struct Failed(pub String);
+
+impl<'a> ::interface::Value<'a> for Failed {
+	fn content(&self) -> Format {
+		failure(self.0.lines().next().unwrap_or(""))
+	}
+
+	fn expandable(&self) -> bool {
+		self.0.trim_end().contains('\n')
+	}
+
+	fn children(&self) -> Vec<BackendValue<'a>> {
+		self.0.trim_end().lines().skip(1).map(|line| Box::new(Failed(line.to_string())) as BackendValue<'a>).collect()
+	}
+}
+
+// The child of an expandable value that has no children of its own
+struct Empty;
+
+impl<'a> ::interface::Value<'a> for Empty {
+	fn content(&self) -> Format {
+		::interface::fmt::hide(::interface::fmt::muted(::interface::fmt::lit("(empty)")))
+	}
+
+	fn expandable(&self) -> bool {
+		false
+	}
+
+	fn children(&self) -> Vec<BackendValue<'a>> {
+		vec![]
+	}
+}
+
+fn failure(msg: &str) -> Format {
+	Format::RawColor(::interface::FG_ERROR, Box::new(::interface::fmt::lit(msg)))
+}
+
+/// What to search for.  A query starting with `type:KIND` matches values the backend says are of
+/// that kind, optionally narrowed further by text after the kind, as in `type:string foo`.
+#[derive(Clone)]
+pub struct Query {
+	pub text: Option<Arc<Regex>>, // Shared so that copies of the same query compare cheaply
+	pub kind: Option<String>,
+	insensitive: bool, // Whether `text` ignores case, which its pattern alone doesn't say
+}
+
+impl PartialEq for Query {
+	fn eq(&self, other: &Self) -> bool {
+		let text = match (&self.text, &other.text) {
+			(Some(a), Some(b)) => Arc::ptr_eq(a, b) || a.as_str() == b.as_str(),
+			(a, b) => a.is_none() && b.is_none(),
+		};
+		text && self.kind == other.kind && self.insensitive == other.insensitive
+	}
+}
+
+impl Query {
+	pub fn new(query: &str, literal: bool, case: SearchCase) -> Option<Self> {
+		let (kind, text) = match query.starts_with("type:") {
+			true => {
+				let rest = &query["type:".len()..];
+				let split = rest.find(char::is_whitespace).unwrap_or(rest.len());
+				(Some(rest[..split].to_string()), rest[split..].trim_start())
+			},
+			false => (None, query),
+		};
+		let insensitive = match case {
+			SearchCase::Smart => !text.chars().any(char::is_uppercase),
+			SearchCase::Sensitive => false,
+			SearchCase::Insensitive => true,
+		};
+		let build = |pattern: &str| RegexBuilder::new(pattern).case_insensitive(insensitive).build();
+		let text = match text {
+			"" => None::<Regex>,
+			q if literal => Some(build(&::regex::escape(q)).expect("Regex construction failed even after escaping")),
+			q => Some(build(q).unwrap_or(build(&::regex::escape(q)).expect("Regex construction failed even after escaping"))),
+		};
+		if text.is_none() && kind.is_none() { None }
+		else { Some(Query { text: text.map(Arc::new), kind: kind, insensitive: insensitive }) }
+	}
+
+	pub fn matches(&self, value: &Value, scope: Render) -> bool {
+		self.kind.as_ref().map(|kind| value.matches_kind(kind)).unwrap_or(true)
+			&& self.text.as_ref().map(|text| value.content().contains(text, scope)).unwrap_or(true)
 	}
 }
 
@@ -28,7 +130,9 @@ pub struct Value<'a> {
 	v: BackendValue<'a>,
 	pub parent: Option<Arc<Mutex<Value<'a>>>>,
 	pub index: usize,
-	childcache: Option<Vec<Arc<Mutex<Value<'a>>>>>,
+	childcache: Option<Arc<Vec<Arc<Mutex<Value<'a>>>>>>, // Shared so handing out the children doesn't copy the list
+	count: usize, // Number of children in the cache, not counting an `(empty)` marker
+	empty_marker: bool, // Whether to give this value an `(empty)` child if it has no others, from `Settings::show_empty_marker`
 }
 
 impl<'a> PartialEq for Value<'a> {
@@ -49,45 +153,104 @@ impl<'a> Eq for Value<'a> { }
 type Ref<'a> = Arc<Mutex<Value<'a>>>;
 
 impl<'a> Value<'a> {
-	pub fn new_raw(v: BackendValue<'a>, parent: Option<Arc<Mutex<Value<'a>>>>, index: usize) -> Ref<'a> {
-		Arc::new(Mutex::new(Value { v: v, parent: parent, index: index, childcache: None }))
+	pub fn new_raw(v: BackendValue<'a>, parent: Option<Arc<Mutex<Value<'a>>>>, index: usize, empty_marker: bool) -> Ref<'a> {
+		Arc::new(Mutex::new(Value { v: v, parent: parent, index: index, childcache: None, count: 0, empty_marker: empty_marker }))
 	}
 
-	pub fn new_root(v: BackendValue<'a>) -> Ref<'a> {
-		Value::new_raw(v, None, 0)
+	/// The rest of the tree inherits `empty_marker` from the root
+	pub fn new_root(v: BackendValue<'a>, empty_marker: bool) -> Ref<'a> {
+		Value::new_raw(v, None, 0, empty_marker)
 	}
 
 	pub fn placeholder(&self) -> FmtCmd {
-		fmtcmd_from_format(self.v.placeholder())
+		FmtCmd::from_format(guard(|| self.v.placeholder()).unwrap_or_else(|e| failure(&e)))
 	}
 
 	pub fn content(&self) -> FmtCmd {
-		fmtcmd_from_format(self.v.content())
+		FmtCmd::from_format(guard(|| self.v.content()).unwrap_or_else(|e| failure(&e)))
+	}
+
+	pub fn summary(&self) -> Option<FmtCmd> {
+		guard(|| self.v.summary()).unwrap_or_else(|e| Some(failure(&e))).map(FmtCmd::from_format)
 	}
 
 	pub fn expandable(&self) -> bool {
-		self.v.expandable()
+		guard(|| self.v.expandable()).unwrap_or(false)
+	}
+
+	pub fn invoke(&self) -> Result<::interface::InvokeResult> {
+		guard(|| self.v.invoke()).map_err(|e| anyhow!(e))
+	}
+
+	pub fn actions(&self) -> Vec<(String, ::interface::ActionId)> {
+		guard(|| self.v.actions()).unwrap_or(vec![])
+	}
+
+	pub fn act(&self, action: ::interface::ActionId) -> Result<::interface::InvokeResult> {
+		guard(|| self.v.act(action)).map_err(|e| anyhow!(e))
+	}
+
+	pub fn link_target(&self) -> Option<Vec<usize>> {
+		guard(|| self.v.link_target()).unwrap_or(None)
 	}
 
-	pub fn invoke(&self) {
-		self.v.invoke()
+	pub fn id(&self) -> Option<String> {
+		guard(|| self.v.id()).unwrap_or(None)
 	}
 
-	pub fn children(this: &Ref<'a>) -> Vec<Ref<'a>> {
-		fn getchildren<'a>(this: &Ref<'a>) -> Vec<Ref<'a>> {
-			if this.lock().expect("Poisoned lock").v.expandable() {
-				this.lock().expect("Poisoned lock").v.children().into_iter().enumerate()
-					.map(|(i, child)| Value::new_raw(child, Some(this.clone()), i)).collect()
+	pub fn grouping(&self) -> bool {
+		guard(|| self.v.grouping()).unwrap_or(false)
+	}
+
+	pub fn matches_kind(&self, kind: &str) -> bool {
+		guard(|| self.v.matches_kind(kind)).unwrap_or(false)
+	}
+
+	pub fn yankable(&self) -> bool {
+		guard(|| self.v.yankable()).unwrap_or(false)
+	}
+
+	// The text to copy, if it isn't the content
+	pub fn yank(&self) -> Option<FmtCmd> {
+		guard(|| self.v.yank()).unwrap_or_else(|e| Some(failure(&e))).map(FmtCmd::from_format)
+	}
+
+	pub fn children(this: &Ref<'a>) -> Arc<Vec<Ref<'a>>> {
+		fn getchildren<'a>(this: &Ref<'a>) -> (Vec<Ref<'a>>, usize) {
+			if this.lock().expect("Poisoned lock").expandable() {
+				let (children, marker) = {
+					let val = this.lock().expect("Poisoned lock");
+					let children = match guard(|| val.v.try_children()) {
+						Ok(Ok(children)) => children,
+						Ok(Err(e)) => vec![Box::new(Failed(format!("Failed to load: {:#}", e))) as BackendValue<'a>],
+						Err(e) => vec![Box::new(Failed(e)) as BackendValue<'a>],
+					};
+					(children, val.empty_marker)
+				};
+				let count = children.len();
+				let children = match children.is_empty() && marker {
+					true => vec![Box::new(Empty) as BackendValue<'a>],
+					false => children,
+				};
+				(children.into_iter().enumerate().map(|(i, child)| Value::new_raw(child, Some(this.clone()), i, marker)).collect(), count)
 			}
 			else {
-				vec![]
+				(vec![], 0)
 			}
 		}
 		if this.lock().expect("Poisoned lock").childcache.is_none() {
-			let cached = Some(getchildren(this));
-			this.lock().expect("Poisoned lock").childcache = cached;
+			let (children, count) = getchildren(this);
+			let mut locked = this.lock().expect("Poisoned lock");
+			locked.childcache = Some(Arc::new(children));
+			locked.count = count;
 		}
-		this.lock().expect("Poisoned lock").childcache.clone().expect("No cached children")
+		Arc::clone(this.lock().expect("Poisoned lock").childcache.as_ref().expect("No cached children"))
+	}
+
+	/// How many children the backend gives this value, loading them if they haven't been already
+	pub fn count(this: &Ref<'a>) -> usize {
+		Self::children(this);
+		this.lock().expect("Poisoned lock").count
 	}
 
 	pub fn refresh(&mut self) {
@@ -142,27 +305,49 @@ impl<'a> Value<'a> {
 		}
 	}
 
-	// Yet again, I don't trust the recursive solution of this not to overflow.
-	pub fn searchfrom(this: &Ref<'a>, query: &Regex, forward: bool) -> Option<Ref<'a>> {
+	// Yet again, I don't trust the recursive solution of this not to overflow.  Besides the match,
+	// returns whether the search ran off the end of the document, and if `wrap` is false, gives up
+	// there instead of continuing from the other end.
+	pub fn searchfrom(this: &Ref<'a>, query: &Query, scope: Render, forward: bool, wrap: bool) -> (Option<Ref<'a>>, bool) {
 		let mut cur = this.clone();
+		let mut hitend = false;
 		loop {
 			let next = if forward { Self::next(&cur) } else { Self::prev(&cur) };
 			cur = match next {
 				Some(n) => n,
-				None => match forward {
-					true => Self::root(this),
-					false => Self::last(&Self::root(this)),
+				None if !wrap => return (None, true),
+				None => {
+					hitend = true;
+					match forward {
+						true => Self::root(this),
+						false => Self::last(&Self::root(this)),
+					}
 				},
 			};
-			if cur.lock().expect("Poisoned lock").content().contains(query) {
-				return Some(cur);
+			if query.matches(&cur.lock().expect("Poisoned lock"), scope) {
+				return (Some(cur), hitend);
 			}
 			else if Arc::ptr_eq(&cur, this) {
-				return None;
+				return (None, hitend);
 			}
 		}
 	}
 
+	// Returns the one-based position of this value among all matches in the document, and the total
+	// number of matches.  This is a full document scan, so it should only be done on request.
+	pub fn matchindex(this: &Ref<'a>, query: &Query, scope: Render) -> (usize, usize) {
+		let mut cur = Some(Self::root(this));
+		let (mut index, mut total) = (0, 0);
+		while let Some(val) = cur {
+			if query.matches(&val.lock().expect("Poisoned lock"), scope) {
+				total += 1;
+				if Arc::ptr_eq(&val, this) { index = total; }
+			}
+			cur = Self::next(&val);
+		}
+		(index, total)
+	}
+
 	pub fn path(&self) -> Vec<usize> {
 		match &self.parent {
 			None => vec![],
@@ -173,4 +358,117 @@ impl<'a> Value<'a> {
 			}
 		}
 	}
+
+	/// The placeholder text of each value from the root down to this one, as shown on screen (yank
+	/// text would leave out array indices)
+	pub fn breadcrumb(&self) -> Vec<String> {
+		let mut ret = match &self.parent {
+			None => vec![],
+			Some(parent) => parent.lock().expect("Poisoned lock").breadcrumb(),
+		};
+		ret.push(self.placeholder().render(Render::Debug, ""));
+		ret
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use anyhow::Context;
+
+	struct Bomb;
+
+	impl<'a> ::interface::Value<'a> for Bomb {
+		fn content(&self) -> Format { ::interface::fmt::lit("bomb") }
+		fn expandable(&self) -> bool { true }
+		fn children(&self) -> Vec<BackendValue<'a>> { panic!("Boom") }
+		fn invoke(&self) -> ::interface::InvokeResult { panic!("Boom: {}", 42) }
+		fn actions(&self) -> Vec<(String, ::interface::ActionId)> { panic!("Trace:\n  one\n  two\n") }
+	}
+
+	#[test]
+	fn backend_panic() {
+		let root = Value::new_root(Box::new(Bomb), false);
+		let children = Value::children(&root);
+		assert_eq!(children.len(), 1);
+		assert_eq!(children[0].lock().expect("Poisoned lock").content().render(Render::Debug, ""), "Backend panicked: Boom");
+		assert!(!children[0].lock().expect("Poisoned lock").expandable());
+		assert_eq!(root.lock().expect("Poisoned lock").invoke().map_err(|e| e.to_string()), Err("Backend panicked: Boom: 42".to_string()));
+		// Long messages are split into lines that can be browsed
+		let msg = guard(|| ::interface::Value::actions(&Bomb)).expect_err("Panic was not caught");
+		let failed = Value::new_root(Box::new(Failed(msg)), false);
+		assert_eq!(failed.lock().expect("Poisoned lock").content().render(Render::Debug, ""), "Backend panicked: Trace:");
+		let lines = Value::children(&failed).iter().map(|line| line.lock().expect("Poisoned lock").content().render(Render::Debug, "")).collect::<Vec<String>>();
+		assert_eq!(lines, vec!["  one", "  two"]);
+	}
+
+	struct Offline;
+
+	impl<'a> ::interface::Value<'a> for Offline {
+		fn content(&self) -> Format { ::interface::fmt::lit("offline") }
+		fn expandable(&self) -> bool { true }
+		fn children(&self) -> Vec<BackendValue<'a>> { vec![] }
+		fn try_children(&self) -> Result<Vec<BackendValue<'a>>> { Err(anyhow!("timeout")).context("Couldn't fetch replies") }
+	}
+
+	#[test]
+	fn load_error() {
+		let root = Value::new_root(Box::new(Offline), false);
+		let children = Value::children(&root);
+		assert_eq!(children.len(), 1);
+		assert_eq!(children[0].lock().expect("Poisoned lock").content().render(Render::Debug, ""), "Failed to load: Couldn't fetch replies: timeout");
+	}
+
+	#[test]
+	fn search_case() {
+		let matches = |q: &str, case: SearchCase, text: &str| Query::new(q, true, case).and_then(|query| query.text).expect("Empty test query").is_match(text);
+		assert!(matches("abc", SearchCase::Smart, "ABC"));
+		assert!(!matches("Abc", SearchCase::Smart, "ABC"));
+		assert!(matches("Abc", SearchCase::Smart, "Abc"));
+		assert!(!matches("abc", SearchCase::Sensitive, "ABC"));
+		assert!(matches("Abc", SearchCase::Insensitive, "aBC"));
+		// Only the text counts, not the kind
+		assert!(matches("type:Key abc", SearchCase::Smart, "ABC"));
+		// Changing the case mode makes a different query, so cached matches are found again
+		let query = |case: SearchCase| Query::new("abc", true, case);
+		assert!(query(SearchCase::Sensitive) != query(SearchCase::Insensitive));
+		assert!(query(SearchCase::Smart) == query(SearchCase::Insensitive));
+	}
+
+	#[test]
+	fn literal() {
+		let matches = |q: &str, literal: bool, text: &str| Query::new(q, literal, SearchCase::Sensitive).and_then(|query| query.text).expect("Empty test query").is_match(text);
+		assert!(matches("a.b", false, "axb"));
+		assert!(!matches("a.b", true, "axb"));
+		assert!(matches("a.b", true, "a.b"));
+		// Invalid patterns are searched for literally even in regex mode
+		assert!(matches("a(b", false, "a(b"));
+	}
+
+	struct Fanout(usize);
+
+	impl<'a> ::interface::Value<'a> for Fanout {
+		fn content(&self) -> Format { ::interface::fmt::lit(&self.0.to_string()) }
+		fn expandable(&self) -> bool { true }
+		fn children(&self) -> Vec<BackendValue<'a>> { (0..self.0).map(|_| Box::new(Fanout(0)) as BackendValue<'a>).collect() }
+	}
+
+	#[test]
+	fn count() {
+		let root = Value::new_root(Box::new(Fanout(3)), true);
+		assert_eq!(Value::count(&root), 3);
+		let child = Arc::clone(&Value::children(&root)[0]);
+		assert_eq!(Value::children(&child).len(), 1);
+		assert_eq!(Value::count(&child), 0); // Not counting the `(empty)` marker
+	}
+
+	#[test]
+	fn shared_children() {
+		// Callers share the cached list rather than each getting a copy, until the value is refreshed
+		let root = Value::new_root(Box::new(Fanout(3)), false);
+		let children = Value::children(&root);
+		assert!(Arc::ptr_eq(&children, &Value::children(&root)));
+		root.lock().expect("Poisoned lock").refresh();
+		assert!(!Arc::ptr_eq(&children, &Value::children(&root)));
+	}
 }
@@ -3,15 +3,54 @@ use ::curses::Output;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::ops::Bound;
-use ::interface::Render;
+use ::interface::{Attr, Format, Render, Wrap};
 use ::regex::Regex;
 use ::interface::BitFlags;
 use anyhow::Result;
 
 const TABWIDTH: usize = 4;
 
+/// Parameters that control how content is laid out on screen.
+#[derive(Clone, Copy, Debug)]
+pub struct Layout {
+	pub width: usize, // Columns available for content, or 0 for no wrapping
+	pub cjk: bool, // Whether East Asian ambiguous-width characters are double-width
+	pub wrapglyph: Option<char>, // Drawn at the end of lines broken to fit the width
+	pub wordwrap: bool, // Break lines at whitespace rather than in the middle of words where possible
+	pub wrap: Wrap, // Where to break lines; `width` is already narrowed to account for it
+	pub indent: usize, // Columns taken up by each level of the tree
+}
+
+pub fn charwidth(c: char, cjk: bool) -> usize {
+	let width = match cjk {
+		true => unicode_width::UnicodeWidthChar::width_cjk(c),
+		false => unicode_width::UnicodeWidthChar::width(c),
+	};
+	width.unwrap_or(0) as usize
+}
+
+/// Drop the first `skip` columns of a line of output, and anything more than `width` columns after
+/// that.  A wide character cut in half is replaced by spaces.
+pub fn clip(line: Vec<Output>, skip: usize, width: usize, cjk: bool) -> Vec<Output> {
+	let mut col = 0;
+	line.into_iter().map(|item| match item {
+		Output::Str(s) => {
+			let mut ret = String::new();
+			for c in s.chars() {
+				let cw = charwidth(c, cjk);
+				let (start, end) = (std::cmp::max(col, skip), std::cmp::min(col + cw, skip + width));
+				if start == col && end == col + cw { ret.push(c); }
+				else if end > start { ret.extend(std::iter::repeat(' ').take(end - start)); }
+				col += cw;
+			}
+			Output::Str(ret)
+		},
+		item => item,
+	}).collect()
+}
+
+#[derive(Default)]
 pub struct Search {
-	query: Option<Regex>,
 	matches: BTreeMap<usize, BTreeMap<usize, BTreeSet<(usize, usize)>>>, // line, item, start, end
 }
 
@@ -19,77 +58,93 @@ impl Search {
 	pub fn matchlines(&self) -> Vec<usize> {
 		self.matches.iter().map(|(k, _)| *k).collect::<Vec<usize>>()
 	}
-	pub fn query(&self) -> Option<Regex> {
-		self.query.clone()
-	}
 	pub fn matches(&self) -> bool {
 		self.matches.iter().next().is_some()
 	}
 }
 
 pub struct Preformatted {
-	width: usize,
+	layout: Layout,
 	content: Vec<Vec<Output>>,
 	raw: Vec<String>,
+	excl: Vec<BitFlags<Render>>, // Search scopes each chunk of `raw` is excluded from
 	mapping: BTreeMap<(usize, usize), (usize, usize, usize)>,
 }
 
 impl Preformatted {
-	pub fn new(width: usize) -> Self {
-		Preformatted { width: width, content: vec![], raw: vec!["".to_string()], mapping: BTreeMap::new() }
+	pub fn new(layout: Layout) -> Self {
+		Preformatted { layout: layout, content: vec![], raw: vec!["".to_string()], excl: vec![BitFlags::empty()], mapping: BTreeMap::new() }
+	}
+
+	// Start a new chunk of `raw`, so that searches can't match across the boundary
+	fn newchunk(&mut self, excl: BitFlags<Render>) {
+		if self.raw.last() != Some(&"".to_string()) {
+			self.raw.push("".to_string());
+			self.excl.push(excl);
+		}
+		else { *self.excl.last_mut().expect("Found a preformatted with an empty raw") = excl; }
 	}
 
 	pub fn len(&self) -> usize {
 		self.content.len()
 	}
 
-	pub fn write(&self, line: usize, p: &curses::Palette, prefix: Vec<Output>, bg: usize, highlight: usize, search: &Option<Search>) -> Result<()> {
-		// TODO With the way this and `highlight` are implemented, we've restricted ourselves to
-		// one background color for each `Preformatted`, and this is not exposed to the Value
-		// implementer.  We need to do some significant re-implementation to expose background
-		// colors and Curses attributes to the Value, and to make those work efficiently with
-		// highlighting.
-		// Also, `bg` and `highlight` are hardcoded into `Node::drawline`.  That's something to
-		// keep in mind as we rearchitect.
+	pub fn text(&self, line: usize) -> String { // The line as drawn, minus colors
+		self.content[line].iter().filter_map(|item| match item {
+			Output::Str(s) => Some(s.as_str()),
+			_ => None,
+		}).collect()
+	}
+
+	// Only the columns `view.0` through `view.0 + view.1` of the line are drawn, so that lines that
+	// weren't wrapped can be scrolled sideways.  `suffix` is drawn after the end of the line, and
+	// isn't highlighted by searches.
+	pub fn write(&self, line: usize, p: &curses::Palette, prefix: Vec<Output>, suffix: Option<&Preformatted>, bg: usize, highlight: usize, search: &Option<Search>, view: (usize, usize)) -> Result<()> {
+		// `bg` and `highlight` are hardcoded into `Node::drawline`.  That's something to keep in mind
+		// as we rearchitect.
 		let mut all = prefix;
+		all.push(Output::Bg(bg));
+		let mut styled = self.styled(line, bg, highlight, search);
+		if let Some(suffix) = suffix { styled.extend(suffix.styled(0, bg, highlight, &None)); }
+		all.extend(clip(styled, view.0, view.1, self.layout.cjk));
+		all.append(&mut vec![Output::Fill(' '), Output::Fg(0), Output::Bg(0)]);
+		Output::write(&all, p)
+	}
+
+	// The line's content with search matches highlighted.  Backgrounds in the content are the
+	// value's own, with 0 for none.  They only show on the regular background, and search matches
+	// are highlighted over them.
+	fn styled(&self, line: usize, bg: usize, highlight: usize, search: &Option<Search>) -> Vec<Output> {
+		let mut all = vec![];
 		let maybe_line = match search {
 			Some(info) => info.matches.get(&line),
 			None => None,
 		};
-		let content = match maybe_line {
-			Some(matches) => {
-				self.content[line].iter().enumerate().flat_map(|(i, item)| {
-					match matches.get(&i) {
-						None => vec![item.clone()],
-						Some(regions) => {
-							match item {
-								Output::Str(s) => {
-									let mut ret = vec![];
-									let mut last = 0;
-									for (start, end) in regions {
-										ret.append(&mut vec![
-											Output::Str(s[last..*start].to_string()),
-											Output::Bg(highlight),
-											Output::Str(s[*start..*end].to_string()),
-											Output::Bg(bg),
-										]);
-										last = *end;
-									}
-									ret.push(Output::Str(s[last..].to_string()));
-									ret
-								},
-								_ => panic!("Tried to highlight within a non-string"),
-							}
-						},
+		let mut curbg = bg;
+		for (i, item) in self.content[line].iter().enumerate() {
+			match (item, maybe_line.and_then(|matches| matches.get(&i))) {
+				(Output::Bg(c), _) => {
+					curbg = if bg == 0 && *c != 0 { *c } else { bg };
+					all.push(Output::Bg(curbg));
+				},
+				(_, None) => all.push(item.clone()),
+				(Output::Str(s), Some(regions)) => {
+					let mut last = 0;
+					for (start, end) in regions {
+						all.append(&mut vec![
+							Output::Str(s[last..*start].to_string()),
+							Output::Bg(highlight),
+							Output::Str(s[*start..*end].to_string()),
+							Output::Bg(curbg),
+						]);
+						last = *end;
 					}
-				}).collect::<Vec<Output>>()
-			},
-			None => self.content[line].clone(),
-		};
-		all.push(Output::Bg(bg));
-		all.extend(content);
-		all.append(&mut vec![Output::Fill(' '), Output::Fg(0), Output::Bg(0)]);
-		Output::write(&all, p)
+					all.push(Output::Str(s[last..].to_string()));
+				},
+				(_, Some(_)) => panic!("Tried to highlight within a non-string"),
+			}
+		}
+		all
 	}
 
 	fn translate(&self, chunk: usize, idx: usize) -> (usize, usize, usize) {
@@ -99,12 +154,12 @@ impl Preformatted {
 		(v.0, v.1, v.2 + delta)
 	}
 
-	pub fn search(&self, query: &Regex) -> Search {
+	pub fn search(&self, query: &Regex, scope: Render) -> Search {
 		let matchmap = match self.mapping.is_empty() {
 			true => BTreeMap::new(), // No searchable content in this node, so no matches possible
 			false => {
 				// Get absolute start-end pairs for each match
-				let mut matches = self.raw.iter().enumerate().flat_map(|(i, chunk)| {
+				let mut matches = self.raw.iter().enumerate().filter(|(i, _)| !self.excl[*i].contains(scope)).flat_map(|(i, chunk)| {
 					query.find_iter(chunk).map(move |res| (self.translate(i, res.start()), self.translate(i, res.end())))
 				}).peekable();
 
@@ -119,7 +174,7 @@ impl Preformatted {
 								if on {
 									let curend = matches.peek().expect("Lost closing match in search").1;
 									if getlineitem(&curend) > (i, j) {
-										splitpairs.push((i, j, 0, s.chars().count()));
+										splitpairs.push((i, j, 0, s.len()));
 										break;
 									}
 									else {
@@ -153,17 +208,27 @@ impl Preformatted {
 
 				// Place the indices in a nested map for easy access later
 				let mut matchmap: BTreeMap<usize, BTreeMap<usize, BTreeSet<(usize, usize)>>> = BTreeMap::new();
-				for (line, item, start, end) in splitpairs {
+				// A match ending right at the start of an item leaves an empty region there; drop it
+				for (line, item, start, end) in splitpairs.into_iter().filter(|(_, _, start, end)| start < end) {
 					matchmap.entry(line).or_insert(BTreeMap::new()).entry(item).or_insert(BTreeSet::new()).insert((start, end));
 				}
 				matchmap
 			},
 		};
 
-		Search { query: Some(query.clone()), matches: matchmap }
+		Search { matches: matchmap }
 	}
 }
 
+// How text is drawn, as palette indices.  A `bg` of 0 leaves the background up to the node.
+#[derive(Clone, Copy)]
+struct Style<'a> {
+	fg: usize,
+	bg: usize,
+	attrs: BitFlags<Attr>,
+	link: Option<&'a str>, // URL the text links to
+}
+
 #[derive(Debug)]
 pub enum FmtCmd {
 	Literal(String),
@@ -172,10 +237,39 @@ pub enum FmtCmd {
 	RawColor(usize, Box<FmtCmd>),
 	NoBreak(Box<FmtCmd>),
 	Exclude(BitFlags<Render>, Box<FmtCmd>),
+	YankSep(String),
+	Attr(BitFlags<Attr>, Box<FmtCmd>),
+	Background(usize, Box<FmtCmd>),
+	Link(String, BitFlags<Render>, Box<FmtCmd>),
 }
 
 impl FmtCmd {
-	fn internal_format(output: &mut Preformatted, content: &FmtCmd, startcol: usize, color: usize, color_offset: usize, record: bool) -> usize {
+	// FIXME! Both tb and its plugins need to be able to access the FmtCmd type.  However, I don't want
+	// to include all of the formatting code in FmtCmd's impl, especially since that will require
+	// drawing in the regex crate, a bunch of curses commands, and a ton of other junk that isn't
+	// necessary to instantiate FmtCmds, which is all that's necessary from the plugin's side.  I'd
+	// rather not pull the majority of my display code into the interface library, because I imagine it
+	// will inflate the sizes of plugins quite a bit.  Until I figure out a more elegant solution, I'm
+	// providing a dummy struct with the same interface in the interface library, and doing a deep-copy
+	// conversion to the full-featured struct in `from_format`.  Ick.  (For testing, the interface
+	// library does have `render_plain` and `render_text`, which mirror the layout here without any
+	// of the terminal code.)
+	pub fn from_format(fmt: Format) -> FmtCmd {
+		match fmt {
+			Format::Literal(s) => FmtCmd::Literal(s),
+			Format::Container(v) => FmtCmd::Container(v.into_iter().map(|x| Self::from_format(x)).collect()),
+			Format::Color(c, v) => FmtCmd::Color(c, Box::new(Self::from_format(*v))),
+			Format::RawColor(c, v) => FmtCmd::RawColor(c, Box::new(Self::from_format(*v))),
+			Format::NoBreak(v) => FmtCmd::NoBreak(Box::new(Self::from_format(*v))),
+			Format::Exclude(r, v) => FmtCmd::Exclude(r, Box::new(Self::from_format(*v))),
+			Format::YankSep(s) => FmtCmd::YankSep(s),
+			Format::Attr(a, v) => FmtCmd::Attr(a, Box::new(Self::from_format(*v))),
+			Format::Background(c, v) => FmtCmd::Background(c, Box::new(Self::from_format(*v))),
+			Format::Link(url, r, v) => FmtCmd::Link(url, r, Box::new(Self::from_format(*v))),
+		}
+	}
+
+	fn internal_format<'a>(output: &mut Preformatted, content: &'a FmtCmd, startcol: usize, style: Style<'a>, color_offset: usize, bg_offset: usize, record: bool) -> usize {
 		let addchar = |target: &mut Vec<Output>, c: char| {
 			if let Some(Output::Str(ref mut s)) = target.last_mut() { s.push(c); }
 			else { target.push(Output::Str(c.to_string())); }
@@ -198,9 +292,17 @@ impl FmtCmd {
 		};
 		match content {
 			FmtCmd::Literal(value) => {
-				let mut cur = vec![Output::Fg(color)];
+				// Every line starts by setting the colors and attributes, so they carry across wraps.  A
+				// link is also ended at the end of each line, so the indentation of the next isn't part
+				// of it.
+				let mut start = vec![Output::Fg(style.fg), Output::Bg(style.bg), Output::Attr(curses::attrs(style.attrs))];
+				start.extend(style.link.map(|url| Output::Link(Some(url.to_string()))));
+				let mut cur = start.clone();
 				let mut cnt = startcol;
 				let mut need_mapping = true;
+				// Leave room for the wrap glyph on every line, since we don't know which ones will wrap
+				let glyph = output.layout.wrapglyph.map(|g| (g, charwidth(g, output.layout.cjk))).filter(|(_, w)| *w < output.layout.width);
+				let width = output.layout.width - glyph.map(|(_, w)| w).unwrap_or(0);
 				/* Things I dislike about Rust:
 				 * Jeez, I found a few lines of code that were duplicated in a couple places, so I
 				 * just wanted to move them into a closure to reduce the repetition.  Is that
@@ -211,33 +313,54 @@ impl FmtCmd {
 				 * I'm modifying many variables).  This greatly decreases the utility of closures
 				 * as a tool for cutting down on code duplication.
 				 */
-				let newline = |output: &mut Preformatted, cur: &mut Vec<Output>, cnt: &mut usize, need_mapping: &mut bool| {
+				let newline = |output: &mut Preformatted, cur: &mut Vec<Output>, cnt: &mut usize, need_mapping: &mut bool, wrap: bool| {
+					if style.link.is_some() { cur.push(Output::Link(None)); }
+					// The glyph goes after everything else on the line, so it doesn't disturb the mapping
+					if let (true, Some((g, _))) = (wrap, glyph) {
+						cur.append(&mut vec![Output::Fg(1), Output::Attr(curses::attrs(BitFlags::empty())), Output::Str(g.to_string())]);
+					}
 					append(&mut output.content, vec![cur.clone(), vec![]]);
-					*cur = vec![Output::Fg(color)];
+					*cur = start.clone();
 					*cnt = 0;
 					*need_mapping = true;
 				};
-				for c in value.chars() {
+				for (i, c) in value.char_indices() {
+					let mut breakafter = false;
 					match c {
 						'\n' => {
 							addchar(&mut cur, ' ');
-							newline(output, &mut cur, &mut cnt, &mut need_mapping);
+							newline(output, &mut cur, &mut cnt, &mut need_mapping, false);
 						},
 						'\t' => {
-							if output.width > 0 && cnt + TABWIDTH >= output.width {
-								newline(output, &mut cur, &mut cnt, &mut need_mapping);
+							if width > 0 && cnt + TABWIDTH >= width {
+								newline(output, &mut cur, &mut cnt, &mut need_mapping, true);
 							}
 							let efftabw =
-								if output.width == 0 || output.width > TABWIDTH { TABWIDTH }
-								else { output.width };
+								if width == 0 || width > TABWIDTH { TABWIDTH }
+								else { width };
 							cur.push(Output::Str(std::iter::repeat(" ").take(efftabw).collect::<String>()));
 							cnt += TABWIDTH;
 							need_mapping = true;
 						},
+						c if output.layout.wordwrap && width > 0 && c.is_whitespace() => {
+							// Break here if the following word would have to be split on this line but fits on
+							// the next.  Whitespace that doesn't fit at the end of a line is dropped from the
+							// display rather than starting the next one.
+							let cw = charwidth(c, output.layout.cjk);
+							let word = value[i + c.len_utf8()..].chars().take_while(|c| !c.is_whitespace()).map(|c| charwidth(c, output.layout.cjk)).sum::<usize>();
+							if cnt + cw > width {
+								newline(output, &mut cur, &mut cnt, &mut need_mapping, true);
+							}
+							else {
+								addchar(&mut cur, c);
+								cnt += cw;
+								breakafter = word > 0 && cnt + word > width && word <= width;
+							}
+						},
 						c => {
-							let cw = unicode_width::UnicodeWidthChar::width(c).unwrap_or(0) as usize;
-							if output.width > 0 && cnt + cw > output.width {
-								newline(output, &mut cur, &mut cnt, &mut need_mapping);
+							let cw = charwidth(c, output.layout.cjk);
+							if width > 0 && cnt + cw > width {
+								newline(output, &mut cur, &mut cnt, &mut need_mapping, true);
 							}
 							addchar(&mut cur, c);
 							cnt += cw;
@@ -269,46 +392,60 @@ impl FmtCmd {
 							need_mapping = false;
 						}
 					}
+					if breakafter {
+						newline(output, &mut cur, &mut cnt, &mut need_mapping, true);
+					}
 				}
+				if style.link.is_some() { cur.push(Output::Link(None)); }
 				append(&mut output.content, vec![cur]);
 				cnt
 			},
 			FmtCmd::Container(children) => {
 				let mut curcol = startcol;
 				for child in children {
-					curcol = Self::internal_format(output, child, curcol, color, color_offset, record);
+					curcol = Self::internal_format(output, child, curcol, style, color_offset, bg_offset, record);
 				}
 				curcol
 			},
 			FmtCmd::Color(newcolor, child) => {
-				Self::internal_format(output, child, startcol, *newcolor + color_offset, color_offset, record)
+				Self::internal_format(output, child, startcol, Style { fg: *newcolor + color_offset, ..style }, color_offset, bg_offset, record)
 			},
 			FmtCmd::RawColor(newcolor, child) => {
-				Self::internal_format(output, child, startcol, *newcolor, color_offset, record)
+				Self::internal_format(output, child, startcol, Style { fg: *newcolor, ..style }, color_offset, bg_offset, record)
 			},
 			FmtCmd::NoBreak(child) => {
-				let mut sub = Preformatted::new(0);
-				let sublen = Self::internal_format(&mut sub, child, 0, color, color_offset, record);
+				let mut sub = Preformatted::new(Layout { width: 0, ..output.layout });
+				sub.excl = vec![*output.excl.last().expect("Found a preformatted with an empty raw")];
+				let sublen = Self::internal_format(&mut sub, child, 0, style, color_offset, bg_offset, record);
 				match sub.content.len() {
 					0 => startcol,
+					// Too long to fit even on a line of its own, so it has to be broken somewhere.
+					// Lay it out like any other text.
+					1 if output.layout.width > 0 && sublen >= output.layout.width => {
+						Self::internal_format(output, child, startcol, style, color_offset, bg_offset, record)
+					},
 					1 => {
+						let fits = output.layout.width == 0 || sublen <= output.layout.width - startcol;
 						let rawstart = (output.raw.len() - 1, output.raw.last().expect("Found a preformatted with an empty raw").len());
-						let valstart = match output.content.last() {
-							None => (0, 0, 0),
-							Some(outlast) => (output.content.len() - 1, outlast.len(), 0),
+						// Where the first item of the group will end up: the end of the current line if
+						// it fits there, or else the start of a new one
+						let valstart = match (fits, output.content.last()) {
+							(_, None) => (0, 0),
+							(true, Some(outlast)) => (output.content.len() - 1, outlast.len()),
+							(false, Some(_)) => (output.content.len(), 0),
 						};
 						for (k, v) in sub.mapping {
 							let key = (k.0 + rawstart.0, if k.0 == 0 { k.1 + rawstart.1 } else { k.1 });
-							let val = (v.0 + valstart.0, if v.0 == 0 { v.1 + valstart.1 } else { v.1 }, 0);
+							let val = (v.0 + valstart.0, if v.0 == 0 { v.1 + valstart.1 } else { v.1 }, v.2);
 							output.mapping.insert(key, val);
 						}
 						strappend(&mut output.raw, sub.raw);
-						if output.width == 0 || sublen <= output.width - startcol {
+						output.excl.extend(sub.excl.into_iter().skip(1));
+						if fits {
 							append(&mut output.content, sub.content);
 							startcol + sublen
 						}
 						else {
-							assert!(sublen < output.width);
 							output.content.append(&mut sub.content);
 							sublen
 						}
@@ -317,20 +454,34 @@ impl FmtCmd {
 				}
 			},
 			FmtCmd::Exclude(render, child) => {
-				if render.contains(Render::Search) && output.raw.last() != Some(&"".to_string()) {
-					output.raw.push("".to_string());
-				}
-				Self::internal_format(output, child, startcol, color, color_offset, record && !render.contains(Render::Search))
+				// Text restricted to keys or values gets its own chunks, so searches can skip them
+				let outer = *output.excl.last().expect("Found a preformatted with an empty raw");
+				let scope = *render & (Render::Keys | Render::Values);
+				if render.contains(Render::Search) || !scope.is_empty() { output.newchunk(outer | scope); }
+				let ret = Self::internal_format(output, child, startcol, style, color_offset, bg_offset, record && !render.contains(Render::Search));
+				if !scope.is_empty() { output.newchunk(outer); }
+				ret
+			},
+			FmtCmd::YankSep(_) => startcol,
+			FmtCmd::Attr(newattrs, child) => {
+				Self::internal_format(output, child, startcol, Style { attrs: style.attrs | *newattrs, ..style }, color_offset, bg_offset, record)
+			},
+			FmtCmd::Background(newbg, child) => {
+				Self::internal_format(output, child, startcol, Style { bg: *newbg + bg_offset, ..style }, color_offset, bg_offset, record)
+			},
+			FmtCmd::Link(url, _, child) => {
+				Self::internal_format(output, child, startcol, Style { link: Some(url), ..style }, color_offset, bg_offset, record)
 			},
 		}
 	}
 
-	pub fn format(&self, width: usize, color_offset: usize) -> Preformatted {
+	pub fn format(&self, layout: Layout, color_offset: usize, bg_offset: usize) -> Preformatted {
 		const DEBUG: bool = false;
-		let mut ret = Preformatted::new(width);
-		Self::internal_format(&mut ret, self, 0, 0, color_offset, true);
+		let mut ret = Preformatted::new(layout);
+		Self::internal_format(&mut ret, self, 0, Style { fg: 0, bg: 0, attrs: BitFlags::empty(), link: None }, color_offset, bg_offset, true);
 		if ret.raw.last() == Some(&"".to_string()) { // Ick.  This is necessary because searching for anchors (^ and $) causes a panic if we leave empty strings in the raw
 			ret.raw.pop();
+			ret.excl.pop();
 		}
 		if ret.len() == 0 { ret.content.push(vec![]); }
 		if DEBUG {
@@ -351,14 +502,16 @@ impl FmtCmd {
 		ret
 	}
 
-	pub fn contains(&self, query: &Regex) -> bool { // Search a value without having to preformat it
+	pub fn contains(&self, query: &Regex, scope: Render) -> bool { // Search a value without having to preformat it
 		match self {
 			FmtCmd::Literal(value) => query.is_match(value),
-			FmtCmd::Container(children) => children.iter().any(|x| x.contains(query)),
-			FmtCmd::Color(_, child) => child.contains(query),
-			FmtCmd::RawColor(_, child) => child.contains(query),
-			FmtCmd::NoBreak(child) => child.contains(query),
-			FmtCmd::Exclude(r, child) => !r.contains(Render::Search) && child.contains(query),
+			FmtCmd::Container(children) => children.iter().any(|x| x.contains(query, scope)),
+			FmtCmd::Color(_, child) => child.contains(query, scope),
+			FmtCmd::RawColor(_, child) => child.contains(query, scope),
+			FmtCmd::NoBreak(child) | FmtCmd::Attr(_, child) | FmtCmd::Background(_, child) => child.contains(query, scope),
+			FmtCmd::Link(_, _, child) => child.contains(query, scope), // The URL isn't drawn, so there's nothing to highlight
+			FmtCmd::Exclude(r, child) => !r.contains(Render::Search) && !r.contains(scope) && child.contains(query, scope),
+			FmtCmd::YankSep(_) => false,
 		}
 	}
 
@@ -368,11 +521,207 @@ impl FmtCmd {
 			FmtCmd::Container(children) => children.iter().map(|x| x.render(kind, sep)).collect::<Vec<String>>().as_slice().join(sep),
 			FmtCmd::Color(_, child) => child.render(kind, sep),
 			FmtCmd::RawColor(_, child) => child.render(kind, sep),
-			FmtCmd::NoBreak(child) => child.render(kind, sep),
+			FmtCmd::NoBreak(child) | FmtCmd::Attr(_, child) | FmtCmd::Background(_, child) => child.render(kind, sep),
+			FmtCmd::Link(url, r, child) => match r.contains(kind) && kind != Render::Search {
+				true => format!("{} {}", child.render(kind, sep), url),
+				false => child.render(kind, sep),
+			},
 			FmtCmd::Exclude(r, child) => match r.contains(kind) {
 				true => "".to_string(),
 				false => child.render(kind, sep),
-			}
+			},
+			FmtCmd::YankSep(s) => if kind == Render::Yank { s.to_string() } else { "".to_string() },
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ambiguous_width() {
+		assert_eq!(charwidth('a', false), 1);
+		assert_eq!(charwidth('a', true), 1);
+		assert_eq!(charwidth('\u{00b1}', false), 1); // PLUS-MINUS SIGN is ambiguous
+		assert_eq!(charwidth('\u{00b1}', true), 2);
+		assert_eq!(charwidth('\u{4e00}', false), 2);
+		assert_eq!(charwidth('\u{4e00}', true), 2);
+	}
+
+	#[test]
+	fn ambiguous_wrap() {
+		let cmd = FmtCmd::Literal("\u{00b1}\u{00b1}\u{00b1}".to_string());
+		assert_eq!(cmd.format(Layout { width: 4, cjk: false, wrapglyph: None, wordwrap: false, wrap: Wrap::Screen, indent: 4 }, 0, 0).len(), 1);
+		assert_eq!(cmd.format(Layout { width: 4, cjk: true, wrapglyph: None, wordwrap: false, wrap: Wrap::Screen, indent: 4 }, 0, 0).len(), 2);
+	}
+
+	#[test]
+	fn clipping() {
+		let text = |line: Vec<Output>| line.into_iter().filter_map(|item| match item {
+			Output::Str(s) => Some(s),
+			_ => None,
+		}).collect::<String>();
+		let line = vec![Output::Str("ab".to_string()), Output::Fg(1), Output::Str("c\u{4e00}d".to_string())];
+		assert_eq!(text(clip(line.clone(), 0, 80, false)), "abc\u{4e00}d");
+		assert_eq!(text(clip(line.clone(), 1, 3, false)), "bc ");
+		assert_eq!(text(clip(line.clone(), 4, 2, false)), " d");
+		assert!(match clip(line, 10, 2, false)[1] { Output::Fg(1) => true, _ => false }); // Styles are kept even when no text is
+	}
+
+	#[test]
+	fn wrap_glyph() {
+		let cmd = FmtCmd::Literal("abcdef\ngh".to_string());
+		let text = |fmt: &Preformatted| (0..fmt.len()).map(|line| fmt.text(line)).collect::<Vec<String>>();
+		assert_eq!(text(&cmd.format(Layout { width: 4, cjk: false, wrapglyph: None, wordwrap: false, wrap: Wrap::Screen, indent: 4 }, 0, 0)), vec!["abcd", "ef ", "gh"]);
+		let wrapped = cmd.format(Layout { width: 4, cjk: false, wrapglyph: Some('>'), wordwrap: false, wrap: Wrap::Screen, indent: 4 }, 0, 0);
+		assert_eq!(text(&wrapped), vec!["abc>", "def ", "gh"]);
+		assert_eq!(wrapped.raw, vec!["abcdef\ngh"]);
+	}
+
+	#[test]
+	fn word_wrap() {
+		let cmd = FmtCmd::Literal("The quick brown fox jumps over the lazy dog, antidisestablishmentarianism".to_string());
+		let text = |fmt: &Preformatted| (0..fmt.len()).map(|line| fmt.text(line)).collect::<Vec<String>>();
+		let wrapped = cmd.format(Layout { width: 20, cjk: false, wrapglyph: None, wordwrap: true, wrap: Wrap::Screen, indent: 4 }, 0, 0);
+		assert_eq!(text(&wrapped), vec!["The quick brown fox ", "jumps over the lazy ", "dog, antidisestablis", "hmentarianism"]);
+		assert_eq!(highlights(&wrapped, "fox jumps"), vec!["0:fox ", "1:jumps"]);
+		assert_eq!(highlights(&wrapped, "lazy dog"), vec!["1:lazy ", "2:dog"]);
+		let full = FmtCmd::Literal("abcd efgh".to_string()).format(Layout { width: 4, cjk: false, wrapglyph: None, wordwrap: true, wrap: Wrap::Screen, indent: 4 }, 0, 0);
+		assert_eq!(text(&full), vec!["abcd", "efgh"]);
+		assert_eq!(highlights(&full, "d e"), vec!["0:d", "1:e"]);
+	}
+
+	fn highlights(fmt: &Preformatted, query: &str) -> Vec<String> {
+		let search = fmt.search(&Regex::new(query).expect("Invalid test regex"), Render::Search);
+		search.matches.iter().flat_map(|(line, items)| items.iter().flat_map(move |(item, regions)| {
+			regions.iter().map(move |(start, end)| match &fmt.content[*line][*item] {
+				Output::Str(s) => format!("{}:{}", line, &s[*start..*end]),
+				_ => panic!("Match in non-string item"),
+			})
+		})).collect()
+	}
+
+	#[test]
+	fn search_scope() {
+		let part = |scope: Render, s: &str| FmtCmd::Exclude(BitFlags::from(scope), Box::new(FmtCmd::Literal(s.to_string())));
+		let cmd = FmtCmd::Container(vec![part(Render::Values, "name"), FmtCmd::Literal(": ".to_string()), part(Render::Keys, "my name")]);
+		let fmt = cmd.format(Layout { width: 80, cjk: false, wrapglyph: None, wordwrap: false, wrap: Wrap::Screen, indent: 4 }, 0, 0);
+		let scoped = |query: &str, scope: Render| fmt.search(&Regex::new(query).expect("Invalid test regex"), scope).matches.values()
+			.flat_map(|items| items.values().flat_map(|regions| regions.iter().map(|(start, _)| *start))).collect::<Vec<usize>>();
+		assert_eq!(scoped("name", Render::Search), vec![0, 3]);
+		assert_eq!(scoped("name", Render::Keys), vec![0]);
+		assert_eq!(scoped("name", Render::Values), vec![3]);
+		assert_eq!(scoped("e: m", Render::Search), Vec::<usize>::new()); // Keys and values are searched separately
+		assert!(cmd.contains(&Regex::new("my").unwrap(), Render::Values));
+		assert!(!cmd.contains(&Regex::new("my").unwrap(), Render::Keys));
+	}
+
+	#[test]
+	fn render_exclusions() {
+		use ::interface::fmt::*;
+		let cmd = FmtCmd::from_format(cat(vec![noyank(lit("key")), nodebug(hide(lit(": "))), nosearch(lit("value"))]));
+		assert_eq!(cmd.render(Render::Debug, ""), "keyvalue");
+		assert_eq!(cmd.render(Render::Yank, ""), "value");
+		assert_eq!(cmd.render(Render::Search, ""), "key");
+		let sep = FmtCmd::from_format(cat(vec![lit("key"), hide(lit(": ")), yanksep("\t"), lit("value")]));
+		assert_eq!(sep.render(Render::Yank, ""), "key\tvalue");
+		assert_eq!(sep.render(Render::Debug, ""), "key: value");
+		assert_eq!(sep.format(Layout { width: 80, cjk: false, wrapglyph: None, wordwrap: false, wrap: Wrap::Screen, indent: 4 }, 0, 0).text(0), "key: value");
+	}
+
+	#[test]
+	fn attrs_across_wraps() {
+		use ::interface::fmt::*;
+		let cmd = FmtCmd::from_format(cat(vec![lit("a"), bold(cat(vec![lit("bcdef"), underline(lit("g"))])), lit("h")]));
+		let fmt = cmd.format(Layout { width: 4, cjk: false, wrapglyph: None, wordwrap: false, wrap: Wrap::Screen, indent: 4 }, 0, 0);
+		let attrs = |line: usize| fmt.content[line].iter().filter_map(|item| match item {
+			Output::Attr(a) => Some(*a),
+			_ => None,
+		}).collect::<Vec<_>>();
+		let (bold, under) = (curses::attrs(BitFlags::from(Attr::Bold)), curses::attrs(BitFlags::from(Attr::Underline)));
+		assert_eq!(attrs(0), vec![0, bold]);
+		assert_eq!(attrs(1), vec![bold, bold | under, 0]); // The wrapped bold text starts bold again
+		assert_eq!(fmt.text(1), "efgh");
+	}
+
+	#[test]
+	fn backgrounds() {
+		use ::interface::fmt::*;
+		let cmd = FmtCmd::from_format(cat(vec![lit("ab"), background(0, lit("cdef")), lit("g")]));
+		let fmt = cmd.format(Layout { width: 4, cjk: false, wrapglyph: None, wordwrap: false, wrap: Wrap::Screen, indent: 4 }, 0, 10);
+		// Each item is a string shown on a background, with runs split by search matches
+		let runs = |line: usize, bg: usize, search: &Option<Search>| {
+			let mut curbg = bg;
+			fmt.styled(line, bg, 2, search).into_iter().filter_map(|item| match item {
+				Output::Bg(c) => { curbg = c; None },
+				Output::Str(s) if s != "" => Some(format!("{}:{}", curbg, s)),
+				_ => None,
+			}).collect::<Vec<String>>()
+		};
+		assert_eq!(runs(0, 0, &None), vec!["0:ab", "10:cd"]);
+		assert_eq!(runs(1, 0, &None), vec!["10:ef", "0:g"]); // The background continues across the wrap
+		assert_eq!(runs(1, 1, &None), vec!["1:ef", "1:g"]); // The selection takes precedence
+		let search = Some(fmt.search(&Regex::new("e").expect("Invalid test regex"), Render::Search));
+		assert_eq!(runs(1, 0, &search), vec!["2:e", "10:f", "0:g"]); // And so do search matches
+	}
+
+	#[test]
+	fn links() {
+		use ::interface::fmt::*;
+		let cmd = FmtCmd::from_format(cat(vec![lit("a "), link("http://b/", lit("bcdef")), lit("g")]));
+		let fmt = cmd.format(Layout { width: 4, cjk: false, wrapglyph: Some('>'), wordwrap: false, wrap: Wrap::Screen, indent: 4 }, 0, 0);
+		// Each line's link is ended before the line is, and the wrap glyph isn't part of it
+		let runs = |line: usize| fmt.content[line].iter().filter_map(|item| match item {
+			Output::Link(url) => Some(format!("<{}>", url.as_ref().map(|url| url.as_str()).unwrap_or(""))),
+			Output::Str(s) if s != "" => Some(s.to_string()),
+			_ => None,
+		}).collect::<Vec<String>>();
+		assert_eq!(runs(0), vec!["a ", "<http://b/>", "b", "<>", ">"]);
+		assert_eq!(runs(1), vec!["<http://b/>", "cde", "<>", ">"]);
+		assert_eq!(runs(2), vec!["<http://b/>", "f", "<>", "g"]);
+		assert_eq!(highlights(&fmt, "cd"), vec!["1:cd"]);
+		let query = Regex::new("http").expect("Invalid test regex");
+		assert!(!cmd.contains(&query, Render::Search));
+		let text = FmtCmd::from_format(textlink("http://b/", lit("b")));
+		assert!(!text.contains(&query, Render::Search));
+		assert_eq!(text.render(Render::Search, ""), "b");
+		assert_eq!(text.render(Render::Yank, ""), "b http://b/");
+		assert_eq!(text.render(Render::Debug, ""), "b");
+	}
+
+	#[test]
+	fn search_around_nobreak() {
+		let layout = |width| Layout { width: width, cjk: false, wrapglyph: None, wordwrap: false, wrap: Wrap::Screen, indent: 4 };
+		let cmd = FmtCmd::from_format(::backends::fmtstr("abc\x01def\tghé\x02ij", 1));
+		let flat = cmd.format(layout(0), 0, 0);
+		assert_eq!(highlights(&flat, "c"), vec!["0:c"]);
+		assert_eq!(highlights(&flat, "def"), vec!["0:def"]);
+		assert_eq!(highlights(&flat, "gh"), vec!["0:gh"]);
+		assert_eq!(highlights(&flat, "hé"), vec!["0:hé"]);
+		assert_eq!(highlights(&flat, "ij"), vec!["0:ij"]);
+		// The control characters don't fit at the end of the line, so they start a new one
+		let wrapped = cmd.format(layout(4), 0, 0);
+		assert_eq!(highlights(&wrapped, "def"), vec!["1:de", "2:f"]);
+		assert_eq!(highlights(&wrapped, "ghé"), vec!["4:ghé"]);
+		assert_eq!(highlights(&wrapped, "f\tg"), vec!["2:f", "3:    ", "4:g"]);
+		assert_eq!(highlights(&wrapped, "ij"), vec!["5:ij"]);
+		let nobreak = FmtCmd::Container(vec![FmtCmd::Literal("abcde".to_string()), FmtCmd::NoBreak(Box::new(FmtCmd::Literal("x\tyz".to_string())))]);
+		let nbwrapped = nobreak.format(layout(8), 0, 0);
+		assert_eq!(highlights(&nbwrapped, "y"), vec!["1:y"]);
+		assert_eq!(highlights(&nbwrapped, "eab"), Vec::<String>::new());
+	}
+
+	#[test]
+	fn long_nobreak() {
+		let long = (0..40).map(|i| format!("{:05}", i)).collect::<String>();
+		let cmd = FmtCmd::NoBreak(Box::new(FmtCmd::Literal(long.clone())));
+		let fmt = cmd.format(Layout { width: 40, cjk: false, wrapglyph: None, wordwrap: false, wrap: Wrap::Screen, indent: 4 }, 0, 0);
+		let text = (0..fmt.len()).map(|line| fmt.text(line)).collect::<Vec<String>>();
+		assert_eq!(text, (0..5).map(|i| long[i * 40..(i + 1) * 40].to_string()).collect::<Vec<String>>());
+		assert_eq!(highlights(&fmt, "0000700008"), vec!["0:00007", "1:00008"]);
+		// Short ones still move to the next line as a whole
+		let short = FmtCmd::Container(vec![FmtCmd::Literal("x".repeat(30)), FmtCmd::NoBreak(Box::new(FmtCmd::Literal("y".repeat(20))))]);
+		assert_eq!(short.format(Layout { width: 40, cjk: false, wrapglyph: None, wordwrap: false, wrap: Wrap::Screen, indent: 4 }, 0, 0).text(1), "y".repeat(20));
+	}
+}
@@ -25,40 +25,120 @@ impl<T> Node<T> {
 		if path.is_empty() { self.action = Some(action); }
 		else { (*self.children.entry(path[0]).or_insert(Box::new(Node::new()))).assign(&path[1..], action); }
 	}
-	pub fn wait(&mut self, t: &mut T, path: &[i32]) -> (Option<Action<T>>, Vec<i32>) {
-		//if let Some(ref mut a) = self.action { let x: &mut dyn FnMut(&mut T, &[i32]) = &mut *a.borrow_mut(); x(t, path); }
-		// TODO This doesn't invoke intermediate actions, only ones at leaf nodes.  Does that need fixing?
-		if self.children.is_empty() { (self.action.as_ref().map(Rc::clone), path.to_vec()) }
-		else {
-			ncurses::timeout(4000);
-			let next = ncurses::getch();
-			ncurses::timeout(-1);
-			if next == ncurses::ERR { (None, path.to_vec()) }
-			else {
-				let mut nextpath = path.to_vec();
-				nextpath.push(next);
-				match self.children.get_mut(&next) {
-					Some(child) => child.wait(t, &nextpath),
-					None => (None, nextpath.to_vec()),
-				}
-			}
-		}
-	}
 }
 
 pub struct Keybinder<T> {
 	root: Node<T>,
+	timeout: i32, // Milliseconds to wait for the next key of a multi-key binding
+	idle: i32, // Milliseconds to wait for the first key before giving up, or forever if negative
+	pending: Vec<i32>, // Keys that were read but not used, to be handled before reading more
+	getch: Box<dyn FnMut(i32) -> i32>, // Reads a key, waiting at most the given number of milliseconds (forever if negative)
 }
 
 impl<'a, T> Keybinder<T> {
 	pub fn new() -> Self {
-		Keybinder { root: Node::new() }
+		Self::with_input(Box::new(|timeout| {
+			ncurses::timeout(timeout);
+			let key = ncurses::getch();
+			ncurses::timeout(-1);
+			key
+		}))
+	}
+	fn with_input(getch: Box<dyn FnMut(i32) -> i32>) -> Self {
+		Keybinder { root: Node::new(), timeout: 4000, idle: -1, pending: vec![], getch: getch }
+	}
+	pub fn set_timeout(&mut self, timeout: i32) {
+		self.timeout = timeout;
+	}
+	/// Make `wait` return with no action and no keys if nothing is pressed for `idle` milliseconds,
+	/// so that the caller can do periodic work.  A negative value waits forever.
+	pub fn set_idle(&mut self, idle: i32) {
+		self.idle = idle;
 	}
 	pub fn register(&mut self, paths: &[&[i32]], action: Box<dyn FnMut(&mut T, &[i32])>) {
 		let ins = Rc::new(RefCell::new(action));
 		for path in paths { self.root.assign(path, ins.clone()); }
 	}
-	pub fn wait(&mut self, t: &mut T) -> (Option<Action<T>>, Vec<i32>) {
-		self.root.wait(t, &[])
+	pub fn wait(&mut self, _t: &mut T) -> (Option<Action<T>>, Vec<i32>) {
+		// TODO This doesn't invoke intermediate actions, only ones at leaf nodes.  Does that need fixing?
+		let mut node = &self.root;
+		let mut path = vec![];
+		loop {
+			if node.children.is_empty() { return (node.action.as_ref().map(Rc::clone), path); }
+			let timeout = if path.is_empty() { self.idle } else { self.timeout };
+			let next = match self.pending.pop() {
+				Some(key) => key,
+				None => (self.getch)(timeout),
+			};
+			if next == ncurses::ERR { return (None, path); }
+			match node.children.get(&next) {
+				Some(child) => {
+					path.push(next);
+					node = child;
+				},
+				None if path.is_empty() => return (None, vec![next]),
+				None => {
+					// The key doesn't continue the sequence, so it starts the next one instead
+					self.pending.push(next);
+					return (None, path);
+				},
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn run(keys: &str, timeout: i32) -> (Vec<&'static str>, Vec<i32>) {
+		let timeouts = Rc::new(RefCell::new(vec![]));
+		let input_timeouts = Rc::clone(&timeouts);
+		let mut input = keys.chars().map(|c| c as i32).collect::<Vec<i32>>().into_iter();
+		let mut kb: Keybinder<Vec<&'static str>> = Keybinder::with_input(Box::new(move |timeout| {
+			input_timeouts.borrow_mut().push(timeout);
+			input.next().unwrap_or(ncurses::ERR)
+		}));
+		kb.set_timeout(timeout);
+		kb.register(&[&['g' as i32, 'g' as i32]], Box::new(|log, _| log.push("first")));
+		kb.register(&[&['x' as i32]], Box::new(|log, _| log.push("expand")));
+		let mut log = vec![];
+		for _ in 0..keys.len() {
+			if let (Some(action), cmd) = kb.wait(&mut log) {
+				let lambda: &mut dyn FnMut(&mut Vec<&'static str>, &[i32]) = &mut *action.borrow_mut();
+				lambda(&mut log, &cmd);
+			}
+		}
+		let ret = timeouts.borrow().clone();
+		(log, ret)
+	}
+
+	#[test]
+	fn test_sequence() {
+		assert_eq!(run("ggx", 500).0, vec!["first", "expand"]);
+	}
+
+	#[test]
+	fn test_unmatched_after_prefix() {
+		assert_eq!(run("gx", 500).0, vec!["expand"]);
+	}
+
+	#[test]
+	fn test_timeout() {
+		let (log, timeouts) = run("g", 500);
+		assert!(log.is_empty());
+		assert_eq!(timeouts, vec![-1, 500]);
+	}
+
+	#[test]
+	fn test_idle() {
+		let mut kb: Keybinder<()> = Keybinder::with_input(Box::new(|timeout| {
+			assert_eq!(timeout, 100);
+			ncurses::ERR
+		}));
+		kb.register(&[&['x' as i32]], Box::new(|_, _| ()));
+		kb.set_idle(100);
+		let (action, keys) = kb.wait(&mut ());
+		assert!(action.is_none() && keys.is_empty());
 	}
 }
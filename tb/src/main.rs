@@ -11,6 +11,12 @@ extern crate textproto;
 #[macro_use]
 extern crate lazy_static;
 extern crate nom;
+extern crate memmap2;
+extern crate flate2;
+extern crate zstd;
+extern crate serde;
+extern crate serde_yaml;
+extern crate toml;
 
 mod display;
 mod keybinder;
@@ -18,6 +24,7 @@ mod curses;
 mod prompt;
 mod backends;
 mod format;
+mod config;
 
 use interface::*;
 use anyhow::{Context, Error, Result};
@@ -75,8 +82,12 @@ impl Backend {
 
 fn load_plugins() -> Result<Vec<Result<(PathBuf, Library)>>> {
 	let dir = std::env::var("XDG_DATA_HOME").or(std::env::var("HOME").map(|home| home + "/.local/share")).with_context(|| "Couldn't find XDG data home")? + "/" + APPNAME + "/plugins";
-	let entries = std::fs::read_dir(dir.clone()).with_context(|| format!("{} does not exist", dir))?
-		.filter_map(|res| res.ok()).filter(|x| x.path().metadata().map(|y| y.is_file()).unwrap_or(false));
+	// Not having a plugin directory is normal, but failing to read one that exists is worth reporting
+	let entries = match std::fs::read_dir(&dir) {
+		Ok(entries) => entries,
+		Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+		Err(e) => return Err(e).with_context(|| format!("Couldn't read plugin directory {}", dir)),
+	}.filter_map(|res| res.ok()).filter(|x| x.path().metadata().map(|y| y.is_file()).unwrap_or(false));
 	Ok(entries.map(|entry| libloading::Library::new(&entry.path()).map(|x| (entry.path(), x)).with_context(|| format!("Failed to open {} as shared library", entry.path().to_string_lossy()))).collect())
 }
 
@@ -88,7 +99,7 @@ fn info_exit(backends: HashMap<String, Backend>, errors: Vec<Error>) {
 Command-line interactive browser for JSON and other tree-structured data
 Copyright (GPLv3) 2020 Matthew Schauer <https://github.com/showermat/tb>
 
-Usage: {} help|<backend> [backend args...]
+Usage: {} help|<backend> [--transform <query>]... [--dump [--color=auto|always|never]] [--tabs] [--expand <depth>] [--fail-fast] [backend args...]
 
 Available backends:
 {}
@@ -112,9 +123,18 @@ fn main() -> Result<()> {
 		backends::fs::get_factory(),
 		backends::txt::get_factory(),
 		backends::textproto::get_factory(),
+		backends::yaml::get_factory(),
+		backends::toml::get_factory(),
+		backends::recent::get_factory(),
 	];
-	let (plugins, load_errors) = extract_errors(load_plugins().unwrap_or(vec![])); // Do NOT consume `plugins`!  Use `iter`, not `into_iter`.  Otherwise the symbols extracted from it will end up with dangling pointers and you have fun segfault time.
+	let (plugins, load_errors) = extract_errors(load_plugins().unwrap_or_else(|e| vec![Err(e)])); // Do NOT consume `plugins`!  Use `iter`, not `into_iter`.  Otherwise the symbols extracted from it will end up with dangling pointers and you have fun segfault time.
 	let (plugin_backends, factory_errors) = extract_errors(plugins.iter().map(|(path, lib)| unsafe {
+		// Plugins that don't say which version they were built for get the benefit of the doubt
+		if let Ok(version) = lib.get::<unsafe extern fn() -> u32>(b"tb_abi_version") {
+			if version() != interface::ABI_VERSION {
+				bail!("Plugin {} was built for interface version {}, but this is version {}", path.to_string_lossy(), version(), interface::ABI_VERSION);
+			}
+		}
 		let func: Result<libloading::Symbol<unsafe extern fn() -> Vec<Box<dyn Factory>>>> = lib.get(b"get_factories").with_context(|| format!("Couldn't load symbol `get_factories` from shared library {}", path.to_string_lossy()));
 		func.map(move |f| f().into_iter().map(move |factory| Backend::fromfile(path.clone(), factory)))
 	}).collect());
@@ -124,10 +144,11 @@ fn main() -> Result<()> {
 	]).into_iter().map(|x| (x.factory.info().name.to_string(), x)).collect();
 	let errors = itertools::concat(vec![load_errors, factory_errors]);
 
-	let backend_re = regex::Regex::new("^([a-z]+)b$").with_context(|| "Invalid regular expression given for backend extraction")?;
+	let config = config::ConfigFile::load()?;
+	let backend_re = &config.backend_re;
 	let args_owned = std::env::args().collect::<Vec<String>>();
 	let args = args_owned.iter().map(|x| x.as_str()).collect::<Vec<&str>>();
-	let (backend, subargs) =
+	let (name, subargs) =
 		if args.len() == 0 {
 			info_exit(backends, errors);
 			unreachable!();
@@ -135,7 +156,10 @@ fn main() -> Result<()> {
 		else {
 			let mypath = PathBuf::from(args[0]);
 			let callname = mypath.file_name().and_then(|x| x.to_str()).unwrap_or("");
-			if callname == APPNAME || !backend_re.is_match(callname) {
+			if config.aliases.contains_key(callname) {
+				(callname.to_string(), &args[1..])
+			}
+			else if callname == APPNAME || !backend_re.is_match(callname) {
 				if args.len() == 1 || ["help", "-h", "--help"].contains(&args[1]) {
 					info_exit(backends, errors);
 					unreachable!();
@@ -150,13 +174,80 @@ fn main() -> Result<()> {
 				(backend.to_string(), &args[1..])
 			}
 		};
+	// An alias stands for a backend and some arguments to put before the ones given
+	let (backend, subargs) = match config.aliases.get(&name) {
+		Some(alias) => (alias[0].clone(), alias[1..].iter().map(|arg| arg.as_str()).chain(subargs.iter().cloned()).collect::<Vec<&str>>()),
+		None => (name, subargs.to_vec()),
+	};
+
+	// Options that apply to every backend are pulled out before the rest go to the backend
+	let mut dump = false;
+	let mut color = None;
+	let mut tabs = false;
+	let mut expand = None;
+	let mut transforms = vec![];
+	let mut backend_args = vec![];
+	let mut subargs_iter = subargs.iter();
+	while let Some(arg) = subargs_iter.next() {
+		match *arg {
+			"--dump" => dump = true,
+			"--tabs" => tabs = true,
+			"--fail-fast" => display::FAIL_FAST.store(true, std::sync::atomic::Ordering::Relaxed),
+			"--color=auto" => color = None,
+			"--color=always" => color = Some(true),
+			"--color=never" => color = Some(false),
+			arg if arg.starts_with("--color=") => bail!("Invalid value for --color (expected auto, always, or never)"),
+			"--transform" => transforms.push(*subargs_iter.next().ok_or(anyhow!("--transform requires an argument"))?),
+			"--expand" => expand = Some(*subargs_iter.next().ok_or(anyhow!("--expand requires an argument"))?),
+			_ => backend_args.push(*arg),
+		}
+	}
 
 	let factory = &backends.get(&backend).ok_or(anyhow!("Could not find backend \"{}\"", backend))?.factory;
-	if let Some(treeres) = factory.from(subargs) {
-		let tree = treeres?;
+	// The config file overrides the backend's defaults, and `--expand` overrides both the backend's
+	// and the config file's initial expansion depth, and limits how much of the tree is dumped
+	let mut tree_settings = factory.settings();
+	let mut settings = config.resolve(&backend, &mut tree_settings)?;
+	let levels = match expand {
+		Some(depth) => {
+			settings.push(("expand-depth".to_string(), depth.to_string()));
+			let mut check = config::Config::new(&tree_settings);
+			check.set("expand-depth", depth).with_context(|| "Invalid value for --expand")?;
+			check.expand_depth
+		},
+		None => usize::MAX,
+	};
+	// With `--tabs`, each argument is opened separately in its own tab
+	let argsets = match tabs && !backend_args.is_empty() {
+		true => backend_args.iter().map(|arg| vec![*arg]).collect::<Vec<Vec<&str>>>(),
+		false => vec![backend_args],
+	};
+	let mut trees = vec![];
+	for argset in argsets {
+		if let Some(treeres) = factory.from(&argset) {
+			let mut tree = treeres?;
+			for transformation in transforms.iter() {
+				tree = tree.transform(transformation)?;
+			}
+			trees.push((argset.join(" "), tree));
+		}
+	}
+	if !trees.is_empty() && backend != "recent" {
+		let mut invocation = vec![backend.as_str()];
+		invocation.extend(subargs.iter());
+		let _ = backends::recent::record(&invocation); // Not worth failing over
+	}
+	let colors = factory.named_colors().into_iter().map(|(_, color)| color).collect::<Vec<Color>>();
+	if dump {
+		let color = color.unwrap_or_else(|| curses::use_color(curses::stdout_tty()));
+		for (_, tree) in trees {
+			display::dump(tree.as_ref(), tree_settings.clone(), if color { Some(colors.clone()) } else { None }, levels, &mut std::io::stdout().lock())?;
+		}
+	}
+	else if !trees.is_empty() {
 		curses::setup()?;
-		let mut dt = display::Tree::new(tree, factory.colors(), factory.settings())?;
-		if let Err(e) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| dt.interactive())) {
+		let mut dt = display::Tree::new(trees, colors, tree_settings, &settings)?;
+		if let Err(e) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| dt.interactive(&config.keys))) {
 			let _ = curses::cleanup();
 			std::panic::resume_unwind(e);
 		}
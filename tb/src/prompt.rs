@@ -1,6 +1,7 @@
 use ::curses;
 use ::curses::Key;
 use ::curses::Output;
+use ::format;
 use anyhow::Result;
 use nom::IResult;
 use nom::branch::alt;
@@ -16,7 +17,9 @@ struct Prompt<'a, T> {
 	width: usize, // Width of the text area without the prompt
 	prompt: String, // Static text preceding the editing area
 	history: Vec<String>, // Vector of past entries the user can scroll through
-	callback: Box<dyn FnMut(&mut T, &str)>, // Called every time the content changes
+	callback: Box<dyn FnMut(&mut T, &str) -> bool>, // Called every time the content changes; returns whether it is valid
+	onresize: Box<dyn FnMut(&mut T) -> ((usize, usize), usize)>, // Redraws the screen after a resize; returns the new location and width
+	valid: bool, // Whether the callback accepted the current content
 	histidx: usize, // Current location in history
 	buf: Vec<char>, // Contents of editing area
 	pos: usize, // Cursor position in buffer
@@ -25,17 +28,21 @@ struct Prompt<'a, T> {
 	dispn: usize, // Number of characters displayed
 	promptw: usize, // Graphical width of prompt
 	palette: &'a curses::Palette, // The color palette for drawing
+	cjk: bool, // Whether ambiguous-width characters are double-width
+	multiline: bool, // Enter inserts a newline, and ^G submits instead
+	tall: bool, // Whether the content is drawn on several rows rather than scrolling sideways in one
+	rows: usize, // Number of rows the prompt covered when last drawn
 }
 
-fn charwidth(c: char) -> usize {
+fn charwidth(c: char, cjk: bool) -> usize {
 	match c.is_ascii_control() {
 		true => 2,
-		false => unicode_width::UnicodeWidthChar::width(c).unwrap_or(0) as usize,
+		false => format::charwidth(c, cjk),
 	}
 }
 
-fn graphwidth(s: &[char]) -> usize {
-	s.iter().map(|c| charwidth(*c)).sum()
+fn graphwidth(s: &[char], cjk: bool) -> usize {
+	s.iter().map(|c| charwidth(*c, cjk)).sum()
 }
 
 fn printchar(c: char) -> Vec<Output> {
@@ -53,7 +60,7 @@ fn repeat(c: char, n: usize) -> String {
 }
 
 impl<'a, T> Prompt<'a, T> {
-	fn new(t: &'a mut T, location: (usize, usize), width: usize, prompt: &str, init: &str, mut history: Vec<String>, callback: Box<dyn FnMut(&mut T, &str)>, palette: &'a curses::Palette) -> Result<Self> {
+	fn new(t: &'a mut T, location: (usize, usize), width: usize, prompt: &str, init: &str, mut history: Vec<String>, callback: Box<dyn FnMut(&mut T, &str) -> bool>, onresize: Box<dyn FnMut(&mut T) -> ((usize, usize), usize)>, palette: &'a curses::Palette, cjk: bool) -> Result<Self> {
 		history.push(init.to_string());
 		let histlen = history.len();
 		let promptw = prompt.chars().count();
@@ -67,6 +74,8 @@ impl<'a, T> Prompt<'a, T> {
 			prompt: prompt.to_string(),
 			history: history,
 			callback: callback,
+			onresize: onresize,
+			valid: true,
 			histidx: histlen - 1,
 			buf: vec![],
 			pos: 0,
@@ -75,27 +84,40 @@ impl<'a, T> Prompt<'a, T> {
 			dispn: 0,
 			promptw: promptw,
 			palette: palette,
+			cjk: cjk,
+			multiline: false,
+			tall: false,
+			rows: 1,
 		})
 	}
 
 	fn goto(&self, offset: usize) {
-		ncurses::mv(self.location.0 as i32, (self.location.1 + self.promptw + offset) as i32);
+		curses::mv(self.location.0, self.location.1 + self.promptw + offset);
+	}
+
+	fn draw_prompt(&self) -> Result<()> {
+		curses::mv(self.location.0, self.location.1);
+		Output::write(&[Output::Fg(if self.valid { 0 } else { 2 }), Output::Str(self.prompt.clone()), Output::Fg(0)], &self.palette)
 	}
 
 	fn do_callback(&mut self) -> Result<()> {
 		curses::prompt_off()?;
-		(*self.callback)(self.t, &self.buf.iter().collect::<String>());
+		let valid = (*self.callback)(self.t, &self.buf.iter().collect::<String>());
 		curses::prompt_on()?;
-		self.goto(graphwidth(&self.buf[self.offset..self.pos]));
+		if valid != self.valid {
+			self.valid = valid;
+			self.draw_prompt()?;
+		}
+		if !self.tall { self.goto(graphwidth(&self.buf[self.offset..self.pos], self.cjk)); }
 		Ok(())
 	}
 
 	fn draw_from(&mut self, offset: usize) -> Result<()> {
 		let start = std::cmp::max(offset, self.offset);
 		let mut ret = vec![];
-		let mut w = graphwidth(&self.buf[self.offset..start]);
+		let mut w = graphwidth(&self.buf[self.offset..start], self.cjk);
 		for c in self.buf[start..].iter() {
-			let curw = charwidth(*c);
+			let curw = charwidth(*c, self.cjk);
 			if w + curw > self.width { break; }
 			w += curw;
 			ret.append(&mut printchar(*c));
@@ -111,12 +133,12 @@ impl<'a, T> Prompt<'a, T> {
 		if ndelta > 0 {
 			if self.dispn < self.buf.len() && self.pos + delta >= self.offset + self.dispn { // We're going off the right end
 				let dispend =
-					if self.pos + delta < self.buf.len() { charwidth(self.buf[self.pos + delta]) - 1 }
+					if self.pos + delta < self.buf.len() { charwidth(self.buf[self.pos + delta], self.cjk) - 1 }
 					else { 0 };
 				self.dispw = dispend;
 				self.dispn = 0;
 				for c in self.buf[0..self.pos + delta].iter().rev() {
-					let curw = charwidth(*c);
+					let curw = charwidth(*c, self.cjk);
 					if self.dispw + curw >= self.width { break; }
 					self.dispw += curw;
 					self.dispn += 1;
@@ -128,7 +150,7 @@ impl<'a, T> Prompt<'a, T> {
 				self.goto(self.dispw - dispend);
 			}
 			else {
-				curses::move_in_line(graphwidth(&self.buf[self.pos..self.pos + delta]) as isize);
+				curses::move_in_line(graphwidth(&self.buf[self.pos..self.pos + delta], self.cjk) as isize);
 			}
 		}
 		else if ndelta < 0 {
@@ -137,7 +159,7 @@ impl<'a, T> Prompt<'a, T> {
 				self.dispw = 0;
 				self.dispn = 0;
 				for c in self.buf[self.offset..].iter() {
-					let curw = charwidth(*c);
+					let curw = charwidth(*c, self.cjk);
 					if self.dispw + curw > self.width { break; }
 					self.dispw += curw;
 					self.dispn += 1;
@@ -148,7 +170,7 @@ impl<'a, T> Prompt<'a, T> {
 				self.goto(0);
 			}
 			else {
-				curses::move_in_line(-(graphwidth(&self.buf[self.pos - delta..self.pos]) as isize));
+				curses::move_in_line(-(graphwidth(&self.buf[self.pos - delta..self.pos], self.cjk) as isize));
 			}
 		}
 		if ndelta > 0 { self.pos += delta; }
@@ -156,29 +178,125 @@ impl<'a, T> Prompt<'a, T> {
 		Ok(())
 	}
 
-	fn reset(&mut self, value: &str) -> Result<()> {
+	// Show `value` on a single row with the cursor at the end, without running the callback
+	fn load(&mut self, value: &str) -> Result<()> {
 		self.buf = value.chars().collect::<Vec<char>>();
 		self.pos = 0;
 		self.offset = 0;
 		self.dispw = 0;
 		self.dispn = 0;
 		for c in self.buf.iter() {
-			let curw = charwidth(*c);
+			let curw = charwidth(*c, self.cjk);
 			if self.dispw + curw > self.width { break }
 			self.dispw += curw;
 			self.dispn += 1;
 		}
-		ncurses::mv(self.location.0 as i32, self.location.1 as i32);
-		ncurses::addstr(&(self.prompt.clone() + &repeat(' ', self.width)));
+		self.draw_prompt()?;
+		curses::addstr(&repeat(' ', self.width));
 		self.goto(0);
 		//curses::prompt_on();
 		self.draw_from(0)?;
 		let buflen = self.buf.len() as isize;
-		self.seek(buflen)?;
-		self.do_callback()?;
+		self.seek(buflen)
+	}
+
+	fn reset(&mut self, value: &str) -> Result<()> {
+		self.load(value)?;
+		self.do_callback()
+	}
+
+	// Have the caller redraw the screen, which also covers up any rows the prompt grew into
+	fn restore(&mut self) -> Result<()> {
+		curses::prompt_off()?;
+		let (location, width) = (*self.onresize)(self.t);
+		curses::prompt_on()?;
+		self.location = location;
+		self.width = std::cmp::max(width, self.promptw + 1) - self.promptw;
+		self.rows = 1;
+		Ok(())
+	}
+
+	fn resize(&mut self) -> Result<()> {
+		self.restore()?;
+		let (content, pos) = (self.buf.iter().collect::<String>(), self.pos);
+		self.reset(&content)?;
+		let back = pos as isize - self.buf.len() as isize;
+		self.seek(back)
+	}
+
+	// Whether the content needs more than one row: it has newlines, or it's too wide, or the user
+	// asked for multi-line editing
+	fn needs_rows(&self) -> bool {
+		self.multiline || self.buf.contains(&'\n') || graphwidth(&self.buf, self.cjk) >= self.width
+	}
+
+	// Split the buffer into rows for the tall layout, as ranges of indices into it.  Newlines end a
+	// row without being drawn, and the last column is left free for the cursor.
+	fn layout(&self) -> Vec<(usize, usize)> {
+		let mut ret = vec![];
+		let (mut start, mut w) = (0, 0);
+		for (i, c) in self.buf.iter().enumerate() {
+			if *c == '\n' {
+				ret.push((start, i));
+				start = i + 1;
+				w = 0;
+				continue;
+			}
+			let curw = charwidth(*c, self.cjk);
+			if w + curw >= self.width {
+				ret.push((start, i));
+				start = i;
+				w = 0;
+			}
+			w += curw;
+		}
+		ret.push((start, self.buf.len()));
+		ret
+	}
+
+	// Draw the content on as many rows as it needs, growing up from the usual location, up to half
+	// the screen.  Beyond that, the rows around the cursor are shown.
+	fn draw_tall(&mut self) -> Result<()> {
+		let rows = self.layout();
+		let maxrows = std::cmp::max((self.location.0 + 1) / 2, 1);
+		let cur = rows.iter().rposition(|(start, _)| *start <= self.pos).unwrap_or(0);
+		let shown = std::cmp::min(rows.len(), maxrows);
+		let first = std::cmp::min(cur, rows.len() - shown);
+		if shown < self.rows { self.restore()?; }
+		let top = self.location.0 + 1 - shown;
+		for (i, (start, end)) in rows[first..first + shown].iter().enumerate() {
+			curses::mv(top + i, self.location.1);
+			let mut line = match first + i {
+				0 => vec![Output::Fg(if self.valid { 0 } else { 2 }), Output::Str(self.prompt.clone()), Output::Fg(0)],
+				_ => vec![Output::Str(repeat(' ', self.promptw))],
+			};
+			for c in self.buf[*start..*end].iter() { line.append(&mut printchar(*c)); }
+			line.push(Output::Str(repeat(' ', self.width.saturating_sub(graphwidth(&self.buf[*start..*end], self.cjk)))));
+			Output::write(&line, &self.palette)?;
+		}
+		self.rows = shown;
+		curses::mv(top + cur - first, self.location.1 + self.promptw + graphwidth(&self.buf[rows[cur].0..self.pos], self.cjk));
 		Ok(())
 	}
 
+	// Switch between the single-row and tall layouts as the content requires.  The tall layout is
+	// redrawn in full after every change; the single row keeps track of what it shows instead.
+	fn settle(&mut self) -> Result<()> {
+		if self.needs_rows() {
+			self.tall = true;
+			self.offset = 0;
+			self.draw_tall()
+		}
+		else if self.tall {
+			self.tall = false;
+			if self.rows > 1 { self.restore()?; }
+			let (content, pos) = (self.buf.iter().collect::<String>(), self.pos);
+			self.load(&content)?;
+			self.seek(pos as isize - self.buf.len() as isize)
+		}
+		else { Ok(()) }
+	}
+
 	fn histseek(&mut self, by: isize) -> Result<()> {
 		let oldidx = self.histidx;
 		let newidx = std::cmp::max(std::cmp::min(oldidx as isize + by, self.history.len() as isize - 1), 0) as usize;
@@ -191,73 +309,124 @@ impl<'a, T> Prompt<'a, T> {
 		Ok(())
 	}
 
-	fn read(&mut self) -> Result<String> {
-		let init = self.history.last().ok_or(anyhow!("Prompt history is empty"))?.clone();
-		self.reset(&init)?;
-		loop {
-			match curses::read(-1) {
-				Key::Char('\x0a') => return Ok(self.buf.iter().collect::<String>()), // Enter
-				Key::Char('\x7f') | Key::Special(ncurses::KEY_BACKSPACE) => { // Backspace
-					if self.pos <= 0 { continue; }
-					self.seek(-1)?;
-					let rmwidth = charwidth(self.buf[self.pos]);
-					self.buf.remove(self.pos);
-					self.dispw -= rmwidth;
-					self.dispn -= 1;
-					for c in self.buf[self.offset + self.dispn..].iter() {
-						let curw = charwidth(*c);
-						if self.dispw + curw > self.width { break; }
-						self.dispw += curw;
-						self.dispn += 1;
-					}
-					let pos = self.pos;
-					self.draw_from(pos)?;
-					self.do_callback()?;
-				},
-				Key::Special(ncurses::KEY_DC) => { // Delete key
-					if self.pos >= self.buf.len() { continue; }
-					let rmwidth = charwidth(self.buf[self.pos]);
-					self.buf.remove(self.pos);
-					self.dispw -= rmwidth;
+	// Handle a key in the single-row layout, returning the result if it ends the prompt
+	fn edit_line(&mut self, key: Key) -> Result<Option<String>> {
+		match key {
+			Key::Char('\x0a') | Key::Char('\x07') => return Ok(Some(self.buf.iter().collect::<String>())), // Enter, ^G
+			Key::Char('\x0f') => self.multiline = true, // ^O
+			Key::Char('\x7f') | Key::Special(ncurses::KEY_BACKSPACE) => { // Backspace
+				if self.pos <= 0 { return Ok(None); }
+				self.seek(-1)?;
+				let rmwidth = charwidth(self.buf[self.pos], self.cjk);
+				self.buf.remove(self.pos);
+				self.dispw -= rmwidth;
+				self.dispn -= 1;
+				for c in self.buf[self.offset + self.dispn..].iter() {
+					let curw = charwidth(*c, self.cjk);
+					if self.dispw + curw > self.width { break; }
+					self.dispw += curw;
+					self.dispn += 1;
+				}
+				let pos = self.pos;
+				self.draw_from(pos)?;
+				self.do_callback()?;
+			},
+			Key::Special(ncurses::KEY_DC) => { // Delete key
+				if self.pos >= self.buf.len() { return Ok(None); }
+				let rmwidth = charwidth(self.buf[self.pos], self.cjk);
+				self.buf.remove(self.pos);
+				self.dispw -= rmwidth;
+				self.dispn -= 1;
+				let pos = self.pos;
+				self.draw_from(pos)?;
+				self.do_callback()?;
+			}
+			Key::Char('\x01') | Key::Special(ncurses::KEY_HOME) => { let newpos = -(self.pos as isize); self.seek(newpos)?; }, // ^A
+			Key::Char('\x05') | Key::Special(ncurses::KEY_END) => { let newpos = (self.buf.len() - self.pos) as isize; self.seek(newpos)?; }, // ^E
+			Key::Char('\x15') | Key::Special(ncurses::KEY_CLEAR) => { self.reset("")?; }, // ^U
+			Key::Char('\x1b') => { return Ok(Some("".to_string())); }, // Escape
+			Key::Special(ncurses::KEY_RIGHT) => self.seek(1)?,
+			Key::Special(ncurses::KEY_LEFT) => self.seek(-1)?,
+			Key::Special(ncurses::KEY_UP) => self.histseek(-1)?,
+			Key::Special(ncurses::KEY_DOWN) => self.histseek(1)?,
+			Key::Special(ncurses::KEY_RESIZE) => self.resize()?,
+			Key::Char(c) => {
+				self.buf.insert(self.pos, c);
+				self.dispw += charwidth(c, self.cjk);
+				self.dispn += 1;
+				while self.dispw + charwidth(c, self.cjk) > self.width {
+					assert!(self.buf.len() >= self.offset + self.dispn);
+					self.dispw -= charwidth(self.buf[self.offset + self.dispn - 1], self.cjk);
 					self.dispn -= 1;
+				}
+				if self.pos - self.offset < self.dispn {
 					let pos = self.pos;
 					self.draw_from(pos)?;
-					self.do_callback()?;
 				}
-				Key::Char('\x01') | Key::Special(ncurses::KEY_HOME) => { let newpos = -(self.pos as isize); self.seek(newpos)?; }, // ^A
-				Key::Char('\x05') | Key::Special(ncurses::KEY_END) => { let newpos = (self.buf.len() - self.pos) as isize; self.seek(newpos)?; }, // ^E
-				Key::Char('\x15') | Key::Special(ncurses::KEY_CLEAR) => { self.reset("")?; }, // ^U
-				Key::Char('\x1b') => { return Ok("".to_string()); }, // Escape
-				Key::Special(ncurses::KEY_RIGHT) => self.seek(1)?,
-				Key::Special(ncurses::KEY_LEFT) => self.seek(-1)?,
-				Key::Special(ncurses::KEY_UP) => self.histseek(-1)?,
-				Key::Special(ncurses::KEY_DOWN) => self.histseek(1)?,
-				Key::Special(ncurses::KEY_RESIZE) => (),
-				Key::Char(c) => {
-					self.buf.insert(self.pos, c);
-					self.dispw += charwidth(c);
-					self.dispn += 1;
-					while self.dispw + charwidth(c) > self.width {
-						assert!(self.buf.len() >= self.offset + self.dispn);
-						self.dispw -= charwidth(self.buf[self.offset + self.dispn - 1]);
-						self.dispn -= 1;
-					}
-					if self.pos - self.offset < self.dispn {
-						let pos = self.pos;
-						self.draw_from(pos)?;
-					}
-					self.seek(1)?;
-					self.do_callback()?;
-				},
-				_ => (),
+				self.seek(1)?;
+				self.do_callback()?;
+			},
+			_ => (),
+		};
+		Ok(None)
+	}
+
+	// Handle a key in the tall layout, which is redrawn afterward by `settle`
+	fn edit_tall(&mut self, key: Key) -> Result<Option<String>> {
+		match key {
+			Key::Char('\x0a') if self.multiline => { self.buf.insert(self.pos, '\n'); self.pos += 1; self.do_callback()?; }, // Enter
+			Key::Char('\x0a') | Key::Char('\x07') => return Ok(Some(self.buf.iter().collect::<String>())), // Enter, ^G
+			Key::Char('\x0f') => self.multiline = !self.multiline, // ^O
+			Key::Char('\x7f') | Key::Special(ncurses::KEY_BACKSPACE) => if self.pos > 0 {
+				self.pos -= 1;
+				self.buf.remove(self.pos);
+				self.do_callback()?;
+			},
+			Key::Special(ncurses::KEY_DC) => if self.pos < self.buf.len() {
+				self.buf.remove(self.pos);
+				self.do_callback()?;
+			},
+			Key::Char('\x01') | Key::Special(ncurses::KEY_HOME) => self.pos = 0, // ^A
+			Key::Char('\x05') | Key::Special(ncurses::KEY_END) => self.pos = self.buf.len(), // ^E
+			Key::Char('\x15') | Key::Special(ncurses::KEY_CLEAR) => self.reset("")?, // ^U
+			Key::Char('\x1b') => return Ok(Some("".to_string())), // Escape
+			Key::Special(ncurses::KEY_RIGHT) => self.pos = std::cmp::min(self.pos + 1, self.buf.len()),
+			Key::Special(ncurses::KEY_LEFT) => self.pos = self.pos.saturating_sub(1),
+			Key::Special(ncurses::KEY_UP) => self.histseek(-1)?,
+			Key::Special(ncurses::KEY_DOWN) => self.histseek(1)?,
+			Key::Special(ncurses::KEY_RESIZE) => self.resize()?,
+			Key::Char(c) => {
+				self.buf.insert(self.pos, c);
+				self.pos += 1;
+				self.do_callback()?;
+			},
+			_ => (),
+		}
+		Ok(None)
+	}
+
+	fn read(&mut self) -> Result<String> {
+		let init = self.history.last().ok_or(anyhow!("Prompt history is empty"))?.clone();
+		self.reset(&init)?;
+		self.settle()?;
+		loop {
+			let key = curses::read(-1);
+			let done = match self.tall {
+				true => self.edit_tall(key)?,
+				false => self.edit_line(key)?,
 			};
+			if let Some(ret) = done {
+				if self.rows > 1 { self.restore()?; }
+				return Ok(ret);
+			}
+			self.settle()?;
 		}
 	}
 }
 
-pub fn prompt<T>(t: &mut T, location: (usize, usize), width: usize, prompt: &str, init: &str, history: Vec<String>, callback: Box<dyn FnMut(&mut T, &str)>, palette: &curses::Palette) -> Result<String> {
+pub fn prompt<T>(t: &mut T, location: (usize, usize), width: usize, prompt: &str, init: &str, history: Vec<String>, callback: Box<dyn FnMut(&mut T, &str) -> bool>, onresize: Box<dyn FnMut(&mut T) -> ((usize, usize), usize)>, palette: &curses::Palette, cjk: bool) -> Result<String> {
 	curses::prompt_on()?;
-	let ret = Prompt::<T>::new(t, location, width, prompt, init, history, callback, palette)?.read()?;
+	let ret = Prompt::<T>::new(t, location, width, prompt, init, history, callback, onresize, palette, cjk)?.read()?;
 	curses::prompt_off()?;
 	Ok(ret)
 }
@@ -265,3 +434,23 @@ pub fn prompt<T>(t: &mut T, location: (usize, usize), width: usize, prompt: &str
 pub fn tokenize(s: &str) -> Result<Vec<String>> {
 	unimplemented!()
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn layout() {
+		let palette = curses::Palette::mono(vec![]);
+		let mut target = ();
+		let mut p = Prompt::new(&mut target, (10, 0), 12, ">", "", vec![], Box::new(|_, _| true), Box::new(|_| ((10, 0), 12)), &palette, false).expect("Failed to create prompt");
+		p.buf = "abc".chars().collect();
+		assert!(!p.needs_rows());
+		assert_eq!(p.layout(), vec![(0, 3)]);
+		p.buf = "abc\ndefghijklmnop".chars().collect();
+		assert!(p.needs_rows());
+		assert_eq!(p.layout(), vec![(0, 3), (4, 14), (14, 17)]); // The last column is left for the cursor
+		p.buf = "abc\n".chars().collect();
+		assert_eq!(p.layout(), vec![(0, 3), (4, 4)]);
+	}
+}